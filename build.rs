@@ -0,0 +1,9 @@
+// With the `test` feature, builds the plugin with the `#[nvim_oxi::test]`
+// functions the test binary loads into Neovim
+#[cfg(feature = "test")]
+fn main() -> Result<(), nvim_oxi::tests::BuildError> {
+    nvim_oxi::tests::build()
+}
+
+#[cfg(not(feature = "test"))]
+fn main() {}
@@ -0,0 +1,320 @@
+use crate::config::{AichatConfig, Mode};
+use crate::error::{AichatError, Result};
+use crate::job_runner;
+use crate::version::{self, Capability};
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+use std::sync::atomic::AtomicBool;
+
+/// Program answering the prompts of requests
+///
+/// The backend only sends the prompt and returns the answer, extracting the
+/// code of it is left to the caller.
+pub trait Backend: Send + Sync {
+    /// Sends `input` and returns the answer, or `Cancelled` as soon as
+    /// `cancel` is set
+    ///
+    /// `code` tells that only the code of the answer is used, for backends
+    /// that can ask for bare code.
+    fn complete(
+        &self,
+        config: &AichatConfig,
+        input: &str,
+        code: bool,
+        cancel: &AtomicBool,
+    ) -> Result<String>;
+
+    /// Whether answers to `code` requests are bare code, so an answer without
+    /// a code block is the code itself
+    fn bare_code(&self, _config: &AichatConfig) -> bool {
+        false
+    }
+}
+
+/// Backend selected with `backend` in `setup()`
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendKind {
+    /// The aichat CLI, with the role, session, RAG and model of the config
+    Aichat,
+    /// Any command reading the prompt on stdin and printing the answer, e.g.
+    /// `{ command = { "sgpt", "--no-interaction" } }`, `{ "llm" }` or
+    /// `{ "mods" }`
+    Command(Vec<String>),
+    /// The command of `:AichatFilter`, given the selection and answering with
+    /// its replacement, not selectable in `setup()`
+    #[serde(skip)]
+    Filter(Vec<String>),
+    /// An OpenAI-compatible chat completions endpoint, without aichat, e.g.
+    /// `{ http = { base_url = "http://localhost:11434/v1", model = "qwen2.5-coder" } }`
+    ///
+    /// Needs a build with the `http` feature.
+    Http(HttpOpts),
+}
+
+/// Endpoint of the `http` backend
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(default)]
+pub struct HttpOpts {
+    /// Base URL the `/chat/completions` path is appended to
+    pub base_url: String,
+    /// Environment variable holding the API key, looked up in `env` first;
+    /// no key is sent when unset
+    pub api_key_env: Option<String>,
+    /// Model of the requests, the config's `model` when unset
+    pub model: Option<String>,
+}
+
+impl Default for HttpOpts {
+    fn default() -> Self {
+        Self {
+            base_url: "https://api.openai.com/v1".to_string(),
+            api_key_env: Some("OPENAI_API_KEY".to_string()),
+            model: None,
+        }
+    }
+}
+
+impl BackendKind {
+    /// The backend sending the requests
+    pub fn backend(&self) -> Box<dyn Backend> {
+        match self {
+            BackendKind::Aichat => Box::new(Aichat),
+            BackendKind::Command(command) => Box::new(Pipe {
+                command: command.clone(),
+                raw: false,
+            }),
+            BackendKind::Filter(command) => Box::new(Pipe {
+                command: command.clone(),
+                raw: true,
+            }),
+            #[cfg(feature = "http")]
+            BackendKind::Http(opts) => Box::new(Http { opts: opts.clone() }),
+            #[cfg(not(feature = "http"))]
+            BackendKind::Http(_) => Box::new(Unavailable("http")),
+        }
+    }
+
+    /// Whether answers are used exactly as printed, without extracting their
+    /// code or passing them through the transformers
+    pub fn raw_output(&self) -> bool {
+        matches!(self, BackendKind::Filter(_))
+    }
+}
+
+/// The aichat CLI
+struct Aichat;
+
+impl Backend for Aichat {
+    fn complete(
+        &self,
+        config: &AichatConfig,
+        input: &str,
+        code: bool,
+        cancel: &AtomicBool,
+    ) -> Result<String> {
+        if matches!(config.mode_flag, Mode::Macro) {
+            version::require(Capability::Macros)?;
+        }
+        if !config.agent_variables().is_empty() {
+            version::require(Capability::AgentVariables)?;
+        }
+
+        let mut cmd = job_runner::aichat_command(config);
+        if code && self.bare_code(config) {
+            cmd.arg("--code");
+        }
+        job_runner::run_cancellable(cmd, input, cancel)
+    }
+
+    /// With `--code`, unless `code_flag` is off or aichat is too old for it
+    fn bare_code(&self, config: &AichatConfig) -> bool {
+        config.code_flag && version::supports(Capability::CodeFlag)
+    }
+}
+
+/// A command the prompt is piped to, such as sgpt, llm or mods
+///
+/// The role, session and model of the config are aichat's and not passed,
+/// the command's own arguments select them. The configured `env` is set.
+struct Pipe {
+    command: Vec<String>,
+    /// Whether the output is kept as printed, terminal artifacts included
+    raw: bool,
+}
+
+impl Backend for Pipe {
+    fn complete(
+        &self,
+        config: &AichatConfig,
+        input: &str,
+        _code: bool,
+        cancel: &AtomicBool,
+    ) -> Result<String> {
+        let Some((program, args)) = self.command.split_first() else {
+            return Err(AichatError::application(
+                "The command backend needs a program, e.g. { command = { \"llm\" } }",
+            ));
+        };
+        let mut cmd = Command::new(program);
+        cmd.envs(job_runner::PLAIN_OUTPUT_ENV);
+        cmd.args(args);
+        cmd.envs(&config.env);
+        if self.raw {
+            job_runner::run_raw(cmd, input, cancel)
+        } else {
+            job_runner::run_cancellable(cmd, input, cancel)
+        }
+    }
+}
+
+/// A backend left out of this build
+#[cfg(not(feature = "http"))]
+struct Unavailable(&'static str);
+
+#[cfg(not(feature = "http"))]
+impl Backend for Unavailable {
+    fn complete(
+        &self,
+        _config: &AichatConfig,
+        _input: &str,
+        _code: bool,
+        _cancel: &AtomicBool,
+    ) -> Result<String> {
+        Err(AichatError::config(format!(
+            "The {0} backend needs aichat_nvim built with `--features {0}`",
+            self.0
+        )))
+    }
+}
+
+/// An OpenAI-compatible endpoint, sent the prompt as a single user message
+///
+/// The generation parameters of the config go in the request body. The
+/// request runs on its own thread so cancelling doesn't wait for the server.
+#[cfg(feature = "http")]
+struct Http {
+    opts: HttpOpts,
+}
+
+#[cfg(feature = "http")]
+impl Http {
+    /// The API key, from the configured `env` or the process environment
+    fn api_key(&self, config: &AichatConfig) -> Result<Option<String>> {
+        let Some(name) = &self.opts.api_key_env else {
+            return Ok(None);
+        };
+        match config
+            .env
+            .get(name)
+            .cloned()
+            .or_else(|| std::env::var(name).ok())
+        {
+            Some(key) => Ok(Some(key)),
+            None => Err(AichatError::config(format!(
+                "The http backend reads its API key from ${}, which is not set",
+                name
+            ))),
+        }
+    }
+
+    /// The chat completions request body
+    fn body(&self, config: &AichatConfig, input: &str) -> Result<String> {
+        let model = match (&self.opts.model, &config.model) {
+            (Some(model), _) => model.clone(),
+            (None, Some(model)) => model.to_string(),
+            (None, None) => {
+                return Err(AichatError::config(
+                    "The http backend needs a model, set backend.http.model or model",
+                ))
+            }
+        };
+
+        let mut body = serde_json::json!({
+            "model": model,
+            "messages": [{ "role": "user", "content": input }],
+        });
+        if let Some(temperature) = config.temperature {
+            body["temperature"] = temperature.into();
+        }
+        if let Some(top_p) = config.top_p {
+            body["top_p"] = top_p.into();
+        }
+        if let Some(max_output_tokens) = config.max_output_tokens {
+            body["max_tokens"] = max_output_tokens.into();
+        }
+        Ok(body.to_string())
+    }
+}
+
+#[cfg(feature = "http")]
+impl Backend for Http {
+    fn complete(
+        &self,
+        config: &AichatConfig,
+        input: &str,
+        _code: bool,
+        cancel: &AtomicBool,
+    ) -> Result<String> {
+        let url = format!(
+            "{}/chat/completions",
+            self.opts.base_url.trim_end_matches('/')
+        );
+        let mut request = ureq::post(&url).set("Content-Type", "application/json");
+        if let Some(key) = self.api_key(config)? {
+            request = request.set("Authorization", &format!("Bearer {}", key));
+        }
+        let body = self.body(config, input)?;
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = sender.send(request.send_string(&body));
+        });
+        let response = loop {
+            if cancel.load(std::sync::atomic::Ordering::Relaxed) {
+                return Err(AichatError::Cancelled);
+            }
+            match receiver.recv_timeout(job_runner::POLL_INTERVAL) {
+                Ok(response) => break response,
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+                Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(AichatError::application("The HTTP request thread died"))
+                }
+            }
+        };
+
+        let (status, text) = match response {
+            Ok(response) => {
+                let status = response.status();
+                (status, response.into_string())
+            }
+            Err(ureq::Error::Status(status, response)) => {
+                return Err(AichatError::Http {
+                    url,
+                    status: Some(status),
+                    message: response.into_string().unwrap_or_default(),
+                })
+            }
+            Err(err) => {
+                return Err(AichatError::Http {
+                    url,
+                    status: None,
+                    message: err.to_string(),
+                })
+            }
+        };
+        let unreadable = |message: String| AichatError::Http {
+            url: url.clone(),
+            status: Some(status),
+            message,
+        };
+        let text = text.map_err(|err| unreadable(err.to_string()))?;
+
+        let answer: serde_json::Value = serde_json::from_str(&text)
+            .map_err(|err| unreadable(format!("unreadable response: {}", err)))?;
+        match answer["choices"][0]["message"]["content"].as_str() {
+            Some(content) => Ok(content.to_string()),
+            None => Err(unreadable(format!("no answer in the response: {}", text))),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use crate::config::get_config;
+use crate::error::{notify_error, Result};
+use crate::prompt::{self, Location, PromptBuilder};
+use crate::selection::Selection;
+use crate::{job_runner, ui, utils};
+use nvim_oxi::{
+    api::{self, Buffer},
+    Object,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The actions offered for a range, with the instruction each one sends
+///
+/// `{diagnostic}` is replaced with the message of the first diagnostic of
+/// the range; `fix` is only offered when there is one.
+const ACTIONS: [(&str, &str); 4] = [
+    (
+        "explain",
+        "Explain what this code does and point out anything surprising.",
+    ),
+    (
+        "fix",
+        "Fix this diagnostic reported on the code: {diagnostic}",
+    ),
+    (
+        "docs",
+        "Add documentation comments to this code, without changing the code itself.",
+    ),
+    (
+        "simplify",
+        "Simplify this code without changing its behavior.",
+    ),
+];
+
+// Whether the language server was started, so that plugins without the
+// feature never load `vim.lsp`
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Starts the in-process language server offering the actions to every
+/// file buffer, or stops it, following `features.code_actions`
+pub fn sync_server() -> Result<()> {
+    let enabled = get_config().features.code_actions;
+    if !enabled && !STARTED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let source = if enabled { START_SOURCE } else { STOP_SOURCE };
+    api::call_function::<_, Object>("luaeval", (source, Object::nil()))?;
+    STARTED.store(enabled, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Runs the action `name` on lines `line1`-`line2` of a buffer, called by
+/// the language server's `workspace/executeCommand`
+///
+/// Exposed to Lua as `require("aichat_nvim").code_action(name, bufnr, line1, line2, diagnostic)`
+pub fn run(
+    (name, bufnr, line1, line2, diagnostic): (String, i32, usize, usize, String),
+) -> nvim_oxi::Result<()> {
+    if let Err(err) = run_action(&name, Buffer::from(bufnr), line1, line2, &diagnostic) {
+        notify_error(&err);
+    }
+    Ok(())
+}
+
+/// Like `run`, returning the error, also used by `:AichatExplain`
+pub fn run_action(
+    name: &str,
+    buffer: Buffer,
+    line1: usize,
+    line2: usize,
+    diagnostic: &str,
+) -> Result<()> {
+    let Some((_, instruction)) = ACTIONS.iter().find(|(action, _)| *action == name) else {
+        utils::warn(&format!("Unknown Aichat code action {}", name));
+        return Ok(());
+    };
+    let instruction = instruction.replace("{diagnostic}", diagnostic);
+
+    let line2 = line2.clamp(line1, buffer.line_count()?.max(line1));
+    let selection = Selection {
+        line1,
+        line2,
+        columns: None,
+    };
+    let code = prompt::fenced(&buffer, &selection.read(&buffer)?.join("\n"))?;
+    let builder = PromptBuilder::new(&instruction)
+        .location(&Location::current(&buffer, &selection))
+        .part(code)
+        .context(&buffer, &selection);
+
+    if name == "explain" {
+        return explain(builder.build());
+    }
+
+    let output = get_config().output;
+    Ok(crate::send(
+        "aichat",
+        buffer,
+        output.target(selection),
+        builder.build(),
+        Some(&builder),
+        output,
+    )?)
+}
+
+/// Shows the explanation of the code in a float instead of editing it
+fn explain(prompt: String) -> Result<()> {
+    let config = get_config().clone();
+    utils::info("Asking Aichat for an explanation");
+
+    job_runner::run_in_background(
+        move || job_runner::run_aichat_response(&config, &prompt),
+        |result| {
+            let shown = result.and_then(|response| {
+                ui::open_float(
+                    "Aichat Explain",
+                    response.lines().map(String::from).collect(),
+                )?;
+                Ok(())
+            });
+            if let Err(err) = shown {
+                notify_error(&err);
+            }
+        },
+    )
+}
+
+/// Starts a language server named `aichat` running inside Neovim and
+/// attaches it to every buffer with a filetype. Its code actions call
+/// `require("aichat_nvim").code_action` on the range they were requested for.
+const START_SOURCE: &str = r#"(function()
+  local actions = {
+    { name = 'explain', title = 'Aichat: Explain' },
+    { name = 'fix', title = 'Aichat: Fix diagnostic', diagnostic = true },
+    { name = 'docs', title = 'Aichat: Add docs' },
+    { name = 'simplify', title = 'Aichat: Simplify' },
+  }
+
+  local function server(dispatchers)
+    local closing = false
+    local srv = {}
+    function srv.request(method, params, callback)
+      if method == 'initialize' then
+        callback(nil, { capabilities = {
+          codeActionProvider = true,
+          executeCommandProvider = { commands = { 'aichat.action' } },
+        } })
+      elseif method == 'textDocument/codeAction' then
+        local diagnostics = (params.context or {}).diagnostics or {}
+        local message = diagnostics[1] and diagnostics[1].message or ''
+        local result = {}
+        for _, action in ipairs(actions) do
+          if not action.diagnostic or #diagnostics > 0 then
+            table.insert(result, {
+              title = action.title,
+              kind = 'refactor',
+              command = {
+                title = action.title,
+                command = 'aichat.action',
+                arguments = { action.name, params.textDocument.uri, params.range, message },
+              },
+            })
+          end
+        end
+        callback(nil, result)
+      elseif method == 'workspace/executeCommand' then
+        local name, uri, range, message = unpack(params.arguments)
+        local line1 = range.start.line + 1
+        local line2 = range['end'].character > 0 and range['end'].line + 1
+          or math.max(range['end'].line, line1)
+        vim.schedule(function()
+          require('aichat_nvim').code_action(name, vim.uri_to_bufnr(uri), line1, line2, message)
+        end)
+        callback(nil, vim.NIL)
+      else
+        callback(nil, vim.NIL)
+      end
+      return true, 1
+    end
+    function srv.notify(method)
+      if method == 'exit' then closing = true end
+      return true
+    end
+    function srv.is_closing() return closing end
+    function srv.terminate() closing = true end
+    return srv
+  end
+
+  local function attach(bufnr)
+    if vim.bo[bufnr].buftype ~= '' or vim.bo[bufnr].filetype == '' then return end
+    vim.lsp.start({ name = 'aichat', cmd = server, root_dir = vim.fn.getcwd() }, { bufnr = bufnr })
+  end
+
+  local group = vim.api.nvim_create_augroup('aichat_nvim_code_actions', { clear = true })
+  vim.api.nvim_create_autocmd('FileType', {
+    group = group,
+    callback = function(args) attach(args.buf) end,
+  })
+  for _, bufnr in ipairs(vim.api.nvim_list_bufs()) do
+    if vim.api.nvim_buf_is_loaded(bufnr) then attach(bufnr) end
+  end
+end)()"#;
+
+/// Stops the `aichat` language server and the autocommand attaching it
+const STOP_SOURCE: &str = r#"(function()
+  pcall(vim.api.nvim_del_augroup_by_name, 'aichat_nvim_code_actions')
+  for _, client in ipairs(vim.lsp.get_clients({ name = 'aichat' })) do
+    client:stop()
+  end
+end)()"#;
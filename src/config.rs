@@ -1,34 +1,212 @@
+use crate::backend::BackendKind;
+use crate::context::{OnModified, Provider as ContextProvider};
+use crate::convert::Format;
+use crate::dual::ModelPair;
 use crate::error::{AichatError, Result};
-use crate::ui;
+use crate::keymaps::KeymapOpts;
+use crate::output::Output;
+use crate::picker::PickerBackend;
+use crate::prose::ProseOpts;
+use crate::ui::{self, FloatOpts, Keys};
 use nvim_oxi::conversion::{Error as ConversionError, FromObject};
-use nvim_oxi::serde::Deserializer;
+use nvim_oxi::serde::{Deserializer, Serializer};
 use nvim_oxi::{
     api::{
         self,
-        opts::{OptionOpts, OptionScope::Local, SetKeymapOpts},
+        opts::{CreateAutocmdOpts, OptionOpts, OptionScope::Local, SetExtmarkOpts},
+        Buffer, Window,
     },
-    lua, Object,
+    lua, Dictionary, Object,
 };
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use std::sync::RwLock;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 #[derive(Serialize, Deserialize)]
 #[serde(default)]
 pub struct AichatConfig {
+    /// The aichat executable, a name looked up in `PATH` or a path, e.g. a
+    /// stub script answering with fixtures
+    pub aichat_path: Box<str>,
+    /// Program answering requests, `"aichat"` or `{ command = { ... } }` to
+    /// pipe prompts to another CLI
+    pub backend: BackendKind,
     pub mode_flag: Mode,
     pub mode_arg: Box<str>,
     pub rag: Option<Box<str>>,
     pub session: Option<Box<str>>,
+    /// Model passed with `--model`, the role or aichat's default is used when unset
+    pub model: Option<Box<str>>,
+    pub picker: PickerBackend,
+    /// Automatic retries for failures that look transient
+    pub retries: u32,
+    /// Delay before the first retry, doubled on every further attempt
+    pub retry_backoff_ms: u64,
+    /// Lines above the cursor sent as context by `:AichatInsert`
+    pub insert_context_lines: usize,
+    /// What to do with the extracted code
+    pub output: Output,
+    /// Register used by the register outputs and `:Aichat!`
+    pub register: Box<str>,
+    /// File that request events are appended to as JSON lines
+    pub telemetry_file: Option<Box<str>>,
+    /// Context providers whose output is appended to every request
+    pub context: Vec<ContextProvider>,
+    /// Maximum bytes of context added to a request
+    pub context_budget: usize,
+    /// How long LSP based context providers wait for the language server
+    pub lsp_timeout_ms: u64,
+    /// Where the `test_file` context provider looks for tests, per file extension
+    pub test_file_patterns: HashMap<String, Vec<String>>,
+    /// Tell aichat the file, lines and cursor line the code comes from
+    pub send_location: bool,
+    /// How many applied answers `:AichatUndoLast` and `:AichatRevert` can restore
+    pub edit_history: usize,
+    /// Format the lines of an applied answer with conform.nvim or the LSP
+    pub format_after_insert: bool,
+    /// Sampling temperature, aichat's own setting is used when unset
+    pub temperature: Option<f64>,
+    /// Nucleus sampling threshold, aichat's own setting is used when unset
+    pub top_p: Option<f64>,
+    /// Upper bound on the tokens of a response, sent by the `http` backend
+    /// only: aichat has no override for it and takes the limit of each
+    /// model from its own config.yaml
+    pub max_output_tokens: Option<u32>,
+    /// Pass `--code` to aichat when only the code of a response is used,
+    /// can be turned off for aichat versions without the flag
+    pub code_flag: bool,
+    /// After how long a running request offers to be cancelled, 0 never asks
+    pub slow_request_ms: u64,
+    /// Extra environment for every aichat process, e.g. `AICHAT_CONFIG_DIR`
+    /// from a project's `.nvim.lua`
+    pub env: HashMap<String, String>,
+    /// Commands that ask a quick and a full model at once, keyed by command
+    /// name (`aichat`, `insert`)
+    pub dual_models: HashMap<String, ModelPair>,
+    /// Reading settings of the floats showing long text
+    pub float: FloatOpts,
+    /// Accept, reject and cancel keys used by every float
+    pub keys: Keys,
+    /// Requests run at the same time, later ones wait in a queue; 0 runs all at once
+    pub max_concurrent_requests: usize,
+    /// Experimental subsystems enabled with `features = { ... }`
+    pub features: Features,
+    /// Instructions wrapped around every prompt
+    pub system_prompt: SystemPrompt,
+    /// Replacements for parts of `system_prompt`, keyed by role, agent or macro
+    pub system_prompts: HashMap<String, SystemPrompt>,
+    /// Variables passed with `--agent-variable` to the agent they are keyed by
+    pub agent_variables: HashMap<String, HashMap<String, String>>,
+    /// Grammar and style review of prose buffers, with `features.prose`
+    pub prose: ProseOpts,
+    /// Answers longer than this many lines are only written once confirmed, 0 never asks
+    pub large_response_lines: usize,
+    /// Suggested `<leader>a` mappings, only set when the table is given
+    pub keymaps: Option<KeymapOpts>,
+    /// What to do with unsaved changes when a request reads the file from disk
+    pub on_modified: OnModified,
+    /// Previous requests on a buffer and their answers sent with the next
+    /// ones, 0 sends none
+    pub memory_turns: usize,
+    /// Language `:AichatTranslateComments` translates to, prompted for when unset
+    pub comment_language: Option<Box<str>>,
+    /// Format the answer must parse as before it is written, set by
+    /// `:AichatConvert` for its own requests only
+    #[serde(skip)]
+    pub validate_as: Option<Format>,
+    /// Without a session, use one named after the project root, e.g.
+    /// `proj-aichat-nvim`, which aichat creates on the first request
+    pub project_sessions: bool,
+    /// Notes file `:AichatSaveExchange` appends to, relative to the project root
+    pub notes_file: Box<str>,
+    /// Model `:AichatLocal` switches to, e.g. an ollama client of aichat
+    pub local_model: Option<Box<str>>,
+    /// Command `:AichatFilter` pipes the selection through, `{prompt}` in it is
+    /// replaced by the instruction, e.g. `llm -m gpt-4 -s "{prompt}"`
+    pub filter_command: Option<Box<str>>,
+    /// Keep the typed instructions across sessions, in Neovim's data directory
+    pub persist_history: bool,
+}
+
+/// Experimental subsystems, which stay off until enabled in `setup()`
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Features {
+    /// `:AichatScaffold`
+    pub scaffold: bool,
+    /// Quick and full answers for the commands in `dual_models`
+    pub dual: bool,
+    /// Signs, highlights and `]h`/`[h` plus the `accept_hunk`/`revert_hunk`
+    /// keys (`ga`/`gr`) to review the hunks of an applied answer
+    pub inline: bool,
+    /// Grammar and style review of prose buffers as diagnostics, see `prose`
+    pub prose: bool,
+    /// Explain, fix, document and simplify actions in `vim.lsp.buf.code_action()`
+    pub code_actions: bool,
+}
+
+/// Text put before and after the prompt of a request, e.g. "Never change
+/// public signatures; keep comments"
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct SystemPrompt {
+    pub prefix: Option<Box<str>>,
+    pub suffix: Option<Box<str>>,
 }
 
 impl Default for AichatConfig {
     fn default() -> Self {
         Self {
+            aichat_path: Box::from("aichat"),
+            backend: BackendKind::Aichat,
             mode_flag: Mode::Role,
             mode_arg: Box::from("sambanova1filecoder"),
             rag: None,
             session: None,
+            model: None,
+            picker: PickerBackend::Auto,
+            retries: 2,
+            retry_backoff_ms: 1000,
+            insert_context_lines: 20,
+            output: Output::Replace,
+            register: Box::from("+"),
+            telemetry_file: None,
+            context: Vec::new(),
+            context_budget: 8000,
+            lsp_timeout_ms: 1000,
+            test_file_patterns: crate::context::default_test_file_patterns(),
+            send_location: false,
+            edit_history: 10,
+            format_after_insert: false,
+            temperature: None,
+            top_p: None,
+            max_output_tokens: None,
+            code_flag: true,
+            slow_request_ms: 15000,
+            env: HashMap::new(),
+            dual_models: HashMap::new(),
+            float: FloatOpts::default(),
+            keys: Keys::default(),
+            max_concurrent_requests: 1,
+            features: Features::default(),
+            system_prompt: SystemPrompt::default(),
+            system_prompts: HashMap::new(),
+            agent_variables: HashMap::new(),
+            prose: ProseOpts::default(),
+            large_response_lines: 2000,
+            keymaps: None,
+            on_modified: OnModified::Warn,
+            memory_turns: 0,
+            comment_language: None,
+            validate_as: None,
+            project_sessions: false,
+            notes_file: Box::from("docs/ai-notes.md"),
+            local_model: None,
+            filter_command: None,
+            persist_history: true,
         }
     }
 }
@@ -36,14 +214,163 @@ impl Default for AichatConfig {
 impl Clone for AichatConfig {
     fn clone(&self) -> Self {
         Self {
+            aichat_path: self.aichat_path.clone(),
+            backend: self.backend.clone(),
             mode_flag: self.mode_flag,
             mode_arg: self.mode_arg.clone(),
             rag: self.rag.clone(),
             session: self.session.clone(),
+            model: self.model.clone(),
+            picker: self.picker,
+            retries: self.retries,
+            retry_backoff_ms: self.retry_backoff_ms,
+            insert_context_lines: self.insert_context_lines,
+            output: self.output,
+            register: self.register.clone(),
+            telemetry_file: self.telemetry_file.clone(),
+            context: self.context.clone(),
+            context_budget: self.context_budget,
+            lsp_timeout_ms: self.lsp_timeout_ms,
+            test_file_patterns: self.test_file_patterns.clone(),
+            send_location: self.send_location,
+            edit_history: self.edit_history,
+            format_after_insert: self.format_after_insert,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_output_tokens: self.max_output_tokens,
+            code_flag: self.code_flag,
+            slow_request_ms: self.slow_request_ms,
+            env: self.env.clone(),
+            dual_models: self.dual_models.clone(),
+            float: self.float.clone(),
+            keys: self.keys.clone(),
+            max_concurrent_requests: self.max_concurrent_requests,
+            features: self.features.clone(),
+            system_prompt: self.system_prompt.clone(),
+            system_prompts: self.system_prompts.clone(),
+            agent_variables: self.agent_variables.clone(),
+            prose: self.prose.clone(),
+            large_response_lines: self.large_response_lines,
+            keymaps: self.keymaps.clone(),
+            on_modified: self.on_modified,
+            memory_turns: self.memory_turns,
+            comment_language: self.comment_language.clone(),
+            validate_as: self.validate_as,
+            project_sessions: self.project_sessions,
+            notes_file: self.notes_file.clone(),
+            local_model: self.local_model.clone(),
+            filter_command: self.filter_command.clone(),
+            persist_history: self.persist_history,
         }
     }
 }
 
+impl AichatConfig {
+    /// The system prompt of the current role, agent or macro, falling back to
+    /// the global `system_prompt` for the parts it doesn't set
+    pub fn effective_system_prompt(&self) -> SystemPrompt {
+        let global = self.system_prompt.clone();
+        match self.system_prompts.get(self.mode_arg.as_ref()) {
+            Some(specific) => SystemPrompt {
+                prefix: specific.prefix.clone().or(global.prefix),
+                suffix: specific.suffix.clone().or(global.suffix),
+            },
+            None => global,
+        }
+    }
+
+    /// Builds the aichat CLI arguments for this configuration
+    pub fn args(&self) -> Vec<String> {
+        let mode_flag = match self.mode_flag {
+            Mode::Role => "--role",
+            Mode::Agent => "--agent",
+            Mode::Macro => "--macro",
+        };
+        let mut args = vec![mode_flag.to_string(), self.mode_arg.to_string()];
+
+        for (name, value) in self.agent_variables() {
+            args.extend(["--agent-variable".to_string(), name, value]);
+        }
+
+        // Add model if set
+        if let Some(model) = &self.model {
+            args.extend(["--model".to_string(), model.to_string()]);
+        }
+
+        // Add RAG if set
+        if let Some(rag) = &self.rag {
+            args.extend(["--rag".to_string(), rag.to_string()]);
+        }
+
+        // Add session if set, a project session is saved so it persists
+        if let Some(session) = &self.session {
+            args.extend(["--session".to_string(), session.to_string()]);
+        } else if let Some(session) = self.session_name() {
+            args.extend([
+                "--session".to_string(),
+                session,
+                "--save-session".to_string(),
+            ]);
+        }
+
+        args
+    }
+
+    /// The session requests join: the configured one, or the project
+    /// session with `project_sessions`
+    pub fn session_name(&self) -> Option<String> {
+        match &self.session {
+            Some(session) => Some(session.to_string()),
+            None if self.project_sessions => project_session(),
+            None => None,
+        }
+    }
+
+    /// The variables of the current agent, sorted so that identical requests
+    /// hash the same; empty outside of the Agent mode
+    pub fn agent_variables(&self) -> Vec<(String, String)> {
+        if !matches!(self.mode_flag, Mode::Agent) {
+            return Vec::new();
+        }
+        let mut variables: Vec<(String, String)> = self
+            .agent_variables
+            .get(self.mode_arg.as_ref())
+            .map(|variables| {
+                variables
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        variables.sort();
+        variables
+    }
+
+    /// Builds the environment variables of a request: the configured `env`
+    /// followed by the temperature and top_p
+    ///
+    /// aichat has no command line flags for the generation parameters, but it
+    /// reads `AICHAT_TEMPERATURE` and `AICHAT_TOP_P` overrides of its config at
+    /// startup; it has none for the output tokens. The variables are sorted so
+    /// that identical requests hash the same.
+    pub fn envs(&self) -> Vec<(String, String)> {
+        let mut envs: Vec<(String, String)> = self
+            .env
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        envs.sort();
+
+        if let Some(temperature) = self.temperature {
+            envs.push(("AICHAT_TEMPERATURE".to_string(), temperature.to_string()));
+        }
+        if let Some(top_p) = self.top_p {
+            envs.push(("AICHAT_TOP_P".to_string(), top_p.to_string()));
+        }
+        envs
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Copy)]
 pub enum Mode {
     Role,
@@ -51,6 +378,17 @@ pub enum Mode {
     Macro,
 }
 
+impl Mode {
+    /// Name of the mode as shown to the user
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Role => "Role",
+            Mode::Agent => "Agent",
+            Mode::Macro => "Macro",
+        }
+    }
+}
+
 impl FromObject for AichatConfig {
     fn from_object(obj: Object) -> std::result::Result<Self, ConversionError> {
         Self::deserialize(Deserializer::new(obj)).map_err(Into::into)
@@ -64,9 +402,117 @@ impl lua::Poppable for AichatConfig {
     }
 }
 
+/// The project of the working directory: the closest directory with a
+/// `.git`, or the working directory itself
+///
+/// Only the file system is read, so it works on background threads.
+pub fn project_root() -> Option<std::path::PathBuf> {
+    let cwd = std::env::current_dir().ok()?;
+    let root = cwd
+        .ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .unwrap_or(&cwd)
+        .to_path_buf();
+    Some(root)
+}
+
+/// Names a session after the project of the working directory
+fn project_session() -> Option<String> {
+    let root = project_root()?;
+    let name = root.file_name()?.to_string_lossy().to_lowercase();
+
+    let slug = name
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    (!slug.is_empty()).then(|| format!("proj-{}", slug))
+}
+
 // Global static to store the config
 static CONFIG: Lazy<RwLock<AichatConfig>> = Lazy::new(|| RwLock::new(AichatConfig::default()));
 
+/// Lays the table passed to `setup()` over the global configuration
+///
+/// Fields missing from the table keep their current values, the defaults on
+/// the first call. Lua functions can't be deserialized, so the `transforms`
+/// and `validators` tables are registered separately.
+pub fn setup(opts: Option<Dictionary>) -> nvim_oxi::Result<()> {
+    let Some(opts) = opts else {
+        return Ok(());
+    };
+
+    let (functions, opts): (Vec<_>, Vec<_>) = opts.into_iter().partition(|(key, _)| {
+        matches!(
+            key.to_string_lossy().as_ref(),
+            "transforms" | "validators" | "variables"
+        )
+    });
+
+    // Merged as JSON, which keeps the unset options as nulls where a
+    // Dictionary drops them
+    let mut merged = serde_json::to_value(&*get_config())
+        .map_err(|err| AichatError::application(err.to_string()))?;
+    let opts = serde_json::Value::deserialize(Deserializer::new(Object::from(
+        Dictionary::from_iter(opts),
+    )))
+    .map_err(ConversionError::from)?;
+    if let (serde_json::Value::Object(merged), serde_json::Value::Object(opts)) =
+        (&mut merged, opts)
+    {
+        merge_table(merged, opts);
+    }
+    let merged = merged
+        .serialize(Serializer::new())
+        .map_err(ConversionError::from)?;
+    let config = AichatConfig::from_object(merged)?;
+    check_generation_params(&config)?;
+    *get_config_mut() = config;
+    for (key, table) in functions {
+        let table = Dictionary::from_object(table)?;
+        match key.to_string_lossy().as_ref() {
+            "transforms" => crate::transform::register(table)?,
+            "variables" => crate::variables::register(table)?,
+            _ => crate::validate::register(table)?,
+        }
+    }
+    Ok(())
+}
+
+/// Settings whose tables a later `setup()` merges key by key, so the keys it
+/// leaves out are kept, e.g. `features = { dual = true }`
+///
+/// The other tables are replaced whole: the maps keyed by the user (`env`,
+/// `dual_models`, `agent_variables`, `test_file_patterns`, `system_prompts`)
+/// hold exactly what the last `setup()` gave, like `backend`.
+const MERGED_TABLES: [&str; 6] = [
+    "features",
+    "float",
+    "keys",
+    "system_prompt",
+    "prose",
+    "keymaps",
+];
+
+/// Lays the keys of `table` over `current`, merging the `MERGED_TABLES`
+fn merge_table(
+    current: &mut serde_json::Map<String, serde_json::Value>,
+    table: serde_json::Map<String, serde_json::Value>,
+) {
+    for (key, value) in table {
+        match (current.get_mut(&key), value) {
+            (Some(serde_json::Value::Object(current)), serde_json::Value::Object(table))
+                if MERGED_TABLES.contains(&key.as_str()) =>
+            {
+                current.extend(table)
+            }
+            (_, value) => {
+                current.insert(key, value);
+            }
+        }
+    }
+}
+
 /// Gets a read-only reference to the global configuration
 pub fn get_config() -> std::sync::RwLockReadGuard<'static, AichatConfig> {
     CONFIG.read().unwrap_or_else(|e| e.into_inner())
@@ -77,10 +523,143 @@ pub fn get_config_mut() -> std::sync::RwLockWriteGuard<'static, AichatConfig> {
     CONFIG.write().unwrap_or_else(|e| e.into_inner())
 }
 
-/// Fetches available options from the aichat CLI tool
+/// Rejects generation parameters given to `setup()` outside the ranges the
+/// settings window and the `key=value` overrides accept
+fn check_generation_params(config: &AichatConfig) -> Result<()> {
+    let values = [
+        ("temperature", config.temperature.map(|v| v.to_string())),
+        ("top_p", config.top_p.map(|v| v.to_string())),
+        (
+            "max_output_tokens",
+            config.max_output_tokens.map(|v| v.to_string()),
+        ),
+    ];
+
+    let mut checked = config.clone();
+    for (name, value) in values {
+        if let Some(value) = value {
+            set_generation_param(&mut checked, name, &value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Menu entry used to clear an optional config value
+const UNSET: &str = "(unset)";
+
+/// Config sections that can be set from the command line, with the option
+/// type they are fetched as and the mode they select
+const CONFIG_SECTIONS: [(&str, &str, Option<Mode>); 5] = [
+    ("role", "roles", Some(Mode::Role)),
+    ("agent", "agents", Some(Mode::Agent)),
+    ("macro", "macros", Some(Mode::Macro)),
+    ("session", "sessions", None),
+    ("rag", "rags", None),
+];
+
+/// Looks up the option type and mode for a config section name
+fn find_section(section: &str) -> Option<(&'static str, Option<Mode>)> {
+    CONFIG_SECTIONS
+        .iter()
+        .find(|(name, _, _)| *name == section)
+        .map(|(_, option_type, mode)| (*option_type, *mode))
+}
+
+/// Generation parameters that can be set from the command line, with a
+/// description of the values they accept
+const GENERATION_PARAMS: [(&str, &str); 3] = [
+    ("temperature", "a number from 0 to 2"),
+    ("top_p", "a number from 0 to 1"),
+    ("max_output_tokens", "a positive integer"),
+];
+
+/// Looks up the description of the values a generation parameter accepts
+fn find_generation_param(name: &str) -> Option<&'static str> {
+    GENERATION_PARAMS
+        .iter()
+        .find(|(param, _)| *param == name)
+        .map(|(_, expected)| *expected)
+}
+
+/// How long a fetched option list is reused before aichat is asked again
+const OPTIONS_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// An option list as returned by aichat, with the time it was fetched
+struct CachedOptions {
+    fetched_at: Instant,
+    options: Vec<String>,
+}
+
+// Global static to store the option lists fetched from aichat, keyed by option type
+static OPTIONS_CACHE: Lazy<Mutex<HashMap<String, CachedOptions>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Returns the cached list for an option type if it has not expired yet
+fn cached_options(option_type: &str) -> Option<Vec<String>> {
+    let cache = OPTIONS_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    cache
+        .get(option_type)
+        .filter(|cached| cached.fetched_at.elapsed() < OPTIONS_TTL)
+        .map(|cached| cached.options.clone())
+}
+
+/// Stores a freshly fetched option list in the cache
+fn store_options(option_type: &str, options: &[String]) {
+    let mut cache = OPTIONS_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    cache.insert(
+        option_type.to_string(),
+        CachedOptions {
+            fetched_at: Instant::now(),
+            options: options.to_vec(),
+        },
+    );
+}
+
+/// Fetches the lists that are not cached yet on a background thread, so the
+/// pickers opened afterwards don't have to wait for aichat
+pub fn warm_options_cache() {
+    let missing: Vec<&'static str> = CONFIG_SECTIONS
+        .iter()
+        .map(|(_, option_type, _)| *option_type)
+        .filter(|option_type| cached_options(option_type).is_none())
+        .collect();
+
+    if missing.is_empty() {
+        return;
+    }
+
+    // Only the cache is touched here, never the Neovim API
+    std::thread::spawn(move || {
+        for option_type in missing {
+            let _ = fetch_aichat_options(option_type);
+        }
+    });
+}
+
+/// Drops every cached option list and fetches them again in the background
+pub fn refresh_option_lists() -> nvim_oxi::Result<()> {
+    OPTIONS_CACHE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clear();
+    warm_options_cache();
+    crate::utils::info("Refreshing aichat lists");
+    Ok(())
+}
+
+/// Fetches available options from the aichat CLI tool, reusing cached lists
 fn fetch_aichat_options(option_type: &str) -> Result<Vec<String>> {
-    use std::process::Command;
+    if let Some(options) = cached_options(option_type) {
+        return Ok(options);
+    }
+
+    let options = list_aichat_options(option_type)?;
+    store_options(option_type, &options);
+    Ok(options)
+}
 
+/// Runs the aichat `--list-*` command for an option type
+fn list_aichat_options(option_type: &str) -> Result<Vec<String>> {
     // Map option type to the appropriate CLI flag
     let flag = match option_type {
         "roles" => "--list-roles",
@@ -93,15 +672,16 @@ fn fetch_aichat_options(option_type: &str) -> Result<Vec<String>> {
         }
     };
 
-    // Execute the aichat command with the appropriate flag
-    let output = Command::new("aichat").arg(flag).output()?;
-
-    if !output.status.success() {
-        return Err(AichatError::command_failed(output.status, output.stderr, output.stdout));
+    if option_type == "macros" {
+        crate::version::require(crate::version::Capability::Macros)?;
     }
 
+    // Execute the aichat command with the appropriate flag
+    let mut cmd = crate::job_runner::base_command(&get_config());
+    cmd.arg(flag);
+    let output_str = crate::job_runner::run_command(cmd, "")?;
+
     // Parse the output into lines
-    let output_str = String::from_utf8_lossy(&output.stdout);
     let mut options: Vec<String> = output_str
         .lines()
         .map(|s| s.trim().to_string())
@@ -110,7 +690,7 @@ fn fetch_aichat_options(option_type: &str) -> Result<Vec<String>> {
 
     // Only add unset option for sessions and rags
     if option_type == "sessions" || option_type == "rags" {
-        options.push("(unset)".into());
+        options.push(UNSET.into());
     }
 
     Ok(options)
@@ -118,12 +698,19 @@ fn fetch_aichat_options(option_type: &str) -> Result<Vec<String>> {
 
 /// Shows the main configuration menu for aichat
 pub fn show_config_menu() -> nvim_oxi::Result<()> {
+    // Fetch the lists while the user is still picking a section
+    warm_options_cache();
+
     let menu_items = vec![
         "Set Role".to_string(),
         "Set Agent".to_string(),
+        "Set Agent Variables".to_string(),
         "Set Macro".to_string(),
         "Set Session".to_string(),
         "Set RAG".to_string(),
+        "Set Temperature".to_string(),
+        "Set Top P".to_string(),
+        "Set Max Output Tokens".to_string(),
     ];
 
     let opts = ui::SelectOpts {
@@ -136,9 +723,13 @@ pub fn show_config_menu() -> nvim_oxi::Result<()> {
             let result = match selection.as_str() {
                 "Set Role" => handle_config_selection("roles", Some(Mode::Role)),
                 "Set Agent" => handle_config_selection("agents", Some(Mode::Agent)),
+                "Set Agent Variables" => pick_agent_variable(),
                 "Set Macro" => handle_config_selection("macros", Some(Mode::Macro)),
                 "Set Session" => handle_config_selection("sessions", None),
                 "Set RAG" => handle_config_selection("rags", None),
+                "Set Temperature" => prompt_generation_param("temperature"),
+                "Set Top P" => prompt_generation_param("top_p"),
+                "Set Max Output Tokens" => prompt_generation_param("max_output_tokens"),
                 _ => Ok(()),
             };
 
@@ -149,45 +740,387 @@ pub fn show_config_menu() -> nvim_oxi::Result<()> {
     })
 }
 
+/// Handles `:AichatSetConfig [section] [value]`
+///
+/// Without arguments the main menu is shown, otherwise the rest is handled
+/// like the per-section commands
+pub fn set_config_from_args(fargs: &[String]) -> nvim_oxi::Result<()> {
+    match fargs.first() {
+        Some(section) => set_section(section, fargs.get(1).map(String::as_str)),
+        None => show_config_menu(),
+    }
+}
+
+/// Sets a config section to `value`, or opens its picker when no value is given
+pub fn set_section(section: &str, value: Option<&str>) -> nvim_oxi::Result<()> {
+    let result = match (find_section(section), find_generation_param(section)) {
+        (Some((option_type, mode)), _) => match value {
+            Some(UNSET) => update_config(option_type, None, mode),
+            Some(value) => update_config(option_type, Some(value.to_string()), mode),
+            None => handle_config_selection(option_type, mode),
+        },
+        (None, Some(_)) => match value {
+            Some(value) => update_generation_param(section, value),
+            None => prompt_generation_param(section),
+        },
+        (None, None) => Err(AichatError::invalid_option_type(section)),
+    };
+
+    if let Err(e) = result {
+        crate::error::notify_error(&e);
+    }
+
+    Ok(())
+}
+
+/// Completes the arguments of `:AichatSetConfig`
+///
+/// The first argument completes to a section name and the second to the
+/// values aichat lists for that section
+pub fn complete_set_config(arg_lead: &str, cmd_line: &str, cursor_pos: usize) -> Vec<String> {
+    let typed = cmd_line.get(..cursor_pos).unwrap_or(cmd_line);
+
+    // Arguments already completed, without the command name and the one being typed
+    let mut args: Vec<&str> = typed.split_whitespace().skip(1).collect();
+    if !arg_lead.is_empty() {
+        args.pop();
+    }
+
+    match args.as_slice() {
+        [] => matching(
+            CONFIG_SECTIONS
+                .iter()
+                .map(|(name, _, _)| name.to_string())
+                .chain(GENERATION_PARAMS.iter().map(|(name, _)| name.to_string())),
+            arg_lead,
+        ),
+        [section] => complete_section_values(section, arg_lead),
+        _ => Vec::new(),
+    }
+}
+
+/// Completes the values aichat lists for a config section
+pub fn complete_section_values(section: &str, arg_lead: &str) -> Vec<String> {
+    match find_section(section) {
+        Some((option_type, _)) => matching(
+            fetch_aichat_options(option_type).unwrap_or_default(),
+            arg_lead,
+        ),
+        None => Vec::new(),
+    }
+}
+
+/// Keeps the completion candidates that start with what has been typed so far
+fn matching(candidates: impl IntoIterator<Item = String>, arg_lead: &str) -> Vec<String> {
+    candidates
+        .into_iter()
+        .filter(|candidate| candidate.starts_with(arg_lead))
+        .collect()
+}
+
 /// Handles the selection of a specific config option type
+///
+/// Cached lists are shown right away, otherwise they are fetched on a
+/// background job and the picker opens once aichat has answered
 fn handle_config_selection(option_type: &str, mode: Option<Mode>) -> Result<()> {
-    // Fetch options from aichat CLI
-    match fetch_aichat_options(option_type) {
-        Ok(options) => {
-            // Clone option_type to own it inside the closure
-            let option_type_owned: String = option_type.into();
-
-            let opts = ui::SelectOpts {
-                prompt: Some(format!("Select {}", option_type)),
-                kind: None,
+    if let Some(options) = cached_options(option_type) {
+        return show_option_picker(option_type, options, mode);
+    }
+
+    crate::utils::info(&format!("Loading {}…", option_type));
+
+    let option_type_owned: String = option_type.into();
+    crate::job_runner::run_in_background(
+        move || {
+            let options = fetch_aichat_options(&option_type_owned);
+            (option_type_owned, options)
+        },
+        move |(option_type, options)| {
+            let result =
+                options.and_then(|options| show_option_picker(&option_type, options, mode));
+
+            if let Err(e) = result {
+                crate::error::notify_error(&e);
+            }
+        },
+    )
+}
+
+/// Opens the picker over the fetched options and applies the selection
+fn show_option_picker(option_type: &str, options: Vec<String>, mode: Option<Mode>) -> Result<()> {
+    // Clone option_type to own it inside the closure
+    let option_type_owned: String = option_type.into();
+
+    let opts = ui::SelectOpts {
+        prompt: Some(format!("Select {}", option_type)),
+        kind: None,
+    };
+
+    ui::vim_ui_select(options, Some(opts), move |selection, _index| {
+        if let Some(selection) = selection {
+            let result = if selection == UNSET {
+                // Unset the config value
+                update_config(&option_type_owned, None, mode)
+            } else {
+                // Set the config value
+                update_config(&option_type_owned, Some(selection), mode)
             };
 
-            ui::vim_ui_select(options, Some(opts), move |selection, _index| {
-                if let Some(selection) = selection {
-                    let result = if selection == "(unset)" {
-                        // Unset the config value
-                        update_config(&option_type_owned, None, mode)
-                    } else {
-                        // Set the config value
-                        update_config(&option_type_owned, Some(selection), mode)
-                    };
-
-                    if let Err(e) = result {
-                        crate::error::notify_error(&e);
-                    }
-                }
-            })?;
+            if let Err(e) = result {
+                crate::error::notify_error(&e);
+            }
+        }
+    })?;
 
+    Ok(())
+}
+
+/// Asks for a new value of a generation parameter
+///
+/// An empty answer keeps the current value
+fn prompt_generation_param(name: &str) -> Result<()> {
+    let expected =
+        find_generation_param(name).ok_or_else(|| AichatError::invalid_option_type(name))?;
+    let prompt = format!("{} ({}, {} to clear) > ", name, expected, UNSET);
+
+    let name = name.to_string();
+    ui::input(&prompt, "", move |value| match value {
+        Some(value) => {
+            update_generation_param(&name, value.trim())?;
+            refresh_open_settings();
             Ok(())
         }
-        Err(e) => Err(e),
+        None => Ok(()),
+    });
+    Ok(())
+}
+
+/// Handles `:AichatSetAgentVariable [name] [value]`
+///
+/// Without a value the new value is prompted for, without a name the
+/// variables of the current agent are listed first. The value `(unset)`
+/// removes a variable.
+pub fn set_agent_variable_from_args(fargs: &[String]) -> nvim_oxi::Result<()> {
+    let result = match fargs {
+        [] => pick_agent_variable(),
+        [name] => prompt_agent_variable(name),
+        [name, value @ ..] => update_agent_variable(name, &value.join(" ")),
+    };
+
+    if let Err(e) = result {
+        crate::error::notify_error(&e);
     }
+
+    Ok(())
 }
 
-/// Updates the AichatConfig with the selected value
-fn update_config(option_type: &str, value: Option<String>, mode: Option<Mode>) -> Result<()> {
+/// Completes the variable names already set for the current agent
+pub fn complete_agent_variables(arg_lead: &str) -> Vec<String> {
+    matching(
+        get_config()
+            .agent_variables()
+            .into_iter()
+            .map(|(name, _)| name),
+        arg_lead,
+    )
+}
+
+/// The current agent, or an error outside of the Agent mode
+fn current_agent() -> Result<String> {
+    let config = get_config();
+    match config.mode_flag {
+        Mode::Agent => Ok(config.mode_arg.to_string()),
+        _ => Err(AichatError::config(
+            "Agent variables need an agent, select one with :AichatSetAgent",
+        )),
+    }
+}
+
+/// Lists the variables of the current agent, with an entry for a new one,
+/// and asks for the value of the chosen variable
+fn pick_agent_variable() -> Result<()> {
+    let agent = current_agent()?;
+    const NEW: &str = "(new variable)";
+
+    let mut items: Vec<String> = get_config()
+        .agent_variables()
+        .into_iter()
+        .map(|(name, value)| format!("{} = {}", name, value))
+        .collect();
+    items.push(NEW.to_string());
+
+    let opts = ui::SelectOpts {
+        prompt: Some(format!("Variables of {}", agent)),
+        kind: None,
+    };
+
+    ui::vim_ui_select(items, Some(opts), |selection, _index| {
+        let result = match selection.as_deref() {
+            Some(NEW) => {
+                ui::input("Variable name >", "", |name| match name {
+                    Some(name) => prompt_agent_variable(name.trim()),
+                    None => Ok(()),
+                });
+                Ok(())
+            }
+            Some(item) => {
+                let name = item.split_once(" = ").map_or(item, |(name, _)| name);
+                prompt_agent_variable(name)
+            }
+            None => Ok(()),
+        };
+
+        if let Err(e) = result {
+            crate::error::notify_error(&e);
+        }
+    })?;
+
+    Ok(())
+}
+
+/// Asks for the value of an agent variable, starting from its current value
+///
+/// An empty answer keeps the current value
+fn prompt_agent_variable(name: &str) -> Result<()> {
+    let agent = current_agent()?;
+    let current = get_config()
+        .agent_variables
+        .get(&agent)
+        .and_then(|variables| variables.get(name))
+        .cloned()
+        .unwrap_or_default();
+    let prompt = format!("{} ({} to remove) > ", name, UNSET);
+
+    let name = name.to_string();
+    ui::input(&prompt, &current, move |value| match value {
+        Some(value) => {
+            update_agent_variable(&name, value.trim())?;
+            refresh_open_settings();
+            Ok(())
+        }
+        None => Ok(()),
+    });
+    Ok(())
+}
+
+/// Stores a variable of the current agent, or removes it when `value` is
+/// `(unset)`
+fn update_agent_variable(name: &str, value: &str) -> Result<()> {
+    let agent = current_agent()?;
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(AichatError::config(format!(
+            "Invalid agent variable name '{}'",
+            name
+        )));
+    }
+    crate::version::require(crate::version::Capability::AgentVariables)?;
+
     let mut config = get_config_mut();
+    let variables = config.agent_variables.entry(agent.clone()).or_default();
+    if value == UNSET {
+        variables.remove(name);
+        crate::utils::info(&format!("Removed {} from {}", name, agent));
+    } else {
+        variables.insert(name.to_string(), value.to_string());
+        crate::utils::info(&format!("Set {} of {} to: {}", name, agent, value));
+    }
+
+    Ok(())
+}
+
+/// Validates `value` and stores it in a generation parameter, or clears the
+/// parameter when `value` is `(unset)`
+fn update_generation_param(name: &str, value: &str) -> Result<()> {
+    set_generation_param(&mut get_config_mut(), name, value)?;
+
+    let http = matches!(get_config().backend, BackendKind::Http(_));
+    if name == "max_output_tokens" && value != UNSET && !http {
+        crate::utils::warn(
+            "max_output_tokens is only sent by the http backend, \
+             aichat takes the limit of each model from its config.yaml",
+        );
+    }
 
+    match value {
+        UNSET => crate::utils::info(&format!("Unset {}", name)),
+        value => crate::utils::info(&format!("Set {} to: {}", name, value)),
+    }
+
+    Ok(())
+}
+
+/// Validates `value` and stores it in a generation parameter of `config`,
+/// clearing it for `(unset)`
+fn set_generation_param(config: &mut AichatConfig, name: &str, value: &str) -> Result<()> {
+    let expected =
+        find_generation_param(name).ok_or_else(|| AichatError::invalid_option_type(name))?;
+    let invalid = || AichatError::config(format!("{} must be {}, got '{}'", name, expected, value));
+
+    let parsed = (value != UNSET).then_some(value);
+    let float_up_to = |max: f64| {
+        parsed
+            .map(|v| {
+                v.parse::<f64>()
+                    .ok()
+                    .filter(|v| (0.0..=max).contains(v))
+                    .ok_or_else(invalid)
+            })
+            .transpose()
+    };
+
+    match name {
+        "temperature" => config.temperature = float_up_to(2.0)?,
+        "top_p" => config.top_p = float_up_to(1.0)?,
+        _ => {
+            config.max_output_tokens = parsed
+                .map(|v| v.parse::<u32>().ok().filter(|v| *v > 0).ok_or_else(invalid))
+                .transpose()?
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies the leading `key=value` words of `text` to `config` and returns
+/// the rest of the text, e.g. `role=rust-expert model=gpt-4o rewrite this`
+///
+/// The keys are the config sections (`role`, `agent`, `macro`, `session`,
+/// `rag`), `model` and the generation parameters. The first word that isn't
+/// one of them starts the instruction.
+pub fn apply_overrides(config: &mut AichatConfig, text: &str) -> Result<String> {
+    let mut rest = text.trim_start();
+
+    while let Some((word, key, value)) = rest.split_whitespace().next().and_then(|word| {
+        let (key, value) = word.split_once('=')?;
+        (!value.is_empty()).then_some((word, key, value))
+    }) {
+        let value_or_unset = (value != UNSET).then(|| value.to_string());
+
+        if key == "model" {
+            config.model = value_or_unset.map(String::into_boxed_str);
+        } else if let Some((option_type, mode)) = find_section(key) {
+            set_section_value(config, option_type, value_or_unset, mode)?;
+        } else if find_generation_param(key).is_some() {
+            set_generation_param(config, key, value)?;
+        } else {
+            break;
+        }
+
+        rest = rest[word.len()..].trim_start();
+    }
+
+    Ok(rest.to_string())
+}
+
+/// Sets one section of `config`, e.g. the role of a single request
+pub fn override_section(config: &mut AichatConfig, section: &str, value: &str) -> Result<()> {
+    let (option_type, mode) =
+        find_section(section).ok_or_else(|| AichatError::invalid_option_type(section))?;
+    set_section_value(config, option_type, Some(value.to_string()), mode)
+}
+
+/// Updates the AichatConfig with the selected value
+fn update_config(option_type: &str, value: Option<String>, mode: Option<Mode>) -> Result<()> {
     //Notify the user about the change
     let status = if let Some(val) = &value {
         format!("Set {} to: {}", option_type, val)
@@ -195,6 +1128,21 @@ fn update_config(option_type: &str, value: Option<String>, mode: Option<Mode>) -
         format!("Unset {}", option_type)
     };
 
+    set_section_value(&mut get_config_mut(), option_type, value, mode)?;
+
+    //Notify the user about the successful update
+    crate::utils::info(&status);
+
+    Ok(())
+}
+
+/// Stores the value of a config section in `config`
+fn set_section_value(
+    config: &mut AichatConfig,
+    option_type: &str,
+    value: Option<String>,
+    mode: Option<Mode>,
+) -> Result<()> {
     // Update the configuration based on the option type
     match option_type {
         "roles" | "agents" | "macros" => {
@@ -220,67 +1168,491 @@ fn update_config(option_type: &str, value: Option<String>, mode: Option<Mode>) -
         }
     }
 
-    //Notify the user about the successful update
-    crate::utils::info(&status);
-
     Ok(())
 }
 
-/// Shows the current aichat configuration in a floating window
-pub fn show_current_config() -> nvim_oxi::Result<()> {
-    // Get the current configuration
-    let config = get_config();
+/// Runs `aichat --info` with extra arguments and parses its `key value` lines
+pub fn aichat_info(config: &AichatConfig, args: &[&str]) -> Result<HashMap<String, String>> {
+    let mut cmd = crate::job_runner::base_command(config);
+    cmd.args(args).arg("--info");
+    let output = crate::job_runner::run_command(cmd, "")?;
+    Ok(parse_info(&output))
+}
 
-    // Create a buffer for the window
-    let mut buffer = api::create_buf(false, true)?;
+/// Parses the `key   value` and `key: value` lines printed by `--info`
+fn parse_info(info: &str) -> HashMap<String, String> {
+    info.lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let (key, value) = line.split_once(char::is_whitespace)?;
+            let value = value.trim();
+            (!value.is_empty()).then(|| (key.trim_end_matches(':').to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// The settings `--info` depends on, the key of its cached lines
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct InfoKey {
+    aichat_path: Box<str>,
+    mode: &'static str,
+    mode_arg: Box<str>,
+    session: Option<Box<str>>,
+    model: Option<Box<str>>,
+}
+
+impl InfoKey {
+    fn of(config: &AichatConfig) -> Self {
+        Self {
+            aichat_path: config.aichat_path.clone(),
+            mode: config.mode_flag.name(),
+            mode_arg: config.mode_arg.clone(),
+            session: config.session_name().map(String::into_boxed_str),
+            model: config.model.clone(),
+        }
+    }
+}
+
+// What aichat reported for each combination of settings, and the fetches
+// still running
+thread_local! {
+    static INFO_LINES: RefCell<HashMap<InfoKey, Vec<String>>> = RefCell::new(HashMap::new());
+    static INFO_FETCHING: RefCell<HashSet<InfoKey>> = RefCell::new(HashSet::new());
+}
+
+/// The `--info` lines of the settings window: the last ones aichat reported
+/// for these settings, or a placeholder until they arrive
+fn cached_info_lines(config: &AichatConfig) -> Vec<String> {
+    INFO_LINES
+        .with(|cache| cache.borrow().get(&InfoKey::of(config)).cloned())
+        .unwrap_or_else(|| vec!["Reported by aichat:".to_string(), "  Loading…".to_string()])
+}
 
-    // Prepare the content lines
-    let mut lines = Vec::new();
-    lines.push("Current Aichat Configuration:".into());
-    lines.push("".into());
+/// Asks aichat for the `--info` lines of the current settings in the
+/// background, then shows them in the settings window if it is still open
+///
+/// Lines already fetched for these settings are kept unless `force`, which
+/// the window uses when it opens so session usage stays current.
+fn fetch_info(buffer: &Buffer, window: &Window, force: bool) -> Result<()> {
+    let config = get_config().clone();
+    let key = InfoKey::of(&config);
+    let cached = INFO_LINES.with(|cache| cache.borrow().contains_key(&key));
+    if (cached && !force)
+        || !INFO_FETCHING.with(|fetching| fetching.borrow_mut().insert(key.clone()))
+    {
+        return Ok(());
+    }
+
+    let (mut buffer, mut window) = (buffer.clone(), window.clone());
+    crate::job_runner::run_in_background(
+        move || aichat_info_lines(&config),
+        move |lines| {
+            INFO_FETCHING.with(|fetching| fetching.borrow_mut().remove(&key));
+            INFO_LINES.with(|cache| cache.borrow_mut().insert(key, lines));
+            if window.is_valid() {
+                if let Err(e) = refresh_settings(&mut buffer, &mut window) {
+                    crate::error::notify_error(&e);
+                }
+            }
+        },
+    )
+}
+
+/// Describes the model, sampling settings and session usage reported by aichat
+///
+/// Failures are shown in place, the rest of the window stays useful without aichat
+fn aichat_info_lines(config: &AichatConfig) -> Vec<String> {
+    let mut lines = vec!["Reported by aichat:".to_string()];
 
-    // Add mode configuration
-    let mode_str = match config.mode_flag {
-        Mode::Role => "Role",
-        Mode::Agent => "Agent",
-        Mode::Macro => "Macro",
+    let global = match aichat_info(config, &[]) {
+        Ok(info) => info,
+        Err(err) => {
+            lines.push(format!("  aichat --info failed: {}", err));
+            return lines;
+        }
     };
-    lines.push(format!("Mode: {} - {}", mode_str, config.mode_arg));
 
-    // Add RAG configuration
-    if let Some(rag) = &config.rag {
-        lines.push(format!("RAG: {}", rag));
-    } else {
-        lines.push("RAG: Not set".into());
+    // Role and agent settings override the global ones
+    let mode_flag = match config.mode_flag {
+        Mode::Role => Some("--role"),
+        Mode::Agent => Some("--agent"),
+        Mode::Macro => None,
+    };
+    let mode = mode_flag
+        .and_then(|flag| aichat_info(config, &[flag, &*config.mode_arg]).ok())
+        .unwrap_or_default();
+    let session_name = config.session_name();
+    let session = session_name
+        .as_deref()
+        .and_then(|session| aichat_info(config, &["--session", session]).ok())
+        .unwrap_or_default();
+
+    let lookup = |key: &str| {
+        [&session, &mode, &global]
+            .iter()
+            .find_map(|info| info.get(key).filter(|value| value.as_str() != "-"))
+            .cloned()
+            .unwrap_or_else(|| "Not set".to_string())
+    };
+
+    lines.push(format!("  Model: {}", lookup("model")));
+    lines.push(format!("  Temperature: {}", lookup("temperature")));
+    lines.push(format!("  Top P: {}", lookup("top_p")));
+    if session_name.is_some() {
+        let tokens = session
+            .get("total_tokens")
+            .cloned()
+            .unwrap_or_else(|| "Unknown".to_string());
+        lines.push(format!("  Session tokens: {}", tokens));
     }
 
-    // Add session configuration
-    if let Some(session) = &config.session {
-        lines.push(format!("Session: {}", session));
-    } else {
-        lines.push("Session: Not set".into());
+    lines
+}
+
+/// A line of the settings window: how its value is shown and how it is
+/// changed, `None` for the settings only `setup()` changes
+struct Field {
+    label: &'static str,
+    value: fn(&AichatConfig) -> String,
+    edit: Option<fn() -> Result<()>>,
+}
+
+/// The settings listed by `:AichatShowConfig`, by section in display order
+static SECTIONS: [(&str, &[Field]); 4] = [
+    (
+        "Mode",
+        &[
+            Field {
+                label: "Mode",
+                value: |config| format!("{} - {}", config.mode_flag.name(), config.mode_arg),
+                edit: Some(pick_mode),
+            },
+            Field {
+                label: "Agent variables",
+                value: |config| {
+                    let variables = config
+                        .agent_variables()
+                        .into_iter()
+                        .map(|(name, value)| format!("{} = {}", name, value))
+                        .collect::<Vec<_>>();
+                    or_not_set(Some(variables.join(", ")).filter(|v| !v.is_empty()))
+                },
+                edit: Some(pick_agent_variable),
+            },
+            Field {
+                label: "Model",
+                value: |config| or_not_set(config.model.as_deref()),
+                edit: None,
+            },
+        ],
+    ),
+    (
+        "Context",
+        &[
+            Field {
+                label: "RAG",
+                value: |config| or_not_set(config.rag.as_deref()),
+                edit: Some(|| handle_config_selection("rags", None)),
+            },
+            Field {
+                label: "Session",
+                value: |config| match (&config.session, config.session_name()) {
+                    (None, Some(project)) => format!("{} (project)", project),
+                    (_, session) => or_not_set(session),
+                },
+                edit: Some(|| handle_config_selection("sessions", None)),
+            },
+            Field {
+                label: "Providers",
+                value: |config| {
+                    let providers = config
+                        .context
+                        .iter()
+                        .map(|provider| format!("{:?}", provider))
+                        .collect::<Vec<_>>();
+                    or_not_set(Some(providers.join(", ")).filter(|v| !v.is_empty()))
+                },
+                edit: None,
+            },
+            Field {
+                label: "Budget",
+                value: |config| format!("{} bytes", config.context_budget),
+                edit: None,
+            },
+        ],
+    ),
+    (
+        "Generation",
+        &[
+            Field {
+                label: "Temperature",
+                value: |config| or_not_set(config.temperature),
+                edit: Some(|| prompt_generation_param("temperature")),
+            },
+            Field {
+                label: "Top P",
+                value: |config| or_not_set(config.top_p),
+                edit: Some(|| prompt_generation_param("top_p")),
+            },
+            Field {
+                label: "Max output tokens",
+                value: |config| or_not_set(config.max_output_tokens),
+                edit: Some(|| prompt_generation_param("max_output_tokens")),
+            },
+        ],
+    ),
+    (
+        "UI",
+        &[
+            Field {
+                label: "Picker",
+                value: |config| format!("{:?}", config.picker),
+                edit: None,
+            },
+            Field {
+                label: "Output",
+                value: |config| format!("{:?}", config.output),
+                edit: None,
+            },
+        ],
+    ),
+];
+
+/// Lines of the settings window above the first section
+const HEADER_LINES: usize = 2;
+
+/// Shown for unset values, and dimmed
+const NOT_SET: &str = "Not set";
+
+/// Namespace of the highlights of the settings window
+const SETTINGS_NAMESPACE: &str = "aichat_nvim_settings";
+
+/// Shows a value, or "Not set"
+fn or_not_set(value: Option<impl ToString>) -> String {
+    value.map_or_else(|| NOT_SET.to_string(), |value| value.to_string())
+}
+
+/// The setting shown on a 0-based row of the settings window
+///
+/// Every section is a heading, its fields, then a blank line
+fn field_at(row: usize) -> Option<&'static Field> {
+    let mut row = row.checked_sub(HEADER_LINES)?;
+    for (_, fields) in &SECTIONS {
+        row = row.checked_sub(1)?;
+        if let Some(field) = fields.get(row) {
+            return Some(field);
+        }
+        row = row.checked_sub(fields.len() + 1)?;
     }
+    None
+}
+
+/// Asks whether to pick a role, an agent or a macro, then opens its picker
+fn pick_mode() -> Result<()> {
+    let opts = ui::SelectOpts {
+        prompt: Some("Select mode".to_string()),
+        kind: None,
+    };
+
+    ui::vim_ui_select(
+        vec!["Role", "Agent", "Macro"],
+        Some(opts),
+        |selection, _index| {
+            let result = match selection.as_deref() {
+                Some("Role") => handle_config_selection("roles", Some(Mode::Role)),
+                Some("Agent") => handle_config_selection("agents", Some(Mode::Agent)),
+                Some("Macro") => handle_config_selection("macros", Some(Mode::Macro)),
+                _ => Ok(()),
+            };
+
+            if let Err(e) = result {
+                crate::error::notify_error(&e);
+            }
+        },
+    )?;
+
+    Ok(())
+}
+
+/// What a line of the settings window shows, for highlighting
+enum Line {
+    Plain,
+    Heading,
+    /// `label: value`, with the byte offset where the label ends
+    Setting(usize),
+    Hint,
+}
+
+/// The content of the settings window
+fn settings_lines(config: &AichatConfig) -> Vec<(String, Line)> {
+    let mut lines = vec![
+        ("Current Aichat Configuration:".to_string(), Line::Heading),
+        (String::new(), Line::Plain),
+    ];
+    for (heading, fields) in &SECTIONS {
+        lines.push((heading.to_string(), Line::Heading));
+        for field in fields.iter() {
+            let label = format!("  {}:", field.label);
+            let len = label.len();
+            lines.push((
+                format!("{} {}", label, (field.value)(config)),
+                Line::Setting(len),
+            ));
+        }
+        lines.push((String::new(), Line::Plain));
+    }
+
+    // Add what aichat itself reports for the active role and session
+    let mut info = cached_info_lines(config).into_iter();
+    if let Some(heading) = info.next() {
+        lines.push((heading, Line::Heading));
+    }
+    lines.extend(info.map(|line| match line.find(':') {
+        Some(colon) => (line, Line::Setting(colon + 1)),
+        None => (line, Line::Plain),
+    }));
+
+    lines.push((String::new(), Line::Plain));
+    lines.push((
+        format!(
+            "{} change the setting under the cursor  {} close",
+            Keys::hint(&config.keys.accept),
+            Keys::hint(&config.keys.cancel)
+        ),
+        Line::Hint,
+    ));
+    lines
+}
+
+/// Writes the settings into the buffer and highlights headings, labels,
+/// unset values and the key hint
+fn render_settings(buffer: &mut Buffer, lines: Vec<(String, Line)>) -> Result<()> {
+    let ns = api::create_namespace(SETTINGS_NAMESPACE);
+    let opts = OptionOpts::builder().scope(Local).buffer(buffer).build();
+    api::set_option_value("modifiable", true, &opts)?;
+    buffer.set_lines(.., false, lines.iter().map(|(text, _)| text.as_str()))?;
+    api::set_option_value("modifiable", false, &opts)?;
+    buffer.clear_namespace(ns, ..)?;
+
+    let mut highlight = |row: usize, start: usize, end: usize, group: &str| {
+        let opts = SetExtmarkOpts::builder()
+            .end_col(end)
+            .hl_group(group)
+            .build();
+        buffer.set_extmark(ns, row, start, &opts).map(|_| ())
+    };
+    for (row, (text, line)) in lines.iter().enumerate() {
+        match line {
+            Line::Plain => {}
+            Line::Heading => highlight(row, 0, text.len(), "Title")?,
+            Line::Hint => highlight(row, 0, text.len(), "Comment")?,
+            Line::Setting(label) => {
+                let indent = text.len() - text.trim_start().len();
+                highlight(row, indent, *label, "Identifier")?;
+                if text[*label..].trim() == NOT_SET {
+                    highlight(row, *label, text.len(), "Comment")?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Size fitting the longest line and every line, within the editor
+fn settings_size(lines: &[(String, Line)]) -> Result<(u32, u32)> {
+    let editor_width: u32 = api::get_option_value("columns", &OptionOpts::default())?;
+    let editor_height: u32 = api::get_option_value("lines", &OptionOpts::default())?;
+    let longest = lines
+        .iter()
+        .map(|(text, _)| {
+            api::call_function::<_, u32>("strdisplaywidth", (text.as_str(),))
+                .unwrap_or(text.len() as u32)
+        })
+        .max()
+        .unwrap_or(0);
+
+    Ok((
+        (longest + 2).min(editor_width.saturating_sub(4)).max(1),
+        (lines.len() as u32)
+            .min(editor_height.saturating_sub(4))
+            .max(1),
+    ))
+}
+
+/// Writes the current settings into the window, e.g. after one was changed
+fn refresh_settings(buffer: &mut Buffer, window: &mut Window) -> Result<()> {
+    let lines = settings_lines(&get_config());
+    let (width, height) = settings_size(&lines)?;
+    render_settings(buffer, lines)?;
+    window.set_width(width)?;
+    window.set_height(height)?;
+    fetch_info(buffer, window, false)
+}
+
+/// Changes the setting on the cursor line of the settings window
+fn edit_setting(window: &Window) -> Result<()> {
+    let (line, _) = window.get_cursor()?;
+    let Some(field) = field_at(line - 1) else {
+        return Ok(());
+    };
+    let Some(edit) = field.edit else {
+        crate::utils::info(&format!("{} is only changed with setup()", field.label));
+        return Ok(());
+    };
+
+    // Prompts refresh the window once answered, pickers once they close
+    edit()
+}
+
+// The open settings window, which prompts refresh once they are answered
+thread_local! {
+    static SETTINGS_WINDOW: RefCell<Option<(Buffer, Window)>> = const { RefCell::new(None) };
+}
+
+/// Shows a changed setting in the settings window, if one is open
+fn refresh_open_settings() {
+    let Some((mut buffer, mut window)) = SETTINGS_WINDOW.with(|open| open.borrow().clone()) else {
+        return;
+    };
+    if !window.is_valid() {
+        return;
+    }
+    if let Err(e) = refresh_settings(&mut buffer, &mut window) {
+        crate::error::notify_error(&e);
+    }
+}
+
+/// Shows the current aichat configuration in a floating window
+///
+/// Settings are grouped in sections, one per line; the accept keys on a line
+/// open the picker or prompt that changes it, and the window follows the
+/// change. What aichat reports is fetched in the background and filled in
+/// once it arrives.
+pub fn show_current_config() -> nvim_oxi::Result<()> {
+    let lines = settings_lines(&get_config());
+    let keys = get_config().keys.clone();
+
+    // Create a buffer for the window
+    let mut buffer = api::create_buf(false, true)?;
+
+    // Get editor dimensions
+    let width_editor: u32 = api::get_option_value("columns", &OptionOpts::default())?;
+    let height_editor: u32 = api::get_option_value("lines", &OptionOpts::default())?;
 
     // Calculate window dimensions
-    let width = 50;
-    let height = lines.len() as u32;
+    let (width, height) = settings_size(&lines)?;
 
     // Set buffer lines
-    buffer.set_lines(0..0, false, lines)?;
+    render_settings(&mut buffer, lines)?;
 
-    // Make buffer read-only
+    // Keep the buffer out of the file list
     let opts = OptionOpts::builder().scope(Local).buffer(&buffer).build();
-    api::set_option_value("modifiable", false, &opts)?;
     api::set_option_value("buftype", "nofile", &opts)?;
 
-    // Get editor dimensions
-    let current_window = api::get_current_win();
-    let width_editor = current_window.get_width()? as u32;
-    let height_editor = current_window.get_height()? as u32;
-
     // Calculate center position
-    let row = (height_editor - height) / 2;
-    let col = (width_editor - width) / 2;
+    let row = height_editor.saturating_sub(height) / 2;
+    let col = width_editor.saturating_sub(width) / 2;
 
     // Create window configuration
     let win_config = api::types::WindowConfig::builder()
@@ -297,30 +1669,110 @@ pub fn show_current_config() -> nvim_oxi::Result<()> {
         .title_pos(api::types::WindowTitlePosition::Center)
         .build();
 
-    // Open the window
-    let window = api::open_win(&buffer, true, &win_config)?;
+    // Open the window on the first setting
+    let mut window = api::open_win(&buffer, true, &win_config)?;
+    window.set_cursor(HEADER_LINES + 2, 0)?;
+    SETTINGS_WINDOW.with(|open| *open.borrow_mut() = Some((buffer.clone(), window.clone())));
+
+    // The `--info` lines arrive later, the window opens right away
+    fetch_info(&buffer, &window, true)?;
 
     // Set window options
     api::set_option_value(
         "cursorline",
-        false,
+        true,
         &OptionOpts::builder().scope(Local).win(&window).build(),
     )?;
 
-    // Add a keymap to close the window with any key
-    buffer.set_keymap(
-        api::types::Mode::Normal,
-        "<Esc>",
-        ":q<CR>",
-        &SetKeymapOpts::builder().noremap(true).silent(true).build(),
-    )?;
+    let edit_window = window.clone();
+    ui::set_keymaps(&mut buffer, &keys.accept, "Change the setting", move || {
+        if let Err(e) = edit_setting(&edit_window) {
+            crate::error::notify_error(&e);
+        }
+    })?;
 
-    buffer.set_keymap(
-        api::types::Mode::Normal,
-        "q",
-        ":q<CR>",
-        &SetKeymapOpts::builder().noremap(true).silent(true).build(),
+    let close_window = window.clone();
+    ui::set_keymaps(&mut buffer, &keys.cancel, "Close the window", move || {
+        let _ = close_window.clone().close(true);
+    })?;
+
+    // Pickers open their own windows, show their result once focus is back
+    let (refresh_buffer, refresh_window) = (buffer.clone(), window.clone());
+    api::create_autocmd(
+        ["WinEnter"],
+        &CreateAutocmdOpts::builder()
+            .buffer(buffer.clone())
+            .callback(move |_| -> nvim_oxi::Result<bool> {
+                // Returning true removes the autocommand once the float is gone
+                if !refresh_window.is_valid() {
+                    return Ok(true);
+                }
+                let (mut buffer, mut window) = (refresh_buffer.clone(), refresh_window.clone());
+                if let Err(e) = refresh_settings(&mut buffer, &mut window) {
+                    crate::error::notify_error(&e);
+                }
+                Ok(false)
+            })
+            .build(),
     )?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    /// The current configuration after a `setup()` given `table`
+    fn merged(current: Value, table: Value) -> Value {
+        let (Value::Object(mut current), Value::Object(table)) = (current, table) else {
+            panic!("both must be tables");
+        };
+        merge_table(&mut current, table);
+        Value::Object(current)
+    }
+
+    #[test]
+    fn merge_keeps_the_settings_a_struct_table_leaves_out() {
+        let current = json!({ "features": { "dual": true, "inline": false } });
+
+        assert_eq!(
+            merged(current, json!({ "features": { "inline": true } })),
+            json!({ "features": { "dual": true, "inline": true } })
+        );
+    }
+
+    #[test]
+    fn merge_replaces_a_map_sharing_keys_with_the_current_one() {
+        let current = json!({ "env": { "A": "1", "B": "2" } });
+
+        assert_eq!(
+            merged(current, json!({ "env": { "A": "3" } })),
+            json!({ "env": { "A": "3" } })
+        );
+    }
+
+    #[test]
+    fn merge_replaces_a_map_sharing_no_key_with_the_current_one() {
+        let current = json!({
+            "env": { "A": "1", "B": "2" },
+            "dual_models": { "aichat": { "quick": "a", "full": "b" } },
+        });
+
+        assert_eq!(
+            merged(current, json!({ "env": { "C": "1" }, "dual_models": {} })),
+            json!({ "env": { "C": "1" }, "dual_models": {} })
+        );
+    }
+
+    #[test]
+    fn merge_sets_an_unset_struct_table() {
+        let current = json!({ "keymaps": null });
+
+        assert_eq!(
+            merged(current, json!({ "keymaps": { "prefix": "<leader>i" } })),
+            json!({ "keymaps": { "prefix": "<leader>i" } })
+        );
+    }
+}
@@ -0,0 +1,597 @@
+use crate::config::get_config;
+use crate::selection::Selection;
+use crate::{history, utils};
+use nvim_oxi::{
+    api::{
+        self,
+        opts::{OptionOpts, OptionScope::Local},
+        Buffer,
+    },
+    conversion::FromObject,
+    Dictionary, Object,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Source of extra context appended to the prompt
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Provider {
+    /// Signatures of the callers and callees of the function under the cursor,
+    /// from the LSP call hierarchy
+    CallHierarchy,
+    /// The test file belonging to the current source file, located with the
+    /// `test_file_patterns` of its extension
+    TestFile,
+    /// Staged and unstaged `git diff` of the current file
+    GitDiff,
+    /// LSP hover of the symbol under the cursor and the definitions of the
+    /// symbols used in the selection
+    LspDefinitions,
+}
+
+/// What a request does when the buffer has unsaved changes and something
+/// reads its file from disk: the `git_diff` provider or a RAG
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum OnModified {
+    /// Write the buffer first, like `:update`
+    Write,
+    /// Send the request anyway, warning that the file on disk is older
+    Warn,
+    /// Send the unsaved content of the buffer as a context section
+    Send,
+}
+
+/// A titled block of context
+#[derive(Clone)]
+pub struct Section {
+    pub title: String,
+    pub body: String,
+}
+
+impl Provider {
+    /// Collects the provider's context, or `None` when it has nothing to add
+    fn collect(self, buffer: &Buffer, selection: &Selection) -> Option<Section> {
+        match self {
+            Provider::CallHierarchy => call_hierarchy(),
+            Provider::TestFile => test_file(buffer),
+            Provider::GitDiff => git_diff(buffer),
+            Provider::LspDefinitions => lsp_definitions(selection),
+        }
+    }
+}
+
+/// Gathers the context of every provider enabled in the config
+///
+/// Providers run once when the prompt is built, so retries reuse the result.
+/// The sections are cut to `budget` bytes in total, in the order the
+/// providers are configured.
+pub fn gather(buffer: &Buffer, selection: &Selection, budget: usize) -> Vec<Section> {
+    let (providers, reads_file, on_modified) = {
+        let config = get_config();
+        (
+            config.context.clone(),
+            config.rag.is_some() || config.context.contains(&Provider::GitDiff),
+            config.on_modified,
+        )
+    };
+
+    // Runs before the providers, so `git_diff` sees the written file
+    let unsaved = reads_file
+        .then(|| unsaved_changes(buffer, on_modified))
+        .flatten();
+
+    within_budget(
+        unsaved.into_iter().chain(memory(buffer)).chain(
+            providers
+                .iter()
+                .filter_map(|provider| provider.collect(buffer, selection)),
+        ),
+        budget,
+    )
+}
+
+/// Cuts `sections` to `budget` bytes of body in total, in order, marking the
+/// cut one; the sections past the budget aren't collected at all
+pub fn within_budget(sections: impl IntoIterator<Item = Section>, budget: usize) -> Vec<Section> {
+    let mut remaining = budget;
+    let mut kept = Vec::new();
+
+    for section in sections {
+        if remaining == 0 {
+            break;
+        }
+
+        let mut body = section.body;
+        if body.len() > remaining {
+            let mut cut = remaining;
+            while !body.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            body.truncate(cut);
+            body.push_str("\n… (truncated)");
+        }
+        remaining = remaining.saturating_sub(body.len());
+
+        kept.push(Section {
+            title: section.title,
+            body,
+        });
+    }
+
+    kept
+}
+
+/// Bytes kept free for the note of the lines `read_sampled` leaves out
+const SAMPLE_NOTE_BYTES: usize = 64;
+
+/// Reads a file attached as context, keeping only its first and last lines
+/// when it is larger than `limit` bytes, with a note of the omitted ones
+///
+/// The file is read line by line and only the kept lines are held, so a huge
+/// file never ends up in memory or in the prompt as a whole.
+pub fn read_sampled(path: &Path, limit: usize) -> std::io::Result<String> {
+    let file = std::fs::File::open(path)?;
+    if file.metadata()?.len() <= limit as u64 {
+        let mut bytes = Vec::new();
+        BufReader::new(file).read_to_end(&mut bytes)?;
+        return Ok(String::from_utf8_lossy(&bytes).into_owned());
+    }
+
+    let half = limit.saturating_sub(SAMPLE_NOTE_BYTES) / 2;
+    let mut reader = BufReader::new(file);
+    let mut line = Vec::new();
+    let mut head = String::new();
+    let mut head_lines = 0;
+    let mut tail: VecDeque<String> = VecDeque::new();
+    let mut tail_bytes = 0;
+    let mut lines = 0;
+
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        let text = String::from_utf8_lossy(&line).into_owned();
+        line.clear();
+        lines += 1;
+
+        // The head ends at the first line that doesn't fit
+        if head_lines + 1 == lines && head.len() + text.len() <= half {
+            head.push_str(&text);
+            head_lines += 1;
+            continue;
+        }
+        tail_bytes += text.len();
+        tail.push_back(text);
+        while tail_bytes > half {
+            tail_bytes -= tail.pop_front().map_or(0, |dropped| dropped.len());
+        }
+    }
+
+    let omitted = lines - head_lines - tail.len();
+    let tail: String = tail.into_iter().collect();
+    if omitted == 0 {
+        return Ok(head + &tail);
+    }
+    Ok(format!(
+        "{}… (lines {}-{} of {} omitted)\n{}",
+        head,
+        head_lines + 1,
+        head_lines + omitted,
+        lines,
+        tail
+    ))
+}
+
+/// Evaluates a Lua expression returning a list of strings, treating errors
+/// and missing results as an empty list
+pub fn lua_lines(source: &str, arg: impl Into<Object>) -> Vec<String> {
+    nvim_oxi::api::call_function::<_, Object>("luaeval", (source, arg.into()))
+        .ok()
+        .and_then(|obj| Vec::<String>::from_object(obj).ok())
+        .unwrap_or_default()
+}
+
+/// Lists the callers and callees of the function under the cursor
+fn call_hierarchy() -> Option<Section> {
+    let timeout = get_config().lsp_timeout_ms as i64;
+    let lines = lua_lines(CALL_HIERARCHY_SOURCE, timeout);
+
+    (!lines.is_empty()).then(|| Section {
+        title: "Callers and callees of the selected function".into(),
+        body: lines.join("\n"),
+    })
+}
+
+/// Identifiers of the selection whose definitions are looked up
+const MAX_DEFINITIONS: i64 = 20;
+
+/// Resolves the hover information and definitions of the identifiers the
+/// selection uses but doesn't define, all the lookups together waiting at
+/// most `lsp_timeout_ms` for the language server
+fn lsp_definitions(selection: &Selection) -> Option<Section> {
+    let args = Dictionary::from_iter([
+        ("timeout", Object::from(get_config().lsp_timeout_ms as i64)),
+        ("line1", Object::from(selection.line1 as i64)),
+        ("line2", Object::from(selection.line2 as i64)),
+        ("max_symbols", Object::from(MAX_DEFINITIONS)),
+    ]);
+    let lines = lua_lines(LSP_DEFINITIONS_SOURCE, args);
+
+    (!lines.is_empty()).then(|| Section {
+        title: "Reference information about the symbols used in the code".into(),
+        body: lines.join("\n"),
+    })
+}
+
+/// Finds the first existing test file of a source file, using the
+/// configured `test_file_patterns` of its extension
+pub fn find_test_file(path: &Path) -> Option<PathBuf> {
+    let ext = path.extension()?.to_string_lossy().into_owned();
+    let name = path.file_stem()?.to_string_lossy().into_owned();
+    let dir = path.parent()?.to_string_lossy().into_owned();
+
+    let patterns = get_config().test_file_patterns.get(&ext).cloned()?;
+    patterns
+        .iter()
+        .map(|pattern| {
+            PathBuf::from(
+                pattern
+                    .replace("{dir}", &dir)
+                    .replace("{name}", &name)
+                    .replace("{ext}", &ext),
+            )
+        })
+        .find(|candidate| candidate.is_file() && candidate != path)
+}
+
+/// Includes the test file of the current source file
+fn test_file(buffer: &Buffer) -> Option<Section> {
+    let test_path = find_test_file(&buffer.get_name().ok()?)?;
+
+    let body = std::fs::read_to_string(&test_path).ok()?;
+    Some(Section {
+        title: "Tests of this file (keep them passing)".into(),
+        body: format!("// {}\n{}", test_path.display(), body),
+    })
+}
+
+/// Includes the uncommitted changes of the current file, staged ones first
+fn git_diff(buffer: &Buffer) -> Option<Section> {
+    let path = buffer.get_name().ok()?;
+    let root = git_root(path.parent()?)?;
+
+    let diff = |staged: bool| {
+        let mut cmd = Command::new("git");
+        cmd.arg("-C").arg(&root).arg("diff");
+        if staged {
+            cmd.arg("--cached");
+        }
+        cmd.arg("--").arg(&path);
+        git_output(cmd)
+    };
+    let body = [diff(true), diff(false)]
+        .into_iter()
+        .flatten()
+        .filter(|diff| !diff.trim().is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    (!body.is_empty()).then(|| Section {
+        title: "Uncommitted changes of this file".into(),
+        body,
+    })
+}
+
+/// Includes the previous instructions sent for the buffer and their
+/// answers, so a request can follow up on them
+fn memory(buffer: &Buffer) -> Option<Section> {
+    let turns = history::turns(buffer);
+    let body = turns
+        .iter()
+        .map(|turn| {
+            format!(
+                "Instruction: {}\nAnswer:\n{}",
+                turn.instruction, turn.response
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n");
+
+    (!body.is_empty()).then(|| Section {
+        title: "Earlier requests on this file and their answers, oldest first".into(),
+        body,
+    })
+}
+
+/// Applies `on_modified` when the buffer of a file has unsaved changes,
+/// returning the section to send for `OnModified::Send`
+fn unsaved_changes(buffer: &Buffer, on_modified: OnModified) -> Option<Section> {
+    let opts = OptionOpts::builder().scope(Local).buffer(buffer).build();
+    let modified: bool = api::get_option_value("modified", &opts).unwrap_or(false);
+    let path = buffer.get_name().ok()?;
+    if !modified || path.as_os_str().is_empty() {
+        return None;
+    }
+
+    match on_modified {
+        OnModified::Write => {
+            let written = api::call_function::<_, Object>(
+                "luaeval",
+                (
+                    "vim.api.nvim_buf_call(_A, function() vim.cmd('silent update') end)",
+                    Object::from(buffer.handle()),
+                ),
+            );
+            if let Err(err) = written {
+                utils::warn(&format!("Could not write {}: {}", path.display(), err));
+            }
+            None
+        }
+        OnModified::Warn => {
+            utils::warn(&format!(
+                "{} has unsaved changes, the request reads the older file on disk",
+                path.display()
+            ));
+            None
+        }
+        OnModified::Send => {
+            let lines = buffer.get_lines(0..buffer.line_count().ok()?, false).ok()?;
+            let body = lines
+                .map(|line| line.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+                .join("\n");
+            Some(Section {
+                title: format!(
+                    "Unsaved content of {}, newer than the file on disk",
+                    path.display()
+                ),
+                body,
+            })
+        }
+    }
+}
+
+/// The root of the git repository containing `dir`
+fn git_root(dir: &Path) -> Option<PathBuf> {
+    let mut cmd = Command::new("git");
+    cmd.arg("-C")
+        .arg(dir)
+        .args(["rev-parse", "--show-toplevel"]);
+    git_output(cmd).map(|root| PathBuf::from(root.trim()))
+}
+
+/// Stdout of a successful git command, None when git is missing or fails
+fn git_output(mut cmd: Command) -> Option<String> {
+    let output = cmd
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Default `test_file_patterns`, keyed by file extension
+///
+/// `{dir}` is the source file's directory, `{name}` its name without
+/// extension and `{ext}` its extension. Relative patterns are resolved from
+/// the working directory.
+pub fn default_test_file_patterns() -> HashMap<String, Vec<String>> {
+    let js_like = || {
+        vec![
+            "{dir}/{name}.test.{ext}".to_string(),
+            "{dir}/{name}.spec.{ext}".to_string(),
+            "{dir}/__tests__/{name}.test.{ext}".to_string(),
+        ]
+    };
+
+    HashMap::from([
+        (
+            "rs".to_string(),
+            vec!["{dir}/tests.rs".to_string(), "tests/{name}.rs".to_string()],
+        ),
+        (
+            "py".to_string(),
+            vec![
+                "{dir}/test_{name}.py".to_string(),
+                "tests/test_{name}.py".to_string(),
+            ],
+        ),
+        ("go".to_string(), vec!["{dir}/{name}_test.go".to_string()]),
+        ("rb".to_string(), vec!["spec/{name}_spec.rb".to_string()]),
+        ("js".to_string(), js_like()),
+        ("jsx".to_string(), js_like()),
+        ("ts".to_string(), js_like()),
+        ("tsx".to_string(), js_like()),
+    ])
+}
+
+/// Returns `caller: <signature>  (<file>)` and `callee: ...` lines for the
+/// function under the cursor, `_A` being the request timeout in milliseconds
+const CALL_HIERARCHY_SOURCE: &str = r#"(function(timeout)
+  local bufnr = vim.api.nvim_get_current_buf()
+  local clients = vim.lsp.get_clients({ bufnr = bufnr, method = 'textDocument/prepareCallHierarchy' })
+  if #clients == 0 then return {} end
+
+  local params = vim.lsp.util.make_position_params(0, clients[1].offset_encoding)
+  local item
+  for _, response in pairs(vim.lsp.buf_request_sync(bufnr, 'textDocument/prepareCallHierarchy', params, timeout) or {}) do
+    if response.result and response.result[1] then
+      item = response.result[1]
+      break
+    end
+  end
+  if not item then return {} end
+
+  local function signature(it)
+    local target = vim.uri_to_bufnr(it.uri)
+    vim.fn.bufload(target)
+    local line = vim.api.nvim_buf_get_lines(target, it.range.start.line, it.range.start.line + 1, false)[1] or it.name
+    return vim.trim(line) .. '  (' .. vim.fn.fnamemodify(vim.uri_to_fname(it.uri), ':~:.') .. ')'
+  end
+
+  local lines = {}
+  for _, direction in ipairs({
+    { 'callHierarchy/incomingCalls', 'from', 'caller' },
+    { 'callHierarchy/outgoingCalls', 'to', 'callee' },
+  }) do
+    local method, field, label = direction[1], direction[2], direction[3]
+    for _, response in pairs(vim.lsp.buf_request_sync(bufnr, method, { item = item }, timeout) or {}) do
+      for _, call in ipairs(response.result or {}) do
+        table.insert(lines, label .. ': ' .. signature(call[field]))
+      end
+    end
+  end
+  return lines
+end)(_A)"#;
+
+/// Returns the hover text of the symbol under the cursor, then for every
+/// identifier of lines `line1`-`line2` defined elsewhere a
+/// `name  (<file>:<line>)` line followed by the first lines of its
+/// definition. `_A` holds the timeout shared by all the requests, the lines
+/// and the maximum number of identifiers to look up.
+///
+/// Identifiers are the identifier nodes of the treesitter tree, so keywords,
+/// literals and the words of comments are never looked up; without a parser
+/// only the hover text is returned.
+const LSP_DEFINITIONS_SOURCE: &str = r#"(function(args)
+  local bufnr = vim.api.nvim_get_current_buf()
+  local clients = vim.lsp.get_clients({ bufnr = bufnr, method = 'textDocument/definition' })
+  if #clients == 0 then return {} end
+  local encoding = clients[1].offset_encoding
+  local lines = {}
+
+  local deadline = vim.uv.hrtime() / 1e6 + args.timeout
+  local function remaining()
+    return math.floor(deadline - vim.uv.hrtime() / 1e6)
+  end
+
+  local hover = vim.lsp.buf_request_sync(bufnr, 'textDocument/hover', vim.lsp.util.make_position_params(0, encoding), remaining())
+  for _, response in pairs(hover or {}) do
+    if response.result and response.result.contents then
+      vim.list_extend(lines, vim.lsp.util.convert_input_to_markdown_lines(response.result.contents))
+      table.insert(lines, '')
+      break
+    end
+  end
+
+  local ok, parser = pcall(vim.treesitter.get_parser, bufnr)
+  if not ok or not parser then return lines end
+  local first, last = args.line1 - 1, args.line2 - 1
+  local identifiers, seen = {}, {}
+  local function collect(node)
+    local start_row, _, end_row = node:range()
+    if end_row < first or start_row > last or #identifiers >= args.max_symbols then return end
+    if node:child_count() > 0 then
+      for child in node:iter_children() do collect(child) end
+    elseif node:named() and node:type():find('identifier') then
+      local name = vim.treesitter.get_node_text(node, bufnr)
+      if not seen[name] then
+        seen[name] = true
+        table.insert(identifiers, node)
+      end
+    end
+  end
+  collect(parser:parse()[1]:root())
+
+  local uri = vim.uri_from_bufnr(bufnr)
+  for _, node in ipairs(identifiers) do
+    if remaining() <= 0 then break end
+    local word = vim.treesitter.get_node_text(node, bufnr)
+    local row, col = node:start()
+    local text = vim.api.nvim_buf_get_lines(bufnr, row, row + 1, false)[1] or ''
+    local params = {
+      textDocument = { uri = uri },
+      position = { line = row, character = vim.str_utfindex(text, encoding, col, false) },
+    }
+    for _, response in pairs(vim.lsp.buf_request_sync(bufnr, 'textDocument/definition', params, remaining()) or {}) do
+      local result = response.result
+      local location = result and (result[1] or result)
+      if location and (location.uri or location.targetUri) then
+        local target_uri = location.targetUri or location.uri
+        local range = location.targetRange or location.range
+        local inside = target_uri == uri and range.start.line >= first and range.start.line <= last
+        if not inside then
+          local target = vim.uri_to_bufnr(target_uri)
+          vim.fn.bufload(target)
+          local last_line = math.min(range['end'].line, range.start.line + 4)
+          table.insert(lines, word .. '  (' .. vim.fn.fnamemodify(vim.uri_to_fname(target_uri), ':~:.') .. ':' .. (range.start.line + 1) .. ')')
+          vim.list_extend(lines, vim.api.nvim_buf_get_lines(target, range.start.line, last_line + 1, false))
+          table.insert(lines, '')
+        end
+        break
+      end
+    end
+  end
+  return lines
+end)(_A)"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section(title: &str, body: &str) -> Section {
+        Section {
+            title: title.into(),
+            body: body.into(),
+        }
+    }
+
+    #[test]
+    fn within_budget_cuts_the_section_crossing_it_and_drops_the_rest() {
+        let sections = within_budget(
+            [
+                section("a", "0123456789"),
+                section("b", "0123456789"),
+                section("c", "0123456789"),
+            ],
+            15,
+        );
+
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0].body, "0123456789");
+        assert_eq!(sections[1].body, "01234\n… (truncated)");
+    }
+
+    /// Writes `text` to a file of the temporary directory named after the test
+    fn temp_file(name: &str, text: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("aichat_nvim_{}_{}", name, std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn read_sampled_keeps_a_small_file_whole() {
+        let path = temp_file("small", "a\nb\n");
+
+        assert_eq!(read_sampled(&path, 100).unwrap(), "a\nb\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn read_sampled_keeps_the_head_and_tail_of_a_large_file() {
+        let text: String = (1..=1000).map(|n| format!("line {:04}\n", n)).collect();
+        let path = temp_file("large", &text);
+
+        let sampled = read_sampled(&path, 64 + 40).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(
+            sampled,
+            "line 0001\nline 0002\n\
+             … (lines 3-998 of 1000 omitted)\n\
+             line 0999\nline 1000\n"
+        );
+    }
+
+    #[test]
+    fn within_budget_cuts_on_a_char_boundary() {
+        let sections = within_budget([section("a", "ééé")], 3);
+
+        assert_eq!(sections[0].body, "é\n… (truncated)");
+    }
+}
@@ -0,0 +1,127 @@
+use crate::config;
+use crate::error::{notify_error, Result};
+use crate::output::Output;
+use crate::prompt::{self, Location, PromptBuilder};
+use crate::selection::Selection;
+use crate::ui;
+use nvim_oxi::api::{self, types::CommandArgs, Buffer};
+
+/// Formats whose answers are parsed before they replace the selection
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Json => "JSON",
+            Format::Yaml => "YAML",
+            Format::Toml => "TOML",
+        }
+    }
+
+    /// Parses `text`, returning the parser's message when it isn't valid
+    pub fn validate(self, text: &str) -> std::result::Result<(), String> {
+        match self {
+            Format::Json => serde_json::from_str::<serde_json::Value>(text)
+                .map(drop)
+                .map_err(|err| err.to_string()),
+            Format::Yaml => serde_yaml::from_str::<serde_yaml::Value>(text)
+                .map(drop)
+                .map_err(|err| err.to_string()),
+            Format::Toml => toml::from_str::<toml::Table>(text)
+                .map(drop)
+                .map_err(|err| err.to_string()),
+        }
+    }
+}
+
+/// Targets of `:AichatConvert`: the name, how the instruction calls it and
+/// the format checking the answer, if any
+const TARGETS: [(&str, &str, Option<Format>); 9] = [
+    ("json", "JSON", Some(Format::Json)),
+    ("yaml", "YAML", Some(Format::Yaml)),
+    ("toml", "TOML", Some(Format::Toml)),
+    ("csv", "CSV", None),
+    ("xml", "XML", None),
+    ("postgresql", "the PostgreSQL dialect of SQL", None),
+    ("mysql", "the MySQL dialect of SQL", None),
+    ("sqlite", "the SQLite dialect of SQL", None),
+    ("tsql", "the T-SQL dialect of SQL", None),
+];
+
+/// Names of the targets, for completion
+pub fn targets() -> Vec<String> {
+    TARGETS
+        .iter()
+        .map(|(name, _, _)| name.to_string())
+        .collect()
+}
+
+/// Handles `:[range]AichatConvert [target]`
+///
+/// The target is picked when not given. Answers in JSON, YAML or TOML only
+/// replace the selection once they parse, or when told to insert them anyway.
+pub fn aichat_convert(args: CommandArgs) -> Result<()> {
+    let buffer = api::get_current_buf();
+    let selection = Selection::from_command(&args, &buffer)?;
+
+    if let Some(target) = args
+        .args
+        .as_deref()
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+    {
+        return convert(buffer, selection, target);
+    }
+
+    let opts = ui::SelectOpts {
+        prompt: Some("Convert to".to_string()),
+        kind: None,
+    };
+    ui::vim_ui_select(targets(), Some(opts), move |target, _index| {
+        if let Some(target) = target {
+            if let Err(err) = convert(buffer.clone(), selection, &target) {
+                notify_error(&err);
+            }
+        }
+    })?;
+    Ok(())
+}
+
+/// Sends the selection with the conversion instruction of `target`
+fn convert(buffer: Buffer, selection: Selection, target: &str) -> Result<()> {
+    let Some(&(_, description, format)) = TARGETS.iter().find(|(name, _, _)| *name == target)
+    else {
+        crate::utils::warn(&format!(
+            "Unknown conversion target {}, use one of {}",
+            target,
+            targets().join(", ")
+        ));
+        return Ok(());
+    };
+
+    let instruction = format!(
+        "Convert this to {}, keeping the same data and meaning. Reply with the converted \
+         text only, in a single code block.",
+        description
+    );
+    let code = prompt::fenced(&buffer, &selection.read(&buffer)?.join("\n"))?;
+    let builder = PromptBuilder::new(&instruction)
+        .location(&Location::current(&buffer, &selection))
+        .part(code);
+
+    let mut config = config::get_config().clone();
+    config.output = Output::Replace;
+    config.validate_as = format;
+    Ok(crate::run_request_with(
+        buffer,
+        selection,
+        builder.build(),
+        Some(&builder),
+        config,
+    )?)
+}
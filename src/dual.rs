@@ -0,0 +1,315 @@
+use crate::error::{notify_error, Result};
+use crate::history::{self, Edit};
+use crate::output::{self, Output};
+use crate::prompt::PromptBuilder;
+use crate::selection::Selection;
+use crate::{config, inline, queue, transform, ui, utils};
+use nvim_oxi::api::{
+    self,
+    opts::{GetExtmarkByIdOpts, SetExtmarkOpts},
+    types::ExtmarkVirtTextPosition,
+    Buffer,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+/// Models used for a quick answer and for the full answer that may replace it
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ModelPair {
+    pub quick: Box<str>,
+    pub full: Box<str>,
+}
+
+/// Progress of a dual request, shared by the callbacks of both answers
+enum State {
+    /// No answer has been written yet
+    Waiting,
+    /// The quick answer is being written in chunks, `full` applies a full
+    /// answer that arrived meanwhile once it is in
+    Writing { full: Option<PendingFull> },
+    /// The quick answer is in the buffer, marked by an extmark on its first line
+    Quick {
+        original: Vec<String>,
+        written: usize,
+        line1: usize,
+        mark: u32,
+    },
+    /// The full answer has been handled
+    Done,
+}
+
+/// Applies the full answer given the state the quick answer left
+type PendingFull = Box<dyn FnOnce(State) -> Result<()> + Send>;
+
+/// Sends the prompt to both models of `pair`
+///
+/// The quick answer is written as soon as it arrives and marked as such.
+/// When the full answer follows, the user is asked whether it should replace
+/// the quick one. If the full answer wins the race, the quick request is
+/// cancelled. Both are ordinary requests: they are queued, can be aborted,
+/// and show up in the stats, the telemetry and the history.
+pub fn run(
+    buffer: Buffer,
+    selection: Selection,
+    complete_prompt: String,
+    builder: Option<&PromptBuilder>,
+    pair: ModelPair,
+    output: Output,
+) -> Result<()> {
+    utils::info(&format!("Sending to {} and {}", pair.quick, pair.full));
+
+    let mut quick_config = config::get_config().clone();
+    quick_config.output = output;
+    // Waiting for a retry or a cancel prompt defeats the point of a quick answer
+    quick_config.retries = 0;
+    quick_config.slow_request_ms = 0;
+    let mut full_config = config::get_config().clone();
+    full_config.output = output;
+    quick_config.model = Some(pair.quick.clone());
+    full_config.model = Some(pair.full.clone());
+
+    let state = Arc::new(Mutex::new(State::Waiting));
+
+    let quick_key = {
+        let state = state.clone();
+        let mut buffer = buffer.clone();
+        let register = quick_config.register.clone();
+        let template = quick_config.mode_arg.to_string();
+        crate::submit_request(
+            buffer.clone(),
+            selection,
+            complete_prompt.clone(),
+            builder,
+            quick_config,
+            move |result, anchor| {
+                // Resolved whatever the outcome, so the extmarks are always removed
+                let target = anchor.resolve();
+                let response = result?;
+                let mut current = state.lock().unwrap_or_else(|e| e.into_inner());
+                if !matches!(*current, State::Waiting) {
+                    return Ok(());
+                }
+
+                let lines = transform::apply(&template, response);
+                // A deleted or unwritable target is reported once the full answer arrives
+                if output.writes_buffer() && !output::writable(&buffer)? {
+                    return Ok(());
+                }
+                let Some(selection) = target? else {
+                    return Ok(());
+                };
+                let original = selection.linewise().read(&buffer)?;
+                *current = State::Writing { full: None };
+                // The write may finish right away, which locks the state again
+                drop(current);
+
+                let mut marked = buffer.clone();
+                let full = pair.full.clone();
+                output.apply(&mut buffer, &selection, lines, &register, move |written| {
+                    let quick = match written {
+                        Some(replacement) => State::Quick {
+                            original,
+                            written: replacement.len(),
+                            line1: selection.line1,
+                            mark: mark_quick_answer(&mut marked, selection.line1, &full)?,
+                        },
+                        // Register outputs have nothing to upgrade, the full answer overwrites them
+                        None => State::Waiting,
+                    };
+                    let mut current = state.lock().unwrap_or_else(|e| e.into_inner());
+                    match std::mem::replace(&mut *current, quick) {
+                        State::Writing {
+                            full: Some(apply_full),
+                        } => {
+                            let quick = std::mem::replace(&mut *current, State::Done);
+                            drop(current);
+                            apply_full(quick)
+                        }
+                        _ => Ok(()),
+                    }
+                })
+            },
+        )?
+    };
+
+    let target_buffer = buffer.clone();
+    let register = full_config.register.clone();
+    let template = full_config.mode_arg.to_string();
+    let full_key = crate::submit_request(
+        buffer,
+        selection,
+        complete_prompt,
+        builder,
+        full_config,
+        move |result, anchor| {
+            let mut current = state.lock().unwrap_or_else(|e| e.into_inner());
+            let previous = std::mem::replace(&mut *current, State::Done);
+            if let Some(quick_key) = quick_key {
+                queue::cancel(quick_key);
+            }
+
+            let target = anchor.resolve();
+            let lines = transform::apply(&template, result?);
+            let target = target?;
+            if let State::Writing { .. } = previous {
+                // Wait for the quick answer to be in before replacing it
+                *current = State::Writing {
+                    full: Some(Box::new(move |quick| {
+                        apply_full_answer(
+                            target_buffer,
+                            selection,
+                            target,
+                            lines,
+                            output,
+                            register,
+                            quick,
+                        )
+                    })),
+                };
+                return Ok(());
+            }
+            drop(current);
+            apply_full_answer(
+                target_buffer,
+                selection,
+                target,
+                lines,
+                output,
+                register,
+                previous,
+            )
+        },
+    )?;
+
+    // Without a full answer to follow, the quick one would stay marked as waiting
+    if let (Some(quick_key), None) = (quick_key, full_key) {
+        queue::cancel(quick_key);
+    }
+    Ok(())
+}
+
+/// Writes the full answer, replacing the quick one if the user agrees
+///
+/// `target` is where the selection is now, `None` when its text was deleted.
+fn apply_full_answer(
+    buffer: Buffer,
+    selection: Selection,
+    target: Option<Selection>,
+    lines: Vec<String>,
+    output: Output,
+    register: Box<str>,
+    previous: State,
+) -> Result<()> {
+    output.check_writable(&buffer.clone(), lines, move |lines| {
+        let mut buffer = buffer;
+        let mut selection = selection;
+
+        let State::Quick {
+            original: quick_original,
+            written,
+            line1,
+            mark,
+        } = previous
+        else {
+            if let State::Waiting = previous {
+                match target {
+                    Some(target) => selection = target,
+                    None if output.writes_buffer() => {
+                        utils::copy_to_registers(&lines.join("\n"));
+                        utils::warn(
+                            "The text targeted by the Aichat request was deleted, \
+                             the answer was copied to the registers instead",
+                        );
+                        return Ok(());
+                    }
+                    None => {}
+                }
+            }
+            return write(buffer, selection, None, lines, output, &register);
+        };
+
+        // Follow the quick answer if lines were added or removed above it
+        let ns = api::create_namespace(NAMESPACE);
+        let line1 = buffer
+            .get_extmark_by_id(ns, mark, &GetExtmarkByIdOpts::default())
+            .map(|(row, _, _)| row + 1)
+            .unwrap_or(line1);
+        let _ = buffer.del_extmark(ns, mark);
+
+        let question = "The full Aichat answer arrived. Replace the quick answer?";
+        ui::confirm(question, move |replace| {
+            if !replace {
+                return Ok(());
+            }
+
+            // Put the original text back, so the full answer is applied to the same selection
+            let quick = if written == 0 {
+                Selection::below(line1 - 1)
+            } else {
+                Selection {
+                    line1,
+                    line2: line1 + written - 1,
+                    columns: None,
+                }
+            };
+            quick.replace(&mut buffer, quick_original.clone())?;
+            selection.line2 = line1 + selection.line2 - selection.line1;
+            selection.line1 = line1;
+            write(
+                buffer,
+                selection,
+                Some(quick_original),
+                lines,
+                output,
+                &register,
+            )
+        });
+        Ok(())
+    })
+}
+
+/// Writes an answer over `selection`, recording the edit with `original`
+/// as the text it replaced, read from the buffer when `None`
+fn write(
+    mut buffer: Buffer,
+    selection: Selection,
+    original: Option<Vec<String>>,
+    lines: Vec<String>,
+    output: Output,
+    register: &str,
+) -> Result<()> {
+    let original = match original {
+        Some(original) => original,
+        None => selection.linewise().read(&buffer)?,
+    };
+    let target = buffer.clone();
+    output.apply(&mut buffer, &selection, lines, register, move |written| {
+        let Some(replacement) = written else {
+            return Ok(());
+        };
+        let edit = Edit {
+            buffer: target,
+            line1: selection.line1,
+            original,
+            replacement,
+        };
+        if let Err(err) = inline::mark_hunks(&edit) {
+            notify_error(&err);
+        }
+        history::record_edit(edit);
+        Ok(())
+    })
+}
+
+/// Namespace of the extmarks that flag quick answers
+const NAMESPACE: &str = "aichat_nvim_dual";
+
+/// Flags the first line of a quick answer until the full answer arrives
+fn mark_quick_answer(buffer: &mut Buffer, line1: usize, full: &str) -> Result<u32> {
+    let ns = api::create_namespace(NAMESPACE);
+    let opts = SetExtmarkOpts::builder()
+        .virt_text([(format!("quick answer, waiting for {}", full), "Comment")])
+        .virt_text_pos(ExtmarkVirtTextPosition::Eol)
+        .build();
+    Ok(buffer.set_extmark(ns, line1 - 1, 0, &opts)?)
+}
@@ -1,5 +1,8 @@
-use nvim_oxi::{api, Error as NvimOxiError};
-use std::process::ExitStatus;
+use nvim_oxi::{
+    api::{self, opts::SetKeymapOpts},
+    Error as NvimOxiError,
+};
+use std::process::{Command, ExitStatus};
 use thiserror::Error;
 
 /// Main error type for the aichat_nvim plugin
@@ -13,9 +16,34 @@ pub enum AichatError {
     #[error("Failed to execute aichat command: {0}")]
     ProcessExecution(#[from] std::io::Error),
 
+    /// The executable of a command doesn't exist, with how to get it when
+    /// it is aichat
+    #[error("{program} was not found{}", render_hint(.hint))]
+    BinaryNotFound {
+        program: String,
+        hint: Option<&'static str>,
+    },
+
     /// Command execution failed with non-zero exit status
-    #[error("Aichat command failed with exit status: {status}. stderr: {stderr}. stdout: {stdout}")]
-    CommandFailed { status: ExitStatus, stderr: String, stdout: String },
+    ///
+    /// aichat reports some failures on stdout, so both streams are kept
+    #[error("{program} failed with exit status: {status}{}", render_streams(.stderr, .stdout))]
+    CommandFailed {
+        program: String,
+        command: String,
+        status: ExitStatus,
+        stderr: String,
+        stdout: String,
+    },
+
+    /// A request of the HTTP backend failed, without a status when no
+    /// response arrived
+    #[error("HTTP request to {url} failed{}: {message}", render_status(.status))]
+    Http {
+        url: String,
+        status: Option<u16>,
+        message: String,
+    },
 
     /// Configuration related errors
     #[error("Configuration error: {0}")]
@@ -29,6 +57,18 @@ pub enum AichatError {
     #[error("Missing required value: {0}")]
     MissingValue(String),
 
+    /// The installed aichat is too old for a feature
+    #[error("{feature} requires aichat >= {required}, found {found}")]
+    UnsupportedVersion {
+        feature: &'static str,
+        required: String,
+        found: String,
+    },
+
+    /// The request was cancelled by the user
+    #[error("Aichat request was cancelled")]
+    Cancelled,
+
     /// No code block found in output
     #[error("No code block found in aichat output")]
     NoCodeBlock,
@@ -71,26 +111,159 @@ impl AichatError {
         Self::Application(msg.into())
     }
 
+    /// Creates the error of a missing executable, pointing to the install
+    /// instructions and `aichat_path` when it is the configured aichat
+    pub fn binary_not_found(program: impl Into<String>) -> Self {
+        let program = program.into();
+        let hint =
+            (program == *crate::config::get_config().aichat_path).then_some(AICHAT_NOT_FOUND_HINT);
+        Self::BinaryNotFound { program, hint }
+    }
+
+    /// Creates the error of a command that couldn't be started, telling a
+    /// missing executable apart from other failures
+    pub fn spawn_failed(command: &Command, err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::NotFound => {
+                Self::binary_not_found(command.get_program().to_string_lossy())
+            }
+            _ => Self::ProcessExecution(err),
+        }
+    }
+
     /// Creates a command failed error from process output
-    pub fn command_failed(status: ExitStatus, stderr: Vec<u8>, stdout: Vec<u8>) -> Self {
+    pub fn command_failed(
+        command: &Command,
+        status: ExitStatus,
+        stderr: Vec<u8>,
+        stdout: Vec<u8>,
+    ) -> Self {
         let stderr_str = String::from_utf8_lossy(&stderr).to_string();
         let stdout_str = String::from_utf8_lossy(&stdout).to_string();
         Self::CommandFailed {
+            program: command.get_program().to_string_lossy().into_owned(),
+            command: command_line(command),
             status,
             stderr: stderr_str,
             stdout: stdout_str,
         }
     }
 
+    /// Whether the failure looks temporary (rate limit, timeout, overloaded
+    /// server) so that retrying the same request may succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::CommandFailed { stderr, stdout, .. } => {
+                looks_transient(&format!("{}\n{}", stderr, stdout))
+            }
+            Self::Http {
+                status: Some(status),
+                ..
+            } => is_transient_status(*status),
+            Self::Http { message, .. } => looks_transient(message),
+            _ => false,
+        }
+    }
+
     /// Creates a string conversion error
     pub fn string_conversion(msg: impl Into<String>) -> Self {
         Self::StringConversion(msg.into())
     }
 }
 
+/// Renders the non-empty output streams of a failed command, one per line
+fn render_streams(stderr: &str, stdout: &str) -> String {
+    [("stderr", stderr.trim()), ("stdout", stdout.trim())]
+        .into_iter()
+        .filter(|(_, output)| !output.is_empty())
+        .map(|(name, output)| format!("\n{}: {}", name, output))
+        .collect()
+}
+
+/// How to get aichat when it is missing
+const AICHAT_NOT_FOUND_HINT: &str = "Install it (https://github.com/sigoden/aichat#install) \
+     or set `aichat_path` in setup() to its location";
+
+/// Renders the hint of a missing executable, if it has one
+fn render_hint(hint: &Option<&'static str>) -> String {
+    hint.map(|hint| format!(". {}", hint)).unwrap_or_default()
+}
+
+/// Renders the HTTP status of a failed request, if one arrived
+fn render_status(status: &Option<u16>) -> String {
+    status
+        .map(|status| format!(" with status {}", status))
+        .unwrap_or_default()
+}
+
+/// Whether error output reports a rate limit, a timeout or an overloaded
+/// server, either as an HTTP status (aichat's `(status: 429)`, `status code
+/// 503`, `HTTP 502`) or in the words of the usual error messages
+fn looks_transient(output: &str) -> bool {
+    const TRANSIENT_PHRASES: [&str; 7] = [
+        "rate limit",
+        "too many requests",
+        "timed out",
+        "connection reset",
+        "connection refused",
+        "temporarily unavailable",
+        "overloaded",
+    ];
+
+    let output = output.to_lowercase();
+    http_statuses(&output).any(is_transient_status)
+        || TRANSIENT_PHRASES
+            .iter()
+            .any(|phrase| output.contains(phrase))
+}
+
+/// The three-digit HTTP statuses mentioned in lowercased error output
+fn http_statuses(output: &str) -> impl Iterator<Item = u16> + '_ {
+    const STATUS_PREFIXES: [&str; 5] =
+        ["status: ", "status code ", "status ", "http ", "http/1.1 "];
+
+    STATUS_PREFIXES.iter().flat_map(move |prefix| {
+        output.match_indices(prefix).filter_map(move |(at, _)| {
+            let digits: String = output[at + prefix.len()..]
+                .chars()
+                .take_while(char::is_ascii_digit)
+                .collect();
+            (digits.len() == 3).then(|| digits.parse().ok()).flatten()
+        })
+    })
+}
+
+/// Rate limits and server errors are worth retrying
+fn is_transient_status(status: u16) -> bool {
+    status == 429 || (500..600).contains(&status)
+}
+
+/// Formats a command as it would be typed in a shell, quoting arguments when needed
+fn command_line(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|part| {
+            let part = part.to_string_lossy();
+            if part.is_empty() || part.contains(|c: char| c.is_whitespace() || c == '\'') {
+                format!("'{}'", part.replace('\'', r"'\''"))
+            } else {
+                part.into_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
 /// Result type alias for convenience
 pub type Result<T> = std::result::Result<T, AichatError>;
 
+/// Wraps errors returned directly by the Neovim API functions
+impl From<api::Error> for AichatError {
+    fn from(err: api::Error) -> Self {
+        Self::NvimApi(err.into())
+    }
+}
+
 /// Converts AichatError to nvim_oxi::Error for compatibility with nvim-oxi functions
 impl From<AichatError> for NvimOxiError {
     fn from(err: AichatError) -> Self {
@@ -105,6 +278,62 @@ impl From<AichatError> for NvimOxiError {
 /// This should be called at the boundary where errors are finally handled
 pub fn notify_error(err: &AichatError) {
     let _ = crate::utils::error(&err.to_string());
+
+    // Notifications truncate long CLI output, so show the full report as well
+    if let AichatError::CommandFailed {
+        command,
+        status,
+        stderr,
+        stdout,
+        ..
+    } = err
+    {
+        let _ = show_command_failure(command, status, stderr, stdout);
+    }
+}
+
+/// Opens a float with everything needed to debug a failed aichat command
+///
+/// `y` copies the report to the unnamed and clipboard registers
+fn show_command_failure(
+    command: &str,
+    status: &ExitStatus,
+    stderr: &str,
+    stdout: &str,
+) -> nvim_oxi::Result<()> {
+    let mut lines = vec![
+        format!("Command: {}", command),
+        format!("Status: {}", status),
+    ];
+    for (name, output) in [("stderr", stderr), ("stdout", stdout)] {
+        lines.push(String::new());
+        lines.push(format!("{}:", name));
+        if output.trim().is_empty() {
+            lines.push("(empty)".into());
+        } else {
+            lines.extend(output.lines().map(String::from));
+        }
+    }
+
+    let report = lines.join("\n");
+    let (mut buffer, _window) = crate::ui::open_float("Aichat Error", lines)?;
+
+    buffer.set_keymap(
+        api::types::Mode::Normal,
+        "y",
+        "",
+        &SetKeymapOpts::builder()
+            .callback(move |_| {
+                crate::utils::copy_to_registers(&report);
+                crate::utils::info("Copied aichat error report");
+            })
+            .noremap(true)
+            .silent(true)
+            .desc("Copy the error report")
+            .build(),
+    )?;
+
+    Ok(())
 }
 
 // /// Utility function to convert Result<T, AichatError> to nvim_oxi::Result<T>
@@ -125,3 +354,27 @@ pub fn notify_error(err: &AichatError) {
 //     handle_error(result)
 // }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses_and_messages_of_temporary_failures_are_transient() {
+        assert!(looks_transient("Error: Too many requests (status: 429)"));
+        assert!(looks_transient("server responded with status code 503"));
+        assert!(looks_transient("HTTP 502 from the proxy"));
+        assert!(looks_transient(
+            "error sending request: operation timed out"
+        ));
+    }
+
+    #[test]
+    fn numbers_and_words_that_only_look_like_failures_are_not_transient() {
+        assert!(!looks_transient("Error: unknown model gpt-4-0429"));
+        assert!(!looks_transient("Error: invalid request (status: 400)"));
+        assert!(!looks_transient(
+            "ValueError: timeout must be positive, got 5030"
+        ));
+        assert!(!looks_transient("status: 4290"));
+    }
+}
@@ -0,0 +1,137 @@
+use crate::backend::BackendKind;
+use crate::config;
+use crate::error::Result;
+use crate::output::Output;
+use crate::selection::Selection;
+use crate::{ui, utils};
+use nvim_oxi::api::{self, types::CommandArgs, Buffer};
+
+/// Placeholder of `filter_command` replaced by the instruction
+const PROMPT_PLACEHOLDER: &str = "{prompt}";
+
+/// Handles `:[range]AichatFilter [instruction]`
+///
+/// Pipes the selection through `filter_command` and replaces it with what the
+/// command prints, as printed, going through the same anchoring, validation,
+/// history and hunk review as aichat answers. The instruction is asked for when the
+/// template has a `{prompt}` and none is given.
+pub fn aichat_filter(args: CommandArgs) -> Result<()> {
+    let Some(template) = config::get_config().filter_command.clone() else {
+        utils::warn("Set filter_command in setup(), e.g. llm -m gpt-4 -s \"{prompt}\"");
+        return Ok(());
+    };
+    let buffer = api::get_current_buf();
+    let selection = Selection::from_command(&args, &buffer)?;
+    let instruction = args.args.as_deref().map(str::trim).unwrap_or_default();
+
+    if !instruction.is_empty() || !template.contains(PROMPT_PLACEHOLDER) {
+        return filter(buffer, selection, &template, instruction);
+    }
+
+    ui::input(
+        "Aichat Filter >",
+        "",
+        move |instruction| match instruction {
+            Some(instruction) => filter(buffer, selection, &template, &instruction),
+            None => Ok(()),
+        },
+    );
+    Ok(())
+}
+
+/// Runs the command of the template with the selection on stdin
+fn filter(buffer: Buffer, selection: Selection, template: &str, instruction: &str) -> Result<()> {
+    let command: Vec<String> = split_words(template)
+        .into_iter()
+        .map(|word| word.replace(PROMPT_PLACEHOLDER, instruction))
+        .collect();
+    if command.is_empty() {
+        utils::warn("filter_command is empty");
+        return Ok(());
+    }
+
+    let mut text = selection.read(&buffer)?.join("\n");
+    text.push('\n');
+
+    let mut config = config::get_config().clone();
+    config.backend = BackendKind::Filter(command);
+    config.output = Output::Replace;
+    Ok(crate::run_request_with(
+        buffer, selection, text, None, config,
+    )?)
+}
+
+/// Splits a command line into words like a shell, honouring single and
+/// double quotes and backslash escapes, without expanding anything
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                words.extend(word.take());
+            }
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                word.extend(chars.by_ref().take_while(|&c| c != '\''));
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => word.extend(chars.next()),
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => {
+                word.get_or_insert_with(String::new).extend(chars.next());
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    words.extend(word);
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_prompt_stays_one_word() {
+        let words = split_words(r#"llm -m gpt-4 -s "{prompt}""#);
+        assert_eq!(words, ["llm", "-m", "gpt-4", "-s", "{prompt}"]);
+
+        let words = split_words("llm -s '{prompt} please'");
+        let command: Vec<String> = words
+            .into_iter()
+            .map(|word| word.replace(PROMPT_PLACEHOLDER, "add docs"))
+            .collect();
+        assert_eq!(command, ["llm", "-s", "add docs please"]);
+    }
+
+    #[test]
+    fn empty_quotes_are_an_empty_word() {
+        assert_eq!(split_words("cmd '' x"), ["cmd", "", "x"]);
+        assert_eq!(split_words(r#"cmd """#), ["cmd", ""]);
+    }
+
+    #[test]
+    fn backslashes_escape_outside_and_inside_double_quotes() {
+        assert_eq!(split_words(r"a\ b c\'d"), ["a b", "c'd"]);
+        assert_eq!(split_words(r#""say \"hi\" \\ now""#), [r#"say "hi" \ now"#]);
+        // Single quotes keep backslashes as they are
+        assert_eq!(split_words(r"'a\b'"), [r"a\b"]);
+    }
+
+    #[test]
+    fn runs_of_whitespace_separate_words() {
+        assert_eq!(split_words("  a \t\n b   "), ["a", "b"]);
+        assert!(split_words(" \t ").is_empty());
+    }
+}
@@ -0,0 +1,433 @@
+use crate::config::{get_config, AichatConfig};
+use crate::error::{AichatError, Result};
+use crate::prompt::PromptBuilder;
+use crate::selection::{Anchor, Selection};
+use nvim_oxi::api::{self, Buffer};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A change a response made to a buffer, kept to follow up on it
+///
+/// Lines are whole lines, so charwise edits are recorded with the text
+/// around the selection
+#[derive(Clone)]
+pub struct Edit {
+    pub buffer: Buffer,
+    /// First changed line, 1-based
+    pub line1: usize,
+    /// The lines before the change
+    pub original: Vec<String>,
+    /// The lines written by the change
+    pub replacement: Vec<String>,
+}
+
+impl Edit {
+    /// The lines the edit occupies now
+    pub fn current_range(&self) -> Selection {
+        match self.replacement.len() {
+            // Nothing was written, the original lines were only removed
+            0 => Selection::below(self.line1 - 1),
+            written => Selection {
+                line1: self.line1,
+                line2: self.line1 + written - 1,
+                columns: None,
+            },
+        }
+    }
+
+    /// Puts the original lines back in place of the replacement, assuming it
+    /// hasn't moved
+    pub fn revert(&self) -> Result<()> {
+        let mut buffer = self.buffer.clone();
+        self.current_range()
+            .replace(&mut buffer, self.original.clone())?;
+        Ok(())
+    }
+
+    /// Renders the edit as a diff of the removed and added lines
+    pub fn as_diff(&self) -> String {
+        let removed = self.original.iter().map(|line| format!("-{}", line));
+        let added = self.replacement.iter().map(|line| format!("+{}", line));
+        removed.chain(added).collect::<Vec<_>>().join("\n")
+    }
+}
+
+/// A request as it was sent, kept to send it again
+#[derive(Clone)]
+pub struct Request {
+    pub buffer: Buffer,
+    pub selection: Selection,
+    pub prompt: String,
+    /// The builder of the prompt, `None` when nothing of it was typed
+    pub builder: Option<PromptBuilder>,
+    /// The configuration at the time of the request
+    pub config: AichatConfig,
+}
+
+impl Request {
+    /// The part of the prompt typed by the user, empty when there is none
+    pub fn instruction(&self) -> &str {
+        self.builder.as_ref().map_or("", PromptBuilder::instruction)
+    }
+}
+
+/// An instruction sent for a buffer and the answer it got
+#[derive(Clone)]
+pub struct Turn {
+    pub instruction: String,
+    pub response: String,
+}
+
+/// Version of the history file written by this build
+///
+/// - 0: a bare array of instructions, some of them objects with their time
+///   and answer
+/// - 1: `{ "version": 1, "instructions": [...] }`, instructions only
+const HISTORY_VERSION: u32 = 1;
+
+/// A history file of any version
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedHistory {
+    Versioned {
+        version: u32,
+        instructions: Vec<SavedInstruction>,
+    },
+    Bare(Vec<SavedInstruction>),
+}
+
+/// The history file as this build writes it
+#[derive(Serialize)]
+struct HistoryFile<'a> {
+    version: u32,
+    instructions: &'a VecDeque<String>,
+}
+
+/// An instruction as the history file holds it, version 0 files also hold
+/// objects
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedInstruction {
+    Instruction(String),
+    Entry { instruction: String },
+}
+
+impl From<SavedInstruction> for String {
+    fn from(saved: SavedInstruction) -> Self {
+        match saved {
+            SavedInstruction::Instruction(instruction) => instruction,
+            SavedInstruction::Entry { instruction } => instruction,
+        }
+    }
+}
+
+/// A remembered edit, with an anchor following its replacement through
+/// later changes to the buffer
+struct TrackedEdit {
+    edit: Edit,
+    anchor: Option<Anchor>,
+}
+
+thread_local! {
+    static EDITS: RefCell<VecDeque<TrackedEdit>> = const { RefCell::new(VecDeque::new()) };
+    static LAST_REQUEST: RefCell<Option<Request>> = const { RefCell::new(None) };
+    // Read from `prompt_history.json` on first use
+    static INSTRUCTIONS: RefCell<Option<VecDeque<String>>> = const { RefCell::new(None) };
+    static TURNS: RefCell<HashMap<i32, VecDeque<Turn>>> = RefCell::new(HashMap::new());
+}
+
+/// Held while the history file is rewritten
+static SAVING: Mutex<()> = Mutex::new(());
+
+/// Number of typed instructions remembered for the history pickers
+const MAX_INSTRUCTIONS: usize = 100;
+
+/// The file keeping the typed instructions across sessions, in Neovim's
+/// data directory, unless `persist_history` is off
+fn instructions_file() -> Option<PathBuf> {
+    if !get_config().persist_history {
+        return None;
+    }
+    let data: String = api::call_function("stdpath", ("data",)).ok()?;
+    Some(
+        PathBuf::from(data)
+            .join("aichat_nvim")
+            .join("prompt_history.json"),
+    )
+}
+
+/// Runs `f` on the typed instructions, oldest first, reading them from the
+/// history file the first time
+fn with_instructions<R>(f: impl FnOnce(&mut VecDeque<String>) -> R) -> R {
+    INSTRUCTIONS.with(|instructions| {
+        let mut instructions = instructions.borrow_mut();
+        let instructions = instructions.get_or_insert_with(|| {
+            instructions_file()
+                .and_then(|path| read_instructions(&path).ok())
+                .unwrap_or_default()
+        });
+        f(instructions)
+    })
+}
+
+/// The instructions saved in a history file, none when it is missing or
+/// unreadable, see `parse_instructions`
+fn read_instructions(path: &Path) -> std::io::Result<VecDeque<String>> {
+    parse_instructions(&std::fs::read_to_string(path).unwrap_or_default())
+}
+
+/// The instructions of the text of a history file, migrated from older
+/// versions, none when it doesn't parse
+///
+/// A file written by a newer build is an error, so it isn't overwritten.
+fn parse_instructions(text: &str) -> std::io::Result<VecDeque<String>> {
+    let instructions = match serde_json::from_str::<SavedHistory>(text) {
+        Ok(SavedHistory::Bare(instructions)) => instructions,
+        Ok(SavedHistory::Versioned {
+            version,
+            instructions,
+        }) if version <= HISTORY_VERSION => instructions,
+        Ok(SavedHistory::Versioned { version, .. }) => {
+            return Err(std::io::Error::other(format!(
+                "it has version {version}, written by a newer aichat_nvim"
+            )))
+        }
+        Err(_) => Vec::new(),
+    };
+    Ok(instructions.into_iter().map(String::from).collect())
+}
+
+/// Makes `instruction` the newest of `instructions`, dropping the oldest
+/// ones beyond `MAX_INSTRUCTIONS`
+fn remember(instructions: &mut VecDeque<String>, instruction: &str) {
+    instructions.retain(|remembered| remembered != instruction);
+    instructions.push_back(instruction.to_string());
+    let excess = instructions.len().saturating_sub(MAX_INSTRUCTIONS);
+    instructions.drain(..excess);
+}
+
+/// Adds an instruction to the history file on a background thread, a
+/// failure only costs the history of the next session
+///
+/// The file is read again first, so Neovim instances used side by side keep
+/// each other's instructions, and replaced through a temporary file, so an
+/// interrupted write never leaves a truncated history behind.
+fn save_instruction(instruction: &str) {
+    let Some(path) = instructions_file() else {
+        return;
+    };
+    let instruction = instruction.to_string();
+    let started = crate::job_runner::run_in_background(
+        move || {
+            // Saves of this instance wait for each other, so none is lost
+            let _saving = SAVING.lock().unwrap_or_else(|e| e.into_inner());
+            write_instruction(&path, &instruction).map_err(|err| (path, err))
+        },
+        |saved| {
+            if let Err((path, err)) = saved {
+                crate::utils::warn(&format!(
+                    "Could not save the Aichat prompt history to {}: {}",
+                    path.display(),
+                    err
+                ));
+            }
+        },
+    );
+    if let Err(err) = started {
+        crate::error::notify_error(&err);
+    }
+}
+
+/// Merges `instruction` into the history file at `path`
+fn write_instruction(path: &Path, instruction: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut instructions = read_instructions(path)?;
+    remember(&mut instructions, instruction);
+    let file = HistoryFile {
+        version: HISTORY_VERSION,
+        instructions: &instructions,
+    };
+    let text = serde_json::to_string(&file).map_err(std::io::Error::other)?;
+    let temporary = path.with_extension(format!("json.{}.tmp", std::process::id()));
+    std::fs::write(&temporary, text)?;
+    std::fs::rename(&temporary, path)
+}
+
+/// Remembers an edit applied to a buffer, keeping the last `edit_history` ones
+pub fn record_edit(edit: Edit) {
+    let anchor = edit.current_range().anchor(&edit.buffer).ok();
+    let dropped: Vec<TrackedEdit> = EDITS.with(|edits| {
+        let mut edits = edits.borrow_mut();
+        edits.push_back(TrackedEdit { edit, anchor });
+        let excess = edits.len().saturating_sub(get_config().edit_history.max(1));
+        edits.drain(..excess).collect()
+    });
+
+    for tracked in dropped {
+        if let Some(anchor) = tracked.anchor {
+            let _ = anchor.resolve();
+        }
+    }
+}
+
+/// Returns the most recent edit
+pub fn last_edit() -> Option<Edit> {
+    EDITS.with(|edits| edits.borrow().back().map(|tracked| tracked.edit.clone()))
+}
+
+/// Number of edits that can be reverted
+pub fn edit_count() -> usize {
+    EDITS.with(|edits| edits.borrow().len())
+}
+
+/// Puts back the original text of the `n`th most recent edit, 1 being the last
+///
+/// The replacement is found where it is now, even if the buffer was edited
+/// elsewhere since. The edit is forgotten afterwards.
+pub fn revert(n: usize) -> Result<Edit> {
+    let tracked = EDITS.with(|edits| {
+        let mut edits = edits.borrow_mut();
+        let index = edits.len().checked_sub(n)?;
+        edits.remove(index)
+    });
+    let Some(tracked) = tracked else {
+        return Err(AichatError::application(format!(
+            "Only {} Aichat edits are remembered",
+            edit_count()
+        )));
+    };
+
+    let edit = tracked.edit;
+    if !edit.buffer.is_valid() {
+        return Err(AichatError::application(
+            "The buffer of that Aichat edit was closed",
+        ));
+    }
+
+    match tracked.anchor {
+        Some(anchor) => match anchor.resolve()? {
+            Some(range) => {
+                let mut buffer = edit.buffer.clone();
+                range.replace(&mut buffer, edit.original.clone())?;
+            }
+            None => {
+                return Err(AichatError::application(
+                    "The text written by that Aichat edit was deleted",
+                ))
+            }
+        },
+        None => edit.revert()?,
+    }
+
+    Ok(edit)
+}
+
+/// Remembers the request that was sent last
+pub fn record_request(request: Request) {
+    let instruction = request.instruction();
+    if !instruction.is_empty() {
+        with_instructions(|instructions| remember(instructions, instruction));
+        save_instruction(instruction);
+    }
+    LAST_REQUEST.with(|last| *last.borrow_mut() = Some(request));
+}
+
+/// Returns the request that was sent last
+pub fn last_request() -> Option<Request> {
+    LAST_REQUEST.with(|last| last.borrow().clone())
+}
+
+/// Returns the instructions typed for past requests, most recent first,
+/// including the ones of earlier sessions
+pub fn instructions() -> Vec<String> {
+    with_instructions(|instructions| instructions.iter().rev().cloned().collect())
+}
+
+/// Remembers an answer to a request on a buffer, keeping its last
+/// `memory_turns` ones
+pub fn record_turn(buffer: &Buffer, turn: Turn) {
+    let max = get_config().memory_turns;
+    if max == 0 {
+        return;
+    }
+    TURNS.with(|turns| {
+        let mut turns = turns.borrow_mut();
+        let buffer_turns = turns.entry(buffer.handle()).or_default();
+        buffer_turns.push_back(turn);
+        let excess = buffer_turns.len().saturating_sub(max);
+        buffer_turns.drain(..excess);
+    });
+}
+
+/// Returns the remembered turns of a buffer, oldest first
+pub fn turns(buffer: &Buffer) -> Vec<Turn> {
+    TURNS.with(|turns| {
+        turns
+            .borrow()
+            .get(&buffer.handle())
+            .map(|turns| turns.iter().cloned().collect())
+            .unwrap_or_default()
+    })
+}
+
+/// Forgets the turns of a buffer, or of every buffer, returning how many
+/// were forgotten
+pub fn forget_turns(buffer: Option<&Buffer>) -> usize {
+    TURNS.with(|turns| {
+        let mut turns = turns.borrow_mut();
+        match buffer {
+            Some(buffer) => turns
+                .remove(&buffer.handle())
+                .map_or(0, |turns| turns.len()),
+            None => turns.drain().map(|(_, turns)| turns.len()).sum(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_files_of_version_0_are_migrated() {
+        assert_eq!(
+            parse_instructions(
+                r#"["add docs", {"instruction": "fix it", "time": "2026-10-14 09:30:00", "response": ""}]"#
+            )
+            .unwrap(),
+            VecDeque::from(["add docs".to_string(), "fix it".to_string()])
+        );
+        assert!(parse_instructions("not json").unwrap().is_empty());
+    }
+
+    #[test]
+    fn history_files_keep_their_version() {
+        let instructions = VecDeque::from(["a".to_string(), "b".to_string()]);
+        let text = serde_json::to_string(&HistoryFile {
+            version: HISTORY_VERSION,
+            instructions: &instructions,
+        })
+        .unwrap();
+
+        assert_eq!(text, r#"{"version":1,"instructions":["a","b"]}"#);
+        assert_eq!(parse_instructions(&text).unwrap(), instructions);
+        assert!(parse_instructions(r#"{"version":2,"instructions":[]}"#).is_err());
+    }
+
+    #[test]
+    fn remember_moves_a_repeated_instruction_to_the_end() {
+        let mut instructions = VecDeque::from(["a".to_string(), "b".to_string()]);
+
+        remember(&mut instructions, "a");
+
+        assert_eq!(
+            instructions,
+            VecDeque::from(["b".to_string(), "a".to_string()])
+        );
+    }
+}
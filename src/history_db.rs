@@ -0,0 +1,366 @@
+use crate::config::get_config;
+use crate::error::{AichatError, Result};
+use crate::{job_runner, utils};
+use nvim_oxi::api;
+use std::path::{Path, PathBuf};
+
+/// Results listed by one `:AichatHistorySearch`
+const MAX_RESULTS: usize = 100;
+
+/// Schema version of the database written by this build, its `user_version`
+///
+/// - 1: the FTS5 table `requests(instruction, response, time UNINDEXED)`
+#[cfg(feature = "sqlite")]
+const DATABASE_VERSION: i64 = 1;
+
+/// A typed instruction that was answered, as the history database keeps it
+#[derive(Clone, PartialEq, Debug)]
+pub struct Entry {
+    pub instruction: String,
+    pub response: String,
+    /// `%Y-%m-%d %H:%M:%S`, local time
+    pub time: String,
+}
+
+/// A `:AichatHistorySearch` query: words that must all appear in the
+/// instruction or the answer of an entry, and `since:`/`until:` dates
+#[derive(Debug, PartialEq)]
+pub struct Query {
+    words: Vec<String>,
+    since: Option<String>,
+    until: Option<String>,
+}
+
+impl Query {
+    /// Reads a query like `rename since:2026-10-01 until:2026-10-14`
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut query = Query {
+            words: Vec::new(),
+            since: None,
+            until: None,
+        };
+        for word in text.split_whitespace() {
+            let (bound, date) = match word.split_once(':') {
+                Some(("since", date)) => (&mut query.since, date),
+                Some(("until", date)) => (&mut query.until, date),
+                _ => {
+                    query.words.push(word.to_string());
+                    continue;
+                }
+            };
+            if !is_date(date) {
+                return Err(AichatError::application(format!(
+                    "{word} is not a date like since:2026-10-01 or until:2026-10-14"
+                )));
+            }
+            *bound = Some(date.to_string());
+        }
+        Ok(query)
+    }
+
+    /// The FTS5 expression requiring every word, each quoted so the
+    /// operators and punctuation of the query are taken literally
+    fn fts_expression(&self) -> Option<String> {
+        if self.words.is_empty() {
+            return None;
+        }
+        let quoted: Vec<String> = self
+            .words
+            .iter()
+            .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+            .collect();
+        Some(quoted.join(" "))
+    }
+}
+
+/// Whether `text` is a `YYYY-MM-DD` date
+fn is_date(text: &str) -> bool {
+    text.len() == 10
+        && text.char_indices().all(|(i, c)| match i {
+            4 | 7 => c == '-',
+            _ => c.is_ascii_digit(),
+        })
+}
+
+/// The database of answered requests, in Neovim's data directory, unless
+/// `persist_history` is off
+fn database_file() -> Option<PathBuf> {
+    if !get_config().persist_history {
+        return None;
+    }
+    let data: String = api::call_function("stdpath", ("data",)).ok()?;
+    Some(
+        PathBuf::from(data)
+            .join("aichat_nvim")
+            .join("history.sqlite3"),
+    )
+}
+
+/// Adds an answered instruction to the database on a background thread,
+/// a failure is only reported
+pub fn record(instruction: &str, response: &str) {
+    if cfg!(not(feature = "sqlite")) || instruction.is_empty() {
+        return;
+    }
+    let Some(path) = database_file() else {
+        return;
+    };
+    let (instruction, response) = (instruction.to_string(), response.to_string());
+    let started = job_runner::run_in_background(
+        move || insert(&path, &instruction, &response),
+        |result| {
+            if let Err(err) = result {
+                utils::warn(&format!(
+                    "Could not save the Aichat request history: {}",
+                    err
+                ));
+            }
+        },
+    );
+    if let Err(err) = started {
+        crate::error::notify_error(&err);
+    }
+}
+
+/// Searches the database on a background thread, handing the entries
+/// matching `query` to `on_found` on the main loop, best first
+pub fn search<F>(query: Query, on_found: F) -> Result<()>
+where
+    F: FnOnce(Result<Vec<Entry>>) + Send + 'static,
+{
+    let Some(path) = database_file() else {
+        return Err(AichatError::config(
+            "The request history is only kept with persist_history",
+        ));
+    };
+    job_runner::run_in_background(move || select(&path, &query), on_found)
+}
+
+#[cfg(feature = "sqlite")]
+use rusqlite::{params, Connection};
+
+/// Opens the database, creating it and its table the first time
+#[cfg(feature = "sqlite")]
+fn open(path: &Path) -> Result<Connection> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let connection = Connection::open(path).map_err(database_error)?;
+    prepare(&connection)?;
+    Ok(connection)
+}
+
+/// Brings the schema of the database to `DATABASE_VERSION`, and waits for
+/// other Neovim instances writing at the same time
+#[cfg(feature = "sqlite")]
+fn prepare(connection: &Connection) -> Result<()> {
+    connection
+        .busy_timeout(std::time::Duration::from_secs(5))
+        .map_err(database_error)?;
+    let version: i64 = connection
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .map_err(database_error)?;
+    if version > DATABASE_VERSION {
+        return Err(AichatError::application(format!(
+            "The history database has version {version}, written by a newer aichat_nvim"
+        )));
+    }
+    if version < 1 {
+        connection
+            .execute_batch(
+                "CREATE VIRTUAL TABLE IF NOT EXISTS requests
+                    USING fts5(instruction, response, time UNINDEXED);",
+            )
+            .map_err(database_error)?;
+    }
+    connection
+        .pragma_update(None, "user_version", DATABASE_VERSION)
+        .map_err(database_error)
+}
+
+#[cfg(feature = "sqlite")]
+fn insert(path: &Path, instruction: &str, response: &str) -> Result<()> {
+    insert_into(&open(path)?, instruction, response)
+}
+
+/// Adds an entry dated now, in local time
+#[cfg(feature = "sqlite")]
+fn insert_into(connection: &Connection, instruction: &str, response: &str) -> Result<()> {
+    connection
+        .execute(
+            "INSERT INTO requests (instruction, response, time)
+                VALUES (?1, ?2, datetime('now', 'localtime'))",
+            params![instruction, response],
+        )
+        .map(|_| ())
+        .map_err(database_error)
+}
+
+#[cfg(feature = "sqlite")]
+fn select(path: &Path, query: &Query) -> Result<Vec<Entry>> {
+    select_from(&open(path)?, query)
+}
+
+/// The entries matching `query`, ranked by bm25 with matches in the
+/// instruction counting three times as much, the newest first among equals
+/// and when there are only dates
+#[cfg(feature = "sqlite")]
+fn select_from(connection: &Connection, query: &Query) -> Result<Vec<Entry>> {
+    let dates = "(?1 IS NULL OR substr(time, 1, 10) >= ?1)
+        AND (?2 IS NULL OR substr(time, 1, 10) <= ?2)";
+    let sql = match query.fts_expression() {
+        Some(_) => format!(
+            "SELECT instruction, response, time FROM requests
+                WHERE requests MATCH ?3 AND {dates}
+                ORDER BY bm25(requests, 3.0, 1.0, 0.0), rowid DESC LIMIT ?4"
+        ),
+        None => format!(
+            "SELECT instruction, response, time FROM requests
+                WHERE ?3 IS NULL AND {dates}
+                ORDER BY rowid DESC LIMIT ?4"
+        ),
+    };
+
+    let mut statement = connection.prepare(&sql).map_err(database_error)?;
+    let rows = statement
+        .query_map(
+            params![
+                query.since,
+                query.until,
+                query.fts_expression(),
+                MAX_RESULTS as i64
+            ],
+            |row| {
+                Ok(Entry {
+                    instruction: row.get(0)?,
+                    response: row.get(1)?,
+                    time: row.get(2)?,
+                })
+            },
+        )
+        .map_err(database_error)?;
+    rows.collect::<rusqlite::Result<Vec<Entry>>>()
+        .map_err(database_error)
+}
+
+#[cfg(feature = "sqlite")]
+fn database_error(err: rusqlite::Error) -> AichatError {
+    AichatError::application(format!("History database error: {}", err))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn insert(_path: &Path, _instruction: &str, _response: &str) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn select(_path: &Path, _query: &Query) -> Result<Vec<Entry>> {
+    Err(AichatError::config(
+        "Searching the request history needs aichat_nvim built with `--features sqlite`",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_reads_words_and_dates() {
+        assert_eq!(
+            Query::parse("rename  since:2026-10-01 until:2026-10-14 iter").unwrap(),
+            Query {
+                words: vec!["rename".into(), "iter".into()],
+                since: Some("2026-10-01".into()),
+                until: Some("2026-10-14".into()),
+            }
+        );
+        assert!(Query::parse("since:yesterday").is_err());
+        assert!(Query::parse("until:2026-1-4").is_err());
+    }
+
+    #[test]
+    fn query_words_are_quoted_for_fts() {
+        let query = Query::parse(r#"say "hi" OR*"#).unwrap();
+
+        assert_eq!(
+            query.fts_expression().as_deref(),
+            Some(r#""say" """hi""" "OR*""#)
+        );
+        assert_eq!(
+            Query::parse("since:2026-10-01").unwrap().fts_expression(),
+            None
+        );
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn database(entries: &[(&str, &str, &str)]) -> Connection {
+        let connection = Connection::open_in_memory().unwrap();
+        prepare(&connection).unwrap();
+        for (instruction, response, time) in entries {
+            connection
+                .execute(
+                    "INSERT INTO requests (instruction, response, time) VALUES (?1, ?2, ?3)",
+                    params![instruction, response, time],
+                )
+                .unwrap();
+        }
+        connection
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn databases_keep_their_version() {
+        let connection = database(&[]);
+        prepare(&connection).unwrap();
+        let version: i64 = connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, DATABASE_VERSION);
+
+        connection
+            .pragma_update(None, "user_version", DATABASE_VERSION + 1)
+            .unwrap();
+        assert!(prepare(&connection).is_err());
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn instructions(connection: &Connection, query: &str) -> Vec<String> {
+        select_from(connection, &Query::parse(query).unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.instruction)
+            .collect()
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn search_ranks_instruction_matches_first_and_needs_every_word() {
+        let connection = database(&[
+            ("rename the buffer", "", "2026-10-02 10:00:00"),
+            ("add docs", "/// Rename the buffer", "2026-10-03 10:00:00"),
+            ("rename it", "no window here", "2026-10-01 10:00:00"),
+        ]);
+
+        assert_eq!(
+            instructions(&connection, "Rename buffer"),
+            ["rename the buffer", "add docs"]
+        );
+        assert!(instructions(&connection, "rename missing").is_empty());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn search_keeps_the_entries_between_the_dates() {
+        let connection = database(&[
+            ("a c", "", "2026-09-30 12:00:00"),
+            ("a b", "", "2026-10-14 23:59:59"),
+            ("a", "", "2026-10-15 08:00:00"),
+        ]);
+
+        assert_eq!(
+            instructions(&connection, "a since:2026-10-01 until:2026-10-14"),
+            ["a b"]
+        );
+        assert_eq!(instructions(&connection, "since:2026-10-01"), ["a", "a b"]);
+    }
+}
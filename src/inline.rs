@@ -0,0 +1,315 @@
+use crate::error::Result;
+use crate::history::Edit;
+use crate::toggle::{self, Automatic};
+use crate::{config, ui, utils};
+use nvim_oxi::{
+    api::{
+        self,
+        opts::{GetExtmarkByIdOpts, SetExtmarkOpts},
+        types::Mode,
+        Buffer,
+    },
+    conversion::FromObject,
+    Dictionary, Object,
+};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// Namespace of the signs and highlights of the hunks under review
+const NAMESPACE: &str = "aichat_nvim_inline";
+
+/// Keys of the hunk review, local to the edited buffer; accepting and
+/// reverting use `keys.accept_hunk` and `keys.revert_hunk`
+const NEXT_HUNK: &str = "]h";
+const PREVIOUS_HUNK: &str = "[h";
+
+/// A changed block of an applied answer, followed with an extmark
+struct Hunk {
+    mark: u32,
+    /// The lines the answer replaced
+    original: Vec<String>,
+    /// How the original lines go back on revert
+    placement: Placement,
+}
+
+/// Where a hunk's extmark sits relative to the lines it stands for
+#[derive(Clone, Copy)]
+enum Placement {
+    /// The mark spans the lines the answer wrote
+    Lines,
+    /// Only lines were removed, they go back after the marked line
+    After,
+    /// Only lines were removed at the top of the buffer, they go back before it
+    Before,
+}
+
+// Hunks of the applied answers still under review, by buffer
+thread_local! {
+    static HUNKS: RefCell<HashMap<i32, Vec<Hunk>>> = RefCell::new(HashMap::new());
+}
+
+/// Marks the hunks of an applied answer with signs and highlights, and maps
+/// the review keys in its buffer
+///
+/// Needs `features.inline`, does nothing otherwise or while `:AichatToggle`
+/// paused it
+pub fn mark_hunks(edit: &Edit) -> Result<()> {
+    if !toggle::is_active(Automatic::Inline) {
+        return Ok(());
+    }
+
+    let ns = api::create_namespace(NAMESPACE);
+    let mut buffer = edit.buffer.clone();
+    let line_count = buffer.line_count()?;
+    let mut hunks = Vec::new();
+
+    for [start_a, count_a, start_b, count_b] in diff_hunks(&edit.original, &edit.replacement) {
+        let original = edit
+            .original
+            .iter()
+            .skip(start_a.saturating_sub(1))
+            .take(count_a)
+            .cloned()
+            .collect();
+        // 0-based row of the first written line, or of the line before a removal
+        let row = edit.line1 - 1 + start_b.saturating_sub(1);
+
+        let (placement, opts) = if count_b > 0 {
+            let group = if count_a > 0 { "DiffChange" } else { "DiffAdd" };
+            let opts = SetExtmarkOpts::builder()
+                .end_row(row + count_b - 1)
+                .line_hl_group(group)
+                .sign_text(if count_a > 0 { "~" } else { "+" })
+                .sign_hl_group(group)
+                .build();
+            (Placement::Lines, opts)
+        } else {
+            let opts = SetExtmarkOpts::builder()
+                .sign_text("_")
+                .sign_hl_group("DiffDelete")
+                .build();
+            match edit.line1 - 1 + start_b {
+                0 => (Placement::Before, opts),
+                _ => (Placement::After, opts),
+            }
+        };
+        let row = match placement {
+            Placement::Lines => row,
+            Placement::After => (edit.line1 + start_b - 2).min(line_count.saturating_sub(1)),
+            Placement::Before => 0,
+        };
+
+        let mark = buffer.set_extmark(ns, row, 0, &opts)?;
+        hunks.push(Hunk {
+            mark,
+            original,
+            placement,
+        });
+    }
+
+    if hunks.is_empty() {
+        return Ok(());
+    }
+
+    HUNKS.with(|all| {
+        all.borrow_mut()
+            .entry(buffer.handle())
+            .or_default()
+            .extend(hunks)
+    });
+    map_keys(&mut buffer)
+}
+
+/// Drops the hunks of a buffer, e.g. once the whole answer was reverted
+pub fn clear(buffer: &Buffer) {
+    let hunks = HUNKS.with(|all| all.borrow_mut().remove(&buffer.handle()));
+    if hunks.is_some() {
+        let mut buffer = buffer.clone();
+        let _ = buffer.clear_namespace(api::create_namespace(NAMESPACE), 0..);
+        unmap_keys(&mut buffer);
+    }
+}
+
+/// Line hunks `[start_a, count_a, start_b, count_b]` between two texts, from `vim.diff`
+///
+/// Starts are 1-based; a count of 0 means the start is the line before
+fn diff_hunks(original: &[String], replacement: &[String]) -> Vec<[usize; 4]> {
+    let text =
+        |lines: &[String]| -> String { lines.iter().map(|line| format!("{}\n", line)).collect() };
+    let texts = Dictionary::from_iter([
+        ("a", Object::from(text(original))),
+        ("b", Object::from(text(replacement))),
+    ]);
+    api::call_function::<_, Object>(
+        "luaeval",
+        (
+            "vim.diff(_A.a, _A.b, { result_type = 'indices' })",
+            Object::from(texts),
+        ),
+    )
+    .ok()
+    .and_then(|obj| Vec::<Vec<i64>>::from_object(obj).ok())
+    .unwrap_or_default()
+    .into_iter()
+    .filter_map(|hunk| match hunk[..] {
+        [start_a, count_a, start_b, count_b] => Some([
+            start_a.max(0) as usize,
+            count_a.max(0) as usize,
+            start_b.max(0) as usize,
+            count_b.max(0) as usize,
+        ]),
+        _ => None,
+    })
+    .collect()
+}
+
+/// Maps the review keys in the buffer
+fn map_keys(buffer: &mut Buffer) -> Result<()> {
+    let keys = config::get_config().keys.clone();
+    let target = buffer.clone();
+    ui::set_keymaps(
+        buffer,
+        &[NEXT_HUNK.to_string()],
+        "Next Aichat hunk",
+        move || jump(&target, true),
+    )?;
+    let target = buffer.clone();
+    ui::set_keymaps(
+        buffer,
+        &[PREVIOUS_HUNK.to_string()],
+        "Previous Aichat hunk",
+        move || jump(&target, false),
+    )?;
+    let target = buffer.clone();
+    ui::set_keymaps(buffer, &keys.accept_hunk, "Accept Aichat hunk", move || {
+        resolve(&target, false)
+    })?;
+    let target = buffer.clone();
+    ui::set_keymaps(buffer, &keys.revert_hunk, "Revert Aichat hunk", move || {
+        resolve(&target, true)
+    })?;
+    Ok(())
+}
+
+/// Removes the review keys once no hunk is left
+fn unmap_keys(buffer: &mut Buffer) {
+    let keys = config::get_config().keys.clone();
+    let resolve_keys = keys.accept_hunk.iter().chain(&keys.revert_hunk);
+    for lhs in [NEXT_HUNK, PREVIOUS_HUNK]
+        .into_iter()
+        .chain(resolve_keys.map(String::as_str))
+    {
+        let _ = buffer.del_keymap(Mode::Normal, lhs);
+    }
+}
+
+/// The current rows of the hunks of a buffer, first and last 0-based row each
+fn hunk_rows(buffer: &Buffer) -> Vec<(usize, usize)> {
+    let ns = api::create_namespace(NAMESPACE);
+    let opts = GetExtmarkByIdOpts::builder().details(true).build();
+    HUNKS.with(|all| {
+        all.borrow()
+            .get(&buffer.handle())
+            .map(|hunks| {
+                hunks
+                    .iter()
+                    .map(
+                        |hunk| match buffer.get_extmark_by_id(ns, hunk.mark, &opts) {
+                            Ok((row, _, details)) => {
+                                let end =
+                                    details.and_then(|details| details.end_row).unwrap_or(row);
+                                (row, end.max(row))
+                            }
+                            Err(_) => (usize::MAX, usize::MAX),
+                        },
+                    )
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
+}
+
+/// Moves the cursor to the start of the next or previous hunk
+fn jump(buffer: &Buffer, forward: bool) {
+    let mut window = api::get_current_win();
+    let Ok((line, _)) = window.get_cursor() else {
+        return;
+    };
+    let row = line - 1;
+    let starts = hunk_rows(buffer)
+        .into_iter()
+        .map(|(start, _)| start)
+        .filter(|&start| start != usize::MAX);
+
+    let target = if forward {
+        starts.filter(|&start| start > row).min()
+    } else {
+        starts.filter(|&start| start < row).max()
+    };
+    match target {
+        Some(start) => {
+            let _ = window.set_cursor(start + 1, 0);
+        }
+        None => utils::info("No more Aichat hunks"),
+    }
+}
+
+/// Accepts or reverts the hunk under the cursor
+fn resolve(buffer: &Buffer, revert: bool) {
+    if let Err(err) = try_resolve(buffer, revert) {
+        crate::error::notify_error(&err);
+    }
+}
+
+/// Like `resolve`, returning the error
+fn try_resolve(buffer: &Buffer, revert: bool) -> Result<()> {
+    let (line, _) = api::get_current_win().get_cursor()?;
+    let row = line - 1;
+    let rows = hunk_rows(buffer);
+    let Some(index) = rows
+        .iter()
+        .position(|&(start, end)| start <= row && row <= end)
+    else {
+        utils::info("No Aichat hunk under the cursor");
+        return Ok(());
+    };
+
+    let hunk = HUNKS.with(|all| {
+        all.borrow_mut()
+            .get_mut(&buffer.handle())
+            .map(|hunks| hunks.remove(index))
+    });
+    let Some(hunk) = hunk else {
+        return Ok(());
+    };
+
+    let mut buffer = buffer.clone();
+    let _ = buffer.del_extmark(api::create_namespace(NAMESPACE), hunk.mark);
+
+    if revert {
+        let (start, end) = rows[index];
+        let range = match hunk.placement {
+            Placement::Lines => start..end + 1,
+            Placement::After => start + 1..start + 1,
+            Placement::Before => 0..0,
+        };
+        buffer.set_lines(range, true, hunk.original)?;
+    }
+
+    let empty = HUNKS.with(|all| {
+        let mut all = all.borrow_mut();
+        let empty = match all.get(&buffer.handle()) {
+            Some(hunks) => hunks.is_empty(),
+            None => true,
+        };
+        if empty {
+            all.remove(&buffer.handle());
+        }
+        empty
+    });
+    if empty {
+        unmap_keys(&mut buffer);
+    }
+
+    Ok(())
+}
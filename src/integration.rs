@@ -0,0 +1,165 @@
+//! End-to-end tests of the `:Aichat` pipeline in an embedded Neovim
+//!
+//! `tests/fixtures/aichat` stands in for aichat: it saves the prompt it is
+//! sent and answers with a canned response. Built with the `test` feature
+//! and run with `cargo test --features test`, which needs `nvim` in `PATH`.
+//! Every test runs in a Neovim process of its own.
+
+use nvim_oxi::api::{
+    self,
+    opts::{OptionOpts, OptionScope::Local},
+    Buffer,
+};
+use nvim_oxi::{Dictionary, Object};
+use std::path::PathBuf;
+
+/// The stub aichat script
+const STUB: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/aichat");
+
+/// An answer with prose around its code block, like models write them
+const ANSWER: &str = "Here is the updated code:
+
+```lua
+local b = 42
+local answer = b
+```
+
+`answer` now holds the result.
+";
+
+/// Files the stub answers from and saves the prompt to
+struct Stub {
+    prompt: PathBuf,
+}
+
+impl Stub {
+    /// Loads the plugin with the stub as aichat, answering `answer` to
+    /// every request
+    fn setup(answer: &str, code_flag: bool) -> Self {
+        let dir = std::env::temp_dir().join(format!("aichat_nvim-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let prompt = dir.join("prompt");
+        let answer_file = dir.join("answer.md");
+        std::fs::write(&answer_file, answer).unwrap();
+
+        let env = Dictionary::from_iter([
+            ("AICHAT_STUB_PROMPT", prompt.display().to_string()),
+            ("AICHAT_STUB_ANSWER", answer_file.display().to_string()),
+        ]);
+        let opts = Dictionary::from_iter([
+            ("aichat_path", Object::from(STUB)),
+            ("env", Object::from(env)),
+            ("code_flag", Object::from(code_flag)),
+            ("retries", Object::from(0)),
+            ("slow_request_ms", Object::from(0)),
+            ("persist_history", Object::from(false)),
+        ]);
+        crate::aichat_nvim().unwrap();
+        crate::setup(Some(opts)).unwrap();
+
+        Self { prompt }
+    }
+
+    /// The prompt of the last request
+    fn prompt(&self) -> String {
+        std::fs::read_to_string(&self.prompt).unwrap()
+    }
+}
+
+/// Fills the current buffer with Lua code
+fn lua_buffer(lines: &[&str]) -> Buffer {
+    let mut buffer = api::get_current_buf();
+    let opts = OptionOpts::builder().scope(Local).buffer(&buffer).build();
+    api::set_option_value("filetype", "lua", &opts).unwrap();
+    buffer.set_lines(.., false, lines.to_vec()).unwrap();
+    buffer
+}
+
+/// The lines of a buffer
+fn lines(buffer: &Buffer) -> Vec<String> {
+    buffer
+        .get_lines(.., false)
+        .unwrap()
+        .map(|line| line.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Runs a command and waits for the request it started to be applied
+fn run(command: &str) {
+    api::command(command).unwrap();
+    assert!(
+        crate::queue::wait(Some(10_000)).unwrap(),
+        "the request of `{}` didn't finish",
+        command
+    );
+}
+
+#[nvim_oxi::test]
+fn aichat_sends_the_range_with_the_instruction() {
+    let stub = Stub::setup(ANSWER, true);
+    lua_buffer(&["local a = 1", "local b = 2", "local c = 3"]);
+
+    run("2Aichat make b the answer");
+
+    let prompt = stub.prompt();
+    assert!(prompt.starts_with("make b the answer\n"), "{}", prompt);
+    assert!(prompt.contains("```lua\nlocal b = 2\n```"), "{}", prompt);
+    assert!(!prompt.contains("local a = 1"), "{}", prompt);
+    assert!(!prompt.contains("local c = 3"), "{}", prompt);
+}
+
+#[nvim_oxi::test]
+fn aichat_replaces_the_range_with_the_code_block() {
+    let _stub = Stub::setup(ANSWER, true);
+    let buffer = lua_buffer(&["local a = 1", "local b = 2", "local c = 3"]);
+
+    run("2Aichat make b the answer");
+
+    assert_eq!(
+        lines(&buffer),
+        [
+            "local a = 1",
+            "local b = 42",
+            "local answer = b",
+            "local c = 3"
+        ]
+    );
+}
+
+#[nvim_oxi::test]
+fn aichat_replaces_a_multiline_range() {
+    let _stub = Stub::setup(ANSWER, true);
+    let buffer = lua_buffer(&["local a = 1", "local b = 2", "local c = 3", "return c"]);
+
+    run("2,3Aichat merge these");
+
+    assert_eq!(
+        lines(&buffer),
+        [
+            "local a = 1",
+            "local b = 42",
+            "local answer = b",
+            "return c"
+        ]
+    );
+}
+
+#[nvim_oxi::test]
+fn aichat_keeps_the_buffer_without_a_code_block() {
+    let _stub = Stub::setup("I can't change this code.\n", false);
+    let buffer = lua_buffer(&["local a = 1", "local b = 2"]);
+
+    run("2Aichat make b the answer");
+
+    assert_eq!(lines(&buffer), ["local a = 1", "local b = 2"]);
+}
+
+#[nvim_oxi::test]
+fn aichat_takes_a_bare_answer_as_the_code_with_code_flag() {
+    let _stub = Stub::setup("local b = 42\n", true);
+    let buffer = lua_buffer(&["local a = 1", "local b = 2"]);
+
+    run("2Aichat make b the answer");
+
+    assert_eq!(lines(&buffer), ["local a = 1", "local b = 42"]);
+}
@@ -1,77 +1,454 @@
-use crate::config::{AichatConfig, Mode};
+use crate::config::AichatConfig;
 use crate::error::{AichatError, Result};
-use std::io::Write;
+use crate::selection::Selection;
+use crate::utils::Outcome;
+use nvim_oxi::{api::Buffer, libuv::AsyncHandle};
+use once_cell::sync::Lazy;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Write};
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
-/// Runs the aichat command with the current configuration and input text
-pub fn run_aichat_command(config: &AichatConfig, input: &str) -> Result<String> {
-    // Start building the command
-    let mut cmd = Command::new("aichat");
+/// Set to abort a running request, its aichat process is killed
+pub type CancelToken = Arc<AtomicBool>;
 
-    // Add mode flag and argument if set
-    match config.mode_flag {
-        Mode::Role => cmd.arg("--role").arg(config.mode_arg.as_ref()),
-        Mode::Agent => cmd.arg("--agent").arg(config.mode_arg.as_ref()),
-        Mode::Macro => cmd.arg("--macro").arg(config.mode_arg.as_ref()),
+/// How often a running process is checked for completion or cancellation
+pub const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Global static to store the keys of the requests that are currently running
+static IN_FLIGHT: Lazy<Mutex<HashSet<u64>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Identifies a request by everything that influences its result
+pub fn request_key(
+    config: &AichatConfig,
+    input: &str,
+    buffer: &Buffer,
+    selection: &Selection,
+) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    config.args().hash(&mut hasher);
+    config.envs().hash(&mut hasher);
+    input.hash(&mut hasher);
+    buffer.handle().hash(&mut hasher);
+    selection.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Registers a request as running, returning false if an identical one already is
+pub fn try_register(key: u64) -> bool {
+    IN_FLIGHT
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(key)
+}
+
+/// Whether a request is still running
+pub fn is_in_flight(key: u64) -> bool {
+    IN_FLIGHT
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .contains(&key)
+}
+
+/// Number of requests that are currently running
+pub fn in_flight_count() -> usize {
+    IN_FLIGHT.lock().unwrap_or_else(|e| e.into_inner()).len()
+}
+
+/// Marks a request as finished
+pub fn unregister(key: u64) {
+    IN_FLIGHT
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(&key);
+}
+
+/// Runs `work` on a background thread and hands its result to `on_done` on
+/// the main loop, where the Neovim API can be used again
+///
+/// `work` must not touch the Neovim API, it only runs processes and computes
+pub fn run_in_background<T, W, D>(work: W, on_done: D) -> Result<()>
+where
+    T: Send + 'static,
+    W: FnOnce() -> T + Send + 'static,
+    D: FnOnce(T) + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel::<T>();
+    let mut on_done = Some(on_done);
+
+    // Woken up by the thread once the result has been sent
+    let handle = AsyncHandle::new(move || {
+        if let (Ok(value), Some(on_done)) = (receiver.try_recv(), on_done.take()) {
+            // libuv callbacks run in a fast event, so defer to a safe point
+            nvim_oxi::schedule(move |_| -> nvim_oxi::Result<()> {
+                on_done(value);
+                Ok(())
+            });
+        }
+    })
+    .map_err(|e| AichatError::application(e.to_string()))?;
+
+    std::thread::spawn(move || {
+        let value = work();
+        if sender.send(value).is_ok() {
+            let _ = handle.send();
+        }
+    });
+
+    Ok(())
+}
+
+/// Runs the aichat command in the background, retrying failures that look
+/// transient with exponential backoff
+///
+/// Other failures of the command itself ask the user whether to try again,
+/// transient ones still failing after `retries` attempts don't. `cancel`
+/// aborts the request at any point, backoff included. `on_done` receives the
+/// final outcome.
+pub fn run_with_retries<D>(
+    config: AichatConfig,
+    input: String,
+    cancel: CancelToken,
+    attempt: u32,
+    on_done: D,
+) -> Result<()>
+where
+    D: FnOnce(Result<String>) + Send + 'static,
+{
+    let delay = match attempt {
+        0 => Duration::ZERO,
+        n => Duration::from_millis(config.retry_backoff_ms)
+            .saturating_mul(2u32.saturating_pow(n - 1)),
     };
 
-    // Add RAG if set
-    if let Some(rag) = &config.rag {
-        cmd.arg("--rag").arg(rag.as_ref());
+    run_in_background(
+        move || {
+            let result = match sleep_unless_cancelled(delay, &cancel) {
+                true => run_aichat_command(&config, &input, &cancel),
+                false => Err(AichatError::Cancelled),
+            };
+            (config, input, cancel, result)
+        },
+        move |(config, input, cancel, result)| {
+            let retry = |config, input, cancel, attempt, on_done| {
+                if let Err(err) = run_with_retries(config, input, cancel, attempt, on_done) {
+                    crate::error::notify_error(&err);
+                }
+            };
+
+            match &result {
+                Err(err) if err.is_transient() && attempt < config.retries => {
+                    crate::utils::report(
+                        Outcome::Retrying,
+                        &format!(
+                            "Aichat request failed, retrying ({}/{})",
+                            attempt + 1,
+                            config.retries
+                        ),
+                        in_flight_count().saturating_sub(1),
+                    );
+                    retry(config, input, cancel, attempt + 1, on_done);
+                }
+                Err(err @ (AichatError::CommandFailed { .. } | AichatError::NoCodeBlock))
+                    if !err.is_transient() =>
+                {
+                    let summary = err.to_string();
+                    let question = format!(
+                        "{}\nRetry the Aichat request?",
+                        summary.lines().next().unwrap_or_default()
+                    );
+                    crate::ui::confirm(&question, move |again| {
+                        match again {
+                            true => retry(config, input, cancel, 0, on_done),
+                            false => on_done(result),
+                        }
+                        Ok(())
+                    });
+                }
+                _ => on_done(result),
+            }
+        },
+    )
+}
+
+/// Sends the input to the configured backend, the aichat CLI by default
+///
+/// Workflows that only use the code get the first code block of the answer,
+/// backends that answer with bare code (aichat's `--code`, unless `code_flag`
+/// is off) may also answer without one. Raw backends get their output as is.
+pub fn run_aichat_command(
+    config: &AichatConfig,
+    input: &str,
+    cancel: &AtomicBool,
+) -> Result<String> {
+    let backend = config.backend.backend();
+    let code = config.output.extracts_code() && !config.backend.raw_output();
+    let output_str = backend.complete(config, input, code, cancel)?;
+    if !code {
+        return Ok(output_str);
     }
 
-    // Add session if set
-    if let Some(session) = &config.session {
-        cmd.arg("--session").arg(session.as_ref());
+    // Some models still fence the code, older aichat versions keep the fences
+    match extract_first_code_block(&output_str) {
+        Some(code) => Ok(code),
+        None if !backend.bare_code(config) || output_str.trim().is_empty() => {
+            Err(AichatError::NoCodeBlock)
+        }
+        None => Ok(output_str),
     }
+}
+
+/// Sends the input to the configured backend and returns the whole response
+pub fn run_aichat_response(config: &AichatConfig, input: &str) -> Result<String> {
+    config
+        .backend
+        .backend()
+        .complete(config, input, false, &AtomicBool::new(false))
+}
 
-    // Configure stdin, stdout, and stderr
-    let mut child = cmd
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
+/// Asks aichat and the tools it runs for plain output without colors or
+/// spinners, set before the configured `env` so it can still override them
+pub const PLAIN_OUTPUT_ENV: [(&str, &str); 2] = [("NO_COLOR", "1"), ("TERM", "dumb")];
 
-    // Write input to stdin
-    if let Some(mut stdin) = child.stdin.take() {
-        stdin.write_all(input.as_bytes())?;
+/// Builds the aichat command for the configuration
+pub fn aichat_command(config: &AichatConfig) -> Command {
+    let mut cmd = Command::new(&*config.aichat_path);
+    cmd.envs(PLAIN_OUTPUT_ENV);
+    cmd.args(config.args());
+    cmd.envs(config.envs());
+    cmd
+}
+
+/// Builds an aichat command with only the configured `env`, for the
+/// commands that don't depend on the role, session or generation parameters
+pub fn base_command(config: &AichatConfig) -> Command {
+    let mut cmd = Command::new(&*config.aichat_path);
+    cmd.envs(PLAIN_OUTPUT_ENV);
+    cmd.envs(&config.env);
+    cmd
+}
+
+/// Asks aichat's execute mode (`-e`) for a shell command matching the description
+pub fn generate_shell_command(description: &str) -> Result<String> {
+    let mut cmd = base_command(&crate::config::get_config());
+    cmd.arg("-e");
+
+    // Without a terminal on stdout aichat prints the command instead of running it
+    let output_str = run_command(cmd, description)?;
+    Ok(output_str.trim().to_string())
+}
+
+/// Spawns a command, writes `input` to its stdin and returns its stdout
+pub fn run_command(cmd: Command, input: &str) -> Result<String> {
+    run_cancellable(cmd, input, &AtomicBool::new(false))
+}
+
+/// Like `run_command`, but kills the process as soon as `cancel` is set
+pub fn run_cancellable(cmd: Command, input: &str, cancel: &AtomicBool) -> Result<String> {
+    run_raw(cmd, input, cancel).map(|output| strip_terminal_artifacts(&output))
+}
+
+/// Like `run_cancellable`, returning stdout exactly as the command printed it
+pub fn run_raw(cmd: Command, input: &str, cancel: &AtomicBool) -> Result<String> {
+    let runner = RUNNER.read().unwrap_or_else(|e| e.into_inner()).clone();
+    runner.run(cmd, input, cancel)
+}
+
+/// Runs the aichat commands of requests, the `--list-*` option lists and
+/// `--info`
+///
+/// The process runner is used unless a test installed the stub runner.
+pub trait CommandRunner: Send + Sync {
+    /// Runs `cmd` with `input` on stdin and returns its stdout, or
+    /// `Cancelled` as soon as `cancel` is set
+    fn run(&self, cmd: Command, input: &str, cancel: &AtomicBool) -> Result<String>;
+}
+
+// Global static to store the runner of every request, shared with the
+// background threads running them
+static RUNNER: Lazy<RwLock<Arc<dyn CommandRunner>>> =
+    Lazy::new(|| RwLock::new(Arc::new(ProcessRunner)));
+
+/// Sleeps for `delay` in `POLL_INTERVAL` steps, returning `false` as soon as
+/// `cancel` is set
+fn sleep_unless_cancelled(delay: Duration, cancel: &AtomicBool) -> bool {
+    let started = Instant::now();
+    loop {
+        if cancel.load(Ordering::Relaxed) {
+            return false;
+        }
+        let Some(left) = delay
+            .checked_sub(started.elapsed())
+            .filter(|d| !d.is_zero())
+        else {
+            return true;
+        };
+        std::thread::sleep(left.min(POLL_INTERVAL));
+    }
+}
+
+/// Spawns the command as a process
+struct ProcessRunner;
+
+impl CommandRunner for ProcessRunner {
+    fn run(&self, mut cmd: Command, input: &str, cancel: &AtomicBool) -> Result<String> {
+        // Configure stdin, stdout, and stderr
+        let mut child = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| AichatError::spawn_failed(&cmd, err))?;
+
+        // Drain both pipes and feed stdin on threads of their own, so neither
+        // side blocks on a full pipe and a cancel is seen while they run
+        let stdout = read_in_background(child.stdout.take());
+        let stderr = read_in_background(child.stderr.take());
+        let stdin = write_in_background(child.stdin.take(), input.as_bytes().to_vec());
+
+        // Wait for the command to complete
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if cancel.load(Ordering::Relaxed) {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(AichatError::Cancelled);
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+
+        let written = stdin.join().unwrap_or(Ok(()));
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = stderr.join().unwrap_or_default();
+
+        // Check if the command was successful
+        if !status.success() {
+            return Err(AichatError::command_failed(&cmd, status, stderr, stdout));
+        }
+        written?;
+
+        // Get the output
+        Ok(String::from_utf8_lossy(&stdout).to_string())
     }
+}
 
-    // Wait for the command to complete
-    let output = child.wait_with_output()?;
+/// Reads a pipe to the end on its own thread
+fn read_in_background(
+    pipe: Option<impl Read + Send + 'static>,
+) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
 
-    // Check if the command was successful
-    if !output.status.success() {
-        return Err(AichatError::command_failed(output.status, output.stderr, output.stdout));
+/// Writes `input` to a pipe on its own thread, closing it when done
+///
+/// A process may exit without reading all of its input, the broken pipe that
+/// leaves is not an error of its own: its exit status tells what happened.
+fn write_in_background(
+    pipe: Option<impl Write + Send + 'static>,
+    input: Vec<u8>,
+) -> std::thread::JoinHandle<std::io::Result<()>> {
+    std::thread::spawn(move || {
+        let Some(mut pipe) = pipe else {
+            return Ok(());
+        };
+        match pipe.write_all(&input) {
+            Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => Err(err),
+            _ => Ok(()),
+        }
+    })
+}
+
+/// Removes what a terminal would interpret rather than show: ANSI escape
+/// sequences, text overwritten after a carriage return, and lines holding
+/// nothing but spinner characters
+fn strip_terminal_artifacts(output: &str) -> String {
+    if !output.contains(['\x1b', '\r']) && !output.chars().any(is_spinner) {
+        return output.to_string();
+    }
+
+    let mut plain = String::with_capacity(output.len());
+    let mut chars = output.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            plain.push(c);
+            continue;
+        }
+        match chars.next() {
+            // CSI: parameters up to a final byte in @..~
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC: up to BEL or ESC \
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                        break;
+                    }
+                }
+            }
+            // Two character sequences
+            _ => {}
+        }
     }
 
-    // Get the output
-    let output_str = String::from_utf8_lossy(&output.stdout).to_string();
+    let lines: Vec<&str> = plain
+        .split('\n')
+        .map(|line| {
+            // `\r\n` endings are kept as line ends, other `\r` overwrite the line
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            line.rsplit('\r').next().unwrap_or(line)
+        })
+        .filter(|line| line.trim().is_empty() || !line.trim().chars().all(is_spinner))
+        .collect();
+    lines.join("\n")
+}
 
-    // Extract the first code block
-    extract_first_code_block(&output_str).ok_or(AichatError::NoCodeBlock)
+/// The braille dots progress spinners cycle through
+fn is_spinner(c: char) -> bool {
+    ('\u{2800}'..='\u{28ff}').contains(&c)
 }
 
 /// Extracts the first code block from the output
 fn extract_first_code_block(text: &str) -> Option<String> {
-    // Look for code blocks with triple backticks
-    let mut in_code_block = false;
+    // Look for code blocks with three or more backticks, closed by a fence
+    // at least as long as the opening one
+    let mut fence_len: Option<usize> = None;
     let mut code_block = String::new();
 
     for line in text.lines() {
-        if line.trim().starts_with("```") {
-            if !in_code_block {
-                // Start of code block
-                in_code_block = true;
-                // Skip the language identifier line
+        let trimmed = line.trim();
+        let backticks = trimmed.len() - trimmed.trim_start_matches('`').len();
+
+        match fence_len {
+            None if backticks >= 3 => {
+                // Start of code block, skipping the language identifier line
+                fence_len = Some(backticks);
                 continue;
-            } else {
+            }
+            Some(len) if backticks >= len && backticks == trimmed.len() => {
                 // End of code block
                 return Some(code_block);
             }
+            _ => {}
         }
 
-        if in_code_block {
+        if fence_len.is_some() {
             code_block.push_str(line);
             code_block.push('\n');
         }
@@ -84,3 +461,317 @@ fn extract_first_code_block(text: &str) -> Option<String> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::process::ExitStatus;
+
+    /// A response given by the stub runner instead of running aichat
+    #[derive(Clone, Default)]
+    struct Canned {
+        /// What aichat prints
+        stdout: String,
+        stderr: String,
+        /// Exit code, failures other than 0 report `stderr` like aichat would
+        exit: i32,
+        /// How long the answer takes, during which the request can be cancelled
+        delay_ms: u64,
+    }
+
+    /// Answers every command with the next canned response, the last one
+    /// answering all the commands after it
+    struct StubRunner {
+        responses: Mutex<VecDeque<Canned>>,
+    }
+
+    impl CommandRunner for StubRunner {
+        fn run(&self, cmd: Command, _input: &str, cancel: &AtomicBool) -> Result<String> {
+            let canned = {
+                let mut responses = self.responses.lock().unwrap_or_else(|e| e.into_inner());
+                match responses.len() {
+                    0 => Canned::default(),
+                    1 => responses[0].clone(),
+                    _ => responses.pop_front().unwrap_or_default(),
+                }
+            };
+
+            let waited = Instant::now();
+            while waited.elapsed() < Duration::from_millis(canned.delay_ms) {
+                if cancel.load(Ordering::Relaxed) {
+                    return Err(AichatError::Cancelled);
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+
+            if canned.exit != 0 {
+                return Err(AichatError::command_failed(
+                    &cmd,
+                    exit_status(canned.exit),
+                    canned.stderr.into_bytes(),
+                    canned.stdout.into_bytes(),
+                ));
+            }
+            Ok(canned.stdout)
+        }
+    }
+
+    /// The status of a process that exited with `code`
+    #[cfg(unix)]
+    fn exit_status(code: i32) -> ExitStatus {
+        std::os::unix::process::ExitStatusExt::from_raw(code << 8)
+    }
+
+    /// The status of a process that exited with `code`
+    #[cfg(windows)]
+    fn exit_status(code: i32) -> ExitStatus {
+        std::os::windows::process::ExitStatusExt::from_raw(code as u32)
+    }
+
+    // The runner is global, tests installing one take turns
+    static RUNNER_LOCK: Mutex<()> = Mutex::new(());
+
+    /// A stub answering `responses`
+    fn stub_runner(responses: Vec<Canned>) -> StubRunner {
+        StubRunner {
+            responses: Mutex::new(responses.into()),
+        }
+    }
+
+    /// Runs `f` with `responses` answering every command, then puts the
+    /// process runner back
+    fn with_stub<R>(responses: Vec<Canned>, f: impl FnOnce() -> R) -> R {
+        let _lock = RUNNER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        *RUNNER.write().unwrap() = Arc::new(stub_runner(responses));
+        let result = f();
+        *RUNNER.write().unwrap() = Arc::new(ProcessRunner);
+        result
+    }
+
+    /// A config asking for the code of the answers, without `--code` so no
+    /// version detection runs
+    fn code_config() -> AichatConfig {
+        AichatConfig {
+            code_flag: false,
+            ..AichatConfig::default()
+        }
+    }
+
+    #[test]
+    fn stub_reports_a_non_zero_exit_like_aichat() {
+        let runner = stub_runner(vec![Canned {
+            stderr: "Error: rate limited".into(),
+            exit: 1,
+            ..Canned::default()
+        }]);
+
+        let err = runner
+            .run(Command::new("aichat"), "prompt", &AtomicBool::new(false))
+            .unwrap_err();
+        let AichatError::CommandFailed { status, stderr, .. } = &err else {
+            panic!("expected a command failure, got {:?}", err);
+        };
+        assert_eq!(status.code(), Some(1));
+        assert_eq!(stderr, "Error: rate limited");
+        assert!(err.is_transient());
+    }
+
+    #[test]
+    fn stub_answers_in_order_then_repeats_the_last() {
+        let runner = stub_runner(vec![
+            Canned {
+                stdout: "first".into(),
+                ..Canned::default()
+            },
+            Canned {
+                stdout: "last".into(),
+                ..Canned::default()
+            },
+        ]);
+        let run = || {
+            runner
+                .run(Command::new("aichat"), "", &AtomicBool::new(false))
+                .unwrap()
+        };
+
+        assert_eq!(run(), "first");
+        assert_eq!(run(), "last");
+        assert_eq!(run(), "last");
+    }
+
+    #[test]
+    fn stub_is_cancelled_during_its_delay() {
+        let runner = stub_runner(vec![Canned {
+            stdout: "too late".into(),
+            delay_ms: 60_000,
+            ..Canned::default()
+        }]);
+        let cancel = Arc::new(AtomicBool::new(false));
+        let canceller = {
+            let cancel = cancel.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(100));
+                cancel.store(true, Ordering::Relaxed);
+            })
+        };
+
+        let started = Instant::now();
+        let result = runner.run(Command::new("aichat"), "", &cancel);
+        canceller.join().unwrap();
+
+        assert!(matches!(result, Err(AichatError::Cancelled)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn answer_without_a_fence_is_no_code_block() {
+        let result = with_stub(
+            vec![Canned {
+                stdout: "I would rather not change this code.".into(),
+                ..Canned::default()
+            }],
+            || run_aichat_command(&code_config(), "prompt", &AtomicBool::new(false)),
+        );
+
+        assert!(matches!(result, Err(AichatError::NoCodeBlock)));
+    }
+
+    #[test]
+    fn answer_code_is_the_first_code_block() {
+        let result = with_stub(
+            vec![Canned {
+                stdout: "Here:\n```rust\nfn main() {}\n```\nand\n```\nother\n```\n".into(),
+                ..Canned::default()
+            }],
+            || run_aichat_command(&code_config(), "prompt", &AtomicBool::new(false)),
+        );
+
+        assert_eq!(result.unwrap(), "fn main() {}\n");
+    }
+
+    #[test]
+    fn failed_request_reports_the_command() {
+        let result = with_stub(
+            vec![Canned {
+                stderr: "Error: unknown role".into(),
+                exit: 2,
+                ..Canned::default()
+            }],
+            || run_aichat_command(&code_config(), "prompt", &AtomicBool::new(false)),
+        );
+
+        let Err(AichatError::CommandFailed { command, .. }) = result else {
+            panic!("expected a command failure");
+        };
+        assert!(command.starts_with("aichat "), "{}", command);
+    }
+
+    #[test]
+    fn failure_names_the_program() {
+        let runner = stub_runner(vec![Canned {
+            exit: 1,
+            ..Canned::default()
+        }]);
+
+        let err = runner
+            .run(Command::new("llm"), "", &AtomicBool::new(false))
+            .unwrap_err();
+        assert!(err.to_string().starts_with("llm failed"), "{}", err);
+    }
+
+    /// More than fits in the stdin and stdout pipes at once
+    #[cfg(unix)]
+    #[test]
+    fn backoff_stops_once_cancelled() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let started = Instant::now();
+        assert!(sleep_unless_cancelled(Duration::from_millis(120), &cancel));
+        assert!(started.elapsed() >= Duration::from_millis(120));
+
+        let setter = cancel.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(POLL_INTERVAL);
+            setter.store(true, Ordering::Relaxed);
+        });
+        let started = Instant::now();
+        assert!(!sleep_unless_cancelled(Duration::from_secs(30), &cancel));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    fn large_input() -> String {
+        "0123456789abcdef\n".repeat(10_000)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn process_echoes_large_input_without_blocking() {
+        let input = large_input();
+
+        let output = ProcessRunner
+            .run(Command::new("cat"), &input, &AtomicBool::new(false))
+            .unwrap();
+        assert!(
+            output == input,
+            "{} of {} bytes echoed",
+            output.len(),
+            input.len()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn process_exiting_without_reading_reports_its_status() {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo 'Error: no input wanted' >&2; exit 3"]);
+
+        let err = ProcessRunner
+            .run(cmd, &large_input(), &AtomicBool::new(false))
+            .unwrap_err();
+        let AichatError::CommandFailed { status, stderr, .. } = &err else {
+            panic!("expected a command failure, got {:?}", err);
+        };
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(stderr.trim(), "Error: no input wanted");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn process_ignoring_its_input_succeeds() {
+        let output = ProcessRunner
+            .run(
+                Command::new("true"),
+                &large_input(),
+                &AtomicBool::new(false),
+            )
+            .unwrap();
+        assert_eq!(output, "");
+    }
+
+    #[test]
+    fn missing_program_has_no_aichat_hint() {
+        let err = ProcessRunner
+            .run(
+                Command::new("aichat-nvim-missing-program"),
+                "",
+                &AtomicBool::new(false),
+            )
+            .unwrap_err();
+
+        let message = err.to_string();
+        assert_eq!(message, "aichat-nvim-missing-program was not found");
+    }
+
+    #[test]
+    fn missing_aichat_points_to_aichat_path() {
+        let err = AichatError::binary_not_found(&*AichatConfig::default().aichat_path);
+
+        let message = err.to_string();
+        assert!(
+            message.starts_with("aichat was not found. Install it"),
+            "{}",
+            message
+        );
+        assert!(message.contains("`aichat_path`"), "{}", message);
+    }
+}
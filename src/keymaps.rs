@@ -0,0 +1,267 @@
+use crate::config::get_config;
+use crate::error::Result;
+use nvim_oxi::{
+    api::{self, opts::SetKeymapOpts, types::Mode},
+    Object,
+};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+/// Suggested global mappings, registered when `keymaps` is given to `setup()`
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct KeymapOpts {
+    /// Put before the key of every mapping
+    pub prefix: Box<str>,
+    /// Actions left unmapped, e.g. `{ "chat", "macro" }`
+    pub disable: Vec<String>,
+}
+
+impl Default for KeymapOpts {
+    fn default() -> Self {
+        Self {
+            prefix: Box::from("<leader>a"),
+            disable: Vec::new(),
+        }
+    }
+}
+
+/// An action of the plugin, available as `<Plug>(Aichat...)`
+struct Action {
+    /// Name used by `keymaps.disable`
+    name: &'static str,
+    /// `<Plug>(...)` name
+    plug: &'static str,
+    /// Key of the suggested mapping, after the prefix
+    key: &'static str,
+    /// The command takes a range, so visual mode passes the selection
+    range: bool,
+    command: &'static str,
+    desc: &'static str,
+}
+
+const ACTIONS: [Action; 16] = [
+    Action {
+        name: "run",
+        plug: "AichatRun",
+        key: "a",
+        range: true,
+        command: "Aichat",
+        desc: "Aichat: Run on selection",
+    },
+    Action {
+        name: "insert",
+        plug: "AichatInsert",
+        key: "i",
+        range: false,
+        command: "AichatInsert",
+        desc: "Aichat: Insert below",
+    },
+    Action {
+        name: "chat",
+        plug: "AichatChat",
+        key: "c",
+        range: true,
+        command: "AichatRepl",
+        desc: "Aichat: Chat in the REPL",
+    },
+    Action {
+        name: "explain",
+        plug: "AichatExplain",
+        key: "?",
+        range: true,
+        command: "AichatExplain",
+        desc: "Aichat: Explain",
+    },
+    Action {
+        name: "grammar",
+        plug: "AichatGrammar",
+        key: "g",
+        range: false,
+        command: "AichatGrammar",
+        desc: "Aichat: Correct the paragraph",
+    },
+    Action {
+        name: "prose_explain",
+        plug: "AichatProseExplain",
+        key: "p",
+        range: false,
+        command: "AichatProseExplain",
+        desc: "Aichat: Explain the prose finding",
+    },
+    Action {
+        name: "prose_ignore",
+        plug: "AichatProseIgnore",
+        key: "P",
+        range: false,
+        command: "AichatProseIgnore",
+        desc: "Aichat: Ignore the rule of the prose finding",
+    },
+    Action {
+        name: "abort",
+        plug: "AichatAbort",
+        key: "x",
+        range: false,
+        command: "AichatAbort",
+        desc: "Aichat: Abort requests",
+    },
+    Action {
+        name: "edit",
+        plug: "AichatEditPrompt",
+        key: "e",
+        range: false,
+        command: "AichatEditPrompt",
+        desc: "Aichat: Edit last prompt",
+    },
+    Action {
+        name: "regenerate",
+        plug: "AichatRegenerate",
+        key: "r",
+        range: false,
+        command: "AichatRegenerate",
+        desc: "Aichat: Regenerate",
+    },
+    Action {
+        name: "undo",
+        plug: "AichatUndo",
+        key: "u",
+        range: false,
+        command: "AichatUndoLast",
+        desc: "Aichat: Undo last answer",
+    },
+    Action {
+        name: "macro",
+        plug: "AichatMacro",
+        key: "m",
+        range: true,
+        command: "AichatMacro",
+        desc: "Aichat: Run macro",
+    },
+    Action {
+        name: "config",
+        plug: "AichatSetConfig",
+        key: "s",
+        range: false,
+        command: "AichatSetConfig",
+        desc: "Aichat: Set config",
+    },
+    Action {
+        name: "show_config",
+        plug: "AichatShowConfig",
+        key: "S",
+        range: false,
+        command: "AichatShowConfig",
+        desc: "Aichat: Settings",
+    },
+    Action {
+        name: "toggle",
+        plug: "AichatToggle",
+        key: "t",
+        range: false,
+        command: "AichatToggle",
+        desc: "Aichat: Pause automatic features",
+    },
+    Action {
+        name: "queue",
+        plug: "AichatQueue",
+        key: "q",
+        range: false,
+        command: "AichatQueue",
+        desc: "Aichat: Show queue",
+    },
+];
+
+impl Action {
+    fn plug_lhs(&self) -> String {
+        format!("<Plug>({})", self.plug)
+    }
+}
+
+/// Defines `<Plug>(AichatRun)`, `<Plug>(AichatChat)`, ... in normal and
+/// visual mode, for users mapping the actions themselves
+///
+/// Visual mappings of commands taking a range run them on the selection.
+pub fn define_plugs() -> Result<()> {
+    for action in &ACTIONS {
+        let opts = SetKeymapOpts::builder()
+            .noremap(true)
+            .silent(true)
+            .desc(action.desc)
+            .build();
+        let lhs = action.plug_lhs();
+        let command = format!("<Cmd>{}<CR>", action.command);
+
+        api::set_keymap(Mode::Normal, &lhs, &command, &opts)?;
+        if action.range {
+            // `:` from visual mode passes the selection as the range
+            api::set_keymap(
+                Mode::Visual,
+                &lhs,
+                &format!(":{}<CR>", action.command),
+                &opts,
+            )?;
+        } else {
+            api::set_keymap(Mode::Visual, &lhs, &command, &opts)?;
+        }
+    }
+    Ok(())
+}
+
+// Mappings registered by the last `setup()`, removed when it runs again
+thread_local! {
+    static REGISTERED: RefCell<Vec<(Mode, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Sets the suggested mappings of `keymaps`, replacing the ones of a
+/// previous `setup()`
+///
+/// Every mapping has a description, so which-key lists it; when which-key
+/// is installed the prefix also gets the `aichat` group name.
+pub fn register() -> Result<()> {
+    for (mode, lhs) in REGISTERED.with(|registered| registered.take()) {
+        let _ = api::del_keymap(mode, &lhs);
+    }
+
+    let Some(opts) = get_config().keymaps.clone() else {
+        return Ok(());
+    };
+
+    let mut registered = Vec::new();
+    for action in &ACTIONS {
+        if opts.disable.iter().any(|disabled| disabled == action.name) {
+            continue;
+        }
+        let lhs = format!("{}{}", opts.prefix, action.key);
+        // Recursive, so the `<Plug>` mapping is followed
+        let set_opts = SetKeymapOpts::builder()
+            .noremap(false)
+            .silent(true)
+            .desc(action.desc)
+            .build();
+
+        let modes: &[Mode] = if action.range {
+            &[Mode::Normal, Mode::Visual]
+        } else {
+            &[Mode::Normal]
+        };
+        for &mode in modes {
+            api::set_keymap(mode, &lhs, &action.plug_lhs(), &set_opts)?;
+            registered.push((mode, lhs.clone()));
+        }
+    }
+    REGISTERED.with(|list| *list.borrow_mut() = registered);
+
+    api::call_function::<_, Object>(
+        "luaeval",
+        (WHICH_KEY_GROUP_SOURCE, Object::from(&*opts.prefix)),
+    )?;
+    Ok(())
+}
+
+/// Names the prefix `aichat` in which-key, when it is installed
+const WHICH_KEY_GROUP_SOURCE: &str = r#"(function(prefix)
+  local ok, wk = pcall(require, 'which-key')
+  if ok and wk.add then
+    wk.add({ { prefix, group = 'aichat', mode = { 'n', 'x' } } })
+  end
+end)(_A)"#;
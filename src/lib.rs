@@ -1,87 +1,1528 @@
+use error::AichatError;
+use history::Edit;
+use job_runner::CancelToken;
 use nvim_oxi::{
     api::{
         self,
         opts::CreateCommandOpts,
-        types::{CommandArgs, CommandNArgs},
+        types::{CommandArgs, CommandComplete, CommandNArgs},
+        Buffer,
     },
-    string, Result,
+    libuv::TimerHandle,
+    Dictionary, Function, Object, Result,
 };
+use output::Output;
+use prompt::{Location, PromptBuilder};
+use selection::{Anchor, Selection};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use telemetry::Event;
+use utils::Outcome;
 
+mod backend;
+mod code_action;
 mod config;
+mod context;
+mod convert;
+mod dual;
 mod error;
+mod filter;
+mod history;
+mod history_db;
+mod inline;
+#[cfg(feature = "test")]
+mod integration;
 mod job_runner;
+mod keymaps;
+mod macros;
+mod markdown;
+mod output;
+mod patch;
+mod picker;
+mod prompt;
+mod prose;
+mod queue;
+mod repl;
+mod scaffold;
+mod scratch;
+mod selection;
+mod shell;
+mod stats;
+mod summarize;
+mod telemetry;
+mod telescope;
+mod toggle;
+mod transcript;
+mod transform;
+mod trust;
 mod ui;
 mod utils;
+mod validate;
+mod variables;
+mod version;
 
 fn aichat(args: CommandArgs) -> Result<()> {
-    let line1 = args.line1;
-    let line2 = args.line2;
-    let mut buffer = api::get_current_buf();
-    let ft = buffer
-        .get_name()?
-        .extension()
-        .map(|x| x.to_string_lossy().to_string())
-        .unwrap_or("".into());
-    let lines: Vec<nvim_oxi::String> = buffer.get_lines(line1 - 1..line2, true)?;
-    let line = if lines.is_empty() {
-        string!("")
+    let buffer = api::get_current_buf();
+
+    // `:Aichat!` yanks the result instead of changing the buffer
+    let output = if args.bang {
+        Output::Register
     } else {
-        lines
-            .into_iter()
-            .reduce(|acc, e| string!("{}\n{}", acc, e))
-            .ok_or(api::Error::Other("No lines found".into()))?
+        config::get_config().output
     };
-    let code = if line.is_empty() {
-        String::new()
+    let selection = output.target(Selection::from_command(&args, &buffer)?);
+
+    // Leading `key=value` words override the config for this request only
+    let text = args.args.unwrap_or_default();
+    let mut config = config::get_config().clone();
+    let user_text = config::apply_overrides(&mut config, &text)?;
+    let overrides = (user_text.len() != text.trim_start().len()).then_some(config);
+
+    // An instruction given as argument skips the prompt, e.g. in scripts
+    match user_text.trim() {
+        "" => aichat_at(buffer, selection, output, "", overrides),
+        user_text => aichat_send(buffer, selection, output, user_text, overrides),
+    }
+}
+
+/// Prompts for an instruction and sends it under the given role, leaving
+/// the current mode as it is
+fn aichat_with_role(args: CommandArgs) -> Result<()> {
+    let Some(role) = args
+        .args
+        .as_deref()
+        .map(str::trim)
+        .filter(|role| !role.is_empty())
+    else {
+        utils::warn("Usage: AichatWithRole {role}");
+        return Ok(());
+    };
+    let buffer = api::get_current_buf();
+
+    let output = if args.bang {
+        Output::Register
     } else {
-        format!("```{}
-{}```", ft, line.to_string())
+        config::get_config().output
     };
+    let selection = output.target(Selection::from_command(&args, &buffer)?);
 
+    let mut config = config::get_config().clone();
+    config::override_section(&mut config, "role", role)?;
+    aichat_at(buffer, selection, output, "", Some(config))
+}
+
+/// Prompts for an instruction, starting from `default`, and sends it with
+/// the selected code
+fn aichat_at(
+    buffer: Buffer,
+    selection: Selection,
+    output: Output,
+    default: &str,
+    overrides: Option<config::AichatConfig>,
+) -> Result<()> {
     // Create input prompt and handle response
-    if let Some(user_text) = ui::show_input_prompt("Aichat Prompt >")? {
-        utils::info("Sending to Aichat");
+    ui::input(
+        "Aichat Prompt >",
+        default,
+        move |user_text| match user_text {
+            Some(user_text) => Ok(aichat_send(
+                buffer, selection, output, &user_text, overrides,
+            )?),
+            None => Ok(()),
+        },
+    );
 
-        let complete_prompt = format!("{}\n{}", user_text, code);
-        let result = match job_runner::run_aichat_command(&config::get_config(), &complete_prompt) {
-            Ok(result) => result,
-            Err(err) => {
-                error::notify_error(&err);
-                return Err(err.into());
+    Ok(())
+}
+
+/// Sends an instruction with the selected code, with the given config
+/// instead of the current one when there are overrides
+fn aichat_send(
+    buffer: Buffer,
+    selection: Selection,
+    output: Output,
+    user_text: &str,
+    overrides: Option<config::AichatConfig>,
+) -> Result<()> {
+    let code = prompt::fenced(&buffer, &selection.read(&buffer)?.join("\n"))?;
+    let mut builder = PromptBuilder::new(user_text);
+    if let Some(config) = &overrides {
+        builder = builder.system_prompt_of(config);
+    }
+    let builder = builder
+        .location(&Location::current(&buffer, &selection))
+        .mentions(&buffer)?
+        .part(code)
+        .context(&buffer, &selection);
+
+    match overrides {
+        Some(mut config) => {
+            config.output = output;
+            run_request_with(buffer, selection, builder.build(), Some(&builder), config)
+        }
+        None => send(
+            "aichat",
+            buffer,
+            selection,
+            builder.build(),
+            Some(&builder),
+            output,
+        ),
+    }
+}
+
+/// Renames the symbol under the cursor in the selection, or in the function
+/// around the cursor, prompting for the new name unless given
+///
+/// The answer is applied like any other, so with `features.inline` its
+/// hunks can be reviewed one by one.
+fn aichat_rename(args: CommandArgs) -> Result<()> {
+    let buffer = api::get_current_buf();
+    let symbol: String = api::call_function("expand", ("<cword>",))?;
+    if symbol.trim().is_empty() {
+        utils::warn("Put the cursor on the symbol to rename");
+        return Ok(());
+    }
+
+    let output = if args.bang {
+        Output::Register
+    } else {
+        config::get_config().output
+    };
+    let selection = output.target(Selection::from_command(&args, &buffer)?);
+    let input_prompt = format!("Rename {} to >", symbol);
+    let default = symbol.clone();
+
+    let rename = move |new_name: String| -> Result<()> {
+        let instruction = format!(
+            "Rename `{}` to `{}` consistently in this code, including the comments \
+             and strings that refer to it. Change nothing else.",
+            symbol, new_name
+        );
+        let code = prompt::fenced(&buffer, &selection.read(&buffer)?.join("\n"))?;
+        let builder = PromptBuilder::new(&instruction)
+            .location(&Location::current(&buffer, &selection))
+            .part(code);
+        send(
+            "aichat",
+            buffer,
+            selection,
+            builder.build(),
+            Some(&builder),
+            output,
+        )
+    };
+
+    match args
+        .args
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        Some(new_name) => rename(new_name.to_string()),
+        None => {
+            ui::input(&input_prompt, &default, move |new_name| match new_name {
+                Some(new_name) => Ok(rename(new_name)?),
+                None => Ok(()),
+            });
+            Ok(())
+        }
+    }
+}
+
+/// Instruction of `:AichatTranslateComments`, `{language}` being the target
+const TRANSLATE_COMMENTS_INSTRUCTION: &str = "Translate the comments and docstrings of \
+    this code to {language}. Leave the code itself, identifiers and string literals \
+    exactly as they are.";
+
+/// Translates the comments of the selection to the language given as
+/// argument, `comment_language`, or one prompted for
+fn aichat_translate_comments(args: CommandArgs) -> Result<()> {
+    let buffer = api::get_current_buf();
+    let output = if args.bang {
+        Output::Register
+    } else {
+        config::get_config().output
+    };
+    let selection = output.target(Selection::from_command(&args, &buffer)?);
+
+    let translate = move |language: String| {
+        let instruction = TRANSLATE_COMMENTS_INSTRUCTION.replace("{language}", &language);
+        aichat_send(buffer, selection, output, &instruction, None)
+    };
+
+    let language = args
+        .args
+        .as_deref()
+        .map(str::trim)
+        .filter(|language| !language.is_empty())
+        .map(String::from)
+        .or_else(|| {
+            config::get_config()
+                .comment_language
+                .as_deref()
+                .map(String::from)
+        });
+    match language {
+        Some(language) => translate(language),
+        None => {
+            ui::input(
+                "Translate comments to >",
+                "",
+                move |language| match language {
+                    Some(language) => Ok(translate(language)?),
+                    None => Ok(()),
+                },
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Generates code from a description and inserts it below the cursor line,
+/// sending the preceding lines as context
+///
+/// A description given as argument skips the prompt.
+fn aichat_insert(args: CommandArgs) -> Result<()> {
+    let buffer = api::get_current_buf();
+    let (cursor_line, _) = api::get_current_win().get_cursor()?;
+
+    match args.args.filter(|text| !text.trim().is_empty()) {
+        Some(user_text) => insert_below(buffer, cursor_line, &user_text),
+        None => {
+            ui::input("Aichat Insert >", "", move |user_text| match user_text {
+                Some(user_text) => Ok(insert_below(buffer, cursor_line, &user_text)?),
+                None => Ok(()),
+            });
+            Ok(())
+        }
+    }
+}
+
+/// Sends a description of the code to insert after `cursor_line`, with the
+/// lines before it as context
+fn insert_below(buffer: Buffer, cursor_line: usize, user_text: &str) -> Result<()> {
+    let context_lines = config::get_config().insert_context_lines;
+    let context = Selection {
+        line1: cursor_line.saturating_sub(context_lines).max(1),
+        line2: cursor_line,
+        columns: None,
+    };
+    let code = prompt::fenced(&buffer, &context.read(&buffer)?.join("\n"))?;
+
+    let target = Selection::below(cursor_line);
+    let mut builder = PromptBuilder::new(user_text)
+        .location(&Location::current(&buffer, &target))
+        .mentions(&buffer)?;
+    if !code.is_empty() {
+        builder = builder.part(format!(
+            "The code will be inserted right after this context:\n{}",
+            code
+        ));
+    }
+    let output = config::get_config().output;
+    send(
+        "insert",
+        buffer,
+        target,
+        builder.build(),
+        Some(&builder),
+        output,
+    )
+}
+
+/// Proposes updates to the test file of the last edited source file, given
+/// the change that was applied
+fn aichat_sync_tests(_args: CommandArgs) -> Result<()> {
+    let Some(edit) = history::last_edit().filter(|edit| edit.buffer.is_valid()) else {
+        utils::warn("No Aichat edit to sync tests with");
+        return Ok(());
+    };
+
+    let source_path = edit.buffer.get_name()?;
+    let Some(test_path) = context::find_test_file(&source_path) else {
+        utils::warn(&format!("No test file found for {}", source_path.display()));
+        return Ok(());
+    };
+
+    // Open the test file so the result lands in a buffer the user can review and undo
+    let escaped: String =
+        api::call_function("fnameescape", (test_path.to_string_lossy().into_owned(),))?;
+    api::command(&format!("split {}", escaped))?;
+    let test_buffer = api::get_current_buf();
+    let whole_file = Selection {
+        line1: 1,
+        line2: test_buffer.line_count()?,
+        columns: None,
+    };
+    let tests = prompt::fenced(&test_buffer, &whole_file.read(&test_buffer)?.join("\n"))?;
+
+    let body = format!(
+        "This change was applied to {}:\n{}\n\
+         Update the tests below so they cover the changed code and keep passing. \
+         Reply with the complete updated test file in a single code block.\n{}",
+        source_path.display(),
+        prompt::fence("diff", &edit.as_diff()),
+        tests
+    );
+    run_request(
+        test_buffer,
+        whole_file,
+        PromptBuilder::new("").part(body).build(),
+        None,
+        Output::Replace,
+    )
+}
+
+/// Sends the last request again with the configuration it had, replacing the
+/// text its answer wrote
+fn aichat_regenerate(_args: CommandArgs) -> Result<()> {
+    let Some(request) = last_request_reverted()? else {
+        return Ok(());
+    };
+
+    run_request_with(
+        request.buffer,
+        request.selection,
+        request.prompt,
+        request.builder.as_ref(),
+        request.config,
+    )
+}
+
+/// Opens the last prompt in the composer and sends the edited prompt to the
+/// original range
+///
+/// Only the typed instruction is edited when there is one, the code and
+/// context are attached again as they were
+fn aichat_edit_prompt(_args: CommandArgs) -> Result<()> {
+    let Some(request) = history::last_request().filter(|request| request.buffer.is_valid()) else {
+        utils::warn("No Aichat prompt to edit");
+        return Ok(());
+    };
+
+    let editable = match &request.builder {
+        Some(builder) if !builder.typed().is_empty() => builder.typed().to_string(),
+        _ => request.prompt.clone(),
+    };
+
+    ui::open_composer("Aichat Edit Prompt", &editable, move |edited| {
+        if edited.trim().is_empty() {
+            return;
+        }
+
+        let result = last_request_reverted().and_then(|reverted| {
+            let request = reverted.unwrap_or(request);
+            let builder = match &request.builder {
+                Some(builder) if !builder.typed().is_empty() => {
+                    Some(builder.edit(&edited, &request.buffer)?)
+                }
+                _ => None,
+            };
+            let prompt = builder.as_ref().map_or(edited, PromptBuilder::build);
+            run_request_with(
+                request.buffer,
+                request.selection,
+                prompt,
+                builder.as_ref(),
+                request.config,
+            )
+        });
+
+        if let Err(err) = result {
+            error::notify_error(&err.into());
+        }
+    })
+}
+
+/// Returns the last request after putting back the text its answer replaced,
+/// so a new answer lands on the same selection
+fn last_request_reverted() -> Result<Option<history::Request>> {
+    let Some(request) = history::last_request().filter(|request| request.buffer.is_valid()) else {
+        utils::warn("No Aichat request to send again");
+        return Ok(None);
+    };
+
+    let answered = history::last_edit().filter(|edit| {
+        edit.buffer.handle() == request.buffer.handle() && edit.line1 == request.selection.line1
+    });
+    if answered.is_some() {
+        let edit = history::revert(1)?;
+        inline::clear(&edit.buffer);
+    }
+
+    Ok(Some(request))
+}
+
+/// Runs an aichat macro, prompting for the variables it declares
+///
+/// Without a name the selected macro of the config is run. With a range the
+/// selection is passed to the macro and replaced with its answer, otherwise
+/// the answer opens in a scratch buffer.
+fn aichat_macro(args: CommandArgs) -> Result<()> {
+    let name = match args.args.as_deref().map(str::trim) {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => {
+            let config = config::get_config();
+            match config.mode_flag {
+                config::Mode::Macro => config.mode_arg.to_string(),
+                _ => {
+                    utils::warn("Usage: AichatMacro {name}");
+                    return Ok(());
+                }
+            }
+        }
+    };
+    version::require(version::Capability::Macros)?;
+
+    let mut config = config::get_config().clone();
+    config.mode_flag = config::Mode::Macro;
+    config.mode_arg = name.clone().into_boxed_str();
+
+    let buffer = api::get_current_buf();
+    let selection = if args.range > 0 {
+        Some(
+            config
+                .output
+                .target(Selection::from_command(&args, &buffer)?),
+        )
+    } else {
+        None
+    };
+    let text = match &selection {
+        Some(selection) => selection.read(&buffer)?.join("\n"),
+        None => String::new(),
+    };
+
+    let variables = macros::variables(&config, &name);
+    Ok(macros::prompt_arguments(
+        name.clone(),
+        variables,
+        text,
+        move |input| match selection {
+            Some(selection) => Ok(run_request_with(buffer, selection, input, None, config)?),
+            None => {
+                utils::info(&format!("Running the {} macro", name));
+                job_runner::run_in_background(
+                    move || job_runner::run_aichat_response(&config, &input),
+                    |result| {
+                        let shown = result.and_then(|response| {
+                            Ok(ui::open_scratch(
+                                response.lines().map(String::from).collect(),
+                                "markdown",
+                            )?)
+                        });
+                        if let Err(err) = shown {
+                            error::notify_error(&err);
+                        }
+                    },
+                )
             }
+        },
+    )?)
+}
+
+/// Restores the text replaced by the `n`th most recent answer, 1 being the last
+fn undo_edit(n: usize) -> Result<()> {
+    match history::revert(n) {
+        Ok(edit) => {
+            inline::clear(&edit.buffer);
+            utils::info("Restored the text replaced by Aichat");
+        }
+        Err(err) => utils::warn(&err.to_string()),
+    }
+    Ok(())
+}
+
+/// Runs a request for `command`, as a quick and a full answer when a model
+/// pair is configured for it
+fn send(
+    command: &str,
+    buffer: Buffer,
+    selection: Selection,
+    complete_prompt: String,
+    builder: Option<&PromptBuilder>,
+    output: Output,
+) -> Result<()> {
+    let config = config::get_config();
+    let pair = config
+        .features
+        .dual
+        .then(|| config.dual_models.get(command).cloned())
+        .flatten();
+    drop(config);
+    match pair {
+        Some(pair) => Ok(dual::run(
+            buffer,
+            selection,
+            complete_prompt,
+            builder,
+            pair,
+            output,
+        )?),
+        None => run_request(buffer, selection, complete_prompt, builder, output),
+    }
+}
+
+/// Sends the prompt to aichat in the background and writes the extracted code
+/// over `selection` as `output` decides
+///
+/// Requests beyond `max_concurrent_requests` wait in the queue. `builder`
+/// laid out the prompt around the instruction typed by the user, which a
+/// slow request offers to edit; it is `None` when the prompt has none
+fn run_request(
+    buffer: Buffer,
+    selection: Selection,
+    complete_prompt: String,
+    builder: Option<&PromptBuilder>,
+    output: Output,
+) -> Result<()> {
+    let mut config = config::get_config().clone();
+    config.output = output;
+    run_request_with(buffer, selection, complete_prompt, builder, config)
+}
+
+/// Like `run_request`, with a given configuration snapshot
+fn run_request_with(
+    buffer: Buffer,
+    selection: Selection,
+    complete_prompt: String,
+    builder: Option<&PromptBuilder>,
+    config: config::AichatConfig,
+) -> Result<()> {
+    let output = config.output;
+    let register = config.register.clone();
+    let template = config.mode_arg.to_string();
+    let raw_output = config.backend.raw_output();
+    let validate_as = config.validate_as;
+    let retry_builder = builder.cloned();
+    let metadata = config.clone();
+    let prompt = complete_prompt.clone();
+    let target_buffer = buffer.clone();
+
+    submit_request(
+        buffer,
+        selection,
+        complete_prompt,
+        builder,
+        config,
+        move |result, anchor| {
+            let buffer = target_buffer;
+            // Resolved whatever the outcome, so the extmarks are always removed
+            let target = anchor.resolve();
+            let lines = if raw_output {
+                transform::split_lines(&result?)
+            } else {
+                transform::apply(&template, result?)
+            };
+            let target = target?;
+            output.check_writable(&buffer.clone(), lines, move |lines| {
+                let selection = match target {
+                    Some(selection) => selection,
+                    None if output.writes_buffer() => {
+                        utils::copy_to_registers(&lines.join("\n"));
+                        utils::warn(
+                            "The text targeted by the Aichat request was deleted, \
+                             the answer was copied to the registers instead",
+                        );
+                        return Ok(());
+                    }
+                    None => selection,
+                };
+                if !output.writes_buffer() {
+                    return write_answer(buffer, selection, lines, output, &register);
+                }
+                validate::check(
+                    &buffer.clone(),
+                    &selection,
+                    &lines.clone(),
+                    validate_as,
+                    move |verdict| match verdict {
+                        validate::Verdict::Write => {
+                            write_answer(buffer, selection, lines, output, &register)
+                        }
+                        validate::Verdict::Retry => Ok(run_request_with(
+                            buffer,
+                            selection,
+                            prompt,
+                            retry_builder.as_ref(),
+                            metadata,
+                        )?),
+                        validate::Verdict::Reject => {
+                            utils::copy_to_registers(&lines.join("\n"));
+                            utils::warn("The Aichat answer was copied to the registers instead");
+                            Ok(())
+                        }
+                    },
+                )
+            })
+        },
+    )?;
+    Ok(())
+}
+
+/// Writes an answer over `selection` as `output` decides, marking the
+/// changed hunks and recording the edit for undo
+fn write_answer(
+    mut buffer: Buffer,
+    selection: Selection,
+    lines: Vec<String>,
+    output: Output,
+    register: &str,
+) -> error::Result<()> {
+    let original = selection.linewise().read(&buffer)?;
+
+    let target = buffer.clone();
+    output.apply(&mut buffer, &selection, lines, register, move |written| {
+        let Some(replacement) = written else {
+            return Ok(());
+        };
+        let edit = Edit {
+            buffer: target,
+            line1: selection.line1,
+            original,
+            replacement,
         };
+        // The answer is written already, failing to mark its hunks must not
+        // also keep it out of the undo history
+        if let Err(err) = inline::mark_hunks(&edit) {
+            error::notify_error(&err);
+        }
+        history::record_edit(edit);
+        Ok(())
+    })
+}
 
-        let lines = result.split_terminator("\n");
-        buffer.set_lines(line1 - 1..line2, true, lines)?;
-        utils::info("Success");
+/// Registers a request and queues it, then hands its answer to `apply` on
+/// the main loop with the anchor following the selection meanwhile
+///
+/// Every request goes through here, so abort, `wait()`, coalescing,
+/// telemetry, stats, the transcript and the history see all of them.
+/// Returns the key of the request, `None` when the same request is already
+/// running.
+fn submit_request<A>(
+    buffer: Buffer,
+    selection: Selection,
+    complete_prompt: String,
+    builder: Option<&PromptBuilder>,
+    config: config::AichatConfig,
+    apply: A,
+) -> Result<Option<u64>>
+where
+    A: FnOnce(error::Result<String>, Anchor) -> error::Result<()> + Send + 'static,
+{
+    // Drop double-fired requests while the first one is still running
+    let key = job_runner::request_key(&config, &complete_prompt, &buffer, &selection);
+    if !job_runner::try_register(key) {
+        utils::warn("The same Aichat request is already running");
+        return Ok(None);
     }
 
+    let description = format!(
+        "{}: {}",
+        config.mode_arg,
+        complete_prompt
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .take(60)
+            .collect::<String>()
+    );
+    // Follow the selection while the buffer is edited during the request
+    let anchor = selection
+        .anchor(&buffer)
+        .inspect_err(|_| job_runner::unregister(key))?;
+    let apply = move |result| apply(result, anchor);
+    let builder = builder.cloned();
+    history::record_request(history::Request {
+        buffer: buffer.clone(),
+        selection,
+        prompt: complete_prompt.clone(),
+        builder: builder.clone(),
+        config: config.clone(),
+    });
+    queue::submit(key, description, move |slot| match slot {
+        queue::Slot::Free => start_request(
+            buffer,
+            selection,
+            complete_prompt,
+            builder,
+            config,
+            key,
+            apply,
+        ),
+        // Answered like a cancelled request, which resolves the anchor
+        queue::Slot::Dropped => {
+            let _ = apply(Err(AichatError::Cancelled));
+            Ok(())
+        }
+    })?;
+    Ok(Some(key))
+}
+
+/// Starts a registered request once the queue has a free slot for it
+fn start_request<A>(
+    buffer: Buffer,
+    selection: Selection,
+    complete_prompt: String,
+    builder: Option<PromptBuilder>,
+    config: config::AichatConfig,
+    key: u64,
+    apply: A,
+) -> Result<()>
+where
+    A: FnOnce(error::Result<String>) -> error::Result<()> + Send + 'static,
+{
+    match queue::waiting_count() {
+        0 => utils::info("Sending to Aichat"),
+        waiting => utils::info(&format!("Sending to Aichat ({} queued)", waiting)),
+    }
+
+    let output = config.output;
+    let slow_request_ms = config.slow_request_ms;
+    let stats_label = match &config.model {
+        Some(model) => model.to_string(),
+        None => format!("{} {}", config.mode_flag.name(), config.mode_arg),
+    };
+    let bytes_sent = complete_prompt.len();
+    let metadata = config.clone();
+    let prompt = complete_prompt.clone();
+
+    let started = Instant::now();
+    telemetry::emit(
+        Event::new("request_started")
+            .str("id", format!("{:016x}", key))
+            .str("mode", config.mode_flag.name())
+            .str("mode_arg", config.mode_arg.as_ref())
+            .int("prompt_bytes", complete_prompt.len() as i64),
+    );
+
+    let instruction = builder
+        .as_ref()
+        .map_or("", PromptBuilder::instruction)
+        .to_string();
+    let turn_instruction = match instruction.as_str() {
+        "" => complete_prompt
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+        instruction => instruction.to_string(),
+    };
+
+    let cancel = CancelToken::default();
+    queue::track(key, cancel.clone());
+    if slow_request_ms > 0 {
+        let slow = SlowRequest {
+            key,
+            cancel: cancel.clone(),
+            buffer: buffer.clone(),
+            selection,
+            builder,
+            output,
+        };
+        let _ = TimerHandle::once(Duration::from_millis(slow_request_ms), move || {
+            // Timer callbacks run in a fast event, so defer to a safe point
+            nvim_oxi::schedule(move |_| -> nvim_oxi::Result<()> {
+                if let Err(err) = slow.offer_cancel(slow_request_ms) {
+                    error::notify_error(&err);
+                }
+                Ok(())
+            });
+        });
+    }
+
+    let cancelled = cancel.clone();
+    job_runner::run_with_retries(config, complete_prompt, cancel, 0, move |result| {
+        // A cancelled request was already unregistered and its key may be reused
+        if !cancelled.load(Ordering::Relaxed) {
+            job_runner::unregister(key);
+        }
+        queue::untrack(key);
+
+        let bytes_received = result.as_ref().map_or(0, String::len);
+        if let Ok(response) = &result {
+            transcript::record(&metadata, &buffer, &selection, &prompt, response);
+            history_db::record(&instruction, response);
+            history::record_turn(
+                &buffer,
+                history::Turn {
+                    instruction: turn_instruction,
+                    response: response.clone(),
+                },
+            );
+        }
+        let result = apply(result);
+
+        let mut finished = Event::new("request_finished")
+            .str("id", format!("{:016x}", key))
+            .int("duration_ms", started.elapsed().as_millis() as i64);
+        finished = match &result {
+            Ok(()) => finished.str("outcome", "success"),
+            Err(AichatError::Cancelled) => finished.str("outcome", "cancelled"),
+            Err(err) => finished
+                .str("outcome", "failure")
+                .str("error", err.to_string()),
+        };
+        telemetry::emit(finished);
+        stats::record(
+            &stats_label,
+            &result,
+            started.elapsed(),
+            bytes_sent,
+            bytes_received,
+        );
+
+        queue::finished();
+        let pending = job_runner::in_flight_count();
+        match result {
+            Ok(()) => utils::report(Outcome::Done, "Success", pending),
+            Err(AichatError::Cancelled) => utils::info("Aichat request cancelled"),
+            Err(err) => {
+                error::notify_error(&err);
+                utils::report(Outcome::Failed, "", pending);
+            }
+        }
+    })
+    .inspect_err(|_| {
+        job_runner::unregister(key);
+        queue::untrack(key);
+    })?;
+
     Ok(())
 }
 
+/// A running request, kept to offer cancelling it once it gets slow
+struct SlowRequest {
+    key: u64,
+    cancel: CancelToken,
+    buffer: Buffer,
+    selection: Selection,
+    builder: Option<PromptBuilder>,
+    output: Output,
+}
+
+impl SlowRequest {
+    /// Asks whether to keep waiting, cancel, or cancel and edit the prompt,
+    /// unless the request has finished in the meantime
+    fn offer_cancel(self, waited_ms: u64) -> error::Result<()> {
+        if !job_runner::is_in_flight(self.key) {
+            return Ok(());
+        }
+
+        let mut choices = vec!["Keep waiting", "Cancel"];
+        let builder = self
+            .builder
+            .clone()
+            .filter(|builder| !builder.typed().is_empty());
+        if builder.is_some() {
+            choices.push("Cancel and edit prompt");
+        }
+        let question = format!(
+            "The Aichat request is still running after {}s",
+            waited_ms / 1000
+        );
+        ui::choose(&question, &choices, move |choice| {
+            // The answer may have arrived while the picker was open
+            if choice < 2 || !job_runner::is_in_flight(self.key) {
+                return Ok(());
+            }
+            self.cancel.store(true, Ordering::Relaxed);
+            job_runner::unregister(self.key);
+
+            if let Some(builder) = builder.filter(|_| choice == 3) {
+                ui::input("Aichat Prompt >", builder.typed(), move |edited| {
+                    let Some(edited) = edited else {
+                        return Ok(());
+                    };
+                    let builder = builder.edit(&edited, &self.buffer)?;
+                    Ok(run_request(
+                        self.buffer,
+                        self.selection,
+                        builder.build(),
+                        Some(&builder),
+                        self.output,
+                    )?)
+                });
+            }
+            Ok(())
+        });
+
+        Ok(())
+    }
+}
+
+/// Applies the table passed to `setup()` and the features it enables
+fn setup(opts: Option<Dictionary>) -> Result<()> {
+    config::setup(opts)?;
+    keymaps::register()?;
+    telescope::register()?;
+    register_feature_commands()
+}
+
 #[nvim_oxi::plugin]
-fn aichat_nvim() -> Result<()> {
+fn aichat_nvim() -> Result<Dictionary> {
+    // Only commands are registered at load time. Option lists, picker
+    // detection and aichat itself are all deferred to the first command that
+    // needs them, so loading the plugin never spawns a process.
+    register_commands()?;
+    keymaps::define_plugs()?;
+
+    // Expose the Lua API, e.g. `require("aichat_nvim").setup({ picker = "telescope" })`
+    Ok(Dictionary::from_iter([
+        ("setup", Object::from(Function::<_, ()>::from_fn(setup))),
+        (
+            "on_event",
+            Object::from(Function::<_, ()>::from_fn(telemetry::on_event)),
+        ),
+        ("status", Object::from(Function::from_fn(toggle::status))),
+        (
+            "wait",
+            Object::from(Function::<_, bool>::from_fn(queue::wait)),
+        ),
+        (
+            "code_action",
+            Object::from(Function::<_, ()>::from_fn(code_action::run)),
+        ),
+        (
+            "telescope_items",
+            Object::from(Function::from_fn(telescope::items)),
+        ),
+        (
+            "telescope_preview",
+            Object::from(Function::from_fn(telescope::preview)),
+        ),
+        (
+            "telescope_choose",
+            Object::from(Function::<_, ()>::from_fn(telescope::choose)),
+        ),
+    ]))
+}
+
+/// Forgets the requests remembered for the current buffer, or for every
+/// buffer with `!`
+fn aichat_forget(args: CommandArgs) -> Result<()> {
+    let buffer = api::get_current_buf();
+    let forgotten = history::forget_turns((!args.bang).then_some(&buffer));
+    utils::info(&format!("Forgot {} Aichat requests", forgotten));
+    Ok(())
+}
+
+/// Lists the answered requests matching a query, best first, and shows
+/// the chosen one with its answer
+fn aichat_history_search(args: CommandArgs) -> Result<()> {
+    let query = history_db::Query::parse(args.args.as_deref().unwrap_or_default())?;
+    history_db::search(query, |found| {
+        if let Err(err) = found.and_then(choose_history_entry) {
+            error::notify_error(&err);
+        }
+    })
+}
+
+/// Lets the user pick one of the entries found by `:AichatHistorySearch`
+fn choose_history_entry(entries: Vec<history_db::Entry>) -> Result<()> {
+    if entries.is_empty() {
+        utils::info("No Aichat request matches");
+        return Ok(());
+    }
+
+    let items: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let first_line = entry.instruction.lines().next().unwrap_or_default();
+            format!("{}  {}", entry.time, first_line)
+        })
+        .collect();
+    let opts = ui::SelectOpts::with_prompt("Aichat history");
+    ui::vim_ui_select(items, Some(opts), move |_, index| {
+        // The index is 1-based
+        let Some(entry) = index.and_then(|index| entries.get(index.checked_sub(1)?)) else {
+            return;
+        };
+        let mut lines: Vec<String> = entry.instruction.lines().map(String::from).collect();
+        lines.push(String::new());
+        lines.extend(entry.response.lines().map(String::from));
+        if let Err(err) = ui::open_float(&entry.time, lines) {
+            error::notify_error(&err);
+        }
+    })?;
+    Ok(())
+}
+
+/// Registers every user command of the plugin
+fn register_commands() -> Result<()> {
     // Create command to run Aichat with the selected text
     let _ = api::create_user_command(
         "Aichat",
         aichat,
         &CreateCommandOpts::builder()
-            .range(api::types::CommandRange::WholeFile)
-            .nargs(CommandNArgs::Zero)
+            .range(api::types::CommandRange::CurrentLine)
+            .bang(true)
+            .nargs(CommandNArgs::Any)
             .desc("Run Aichat command")
             .build(),
     )?;
 
+    // Create command to run Aichat once under another role
+    let _ = api::create_user_command(
+        "AichatWithRole",
+        aichat_with_role,
+        &CreateCommandOpts::builder()
+            .range(api::types::CommandRange::CurrentLine)
+            .bang(true)
+            .nargs(CommandNArgs::One)
+            .complete(CommandComplete::CustomList(Function::from_fn(
+                |(arg_lead, _, _): (String, String, usize)| {
+                    config::complete_section_values("role", &arg_lead)
+                },
+            )))
+            .desc("Run Aichat on the selection under a role, for this request only")
+            .build(),
+    )?;
+
+    // Create command to rename the symbol under the cursor
+    let _ = api::create_user_command(
+        "AichatRename",
+        aichat_rename,
+        &CreateCommandOpts::builder()
+            .range(api::types::CommandRange::CurrentLine)
+            .bang(true)
+            .nargs(CommandNArgs::ZeroOrOne)
+            .desc("Rename the symbol under the cursor in the selection with Aichat")
+            .build(),
+    )?;
+
+    // Create command to translate the comments of the selection
+    let _ = api::create_user_command(
+        "AichatTranslateComments",
+        aichat_translate_comments,
+        &CreateCommandOpts::builder()
+            .range(api::types::CommandRange::CurrentLine)
+            .bang(true)
+            .nargs(CommandNArgs::ZeroOrOne)
+            .desc("Translate the comments of the selection with Aichat, leaving the code")
+            .build(),
+    )?;
+
+    // Create command to summarize the selection or the buffer
+    let _ = api::create_user_command(
+        "AichatSummarize",
+        summarize::aichat_summarize,
+        &CreateCommandOpts::builder()
+            .range(api::types::CommandRange::CurrentLine)
+            .bang(true)
+            .nargs(CommandNArgs::ZeroOrOne)
+            .complete(CommandComplete::CustomList(Function::from_fn(
+                |(arg_lead, _, _): (String, String, usize)| {
+                    summarize::presets()
+                        .into_iter()
+                        .filter(|preset| preset.starts_with(&arg_lead))
+                        .collect::<Vec<_>>()
+                },
+            )))
+            .desc("Summarize the selection or the buffer with Aichat, in a float")
+            .build(),
+    )?;
+
+    // Create commands adding a title, headings or a table of contents to markdown
+    for (name, addition, desc) in [
+        (
+            "AichatTitle",
+            markdown::Addition::Title,
+            "Add an Aichat generated title at the top of the markdown buffer",
+        ),
+        (
+            "AichatHeadings",
+            markdown::Addition::Headings,
+            "Add Aichat generated section headings to the markdown buffer",
+        ),
+        (
+            "AichatToc",
+            markdown::Addition::Toc,
+            "Add an Aichat generated table of contents below the title",
+        ),
+    ] {
+        let _ = api::create_user_command(
+            name,
+            move |_| markdown::generate(addition),
+            &CreateCommandOpts::builder()
+                .nargs(CommandNArgs::Zero)
+                .desc(desc)
+                .build(),
+        )?;
+    }
+
+    // Create command to correct the prose under the cursor in place
+    let _ = api::create_user_command(
+        "AichatGrammar",
+        prose::quick_fix,
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::ZeroOrOne)
+            .complete(CommandComplete::CustomList(Function::from_fn(
+                |(arg_lead, _, _): (String, String, usize)| {
+                    ["paragraph", "sentence"]
+                        .into_iter()
+                        .filter(|unit| unit.starts_with(arg_lead.as_str()))
+                        .map(String::from)
+                        .collect::<Vec<_>>()
+                },
+            )))
+            .desc("Correct the spelling and grammar of the paragraph or sentence under the cursor")
+            .build(),
+    )?;
+
+    // Create command to convert the selection to another format
+    let _ = api::create_user_command(
+        "AichatConvert",
+        convert::aichat_convert,
+        &CreateCommandOpts::builder()
+            .range(api::types::CommandRange::CurrentLine)
+            .nargs(CommandNArgs::ZeroOrOne)
+            .complete(CommandComplete::CustomList(Function::from_fn(
+                |(arg_lead, _, _): (String, String, usize)| {
+                    convert::targets()
+                        .into_iter()
+                        .filter(|target| target.starts_with(&arg_lead))
+                        .collect::<Vec<_>>()
+                },
+            )))
+            .desc("Convert the selection to another format with Aichat")
+            .build(),
+    )?;
+
+    // Create command to pipe the selection through filter_command
+    let _ = api::create_user_command(
+        "AichatFilter",
+        filter::aichat_filter,
+        &CreateCommandOpts::builder()
+            .range(api::types::CommandRange::CurrentLine)
+            .nargs(CommandNArgs::Any)
+            .desc("Replace the selection with the output of filter_command")
+            .build(),
+    )?;
+
+    // Create command to generate code at the cursor
+    let _ = api::create_user_command(
+        "AichatInsert",
+        aichat_insert,
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::Any)
+            .desc("Insert Aichat generated code below the cursor")
+            .build(),
+    )?;
+
+    // Create command to toggle an interactive aichat REPL
+    let _ = api::create_user_command(
+        "AichatRepl",
+        repl::toggle,
+        &CreateCommandOpts::builder()
+            .range(api::types::CommandRange::CurrentLine)
+            .nargs(CommandNArgs::Zero)
+            .desc("Toggle a terminal running the aichat REPL")
+            .build(),
+    )?;
+
+    // Create command to open a named scratchpad for iterative prompting
+    let _ = api::create_user_command(
+        "AichatScratch",
+        |args: CommandArgs| scratch::open(args.args.as_deref()),
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::ZeroOrOne)
+            .complete(CommandComplete::CustomList(Function::from_fn(
+                |(arg_lead, _, _): (String, String, usize)| {
+                    scratch::names()
+                        .into_iter()
+                        .filter(|name| name.starts_with(&arg_lead))
+                        .collect::<Vec<_>>()
+                },
+            )))
+            .desc("Open an Aichat scratchpad where <CR> sends everything above")
+            .build(),
+    )?;
+
+    // Create command to explain the selected code in a float
+    let _ = api::create_user_command(
+        "AichatExplain",
+        |args: CommandArgs| {
+            let buffer = api::get_current_buf();
+            let selection = Selection::from_command(&args, &buffer)?;
+            code_action::run_action("explain", buffer, selection.line1, selection.line2, "")
+        },
+        &CreateCommandOpts::builder()
+            .range(api::types::CommandRange::CurrentLine)
+            .nargs(CommandNArgs::Zero)
+            .desc("Explain the selected code in a float")
+            .build(),
+    )?;
+
+    // Create command to generate a shell command with aichat's execute mode
+    let _ = api::create_user_command(
+        "AichatShell",
+        |args: CommandArgs| shell::aichat_shell(args.args),
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::Any)
+            .desc("Generate a shell command from a description")
+            .build(),
+    )?;
+
+    // Create command to refactor the open files with a reviewed diff
+    let _ = api::create_user_command(
+        "AichatRefactor",
+        |args: CommandArgs| patch::aichat_refactor(args.args),
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::Any)
+            .desc("Refactor the open files and review the diff file by file")
+            .build(),
+    )?;
+
+    // Create command to clear the requests remembered for the next ones
+    let _ = api::create_user_command(
+        "AichatForget",
+        aichat_forget,
+        &CreateCommandOpts::builder()
+            .bang(true)
+            .nargs(CommandNArgs::Zero)
+            .desc("Forget the Aichat requests on this buffer, or on every buffer with !")
+            .build(),
+    )?;
+
+    // Create command to search the instructions and answers kept in the history
+    let _ = api::create_user_command(
+        "AichatHistorySearch",
+        aichat_history_search,
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::OneOrMore)
+            .desc("Search the Aichat history, with since:YYYY-MM-DD and until:YYYY-MM-DD filters")
+            .build(),
+    )?;
+
+    // Create commands to restore the text replaced by recent answers
+    let _ = api::create_user_command(
+        "AichatUndoLast",
+        |_| undo_edit(1),
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::Zero)
+            .desc("Restore the text replaced by the last Aichat answer")
+            .build(),
+    )?;
+    let _ = api::create_user_command(
+        "AichatRevert",
+        |args: CommandArgs| match args.args.as_deref().map(str::trim).map(str::parse::<usize>) {
+            Some(Ok(n)) if n > 0 => undo_edit(n),
+            _ => {
+                utils::warn("Usage: AichatRevert {n}, 1 being the last Aichat edit");
+                Ok(())
+            }
+        },
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::One)
+            .desc("Restore the text replaced by the nth most recent Aichat answer")
+            .build(),
+    )?;
+
+    // Create command to send the last request again
+    let _ = api::create_user_command(
+        "AichatRegenerate",
+        aichat_regenerate,
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::Zero)
+            .desc("Send the last Aichat request again and replace its answer")
+            .build(),
+    )?;
+
+    // Create command to edit the last prompt and send it again
+    let _ = api::create_user_command(
+        "AichatEditPrompt",
+        aichat_edit_prompt,
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::Zero)
+            .desc("Edit the last Aichat prompt and send it again")
+            .build(),
+    )?;
+
+    // Create command to run a macro with its arguments
+    let _ = api::create_user_command(
+        "AichatMacro",
+        aichat_macro,
+        &CreateCommandOpts::builder()
+            .range(api::types::CommandRange::CurrentLine)
+            .nargs(CommandNArgs::ZeroOrOne)
+            .complete(CommandComplete::CustomList(Function::from_fn(
+                |(arg_lead, _, _): (String, String, usize)| {
+                    config::complete_section_values("macro", &arg_lead)
+                },
+            )))
+            .desc("Run an Aichat macro on the selection, prompting for its arguments")
+            .build(),
+    )?;
+
+    // Create command to update tests after an applied edit
+    let _ = api::create_user_command(
+        "AichatSyncTests",
+        aichat_sync_tests,
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::Zero)
+            .desc("Update the tests of the last Aichat edit")
+            .build(),
+    )?;
+
+    // Create command to write the exchanges of this session to a markdown file
+    let _ = api::create_user_command(
+        "AichatExport",
+        |args: CommandArgs| transcript::export(args.args.as_deref().unwrap_or_default()),
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::One)
+            .complete(CommandComplete::File)
+            .desc("Export the Aichat transcript of this session as markdown")
+            .build(),
+    )?;
+
+    // Create command to keep the last exchange in the project's notes
+    let _ = api::create_user_command(
+        "AichatSaveExchange",
+        |args: CommandArgs| transcript::save_last(args.args.as_deref()),
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::ZeroOrOne)
+            .complete(CommandComplete::File)
+            .desc("Append the last Aichat prompt and response to the notes file")
+            .build(),
+    )?;
+
+    // Create command to show the request statistics of this session
+    let _ = api::create_user_command(
+        "AichatStats",
+        |_| stats::show_stats(),
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::Zero)
+            .desc("Show Aichat request statistics for this session")
+            .build(),
+    )?;
+
+    // Create command to inspect or clear the request queue
+    let _ = api::create_user_command(
+        "AichatQueue",
+        |args: CommandArgs| queue::show_queue(args.args.as_deref()),
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::ZeroOrOne)
+            .complete(CommandComplete::CustomList(Function::from_fn(
+                |(arg_lead, _, _): (String, String, usize)| {
+                    ["clear"]
+                        .into_iter()
+                        .filter(|arg| arg.starts_with(arg_lead.as_str()))
+                        .map(String::from)
+                        .collect::<Vec<_>>()
+                },
+            )))
+            .desc("Show the queued Aichat requests, or clear them")
+            .build(),
+    )?;
+
+    // Create command to cancel every running and queued request
+    let _ = api::create_user_command(
+        "AichatAbort",
+        |_| queue::abort(),
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::Zero)
+            .desc("Cancel the running Aichat requests and drop the queued ones")
+            .build(),
+    )?;
+
     // Create command to set Aichat configuration
     let _ = api::create_user_command(
         "AichatSetConfig",
-        |_| config::show_config_menu(),
+        |args: CommandArgs| config::set_config_from_args(&args.fargs),
         &CreateCommandOpts::builder()
-            .nargs(CommandNArgs::Zero)
+            .nargs(CommandNArgs::Any)
+            .complete(CommandComplete::CustomList(Function::from_fn(
+                |(arg_lead, cmd_line, cursor_pos): (String, String, usize)| {
+                    config::complete_set_config(&arg_lead, &cmd_line, cursor_pos)
+                },
+            )))
             .desc("Set the Config for Aichat")
             .build(),
     )?;
 
+    // Create one command per config section, e.g. `:AichatSetRole [role]`
+    for (name, section) in [
+        ("AichatSetRole", "role"),
+        ("AichatSetAgent", "agent"),
+        ("AichatSetMacro", "macro"),
+        ("AichatSetSession", "session"),
+        ("AichatSetRag", "rag"),
+    ] {
+        let _ = api::create_user_command(
+            name,
+            move |args: CommandArgs| config::set_section(section, args.args.as_deref()),
+            &CreateCommandOpts::builder()
+                .nargs(CommandNArgs::ZeroOrOne)
+                .complete(CommandComplete::CustomList(Function::from_fn(
+                    move |(arg_lead, _, _): (String, String, usize)| {
+                        config::complete_section_values(section, &arg_lead)
+                    },
+                )))
+                .desc(format!("Set the Aichat {}", section).as_str())
+                .build(),
+        )?;
+    }
+
+    // Create command to set the variables of the current agent
+    let _ = api::create_user_command(
+        "AichatSetAgentVariable",
+        |args: CommandArgs| config::set_agent_variable_from_args(&args.fargs),
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::Any)
+            .complete(CommandComplete::CustomList(Function::from_fn(
+                |(arg_lead, _, _): (String, String, usize)| {
+                    config::complete_agent_variables(&arg_lead)
+                },
+            )))
+            .desc("Set a variable of the current Aichat agent")
+            .build(),
+    )?;
+
+    // Create command to switch the automatic features at runtime
+    let _ = api::create_user_command(
+        "AichatToggle",
+        |args: CommandArgs| toggle::toggle(args.args.as_deref()),
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::ZeroOrOne)
+            .complete(CommandComplete::CustomList(Function::from_fn(
+                |(arg_lead, _, _): (String, String, usize)| toggle::complete(&arg_lead),
+            )))
+            .desc("Pause or resume the automatic Aichat features, or toggle one")
+            .build(),
+    )?;
+
+    // Create command to switch between the local model and the remote one
+    let _ = api::create_user_command(
+        "AichatLocal",
+        |args: CommandArgs| toggle::local(args.args.as_deref()),
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::ZeroOrOne)
+            .complete(CommandComplete::CustomList(Function::from_fn(
+                |(arg_lead, _, _): (String, String, usize)| {
+                    ["on", "off"]
+                        .into_iter()
+                        .filter(|arg| arg.starts_with(arg_lead.as_str()))
+                        .map(String::from)
+                        .collect::<Vec<_>>()
+                },
+            )))
+            .desc("Switch Aichat to the local model and back")
+            .build(),
+    )?;
+
+    // Create command to refetch the cached aichat lists
+    let _ = api::create_user_command(
+        "AichatRefreshLists",
+        |_| config::refresh_option_lists(),
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::Zero)
+            .desc("Refresh the cached Aichat roles, agents, macros, sessions and RAGs")
+            .build(),
+    )?;
+
     // Create command to display current Aichat configuration
     let _ = api::create_user_command(
         "AichatShowConfig",
@@ -92,5 +1533,71 @@ fn aichat_nvim() -> Result<()> {
             .build(),
     )?;
 
-    Ok(())
-}
\ No newline at end of file
+    register_feature_commands()
+}
+
+/// Creates the commands of the enabled experimental features and removes
+/// the ones of disabled features
+fn register_feature_commands() -> Result<()> {
+    let features = config::get_config().features.clone();
+
+    if features.scaffold {
+        // Create command to generate a project skeleton from a description
+        let _ = api::create_user_command(
+            "AichatScaffold",
+            |args: CommandArgs| scaffold::aichat_scaffold(args.args),
+            &CreateCommandOpts::builder()
+                .nargs(CommandNArgs::Any)
+                .desc("Create the files of a project from a description")
+                .build(),
+        )?;
+    } else {
+        let _ = api::del_user_command("AichatScaffold");
+    }
+
+    if features.prose {
+        // Create commands to review prose now, explain, apply and ignore the suggestions
+        let _ = api::create_user_command(
+            "AichatProseCheck",
+            |_| prose::check_current(),
+            &CreateCommandOpts::builder()
+                .nargs(CommandNArgs::Zero)
+                .desc("Review the changed paragraphs of this buffer")
+                .build(),
+        )?;
+        let _ = api::create_user_command(
+            "AichatProseFix",
+            |_| prose::pick_fix(),
+            &CreateCommandOpts::builder()
+                .nargs(CommandNArgs::Zero)
+                .desc("Apply an Aichat grammar or style suggestion")
+                .build(),
+        )?;
+        let _ = api::create_user_command(
+            "AichatProseExplain",
+            |_| prose::explain(),
+            &CreateCommandOpts::builder()
+                .nargs(CommandNArgs::Zero)
+                .desc("Explain the Aichat prose finding under the cursor")
+                .build(),
+        )?;
+        let _ = api::create_user_command(
+            "AichatProseIgnore",
+            |_| prose::ignore(),
+            &CreateCommandOpts::builder()
+                .nargs(CommandNArgs::Zero)
+                .desc(
+                    "Ignore the rule of the Aichat prose finding under the cursor in its paragraph",
+                )
+                .build(),
+        )?;
+    } else {
+        let _ = api::del_user_command("AichatProseCheck");
+        let _ = api::del_user_command("AichatProseFix");
+        let _ = api::del_user_command("AichatProseExplain");
+        let _ = api::del_user_command("AichatProseIgnore");
+    }
+
+    prose::sync_autocmds()?;
+    Ok(code_action::sync_server()?)
+}
@@ -0,0 +1,158 @@
+use crate::config::{self, AichatConfig};
+use crate::error::Result;
+use crate::ui;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+/// A variable declared by an aichat macro, filled from its arguments
+pub struct Variable {
+    pub name: String,
+    pub default: Option<String>,
+    /// Takes every remaining argument, only the last variable can
+    pub rest: bool,
+}
+
+/// Reads the variables declared by the macro `name`
+///
+/// The macro file is looked up in the `macros_dir` reported by
+/// `aichat --info`, or the `macros` directory next to its `config_file`. A
+/// macro that can't be found is treated as having no variables, aichat
+/// reports unknown macros itself.
+pub fn variables(config: &AichatConfig, name: &str) -> Vec<Variable> {
+    let Ok(info) = config::aichat_info(config, &[]) else {
+        return Vec::new();
+    };
+    let dir = info.get("macros_dir").map(PathBuf::from).or_else(|| {
+        info.get("config_file")
+            .map(PathBuf::from)
+            .and_then(|file| file.parent().map(|dir| dir.join("macros")))
+    });
+
+    dir.and_then(|dir| std::fs::read_to_string(dir.join(format!("{}.yaml", name))).ok())
+        .map(|yaml| parse_variables(&yaml))
+        .unwrap_or_default()
+}
+
+/// Reads the `variables:` list of a macro file
+///
+/// Only the flat `- name:` / `default:` / `rest:` entries aichat supports are
+/// understood, which avoids a YAML parser for this one list
+fn parse_variables(yaml: &str) -> Vec<Variable> {
+    let mut variables: Vec<Variable> = Vec::new();
+    let mut in_variables = false;
+
+    for line in yaml.lines() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        if !line.starts_with(char::is_whitespace) && !line.starts_with('-') {
+            in_variables = line.trim_end() == "variables:";
+            continue;
+        }
+        if !in_variables {
+            continue;
+        }
+
+        let entry = line.trim_start();
+        let (entry, new) = match entry.strip_prefix("- ") {
+            Some(entry) => (entry.trim_start(), true),
+            None => (entry, false),
+        };
+        let Some((key, value)) = entry.split_once(':') else {
+            continue;
+        };
+        let value = unquote(value.trim());
+
+        if new {
+            variables.push(Variable {
+                name: String::new(),
+                default: None,
+                rest: false,
+            });
+        }
+        let Some(variable) = variables.last_mut() else {
+            continue;
+        };
+        match key.trim() {
+            "name" => variable.name = value,
+            "default" => variable.default = Some(value),
+            "rest" => variable.rest = value == "true",
+            _ => {}
+        }
+    }
+
+    variables.retain(|variable| !variable.name.is_empty());
+    variables
+}
+
+/// Strips the quotes around a YAML scalar
+fn unquote(value: &str) -> String {
+    ['"', '\'']
+        .iter()
+        .find_map(|quote| {
+            value
+                .strip_prefix(*quote)
+                .and_then(|value| value.strip_suffix(*quote))
+        })
+        .unwrap_or(value)
+        .to_string()
+}
+
+/// Prompts for the value of every variable, one after the other, and calls
+/// `on_done` with the arguments of the macro, unless a prompt was cancelled
+///
+/// `text` (the selection) is added after the values, so a trailing `rest`
+/// variable receives it. Values are quoted unless they go to a `rest`
+/// variable, which takes the remaining words as they are.
+pub fn prompt_arguments<F>(
+    name: String,
+    variables: Vec<Variable>,
+    text: String,
+    on_done: F,
+) -> Result<()>
+where
+    F: FnOnce(String) -> Result<()> + 'static,
+{
+    prompt_next(name, variables.into(), Vec::new(), text, Box::new(on_done))
+}
+
+/// Prompts for the first of the `remaining` variables, then for the next ones
+fn prompt_next(
+    name: String,
+    mut remaining: VecDeque<Variable>,
+    mut args: Vec<String>,
+    text: String,
+    on_done: Box<dyn FnOnce(String) -> Result<()>>,
+) -> Result<()> {
+    let Some(variable) = remaining.pop_front() else {
+        if !text.is_empty() {
+            args.push(text);
+        }
+        return on_done(args.join(" "));
+    };
+
+    let prompt = format!("Aichat {} {} >", name, variable.name);
+    let default = variable.default.clone().unwrap_or_default();
+    ui::input(&prompt, &default, move |value| {
+        let value = match value {
+            Some(value) => value,
+            // An empty answer is fine for the free text taking the selection
+            None if variable.rest && !text.is_empty() => String::new(),
+            None => return Ok(()),
+        };
+        if !value.is_empty() {
+            args.push(if variable.rest { value } else { quote(&value) });
+        }
+        prompt_next(name, remaining, args, text, on_done)
+    });
+    Ok(())
+}
+
+/// Quotes a single argument for aichat's argument splitting
+fn quote(value: &str) -> String {
+    if value.contains(char::is_whitespace) || value.contains('"') {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        value.to_string()
+    }
+}
@@ -0,0 +1,178 @@
+use crate::config::get_config;
+use crate::error::{notify_error, Result};
+use crate::{job_runner, utils};
+use nvim_oxi::api::{
+    self,
+    opts::{OptionOpts, OptionScope::Local},
+    Buffer,
+};
+use std::cmp::Reverse;
+
+/// What `:AichatTitle`, `:AichatHeadings` and `:AichatToc` add to a document
+#[derive(Clone, Copy)]
+pub enum Addition {
+    /// A `#` title at the top of the file
+    Title,
+    /// Headings above the lines starting new sections
+    Headings,
+    /// A table of contents below the title
+    Toc,
+}
+
+impl Addition {
+    /// The instruction sent with the numbered lines of the document
+    fn instruction(self) -> &'static str {
+        match self {
+            Addition::Title => {
+                "Write a title for this Markdown document. Answer with the title only, \
+                 on one line, without `#`."
+            }
+            Addition::Headings => {
+                "Propose section headings for this Markdown document, which is shown \
+                 with line numbers. Answer with one line per heading, formatted as \
+                 `<line number>: <heading>`, the heading starting with `##` or `###` \
+                 and going above the numbered line. Don't propose headings above \
+                 lines that already are headings."
+            }
+            Addition::Toc => {
+                "Write a table of contents for this Markdown document, as a nested \
+                 Markdown list of links to its headings. Answer with the list only."
+            }
+        }
+    }
+}
+
+/// Handles `:AichatTitle`, `:AichatHeadings` and `:AichatToc` on the current
+/// markdown buffer
+///
+/// The additions are inserted where they belong, nothing is replaced.
+pub fn generate(addition: Addition) -> Result<()> {
+    let buffer = api::get_current_buf();
+    let opts = OptionOpts::builder().scope(Local).buffer(&buffer).build();
+    let filetype: String = api::get_option_value("filetype", &opts).unwrap_or_default();
+    if filetype != "markdown" {
+        utils::warn("Only works in markdown buffers");
+        return Ok(());
+    }
+
+    let lines = buffer_lines(&buffer)?;
+    let numbered = lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| format!("{}: {}", i + 1, line))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let prompt = format!("{}\n\n{}", addition.instruction(), numbered);
+
+    let config = get_config().clone();
+    utils::info("Asking Aichat");
+
+    job_runner::run_in_background(
+        move || job_runner::run_aichat_response(&config, &prompt),
+        move |result| {
+            if let Err(err) = result.and_then(|response| insert(buffer, addition, &response)) {
+                notify_error(&err);
+            }
+        },
+    )
+}
+
+/// Inserts the answer into the buffer
+fn insert(mut buffer: Buffer, addition: Addition, response: &str) -> Result<()> {
+    if !buffer.is_valid() {
+        return Ok(());
+    }
+    let lines = buffer_lines(&buffer)?;
+
+    match addition {
+        Addition::Title => {
+            let title = response
+                .lines()
+                .map(|line| line.trim().trim_start_matches('#').trim())
+                .find(|line| !line.is_empty())
+                .unwrap_or_default();
+            if title.is_empty() {
+                utils::warn("aichat did not propose a title");
+                return Ok(());
+            }
+            buffer.set_lines(0..0, false, [format!("# {}", title), String::new()])?;
+        }
+        Addition::Headings => {
+            let headings = parse_headings(response, lines.len());
+            if headings.is_empty() {
+                utils::warn("aichat did not propose any headings");
+                return Ok(());
+            }
+            for (line, heading) in &headings {
+                let row = line - 1;
+                let mut insert = vec![heading.clone(), String::new()];
+                if row > 0 && !lines[row - 1].trim().is_empty() {
+                    insert.insert(0, String::new());
+                }
+                buffer.set_lines(row..row, false, insert)?;
+            }
+            utils::info(&format!("Added {} headings", headings.len()));
+        }
+        Addition::Toc => {
+            let toc: Vec<String> = strip_fence(response).lines().map(String::from).collect();
+            if toc.iter().all(|line| line.trim().is_empty()) {
+                utils::warn("aichat did not propose a table of contents");
+                return Ok(());
+            }
+            // Below the title and the blank lines after it, or at the top
+            let row = match lines.first() {
+                Some(first) if first.starts_with("# ") => {
+                    1 + lines[1..]
+                        .iter()
+                        .take_while(|line| line.trim().is_empty())
+                        .count()
+                }
+                _ => 0,
+            };
+            let mut insert = toc;
+            insert.push(String::new());
+            buffer.set_lines(row..row, false, insert)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads `<line>: <heading>` answers, keeping the ones within the document
+///
+/// The headings are sorted from the bottom, so inserting them in order keeps
+/// the line numbers of the next ones right.
+fn parse_headings(response: &str, line_count: usize) -> Vec<(usize, String)> {
+    let mut headings: Vec<(usize, String)> = response
+        .lines()
+        .filter_map(|line| {
+            let (number, heading) = line.trim().trim_matches('`').split_once(':')?;
+            let number: usize = number.trim().parse().ok()?;
+            let heading = heading.trim();
+            (heading.starts_with('#') && (1..=line_count).contains(&number))
+                .then(|| (number, heading.to_string()))
+        })
+        .collect();
+    headings.sort_by_key(|(line, _)| Reverse(*line));
+    headings.dedup_by_key(|(line, _)| *line);
+    headings
+}
+
+/// The content of the first code block of a response, or the response
+fn strip_fence(response: &str) -> &str {
+    let trimmed = response.trim();
+    match trimmed.strip_prefix("```") {
+        Some(rest) => {
+            let body = rest.split_once('\n').map_or("", |(_, body)| body);
+            body.rsplit_once("```").map_or(body, |(body, _)| body)
+        }
+        None => trimmed,
+    }
+}
+
+/// All lines of a buffer
+fn buffer_lines(buffer: &Buffer) -> Result<Vec<String>> {
+    Ok(buffer
+        .get_lines(0..buffer.line_count()?, false)?
+        .map(|line| line.to_string_lossy().into_owned())
+        .collect())
+}
@@ -0,0 +1,294 @@
+use crate::error::Result;
+use crate::selection::Selection;
+use crate::toggle::{self, Automatic};
+use nvim_oxi::{
+    api::{
+        self,
+        opts::{OptionOpts, OptionScope::Local},
+        Buffer,
+    },
+    Dictionary, Object,
+};
+use serde::{Deserialize, Serialize};
+
+/// What happens with the code extracted from a response
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Output {
+    /// Replace the selection
+    Replace,
+    /// Insert the result and keep the original lines commented out above it,
+    /// so both versions can be compared
+    CommentOriginal,
+    /// Put the extracted code in a register and leave the buffer untouched
+    Register,
+    /// Put the full response, not only the code, in a register
+    RegisterResponse,
+}
+
+impl Output {
+    /// Adjusts the selection to what the output variant works on
+    ///
+    /// Commenting out works on whole lines, so charwise selections are widened
+    pub fn target(self, selection: Selection) -> Selection {
+        match self {
+            Output::CommentOriginal => selection.linewise(),
+            Output::Replace | Output::Register | Output::RegisterResponse => selection,
+        }
+    }
+
+    /// Whether only the first code block of the response is used
+    pub fn extracts_code(self) -> bool {
+        self != Output::RegisterResponse
+    }
+
+    /// Whether the response is written to the buffer
+    pub fn writes_buffer(self) -> bool {
+        matches!(self, Output::Replace | Output::CommentOriginal)
+    }
+
+    /// Hands the response lines to `on_writable` if they can still be written
+    /// where they were asked for
+    ///
+    /// A buffer that was wiped out or is 'nomodifiable' by the time the
+    /// response arrives is left alone, and the response is offered in a
+    /// scratch buffer instead. Responses longer than `large_response_lines`
+    /// are only written once confirmed, so `on_writable` may run later, from
+    /// the answer of the question.
+    pub fn check_writable<F>(
+        self,
+        buffer: &Buffer,
+        lines: Vec<String>,
+        on_writable: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(Vec<String>) -> Result<()> + Send + 'static,
+    {
+        if !self.writes_buffer() {
+            return on_writable(lines);
+        }
+
+        let buffer = buffer.clone();
+        confirm_size(lines, move |lines| {
+            if writable(&buffer)? {
+                return on_writable(lines);
+            }
+
+            let reason = if buffer.is_valid() {
+                "is not modifiable"
+            } else {
+                "was closed"
+            };
+            let question = format!(
+                "The buffer of the Aichat request {}. Open the response in a scratch buffer?",
+                reason
+            );
+            crate::ui::confirm(&question, move |scratch| {
+                if scratch {
+                    let filetype = match buffer.is_valid() {
+                        true => api::get_option_value("filetype", &local(&buffer))?,
+                        false => String::new(),
+                    };
+                    crate::ui::open_scratch(lines, &filetype)?;
+                } else {
+                    crate::utils::copy_to_registers(&lines.join("\n"));
+                    crate::utils::info("Aichat response copied to the registers");
+                }
+                Ok(())
+            });
+            Ok(())
+        })
+    }
+
+    /// Writes the response lines to the buffer, or to `register` for the register variants
+    ///
+    /// Hands the whole lines written to the buffer, if any, to `on_written`
+    /// once they are all in; long answers are written in chunks, see
+    /// `Selection::replace_in_chunks`.
+    pub fn apply<F>(
+        self,
+        buffer: &mut Buffer,
+        selection: &Selection,
+        lines: Vec<String>,
+        register: &str,
+        on_written: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(Option<Vec<String>>) -> Result<()> + 'static,
+    {
+        let replacement = match self {
+            Output::Replace => {
+                let original = selection.read(buffer)?;
+                match_original(buffer, &original, lines)?
+            }
+            Output::CommentOriginal => {
+                let original = selection.read(buffer)?;
+                let lines = match_original(buffer, &original, lines)?;
+                let mut commented = comment_lines(buffer, original)?;
+                commented.extend(lines);
+                commented
+            }
+            Output::Register | Output::RegisterResponse => {
+                let _: i64 = api::call_function("setreg", (register, lines.join("\n")))?;
+                crate::utils::info(&format!("Aichat response copied to register {}", register));
+                return on_written(None);
+            }
+        };
+
+        let target = buffer.clone();
+        let line1 = selection.line1;
+        selection.replace_in_chunks(buffer, replacement, move |written| {
+            if written.is_empty() || !toggle::is_active(Automatic::Format) {
+                return on_written(Some(written));
+            }
+            let range = Selection {
+                line1,
+                line2: line1 + written.len() - 1,
+                columns: None,
+            };
+            on_written(Some(format_lines(&target, range)?))
+        })
+    }
+}
+
+/// Gives the response the trailing blank lines and line endings of the text
+/// it replaces, so a round trip through the model doesn't add or drop a
+/// final newline or turn line endings into `^M`
+///
+/// Lines of 'fileformat' dos and mac buffers never hold a `\r`. Unix
+/// buffers keep one at the end of every line only when the original lines
+/// all had it, as for a CRLF file read as unix.
+fn match_original(
+    buffer: &Buffer,
+    original: &[String],
+    mut lines: Vec<String>,
+) -> Result<Vec<String>> {
+    if lines.is_empty() || original.is_empty() {
+        return Ok(lines);
+    }
+
+    let blank_at_end = |lines: &[String]| {
+        lines
+            .iter()
+            .rev()
+            .take_while(|line| line.trim_end_matches('\r').is_empty())
+            .count()
+    };
+    let wanted = blank_at_end(original).min(original.len() - 1);
+    let present = blank_at_end(&lines).min(lines.len() - 1);
+    lines.truncate(lines.len() - present);
+    lines.resize(lines.len() + wanted, String::new());
+
+    let fileformat: String = api::get_option_value("fileformat", &local(buffer))?;
+    let carriage_returns = fileformat == "unix" && original.iter().all(|line| line.ends_with('\r'));
+    for line in &mut lines {
+        let trimmed = line.trim_end_matches('\r').len();
+        line.truncate(trimmed);
+        if carriage_returns {
+            line.push('\r');
+        }
+    }
+
+    Ok(lines)
+}
+
+/// Runs conform.nvim when installed, the LSP formatter otherwise, on the
+/// lines an answer wrote
+///
+/// Returns the lines as they are after formatting. A formatter failure is
+/// reported and leaves the lines as they were written.
+fn format_lines(buffer: &Buffer, range: Selection) -> Result<Vec<String>> {
+    let anchor = range.anchor(buffer)?;
+    let args = Dictionary::from_iter([
+        ("bufnr", Object::from(buffer.handle())),
+        ("line1", Object::from(range.line1 as i64)),
+        ("line2", Object::from(range.line2 as i64)),
+    ]);
+    let error: String = api::call_function("luaeval", (FORMAT_SOURCE, args))?;
+    if !error.is_empty() {
+        crate::utils::warn(&format!("Formatting the Aichat answer failed: {}", error));
+    }
+
+    // Formatting may change the number of lines, the anchor follows them
+    match anchor.resolve()? {
+        Some(formatted) => formatted.read(buffer),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Asks before writing a response longer than `large_response_lines`,
+/// copying it to the registers when declined
+fn confirm_size<F>(lines: Vec<String>, on_confirmed: F) -> Result<()>
+where
+    F: FnOnce(Vec<String>) -> Result<()> + Send + 'static,
+{
+    let limit = crate::config::get_config().large_response_lines;
+    if limit == 0 || lines.len() <= limit {
+        return on_confirmed(lines);
+    }
+
+    let question = format!(
+        "The Aichat answer has {} lines. Write it to the buffer?",
+        lines.len()
+    );
+    crate::ui::confirm(&question, move |write| {
+        if write {
+            return on_confirmed(lines);
+        }
+        crate::utils::copy_to_registers(&lines.join("\n"));
+        crate::utils::info("Aichat response copied to the registers");
+        Ok(())
+    });
+    Ok(())
+}
+
+/// Whether the buffer still exists and can be edited
+pub fn writable(buffer: &Buffer) -> Result<bool> {
+    if !buffer.is_valid() {
+        return Ok(false);
+    }
+    Ok(api::get_option_value("modifiable", &local(buffer))?)
+}
+
+/// Options local to the buffer
+fn local(buffer: &Buffer) -> OptionOpts {
+    OptionOpts::builder().scope(Local).buffer(buffer).build()
+}
+
+/// Comments out lines with the buffer's 'commentstring', keeping indentation
+pub fn comment_lines(buffer: &Buffer, lines: Vec<String>) -> Result<Vec<String>> {
+    let commentstring: String = api::get_option_value("commentstring", &local(buffer))?;
+    let commentstring = if commentstring.contains("%s") {
+        commentstring
+    } else {
+        "# %s".to_string()
+    };
+
+    Ok(lines
+        .into_iter()
+        .map(|line| {
+            if line.trim().is_empty() {
+                return line;
+            }
+            let content = line.trim_start();
+            let indent = &line[..line.len() - content.len()];
+            format!("{}{}", indent, commentstring.replacen("%s", content, 1))
+        })
+        .collect())
+}
+
+/// Formats lines `line1`-`line2` of buffer `bufnr` synchronously, returning
+/// the error message or an empty string. `_A` holds the buffer and lines.
+const FORMAT_SOURCE: &str = r#"(function(args)
+  local last = vim.api.nvim_buf_get_lines(args.bufnr, args.line2 - 1, args.line2, false)[1] or ''
+  local range = { start = { args.line1, 0 }, ['end'] = { args.line2, #last } }
+  local has_conform, conform = pcall(require, 'conform')
+  local ok, err = pcall(function()
+    if has_conform then
+      conform.format({ bufnr = args.bufnr, range = range, async = false, lsp_format = 'fallback' })
+    else
+      vim.lsp.buf.format({ bufnr = args.bufnr, range = range, async = false })
+    end
+  end)
+  return ok and '' or tostring(err)
+end)(_A)"#;
@@ -0,0 +1,572 @@
+use crate::error::{notify_error, AichatError, Result};
+use crate::prompt::{self, PromptBuilder};
+use crate::ui::Keys;
+use crate::{config, job_runner, ui, utils};
+use nvim_oxi::{
+    api::{
+        self,
+        opts::{OptionOpts, OptionScope::Local},
+        Buffer, Window,
+    },
+    Array, Object,
+};
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// What the model is asked to reply with
+const DIFF_INSTRUCTIONS: &str = "Make the change across the files below. Reply only with a \
+    unified diff: a `--- a/path` and `+++ b/path` header per file, then `@@` hunks with three \
+    lines of context. Paths are relative to the working directory. A new file uses \
+    `--- /dev/null`.";
+
+/// Line of the summary window showing the first file, 1-based
+const FIRST_FILE_LINE: usize = 3;
+
+/// The changes proposed for one file
+struct FilePatch {
+    path: PathBuf,
+    hunks: Vec<Hunk>,
+}
+
+/// A block of lines to replace, near the line the diff expects it at
+struct Hunk {
+    /// First line of the block in the original file, as given by the `@@` header
+    old_start: usize,
+    /// The lines the block has now, context included
+    old: Vec<String>,
+    /// The lines it should have
+    new: Vec<String>,
+    added: usize,
+    removed: usize,
+}
+
+/// Review state of a file in the summary window
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Pending,
+    Applied,
+    Rejected,
+    Failed,
+}
+
+/// The files of a proposed refactoring and what was decided for each
+struct Review {
+    root: PathBuf,
+    patches: Vec<FilePatch>,
+    statuses: Vec<Status>,
+}
+
+/// Handles `:AichatRefactor {description}`
+///
+/// The files open in buffers are sent with the description, the current one
+/// first. The unified diff of the response is shown as a list of files that
+/// are applied or rejected one by one.
+pub fn aichat_refactor(description: Option<String>) -> nvim_oxi::Result<()> {
+    let description = match description.filter(|description| !description.trim().is_empty()) {
+        Some(description) => description,
+        None => {
+            ui::input("Aichat Refactor >", "", |description| match description {
+                Some(description) => Ok(aichat_refactor(Some(description))?),
+                None => Ok(()),
+            });
+            return Ok(());
+        }
+    };
+
+    let root: String = api::call_function("getcwd", Array::new())?;
+    let root = PathBuf::from(root);
+    let files = open_files(&root)?;
+    if files.is_empty() {
+        utils::warn("No files of the working directory are open to refactor");
+        return Ok(());
+    }
+
+    let mut builder = PromptBuilder::new(&description).part(DIFF_INSTRUCTIONS);
+    for (path, buffer) in &files {
+        let text = buffer
+            .get_lines(0..buffer.line_count()?, false)?
+            .map(|line| line.to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+            .join("\n");
+        builder = builder.part(format!(
+            "File: {}\n{}",
+            path.display(),
+            prompt::fenced(buffer, &text)?
+        ));
+    }
+    let prompt = builder.build();
+    let config = config::get_config().clone();
+
+    utils::info(&format!("Asking Aichat to refactor {} files", files.len()));
+
+    job_runner::run_in_background(
+        move || {
+            let response = job_runner::run_aichat_response(&config, &prompt)?;
+            parse(&response)
+        },
+        move |result| {
+            if let Err(err) = result.and_then(|patches| show_summary(root, patches)) {
+                notify_error(&err);
+            }
+        },
+    )?;
+
+    Ok(())
+}
+
+/// The listed file buffers below `root`, relative to it, the current buffer first
+pub fn open_files(root: &Path) -> Result<Vec<(PathBuf, Buffer)>> {
+    let current = api::get_current_buf();
+    let mut buffers: Vec<Buffer> = api::list_bufs()
+        .filter(|buffer| buffer.is_loaded() && *buffer != current)
+        .collect();
+    buffers.insert(0, current);
+
+    let mut files = Vec::new();
+    for buffer in buffers {
+        let opts = OptionOpts::builder().scope(Local).buffer(&buffer).build();
+        let listed: bool = api::get_option_value("buflisted", &opts)?;
+        let buftype: String = api::get_option_value("buftype", &opts)?;
+        if !listed || !buftype.is_empty() {
+            continue;
+        }
+        if let Ok(path) = buffer.get_name()?.strip_prefix(root) {
+            files.push((path.to_path_buf(), buffer));
+        }
+    }
+
+    Ok(files)
+}
+
+/// Parses the unified diff in a response into per-file patches
+///
+/// Hunks end at the first line that isn't part of a diff, so fences and
+/// explanations around the diff are ignored and wrong line counts in the
+/// `@@` headers don't matter
+fn parse(response: &str) -> Result<Vec<FilePatch>> {
+    let lines: Vec<&str> = response.lines().collect();
+    let is_file_header = |i: usize| {
+        lines[i].starts_with("--- ")
+            && lines
+                .get(i + 1)
+                .is_some_and(|next| next.starts_with("+++ "))
+    };
+
+    let mut patches: Vec<FilePatch> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        if is_file_header(i) {
+            let path = diff_path(&lines[i + 1][4..]);
+            if path == "/dev/null" {
+                return Err(AichatError::application(format!(
+                    "aichat proposed deleting {}, which :AichatRefactor doesn't do",
+                    diff_path(&lines[i][4..])
+                )));
+            }
+            patches.push(FilePatch {
+                path: utils::relative_path(path)?,
+                hunks: Vec::new(),
+            });
+            i += 2;
+            continue;
+        }
+
+        let Some(header) = lines[i].strip_prefix("@@") else {
+            i += 1;
+            continue;
+        };
+        i += 1;
+        let Some(patch) = patches.last_mut() else {
+            continue;
+        };
+
+        let mut hunk = Hunk {
+            old_start: hunk_start(header),
+            old: Vec::new(),
+            new: Vec::new(),
+            added: 0,
+            removed: 0,
+        };
+        // Blank lines after the last hunk are more likely prose than context
+        let mut blank = 0;
+        while i < lines.len() && !is_file_header(i) {
+            let line = lines[i];
+            match line.chars().next() {
+                Some('+') => {
+                    hunk.new.push(line[1..].to_string());
+                    hunk.added += 1;
+                }
+                Some('-') => {
+                    hunk.old.push(line[1..].to_string());
+                    hunk.removed += 1;
+                }
+                Some(' ') => {
+                    hunk.old.push(line[1..].to_string());
+                    hunk.new.push(line[1..].to_string());
+                }
+                // Models often drop the space of empty context lines
+                None => {
+                    hunk.old.push(String::new());
+                    hunk.new.push(String::new());
+                }
+                // `\ No newline at end of file`
+                Some('\\') => {}
+                _ => break,
+            }
+            blank = if line.is_empty() { blank + 1 } else { 0 };
+            i += 1;
+        }
+        hunk.old.truncate(hunk.old.len() - blank);
+        hunk.new.truncate(hunk.new.len() - blank);
+        patch.hunks.push(hunk);
+    }
+
+    patches.retain(|patch| !patch.hunks.is_empty());
+    if patches.is_empty() {
+        return Err(AichatError::application(
+            "aichat did not propose any changes as a diff",
+        ));
+    }
+
+    Ok(patches)
+}
+
+/// The path of a `---`/`+++` header, without the `a/`/`b/` prefix and timestamp
+fn diff_path(header: &str) -> &str {
+    let path = header.split('\t').next().unwrap_or_default().trim();
+    path.strip_prefix("a/")
+        .or_else(|| path.strip_prefix("b/"))
+        .unwrap_or(path)
+}
+
+/// The original start line of a `@@ -12,5 +12,6 @@` header, 1 when unreadable
+fn hunk_start(header: &str) -> usize {
+    header
+        .split_whitespace()
+        .next()
+        .and_then(|old| old.trim_start_matches('-').split(',').next())
+        .and_then(|start| start.parse().ok())
+        .unwrap_or(1)
+}
+
+impl FilePatch {
+    /// Lines added and removed over all hunks
+    fn counts(&self) -> (usize, usize) {
+        self.hunks.iter().fold((0, 0), |(added, removed), hunk| {
+            (added + hunk.added, removed + hunk.removed)
+        })
+    }
+
+    /// Applies the hunks to the file's buffer, loading or creating it
+    ///
+    /// Every hunk is located before anything changes, so a file is patched
+    /// completely or not at all. The buffer is left modified for review.
+    fn apply(&self, root: &Path) -> Result<()> {
+        let path = root.join(&self.path);
+        let bufnr: i32 = api::call_function("bufadd", (path.to_string_lossy().into_owned(),))?;
+        let _: Object = api::call_function("bufload", (bufnr,))?;
+        let mut buffer = Buffer::from(bufnr);
+        let opts = OptionOpts::builder().scope(Local).buffer(&buffer).build();
+        api::set_option_value("buflisted", true, &opts)?;
+
+        let mut lines: Vec<String> = buffer
+            .get_lines(0..buffer.line_count()?, false)?
+            .map(|line| line.to_string_lossy().into_owned())
+            .collect();
+        // A new or empty file still has one empty line in its buffer
+        let was_empty = lines.len() == 1 && lines[0].is_empty();
+        if was_empty {
+            lines.clear();
+        }
+
+        let mut ranges = Vec::new();
+        let mut from = 0;
+        for (n, hunk) in self.hunks.iter().enumerate() {
+            let start = locate(&lines, hunk, from).ok_or_else(|| {
+                AichatError::application(format!(
+                    "Hunk {} of {} doesn't match the file",
+                    n + 1,
+                    self.path.display()
+                ))
+            })?;
+            from = start + hunk.old.len();
+            ranges.push(start..from);
+        }
+
+        // Bottom up, so the ranges above stay valid
+        for (hunk, range) in self.hunks.iter().zip(ranges).rev() {
+            buffer.set_lines(range, true, hunk.new.clone())?;
+        }
+        if was_empty {
+            let count = buffer.line_count()?;
+            buffer.set_lines(count - 1..count, true, Vec::<String>::new())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Finds where the original lines of a hunk are, at or after `from`,
+/// preferring the match closest to the line given by the diff
+fn locate(lines: &[String], hunk: &Hunk, from: usize) -> Option<usize> {
+    // A hunk without original lines inserts after `old_start`
+    if hunk.old.is_empty() {
+        return Some(hunk.old_start.clamp(from, lines.len()));
+    }
+
+    let hint = hunk.old_start.saturating_sub(1);
+    let last = lines.len().checked_sub(hunk.old.len())?;
+    (from..=last)
+        .filter(|&start| {
+            lines[start..start + hunk.old.len()]
+                .iter()
+                .zip(&hunk.old)
+                .all(|(line, old)| line.trim_end() == old.trim_end())
+        })
+        .min_by_key(|&start| start.abs_diff(hint))
+}
+
+/// Shows the touched files in a float, where they are applied or rejected
+fn show_summary(root: PathBuf, patches: Vec<FilePatch>) -> Result<()> {
+    let keys = config::get_config().keys.clone();
+    let review = Review {
+        statuses: vec![Status::Pending; patches.len()],
+        root,
+        patches,
+    };
+
+    let mut lines = vec!["Changes proposed by Aichat:".to_string(), String::new()];
+    lines.extend((0..review.patches.len()).map(|index| review.line(index)));
+    lines.push(String::new());
+    lines.push(format!(
+        "{} apply file  {} reject file  {} close",
+        Keys::hint(&keys.accept),
+        Keys::hint(&keys.reject),
+        Keys::hint(&keys.cancel)
+    ));
+
+    let (mut buffer, window) = ui::open_float("Aichat Refactor", lines)?;
+    let _ = window.clone().set_cursor(FIRST_FILE_LINE, 0);
+
+    let review = Rc::new(RefCell::new(review));
+    for (lhs, status, desc) in [
+        (
+            &keys.accept,
+            Status::Applied,
+            "Apply the changes to this file",
+        ),
+        (
+            &keys.reject,
+            Status::Rejected,
+            "Reject the changes to this file",
+        ),
+    ] {
+        let review = review.clone();
+        let target = buffer.clone();
+        let window = window.clone();
+        ui::set_keymaps(&mut buffer, lhs, desc, move || {
+            if let Err(err) = decide(&review, &target, &window, status) {
+                notify_error(&err);
+            }
+        })?;
+    }
+
+    Ok(())
+}
+
+impl Review {
+    /// The summary line of a file
+    fn line(&self, index: usize) -> String {
+        let patch = &self.patches[index];
+        let (added, removed) = patch.counts();
+        let mark = match self.statuses[index] {
+            Status::Pending => "[ ]",
+            Status::Applied => "[x]",
+            Status::Rejected => "[-]",
+            Status::Failed => "[!]",
+        };
+        format!("{} {}  +{} -{}", mark, patch.path.display(), added, removed)
+    }
+}
+
+/// Applies or rejects the file under the cursor, then moves to the next line
+fn decide(
+    review: &Rc<RefCell<Review>>,
+    buffer: &Buffer,
+    window: &Window,
+    decision: Status,
+) -> Result<()> {
+    let mut window = window.clone();
+    let (row, _) = window.get_cursor()?;
+    let mut review = review.borrow_mut();
+
+    let Some(index) = row
+        .checked_sub(FIRST_FILE_LINE)
+        .filter(|&index| index < review.patches.len())
+    else {
+        return Ok(());
+    };
+    if review.statuses[index] != Status::Pending {
+        return Ok(());
+    }
+
+    review.statuses[index] = match decision {
+        Status::Applied => match review.patches[index].apply(&review.root) {
+            Ok(()) => {
+                utils::info(&format!(
+                    "Applied the changes to {}",
+                    review.patches[index].path.display()
+                ));
+                Status::Applied
+            }
+            Err(err) => {
+                notify_error(&err);
+                Status::Failed
+            }
+        },
+        other => other,
+    };
+
+    // The float is read-only, unlock it for the update
+    let mut buffer = buffer.clone();
+    let opts = OptionOpts::builder().scope(Local).buffer(&buffer).build();
+    api::set_option_value("modifiable", true, &opts)?;
+    buffer.set_lines(row - 1..row, true, [review.line(index)])?;
+    api::set_option_value("modifiable", false, &opts)?;
+
+    if index + 1 < review.patches.len() {
+        window.set_cursor(row + 1, 0)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn fenced_diff_with_prose_around_it() {
+        let response = "Here is the change:\n\
+            \n\
+            ```diff\n\
+            --- a/src/lib.rs\n\
+            +++ b/src/lib.rs\n\
+            @@ -1,2 +1,3 @@\n\
+            \x20a\n\
+            +b\n\
+            \x20c\n\
+            ```\n\
+            \n\
+            This adds b.";
+
+        let patches = parse(response).unwrap();
+
+        assert_eq!(patches.len(), 1);
+        assert_eq!(patches[0].path, PathBuf::from("src/lib.rs"));
+        let hunk = &patches[0].hunks[0];
+        assert_eq!(hunk.old, ["a", "c"]);
+        assert_eq!(hunk.new, ["a", "b", "c"]);
+        assert_eq!((hunk.added, hunk.removed), (1, 0));
+    }
+
+    #[test]
+    fn blank_lines_before_trailing_prose_are_not_context() {
+        let response = "--- a/f\n+++ b/f\n@@ -1 +1,2 @@\n a\n+c\n\n\nThis changes f.";
+
+        let hunk = &parse(response).unwrap()[0].hunks[0];
+
+        assert_eq!(hunk.old, ["a"]);
+        assert_eq!(hunk.new, ["a", "c"]);
+    }
+
+    #[test]
+    fn empty_context_lines_without_their_space() {
+        let response = "--- a/f\n+++ b/f\n@@ -1,3 +1,3 @@\n a\n\n-b\n+B";
+
+        let hunk = &parse(response).unwrap()[0].hunks[0];
+
+        assert_eq!(hunk.old, ["a", "", "b"]);
+        assert_eq!(hunk.new, ["a", "", "B"]);
+    }
+
+    #[test]
+    fn no_newline_markers_are_skipped() {
+        let response = "--- a/f\n+++ b/f\n@@ -1 +1 @@\n-a\n\\ No newline at end of file\n+b\n\\ No newline at end of file";
+
+        let hunk = &parse(response).unwrap()[0].hunks[0];
+
+        assert_eq!(hunk.old, ["a"]);
+        assert_eq!(hunk.new, ["b"]);
+    }
+
+    #[test]
+    fn new_files_come_from_dev_null() {
+        let response = "--- /dev/null\n+++ b/new.rs\n@@ -0,0 +1,2 @@\n+fn main() {\n+}";
+
+        let patches = parse(response).unwrap();
+
+        assert_eq!(patches[0].path, PathBuf::from("new.rs"));
+        let hunk = &patches[0].hunks[0];
+        assert!(hunk.old.is_empty());
+        assert_eq!(locate(&[], hunk, 0), Some(0));
+    }
+
+    #[test]
+    fn deleting_a_file_is_refused() {
+        let response = "--- a/old.rs\n+++ /dev/null\n@@ -1 +0,0 @@\n-fn main() {}";
+
+        let err = parse(response).err().unwrap();
+
+        assert!(err.to_string().contains("deleting old.rs"));
+    }
+
+    #[test]
+    fn several_files_keep_their_own_hunks() {
+        let response = "--- a/one\n+++ b/one\n@@ -1 +1 @@\n-1\n+one\n@@ -9 +9 @@\n-9\n+nine\n\
+            --- a/two\n+++ b/two\n@@ -2 +2 @@\n-2\n+two";
+
+        let patches = parse(response).unwrap();
+
+        let paths: Vec<&Path> = patches.iter().map(|patch| patch.path.as_path()).collect();
+        assert_eq!(paths, [Path::new("one"), Path::new("two")]);
+        assert_eq!(patches[0].hunks.len(), 2);
+        assert_eq!(patches[0].hunks[1].old_start, 9);
+        assert_eq!(patches[1].hunks[0].new, ["two"]);
+    }
+
+    #[test]
+    fn headers_lose_their_prefix_and_timestamp() {
+        assert_eq!(
+            diff_path("a/src/main.rs\t2026-10-15 10:00:00"),
+            "src/main.rs"
+        );
+        assert_eq!(diff_path("b/src/main.rs"), "src/main.rs");
+        assert_eq!(diff_path("/dev/null"), "/dev/null");
+        assert_eq!(hunk_start(" -12,5 +12,6 @@ fn main()"), 12);
+        assert_eq!(hunk_start(" -7 +7 @@"), 7);
+        assert_eq!(hunk_start(" garbage @@"), 1);
+    }
+
+    #[test]
+    fn hunks_are_found_near_a_wrong_line_number() {
+        let file = lines("x\na\nb\nx\nx\nx\na\nb\nx");
+        let hunk = |old_start| Hunk {
+            old_start,
+            old: lines("a\nb"),
+            new: lines("a\nB"),
+            added: 1,
+            removed: 1,
+        };
+
+        // The copy at line 7 is closer to line 6 than the one at line 2
+        assert_eq!(locate(&file, &hunk(6), 0), Some(6));
+        assert_eq!(locate(&file, &hunk(1), 0), Some(1));
+        assert_eq!(locate(&file[..5], &hunk(40), 2), None);
+    }
+}
@@ -0,0 +1,171 @@
+use nvim_oxi::{api, Array, Dictionary, Function, Object, Result};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// Global static to remember what each configured backend resolved to, so
+// plugin detection only runs the first time a picker is opened
+static RESOLVED: Lazy<Mutex<HashMap<PickerBackend, PickerBackend>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Picker plugin used for every selection UI
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum PickerBackend {
+    /// First installed picker in the order below, falling back to `vim.ui.select`
+    #[serde(rename = "auto")]
+    Auto,
+    #[serde(rename = "telescope")]
+    Telescope,
+    #[serde(rename = "fzf-lua")]
+    FzfLua,
+    #[serde(rename = "snacks")]
+    Snacks,
+    #[serde(rename = "mini.pick")]
+    MiniPick,
+    /// Neovim's `vim.ui.select`, which respects whatever overrides it
+    #[serde(rename = "builtin")]
+    Builtin,
+}
+
+impl PickerBackend {
+    /// Backends tried in order when `auto` is selected
+    const DETECTION_ORDER: [PickerBackend; 4] = [
+        PickerBackend::Telescope,
+        PickerBackend::FzfLua,
+        PickerBackend::Snacks,
+        PickerBackend::MiniPick,
+    ];
+
+    /// Lua module that has to be installed for the backend to be usable
+    fn module(self) -> Option<&'static str> {
+        match self {
+            PickerBackend::Telescope => Some("telescope"),
+            PickerBackend::FzfLua => Some("fzf-lua"),
+            PickerBackend::Snacks => Some("snacks"),
+            PickerBackend::MiniPick => Some("mini.pick"),
+            PickerBackend::Auto | PickerBackend::Builtin => None,
+        }
+    }
+
+    /// Lua expression that opens the picker with `_A = { items, prompt, on_choice }`
+    ///
+    /// `on_choice` follows the `vim.ui.select` convention of `(item, idx)`
+    fn lua_source(self) -> &'static str {
+        match self {
+            PickerBackend::Telescope => TELESCOPE_SOURCE,
+            PickerBackend::FzfLua => FZF_LUA_SOURCE,
+            PickerBackend::Snacks => SNACKS_SOURCE,
+            PickerBackend::MiniPick => MINI_PICK_SOURCE,
+            PickerBackend::Auto | PickerBackend::Builtin => BUILTIN_SOURCE,
+        }
+    }
+
+    /// Resolves the backend on first use and reuses the answer afterwards
+    fn resolve_cached(self) -> PickerBackend {
+        let mut resolved = RESOLVED.lock().unwrap_or_else(|e| e.into_inner());
+        *resolved.entry(self).or_insert_with(|| self.resolve())
+    }
+
+    /// Resolves `auto` to the first installed picker, and any picker that is
+    /// not installed to the builtin one
+    fn resolve(self) -> PickerBackend {
+        match self {
+            PickerBackend::Auto => Self::DETECTION_ORDER
+                .into_iter()
+                .find(|backend| backend.is_installed())
+                .unwrap_or(PickerBackend::Builtin),
+            backend if backend.is_installed() => backend,
+            _ => PickerBackend::Builtin,
+        }
+    }
+
+    /// Checks whether the backend's Lua module can be required
+    fn is_installed(self) -> bool {
+        match self.module() {
+            Some(module) => {
+                api::call_function::<_, bool>("luaeval", ("pcall(require, _A)", module))
+                    .unwrap_or(false)
+            }
+            None => true,
+        }
+    }
+}
+
+/// Opens the configured picker over `items`
+///
+/// # Arguments
+/// * `backend` - The configured picker backend
+/// * `items` - The items to choose from
+/// * `opts` - The `vim.ui.select` style options (prompt, kind)
+/// * `on_choice` - Lua callback receiving the chosen item and its 1-based index
+pub fn open(
+    backend: PickerBackend,
+    items: Array,
+    opts: Dictionary,
+    on_choice: Function<Array, ()>,
+) -> Result<()> {
+    let args = Array::from_iter([
+        Object::from(items),
+        Object::from(opts),
+        Object::from(on_choice),
+    ]);
+
+    api::call_function::<_, Object>("luaeval", (backend.resolve_cached().lua_source(), args))?;
+
+    Ok(())
+}
+
+const BUILTIN_SOURCE: &str = r#"vim.ui.select(_A[1], _A[2], _A[3])"#;
+
+const TELESCOPE_SOURCE: &str = r#"(function(items, opts, on_choice)
+  local actions = require('telescope.actions')
+  local state = require('telescope.actions.state')
+  require('telescope.pickers').new({}, {
+    prompt_title = opts.prompt,
+    finder = require('telescope.finders').new_table({ results = items }),
+    sorter = require('telescope.config').values.generic_sorter({}),
+    attach_mappings = function(bufnr)
+      actions.select_default:replace(function()
+        local entry = state.get_selected_entry()
+        actions.close(bufnr)
+        if entry then on_choice(entry[1], entry.index) else on_choice(nil, nil) end
+      end)
+      return true
+    end,
+  }):find()
+end)(_A[1], _A[2], _A[3])"#;
+
+const FZF_LUA_SOURCE: &str = r#"(function(items, opts, on_choice)
+  require('fzf-lua').fzf_exec(items, {
+    prompt = (opts.prompt or 'Select') .. '> ',
+    actions = {
+      ['default'] = function(selected)
+        local choice = selected and selected[1]
+        for idx, item in ipairs(items) do
+          if item == choice then return on_choice(item, idx) end
+        end
+        on_choice(nil, nil)
+      end,
+    },
+  })
+end)(_A[1], _A[2], _A[3])"#;
+
+const SNACKS_SOURCE: &str = r#"require('snacks').picker.select(_A[1], _A[2], _A[3])"#;
+
+const MINI_PICK_SOURCE: &str = r#"(function(items, opts, on_choice)
+  require('mini.pick').start({
+    source = {
+      items = items,
+      name = opts.prompt,
+      choose = function(choice)
+        for idx, item in ipairs(items) do
+          if item == choice then
+            vim.schedule(function() on_choice(item, idx) end)
+            return
+          end
+        end
+      end,
+    },
+  })
+end)(_A[1], _A[2], _A[3])"#;
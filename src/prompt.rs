@@ -0,0 +1,544 @@
+use crate::config::{get_config, AichatConfig, SystemPrompt};
+use crate::context::{self, Section};
+use crate::error::{AichatError, Result};
+use crate::patch;
+use crate::selection::Selection;
+use nvim_oxi::{
+    api::{
+        self,
+        opts::{OptionOpts, OptionScope::Local},
+        Buffer,
+    },
+    Array, Dictionary, Object,
+};
+use std::path::PathBuf;
+
+/// Builds the prompt of a request from its parts
+///
+/// The parts are always laid out in the same order: the system prefix, the
+/// typed instruction, the location header, the other parts in the order they
+/// were added (fenced code, fixed text), the context sections, then the
+/// system suffix.
+///
+/// A request keeps its builder, so its prompt can be built again around an
+/// edited instruction.
+#[derive(Clone)]
+pub struct PromptBuilder {
+    typed: String,
+    instruction: String,
+    location: Option<Location>,
+    header: Option<String>,
+    parts: Vec<String>,
+    /// Sections of the mentions, `None` when mentions aren't attached
+    mentioned: Option<Vec<Section>>,
+    sections: Vec<Section>,
+    system: SystemPrompt,
+}
+
+impl PromptBuilder {
+    /// Starts a prompt with the typed instruction, empty for prompts without
+    /// one, and the system prompt of the current configuration
+    pub fn new(instruction: &str) -> Self {
+        Self {
+            typed: instruction.to_string(),
+            instruction: instruction.to_string(),
+            location: None,
+            header: None,
+            parts: Vec::new(),
+            mentioned: None,
+            sections: Vec::new(),
+            system: get_config().effective_system_prompt(),
+        }
+    }
+
+    /// Takes the system prompt of `config` instead of the current configuration
+    pub fn system_prompt_of(mut self, config: &AichatConfig) -> Self {
+        self.system = config.effective_system_prompt();
+        self
+    }
+
+    /// Expands the placeholders of the instruction, and adds the location
+    /// header when `send_location` is on
+    pub fn location(mut self, location: &Location) -> Self {
+        self.instruction = location.expand(&self.typed);
+        if get_config().send_location {
+            self.header = Some(location.header());
+        }
+        self.location = Some(location.clone());
+        self
+    }
+
+    /// Adds a part after the previous ones, empty parts are left out
+    pub fn part(mut self, text: impl Into<String>) -> Self {
+        self.parts.push(text.into());
+        self
+    }
+
+    /// Adds the context of the configured providers, within what the
+    /// mentions left of `context_budget`
+    pub fn context(mut self, buffer: &Buffer, selection: &Selection) -> Self {
+        let mentioned: usize = self
+            .mentioned
+            .iter()
+            .flatten()
+            .map(|section| section.body.len())
+            .sum();
+        let budget = get_config().context_budget.saturating_sub(mentioned);
+        self.sections
+            .extend(context::gather(buffer, selection, budget));
+        self
+    }
+
+    /// Attaches the context the instruction mentions: `@file:<path>` for a
+    /// file (its buffer if it is open), `@selection` for the last visual
+    /// selection of `buffer` and `@buffers` for the open files of the working
+    /// directory
+    ///
+    /// The mentions stay in the instruction; each one is attached once. They
+    /// are cut to `context_budget` like the context of the providers, which
+    /// gets what they leave of it.
+    pub fn mentions(mut self, buffer: &Buffer) -> Result<Self> {
+        let mut mentions: Vec<Mention> = Vec::new();
+        let mut mentioned = Vec::new();
+        for mention in self
+            .instruction
+            .split_whitespace()
+            .filter_map(Mention::parse)
+        {
+            if !mentions.contains(&mention) {
+                mentions.push(mention);
+            }
+        }
+
+        let budget = get_config().context_budget;
+        for mention in mentions {
+            mentioned.extend(mention.attach(buffer, budget)?);
+        }
+        self.mentioned = Some(context::within_budget(mentioned, budget));
+        Ok(self)
+    }
+
+    /// The instruction as it is sent, with its placeholders expanded
+    pub fn instruction(&self) -> &str {
+        &self.instruction
+    }
+
+    /// The instruction as it was typed, before its placeholders were expanded
+    pub fn typed(&self) -> &str {
+        &self.typed
+    }
+
+    /// The same prompt around another typed instruction, its placeholders
+    /// expanded and its mentions attached again when the original ones were
+    pub fn edit(&self, typed: &str, buffer: &Buffer) -> Result<Self> {
+        let mut edited = self.clone();
+        edited.typed = typed.to_string();
+        edited.instruction = match &self.location {
+            Some(location) => location.expand(typed),
+            None => typed.to_string(),
+        };
+        if self.mentioned.is_some() {
+            edited.mentions(buffer)
+        } else {
+            Ok(edited)
+        }
+    }
+
+    /// Renders the prompt
+    pub fn build(&self) -> String {
+        let mut prompt = [
+            self.system.prefix.as_deref().map(str::trim),
+            Some(self.instruction.as_str()),
+            self.header.as_deref(),
+        ]
+        .into_iter()
+        .flatten()
+        .chain(self.parts.iter().map(String::as_str))
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+        for section in self.mentioned.iter().flatten().chain(&self.sections) {
+            prompt.push_str(&format!(
+                "\n\n{}:\n{}",
+                section.title,
+                fence("", section.body.trim_end())
+            ));
+        }
+
+        if let Some(suffix) = self.system.suffix.as_deref().map(str::trim) {
+            if !suffix.is_empty() {
+                prompt.push('\n');
+                prompt.push_str(suffix);
+            }
+        }
+
+        prompt
+    }
+}
+
+/// Context mentioned in a typed instruction
+#[derive(PartialEq, Eq)]
+enum Mention {
+    File(String),
+    Selection,
+    Buffers,
+}
+
+impl Mention {
+    /// Reads a mention from a word of the instruction, trailing punctuation
+    /// aside
+    fn parse(word: &str) -> Option<Self> {
+        let word = word.trim_end_matches([',', '.', ';', ':', ')', '?', '!']);
+        match word.strip_prefix('@')? {
+            "selection" => Some(Self::Selection),
+            "buffers" => Some(Self::Buffers),
+            mention => mention
+                .strip_prefix("file:")
+                .filter(|path| !path.is_empty())
+                .map(|path| Self::File(path.to_string())),
+        }
+    }
+
+    /// The sections of the mentioned context, files that aren't open read
+    /// down to their head and tail when larger than `budget`
+    fn attach(&self, buffer: &Buffer, budget: usize) -> Result<Vec<Section>> {
+        match self {
+            Self::File(path) => {
+                let full: String = api::call_function("fnamemodify", (path.as_str(), ":p"))?;
+                let full = PathBuf::from(full);
+                let open = api::list_bufs()
+                    .find(|open| open.is_loaded() && open.get_name().ok().as_ref() == Some(&full));
+                let body = match open {
+                    Some(open) => buffer_text(&open)?,
+                    None => context::read_sampled(&full, budget).map_err(|err| {
+                        AichatError::application(format!("Can't read @file:{}: {}", path, err))
+                    })?,
+                };
+                Ok(vec![Section {
+                    title: format!("File {}", path),
+                    body,
+                }])
+            }
+            Self::Selection => {
+                let (line1, _) = buffer.get_mark('<')?;
+                let (line2, _) = buffer.get_mark('>')?;
+                if line1 == 0 || line2 < line1 {
+                    return Err(AichatError::missing_value(
+                        "a visual selection in this buffer for @selection",
+                    ));
+                }
+                let selection = Selection {
+                    line1,
+                    line2,
+                    columns: None,
+                };
+                let location = Location::current(buffer, &selection);
+                Ok(vec![Section {
+                    title: format!("Selected {}", location.lines()),
+                    body: selection.read(buffer)?.join("\n"),
+                }])
+            }
+            Self::Buffers => {
+                let root: String = api::call_function("getcwd", Array::new())?;
+                patch::open_files(&PathBuf::from(root))?
+                    .into_iter()
+                    .map(|(path, buffer)| {
+                        Ok(Section {
+                            title: format!("File {}", path.display()),
+                            body: buffer_text(&buffer)?,
+                        })
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// The whole text of a buffer
+fn buffer_text(buffer: &Buffer) -> Result<String> {
+    Ok(buffer
+        .get_lines(0..buffer.line_count()?, false)?
+        .map(|line| line.to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// Markdown fence languages of the filetypes whose name isn't the one
+/// models know the language by
+const FENCE_LANGUAGES: [(&str, &str); 8] = [
+    ("typescriptreact", "tsx"),
+    ("javascriptreact", "jsx"),
+    ("sh", "bash"),
+    ("cs", "csharp"),
+    ("objc", "objective-c"),
+    ("objcpp", "objective-cpp"),
+    ("dockerfile", "docker"),
+    ("text", ""),
+];
+
+/// Wraps text in a code fence tagged with the buffer's language
+pub fn fenced(buffer: &Buffer, text: &str) -> Result<String> {
+    Ok(fence(&fence_language(buffer)?, text))
+}
+
+/// The fence language of a buffer: its `filetype`, or the one
+/// `vim.filetype.match` detects when unset, translated by `FENCE_LANGUAGES`
+///
+/// Falls back to the file extension when no filetype is known.
+pub fn fence_language(buffer: &Buffer) -> Result<String> {
+    let opts = OptionOpts::builder().scope(Local).buffer(buffer).build();
+    let mut filetype: String = api::get_option_value("filetype", &opts).unwrap_or_default();
+    if filetype.is_empty() {
+        filetype = api::call_function::<_, Option<String>>(
+            "luaeval",
+            (
+                "vim.filetype.match({ buf = _A })",
+                nvim_oxi::Object::from(buffer.handle()),
+            ),
+        )
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    }
+    if filetype.is_empty() {
+        filetype = buffer
+            .get_name()?
+            .extension()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default();
+    }
+
+    // Compound filetypes like `javascript.jsx` start with the main language
+    let filetype = filetype.split('.').next().unwrap_or_default();
+    Ok(FENCE_LANGUAGES
+        .iter()
+        .find(|(name, _)| *name == filetype)
+        .map_or(filetype, |(_, lang)| *lang)
+        .to_string())
+}
+
+/// Wraps text in a code fence tagged with `lang`, or nothing for empty text
+///
+/// The fence is longer than any run of backticks in the text, so code with
+/// fences of its own can't close it early. NUL bytes are dropped, they would
+/// end the prompt early on aichat's stdin.
+pub fn fence(lang: &str, text: &str) -> String {
+    if text.is_empty() {
+        return String::new();
+    }
+
+    let text = text.replace('\0', "");
+    let longest_run = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest_run.max(2) + 1);
+
+    format!(
+        "{}{}\n{}\n{}",
+        fence,
+        lang,
+        text.trim_end_matches('\n'),
+        fence
+    )
+}
+
+/// Where the code of a request comes from, for the `{file}`, `{line1}`,
+/// `{line2}`, `{cursor}` and `{filetype}` placeholders of a typed prompt, the
+/// variables registered in `setup()` and the `send_location` header
+#[derive(Clone)]
+pub struct Location {
+    file: String,
+    filetype: String,
+    selection: Selection,
+    cursor: usize,
+}
+
+impl Location {
+    /// The location of `selection` in `buffer`, with the current cursor line
+    pub fn current(buffer: &Buffer, selection: &Selection) -> Self {
+        let file = buffer
+            .get_name()
+            .ok()
+            .filter(|path| !path.as_os_str().is_empty())
+            .and_then(|path| {
+                api::call_function::<_, String>(
+                    "fnamemodify",
+                    (path.to_string_lossy().into_owned(), ":~:."),
+                )
+                .ok()
+            })
+            .unwrap_or_else(|| "[No Name]".to_string());
+        let opts = OptionOpts::builder().scope(Local).buffer(buffer).build();
+        let filetype = api::get_option_value("filetype", &opts).unwrap_or_default();
+        let cursor = api::get_current_win()
+            .get_cursor()
+            .map_or(selection.line1, |(line, _)| line);
+
+        Self {
+            file,
+            filetype,
+            selection: *selection,
+            cursor,
+        }
+    }
+
+    /// Replaces the placeholders in `text`, then the ones of the variables
+    /// registered in `setup()`, which are given the location as a table
+    pub fn expand(&self, text: &str) -> String {
+        let expanded = text
+            .replace("{file}", &self.file)
+            .replace("{line1}", &self.selection.line1.to_string())
+            .replace("{line2}", &self.selection.line2.to_string())
+            .replace("{cursor}", &self.cursor.to_string())
+            .replace("{filetype}", &self.filetype);
+
+        let context = Dictionary::from_iter([
+            ("file", Object::from(self.file.as_str())),
+            ("filetype", Object::from(self.filetype.as_str())),
+            ("line1", Object::from(self.selection.line1 as i64)),
+            ("line2", Object::from(self.selection.line2 as i64)),
+            ("cursor", Object::from(self.cursor as i64)),
+        ]);
+        crate::variables::expand(&expanded, &context)
+    }
+
+    /// `File: src/ui.rs, lines 120-160, cursor at 133`
+    pub fn header(&self) -> String {
+        let Selection { line1, line2, .. } = self.selection;
+        let lines = match line2.cmp(&line1) {
+            std::cmp::Ordering::Less => format!("inserting after line {}", line2),
+            std::cmp::Ordering::Equal => format!("line {}", line1),
+            std::cmp::Ordering::Greater => format!("lines {}-{}", line1, line2),
+        };
+        format!("File: {}, {}, cursor at {}", self.file, lines, self.cursor)
+    }
+
+    /// `lines 120-160 of src/ui.rs`
+    fn lines(&self) -> String {
+        let Selection { line1, line2, .. } = self.selection;
+        if line1 == line2 {
+            format!("line {} of {}", line1, self.file)
+        } else {
+            format!("lines {}-{} of {}", line1, line2, self.file)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder(instruction: &str, system: SystemPrompt) -> PromptBuilder {
+        PromptBuilder {
+            typed: instruction.to_string(),
+            instruction: instruction.to_string(),
+            location: None,
+            header: None,
+            parts: Vec::new(),
+            mentioned: None,
+            sections: Vec::new(),
+            system,
+        }
+    }
+
+    #[test]
+    fn build_lays_the_parts_out_in_order() {
+        let system = SystemPrompt {
+            prefix: Some(Box::from("  You are terse.\n")),
+            suffix: Some(Box::from("Answer with code only.")),
+        };
+        let mut builder = builder("fix it", system)
+            .part(fence("rust", "fn a() {}"))
+            .part("")
+            .part("Keep the names.");
+        builder.header = Some("File: src/a.rs, line 1, cursor at 1".into());
+        builder.sections.push(Section {
+            title: "Test file".into(),
+            body: "#[test]\nfn t() {}\n\n".into(),
+        });
+
+        assert_eq!(
+            builder.build(),
+            "You are terse.\n\
+             fix it\n\
+             File: src/a.rs, line 1, cursor at 1\n\
+             ```rust\nfn a() {}\n```\n\
+             Keep the names.\n\
+             \n\
+             Test file:\n\
+             ```\n#[test]\nfn t() {}\n```\n\
+             Answer with code only."
+        );
+    }
+
+    #[test]
+    fn build_leaves_out_an_empty_instruction() {
+        let builder = builder("", SystemPrompt::default()).part("Summarize this.");
+
+        assert_eq!(builder.build(), "Summarize this.");
+    }
+
+    #[test]
+    fn fence_outgrows_the_backtick_runs_of_the_text() {
+        assert_eq!(
+            fence("md", "````rust\nfn a() {}\n````"),
+            "`````md\n````rust\nfn a() {}\n````\n`````"
+        );
+        assert_eq!(fence("", "a `b` c"), "```\na `b` c\n```");
+    }
+
+    #[test]
+    fn fence_drops_nul_bytes_and_trailing_newlines() {
+        assert_eq!(fence("c", "a\0b\n\n"), "```c\nab\n```");
+    }
+
+    #[test]
+    fn fence_of_empty_text_is_empty() {
+        assert_eq!(fence("rust", ""), "");
+    }
+
+    #[test]
+    fn edit_only_swaps_the_typed_instruction() {
+        let system = SystemPrompt {
+            prefix: Some(Box::from("Don't fix it unless asked to fix it.")),
+            suffix: None,
+        };
+        let builder = builder("fix it", system).part(fence("", "// fix it later"));
+        let edited = builder.edit("rename it", &Buffer::from(0)).unwrap();
+
+        assert_eq!(
+            edited.build(),
+            "Don't fix it unless asked to fix it.\n\
+             rename it\n\
+             ```\n// fix it later\n```"
+        );
+        assert_eq!(edited.instruction(), "rename it");
+    }
+
+    #[test]
+    fn mention_ignores_trailing_punctuation() {
+        assert!(matches!(
+            Mention::parse("@file:src/lib.rs,"),
+            Some(Mention::File(path)) if path == "src/lib.rs"
+        ));
+        assert!(matches!(
+            Mention::parse("@file:src/lib.rs."),
+            Some(Mention::File(path)) if path == "src/lib.rs"
+        ));
+        assert!(matches!(
+            Mention::parse("@selection?"),
+            Some(Mention::Selection)
+        ));
+        assert!(matches!(
+            Mention::parse("@buffers)."),
+            Some(Mention::Buffers)
+        ));
+    }
+
+    #[test]
+    fn mention_needs_a_path_and_a_known_name() {
+        assert!(Mention::parse("@file:").is_none());
+        assert!(Mention::parse("@file:,").is_none());
+        assert!(Mention::parse("file:src/lib.rs").is_none());
+        assert!(Mention::parse("@everything").is_none());
+    }
+}
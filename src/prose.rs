@@ -0,0 +1,871 @@
+use crate::config::{self, get_config};
+use crate::error::{notify_error, Result};
+use crate::output::{self, Output};
+use crate::selection::Selection;
+use crate::toggle::{self, Automatic};
+use crate::{job_runner, prompt, ui, utils};
+use nvim_oxi::{
+    api::{
+        self,
+        opts::{CreateAugroupOpts, CreateAutocmdOpts, OptionOpts, OptionScope::Local},
+        types::CommandArgs,
+        Buffer,
+    },
+    libuv::TimerHandle,
+    Array, Dictionary, Object,
+};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+
+/// Settings of the grammar and style review of prose buffers, set with
+/// `prose = { ... }` and enabled with `features.prose`
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ProseOpts {
+    /// Filetypes whose buffers are reviewed
+    pub filetypes: Vec<String>,
+    /// When changed paragraphs are sent
+    pub trigger: Trigger,
+    /// How long the buffer must stay unchanged before an `idle` review
+    pub idle_ms: u64,
+    /// Role of `:AichatGrammar`, e.g. a terse proofreader; the current mode
+    /// is used when unset
+    pub grammar_role: Option<String>,
+}
+
+impl Default for ProseOpts {
+    fn default() -> Self {
+        Self {
+            filetypes: vec!["markdown".into(), "text".into(), "tex".into()],
+            trigger: Trigger::Save,
+            idle_ms: 3000,
+            grammar_role: None,
+        }
+    }
+}
+
+/// When a prose buffer is reviewed
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    /// After every write
+    Save,
+    /// Once the buffer was left unchanged for `idle_ms`
+    Idle,
+}
+
+/// Augroup of the review autocommands, recreated whenever they change
+const AUGROUP: &str = "aichat_nvim_prose";
+
+/// Namespace of the review diagnostics
+const NAMESPACE: &str = "aichat_nvim_prose";
+
+/// Paragraphs sent in one request, the rest waits for the next review
+const MAX_PARAGRAPHS: usize = 10;
+
+/// Instructions of a review, followed by the numbered paragraphs
+const REVIEW_INSTRUCTIONS: &str =
+    "Review the grammar, spelling and style of the numbered paragraphs below.
+Reply with one line per issue and nothing else, in this format:
+<paragraph number> | <rule> | <exact text with the issue> | <corrected text> | <short explanation>
+The rule is one of spelling, grammar, punctuation, style, wordiness, passive-voice, repetition or clarity.
+The text with the issue must be copied exactly from a single line of the paragraph.
+Reply with NONE when there are no issues.";
+
+/// Marks a comment naming the rules whose issues aren't reported in its
+/// paragraph, e.g. `<!-- aichat-ignore: passive-voice, style -->`
+const IGNORE_MARKER: &str = "aichat-ignore:";
+
+/// Instruction of `:AichatGrammar`, followed by the fenced text
+const QUICK_FIX_INSTRUCTIONS: &str = "Correct the spelling and grammar of this text. \
+    Keep its wording, style, line breaks and markup otherwise. Reply with the corrected \
+    text only, in a single code block.";
+
+/// Instruction of the fix proposed for one finding, followed by the finding
+/// and its fenced line
+const PROPOSE_FIX_INSTRUCTIONS: &str = "Fix only the issue below in this line, keeping \
+    everything else as it is. Reply with the corrected line only, in a single code block.";
+
+/// A blank-line separated block of a buffer
+struct Paragraph {
+    /// 0-based row of the first line
+    start: usize,
+    lines: Vec<String>,
+    hash: u64,
+}
+
+/// A reported issue, positioned within its paragraph so that edits
+/// elsewhere in the buffer don't invalidate it
+#[derive(Clone)]
+struct Issue {
+    paragraph: u64,
+    /// Line within the paragraph
+    line: usize,
+    /// Byte column of `original` in that line
+    col: usize,
+    /// Kind of the issue, e.g. `spelling`, named by `aichat-ignore` comments
+    rule: String,
+    original: String,
+    replacement: String,
+    message: String,
+}
+
+/// The review of one buffer
+#[derive(Default)]
+struct Review {
+    /// Paragraphs already sent, by hash
+    checked: HashSet<u64>,
+    issues: Vec<Issue>,
+    /// A review request is running
+    pending: bool,
+    /// Bumped on every change, so only the last idle timer reviews
+    generation: u64,
+}
+
+// Prose reviews, by buffer
+thread_local! {
+    static REVIEWS: RefCell<HashMap<i32, Review>> = RefCell::new(HashMap::new());
+}
+
+/// Creates the review autocommands when `features.prose` is on, removes
+/// them otherwise
+pub fn sync_autocmds() -> Result<()> {
+    let config = get_config();
+    let (enabled, trigger) = (config.features.prose, config.prose.trigger);
+    drop(config);
+
+    if !enabled {
+        let _ = api::del_augroup_by_name(AUGROUP);
+        return Ok(());
+    }
+
+    let group = api::create_augroup(AUGROUP, &CreateAugroupOpts::builder().clear(true).build())?;
+    match trigger {
+        Trigger::Save => {
+            api::create_autocmd(
+                ["BufWritePost"],
+                &CreateAutocmdOpts::builder()
+                    .group(group)
+                    .callback(
+                        |args: api::types::AutocmdCallbackArgs| -> nvim_oxi::Result<bool> {
+                            review(args.buffer);
+                            Ok(false)
+                        },
+                    )
+                    .build(),
+            )?;
+        }
+        Trigger::Idle => {
+            api::create_autocmd(
+                ["TextChanged", "InsertLeave"],
+                &CreateAutocmdOpts::builder()
+                    .group(group)
+                    .callback(
+                        |args: api::types::AutocmdCallbackArgs| -> nvim_oxi::Result<bool> {
+                            schedule_review(args.buffer);
+                            Ok(false)
+                        },
+                    )
+                    .build(),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Handles `:AichatProseCheck`, reviewing the changed paragraphs of the
+/// current buffer right away
+pub fn check_current() -> nvim_oxi::Result<()> {
+    let buffer = api::get_current_buf();
+    if let Err(err) = start_review(buffer, true) {
+        notify_error(&err);
+    }
+    Ok(())
+}
+
+/// Handles `:AichatGrammar [sentence]`, correcting the paragraph under the
+/// cursor, or only its sentence, in place
+///
+/// Sent under `prose.grammar_role` when set, and works in any buffer, with
+/// or without `features.prose`.
+pub fn quick_fix(args: CommandArgs) -> Result<()> {
+    let buffer = api::get_current_buf();
+    let selection = match args.args.as_deref().map(str::trim) {
+        Some("sentence") => Selection::sentence_at_cursor(&buffer)?,
+        None | Some("") | Some("paragraph") => Selection::paragraph_at_cursor(&buffer)?,
+        Some(unit) => {
+            utils::warn(&format!("Unknown unit {}, use sentence or paragraph", unit));
+            return Ok(());
+        }
+    };
+    let text = selection.read(&buffer)?.join("\n");
+    if text.trim().is_empty() {
+        utils::warn("No text under the cursor to correct");
+        return Ok(());
+    }
+
+    let mut config = get_config().clone();
+    config.output = Output::Replace;
+    if let Some(role) = config.prose.grammar_role.clone() {
+        config::override_section(&mut config, "role", &role)?;
+    }
+
+    let mut instructions = QUICK_FIX_INSTRUCTIONS.to_string();
+    let mut ignored: Vec<String> = ignored_rules(text.lines()).into_iter().collect();
+    if !ignored.is_empty() {
+        ignored.sort();
+        instructions.push_str(&format!(
+            " Leave alone what only these kinds of issues would change: {}.",
+            ignored.join(", ")
+        ));
+    }
+    let prompt = format!("{}\n\n{}", instructions, prompt::fenced(&buffer, &text)?);
+    Ok(crate::run_request_with(
+        buffer, selection, prompt, None, config,
+    )?)
+}
+
+/// Reviews a buffer from an autocommand, reporting errors
+fn review(buffer: Buffer) {
+    if let Err(err) = start_review(buffer, false) {
+        notify_error(&err);
+    }
+}
+
+/// Reviews the buffer once it stays unchanged for `idle_ms`
+fn schedule_review(buffer: Buffer) {
+    if !is_prose(&buffer) {
+        return;
+    }
+    let generation = REVIEWS.with(|reviews| {
+        let mut reviews = reviews.borrow_mut();
+        let review = reviews.entry(buffer.handle()).or_default();
+        review.generation += 1;
+        review.generation
+    });
+
+    let idle_ms = get_config().prose.idle_ms;
+    let _ = TimerHandle::once(Duration::from_millis(idle_ms), move || {
+        // Timer callbacks run in a fast event, so defer to a safe point
+        nvim_oxi::schedule(move |_| -> nvim_oxi::Result<()> {
+            let current = REVIEWS.with(|reviews| {
+                reviews
+                    .borrow()
+                    .get(&buffer.handle())
+                    .map(|review| review.generation)
+            });
+            if current == Some(generation) && buffer.is_valid() {
+                review(buffer);
+            }
+            Ok(())
+        });
+    });
+}
+
+/// Whether the buffer has one of the reviewed filetypes
+fn is_prose(buffer: &Buffer) -> bool {
+    let opts = OptionOpts::builder().scope(Local).buffer(buffer).build();
+    let filetype: String = api::get_option_value("filetype", &opts).unwrap_or_default();
+    get_config().prose.filetypes.contains(&filetype)
+}
+
+/// Sends the paragraphs of the buffer that weren't reviewed yet
+///
+/// The issues of paragraphs that changed since their review are dropped
+/// first. `manual` reviews also run while `:AichatToggle` paused the feature
+/// and report when there is nothing to send.
+fn start_review(buffer: Buffer, manual: bool) -> Result<()> {
+    if !manual && (!toggle::is_active(Automatic::Prose) || !is_prose(&buffer)) {
+        return Ok(());
+    }
+
+    let paragraphs = paragraphs(&buffer)?;
+    let sent: Vec<&Paragraph> = REVIEWS.with(|reviews| {
+        let mut reviews = reviews.borrow_mut();
+        let review = reviews.entry(buffer.handle()).or_default();
+        let current: HashSet<u64> = paragraphs.iter().map(|p| p.hash).collect();
+        review.checked.retain(|hash| current.contains(hash));
+        review
+            .issues
+            .retain(|issue| current.contains(&issue.paragraph));
+
+        if review.pending {
+            return Vec::new();
+        }
+        let sent: Vec<&Paragraph> = paragraphs
+            .iter()
+            .filter(|paragraph| !review.checked.contains(&paragraph.hash))
+            .take(MAX_PARAGRAPHS)
+            .collect();
+        review
+            .checked
+            .extend(sent.iter().map(|paragraph| paragraph.hash));
+        review.pending = !sent.is_empty();
+        sent
+    });
+    publish(&buffer, &paragraphs)?;
+
+    if sent.is_empty() {
+        if manual {
+            utils::info("No changed paragraphs to review");
+        }
+        return Ok(());
+    }
+
+    let mut prompt = REVIEW_INSTRUCTIONS.to_string();
+    for (number, paragraph) in sent.iter().enumerate() {
+        prompt.push_str(&format!(
+            "\n\n[{}]\n{}",
+            number + 1,
+            paragraph.lines.join("\n")
+        ));
+    }
+    let sent: Vec<(u64, Vec<String>)> = sent
+        .into_iter()
+        .map(|paragraph| (paragraph.hash, paragraph.lines.clone()))
+        .collect();
+    let config = get_config().clone();
+
+    job_runner::run_in_background(
+        move || job_runner::run_aichat_response(&config, &prompt),
+        move |result| {
+            let handle = buffer.handle();
+            let issues = result.map(|response| parse_issues(&response, &sent));
+            REVIEWS.with(|reviews| {
+                if let Some(review) = reviews.borrow_mut().get_mut(&handle) {
+                    review.pending = false;
+                    match &issues {
+                        Ok(issues) => review.issues.extend(issues.iter().cloned()),
+                        // Send the paragraphs again with the next review
+                        Err(_) => {
+                            for (hash, _) in &sent {
+                                review.checked.remove(hash);
+                            }
+                        }
+                    }
+                }
+            });
+
+            let result = issues.and_then(|_| {
+                if !buffer.is_valid() {
+                    return Ok(());
+                }
+                publish(&buffer, &paragraphs_or_empty(&buffer))
+            });
+            if let Err(err) = result {
+                notify_error(&err);
+            }
+        },
+    )
+}
+
+/// Splits the buffer into blank-line separated paragraphs
+fn paragraphs(buffer: &Buffer) -> Result<Vec<Paragraph>> {
+    let lines: Vec<String> = buffer
+        .get_lines(0..buffer.line_count()?, false)?
+        .map(|line| line.to_string_lossy().into_owned())
+        .collect();
+
+    let mut paragraphs = Vec::new();
+    let mut start = 0;
+    for (row, line) in lines.iter().chain([&String::new()]).enumerate() {
+        if !line.trim().is_empty() {
+            continue;
+        }
+        if row > start {
+            let block = lines[start..row].to_vec();
+            let mut hasher = DefaultHasher::new();
+            block.hash(&mut hasher);
+            paragraphs.push(Paragraph {
+                start,
+                lines: block,
+                hash: hasher.finish(),
+            });
+        }
+        start = row + 1;
+    }
+
+    Ok(paragraphs)
+}
+
+/// Like `paragraphs`, treating an unreadable buffer as empty
+fn paragraphs_or_empty(buffer: &Buffer) -> Vec<Paragraph> {
+    paragraphs(buffer).unwrap_or_default()
+}
+
+/// Reads the `number | rule | original | replacement | explanation` lines
+/// of a review, keeping the issues whose text is found in its paragraph and
+/// whose rule the paragraph doesn't ignore
+fn parse_issues(response: &str, sent: &[(u64, Vec<String>)]) -> Vec<Issue> {
+    response
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(5, '|').map(str::trim);
+            let number = parts
+                .next()?
+                .trim_matches(|c| c == '[' || c == ']')
+                .parse::<usize>()
+                .ok()?;
+            let rule = parts.next()?.to_lowercase();
+            let original = parts.next()?.trim_matches('"');
+            let replacement = parts.next()?.trim_matches('"');
+            let message = parts.next().unwrap_or_default();
+            let (hash, lines) = sent.get(number.checked_sub(1)?)?;
+
+            if original.is_empty()
+                || original == replacement
+                || ignored_rules(lines.iter().map(String::as_str)).contains(&rule)
+            {
+                return None;
+            }
+            let (line, col) = lines
+                .iter()
+                .enumerate()
+                .find_map(|(line, text)| text.find(original).map(|col| (line, col)))?;
+
+            Some(Issue {
+                paragraph: *hash,
+                line,
+                col,
+                rule,
+                original: original.to_string(),
+                replacement: replacement.to_string(),
+                message: message.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// The rules named by the `aichat-ignore` comments among `lines`, in
+/// lowercase
+fn ignored_rules<'a>(lines: impl IntoIterator<Item = &'a str>) -> HashSet<String> {
+    lines
+        .into_iter()
+        .filter_map(|line| Some(line.split_once(IGNORE_MARKER)?.1))
+        .flat_map(|rules| {
+            rules
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|rule| !rule.is_empty())
+                // Up to whatever closes the comment, e.g. `-->` or `*/`
+                .take_while(|rule| {
+                    rule.chars()
+                        .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+                })
+                .map(str::to_lowercase)
+        })
+        .collect()
+}
+
+/// The current 0-based row of an issue, `None` once its paragraph changed
+fn issue_row(issue: &Issue, paragraphs: &[Paragraph]) -> Option<usize> {
+    paragraphs
+        .iter()
+        .find(|paragraph| paragraph.hash == issue.paragraph)
+        .map(|paragraph| paragraph.start + issue.line)
+}
+
+/// Shows the issues of the buffer as diagnostics
+fn publish(buffer: &Buffer, paragraphs: &[Paragraph]) -> Result<()> {
+    let issues = REVIEWS.with(|reviews| {
+        reviews
+            .borrow()
+            .get(&buffer.handle())
+            .map(|review| review.issues.clone())
+            .unwrap_or_default()
+    });
+
+    let items: Array = issues
+        .iter()
+        .filter_map(|issue| {
+            let row = issue_row(issue, paragraphs)?;
+            Some(Object::from(Dictionary::from_iter([
+                ("lnum", Object::from(row as i64)),
+                ("col", Object::from(issue.col as i64)),
+                (
+                    "end_col",
+                    Object::from((issue.col + issue.original.len()) as i64),
+                ),
+                (
+                    "message",
+                    Object::from(format!("{} (→ {})", issue.message, issue.replacement)),
+                ),
+                ("code", Object::from(issue.rule.as_str())),
+            ])))
+        })
+        .collect();
+
+    let args = Dictionary::from_iter([
+        ("ns", Object::from(api::create_namespace(NAMESPACE) as i64)),
+        ("buf", Object::from(buffer.handle() as i64)),
+        ("items", Object::from(items)),
+    ]);
+    api::call_function::<_, Object>("luaeval", (PUBLISH_SOURCE, Object::from(args)))?;
+    Ok(())
+}
+
+/// Sets the diagnostics `_A.items` of buffer `_A.buf` in namespace `_A.ns`
+const PUBLISH_SOURCE: &str = r#"(function(args)
+  for _, item in ipairs(args.items) do
+    item.severity = vim.diagnostic.severity.INFO
+    item.source = 'aichat'
+  end
+  vim.diagnostic.set(args.ns, args.buf, args.items)
+end)(_A)"#;
+
+/// Handles `:AichatProseFix`
+///
+/// Lists the suggested fixes of the cursor line, or of the whole buffer when
+/// the line has none, and applies the chosen one.
+pub fn pick_fix() -> nvim_oxi::Result<()> {
+    let buffer = api::get_current_buf();
+    let (line, _) = api::get_current_win().get_cursor()?;
+    let issues = located_issues(&buffer)?;
+    let on_line: Vec<(usize, Issue)> = issues
+        .iter()
+        .filter(|(row, _)| *row == line - 1)
+        .cloned()
+        .collect();
+    let mut issues = if on_line.is_empty() { issues } else { on_line };
+    issues.sort_by_key(|(row, issue)| (*row, issue.col));
+
+    if issues.is_empty() {
+        utils::info("No Aichat prose suggestions in this buffer");
+        return Ok(());
+    }
+
+    let items: Vec<String> = issues
+        .iter()
+        .map(|(row, issue)| {
+            format!(
+                "{}: {} → {}  ({})",
+                row + 1,
+                issue.original,
+                issue.replacement,
+                issue.message
+            )
+        })
+        .collect();
+    let opts = ui::SelectOpts::with_prompt("Apply Aichat suggestion");
+
+    ui::vim_ui_select(items, Some(opts), move |_, index| {
+        // The index is 1-based
+        let Some((_, issue)) = index.and_then(|index| issues.get(index.checked_sub(1)?)) else {
+            return;
+        };
+        if let Err(err) = apply_fix(&buffer, issue) {
+            notify_error(&err);
+        }
+    })
+}
+
+/// The issues of a buffer with their current 0-based row, leaving out the
+/// ones whose paragraph changed
+fn located_issues(buffer: &Buffer) -> Result<Vec<(usize, Issue)>> {
+    let paragraphs = paragraphs(buffer)?;
+    Ok(REVIEWS.with(|reviews| {
+        reviews
+            .borrow()
+            .get(&buffer.handle())
+            .map(|review| {
+                review
+                    .issues
+                    .iter()
+                    .filter_map(|issue| Some((issue_row(issue, &paragraphs)?, issue.clone())))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }))
+}
+
+/// Handles `:AichatProseExplain`
+///
+/// Shows the finding under the cursor, or the first one of the cursor line,
+/// in a float with its explanation; the accept keys send a request fixing
+/// that finding alone.
+pub fn explain() -> nvim_oxi::Result<()> {
+    let buffer = api::get_current_buf();
+    let Some((row, issue)) = finding_at_cursor(&buffer)? else {
+        utils::info("No Aichat prose finding on this line");
+        return Ok(());
+    };
+
+    let keys = get_config().keys.clone();
+    let mut lines = vec![
+        format!(
+            "Line {}: {} → {}",
+            row + 1,
+            issue.original,
+            issue.replacement
+        ),
+        String::new(),
+    ];
+    lines.extend(issue.message.lines().map(String::from));
+    lines.push(String::new());
+    lines.push(format!("Rule: {}", issue.rule));
+    lines.push(String::new());
+    lines.push(format!(
+        "{} propose a fix  {} ignore {} in this paragraph  {} close",
+        ui::Keys::hint(&keys.accept),
+        ui::Keys::hint(&keys.reject),
+        issue.rule,
+        ui::Keys::hint(&keys.cancel)
+    ));
+    let (mut float, window) = ui::open_float("Aichat Prose", lines)?;
+
+    let (fix_buffer, fix_issue, fix_window) = (buffer.clone(), issue.clone(), window.clone());
+    ui::set_keymaps(
+        &mut float,
+        &keys.accept,
+        "Propose a fix for this finding",
+        move || {
+            ui::close(&fix_window);
+            if let Err(err) = propose_fix(&fix_buffer, &fix_issue) {
+                notify_error(&err);
+            }
+        },
+    )?;
+    ui::set_keymaps(
+        &mut float,
+        &keys.reject,
+        "Ignore the rule of this finding in its paragraph",
+        move || {
+            ui::close(&window);
+            if let Err(err) = ignore_rule(&buffer, &issue) {
+                notify_error(&err);
+            }
+        },
+    )?;
+    Ok(())
+}
+
+/// Handles `:AichatProseIgnore`, adding an `aichat-ignore` comment for the
+/// rule of the finding under the cursor to its paragraph
+pub fn ignore() -> nvim_oxi::Result<()> {
+    let buffer = api::get_current_buf();
+    let Some((_, issue)) = finding_at_cursor(&buffer)? else {
+        utils::info("No Aichat prose finding on this line");
+        return Ok(());
+    };
+    Ok(ignore_rule(&buffer, &issue)?)
+}
+
+/// The finding under the cursor, or the first one of the cursor line, with
+/// its 0-based row
+fn finding_at_cursor(buffer: &Buffer) -> Result<Option<(usize, Issue)>> {
+    let (line, col) = api::get_current_win().get_cursor()?;
+    let mut on_line: Vec<(usize, Issue)> = located_issues(buffer)?
+        .into_iter()
+        .filter(|(row, _)| *row == line - 1)
+        .collect();
+    on_line.sort_by_key(|(_, issue)| issue.col);
+    let under_cursor = on_line
+        .iter()
+        .position(|(_, issue)| (issue.col..issue.col + issue.original.len()).contains(&col));
+    Ok(on_line.into_iter().nth(under_cursor.unwrap_or(0)))
+}
+
+/// Puts an `aichat-ignore` comment for the rule of an issue at the top of
+/// its paragraph
+///
+/// The issues of that rule in the paragraph go away, the others move to its
+/// new text, so they stay listed without sending the paragraph again.
+fn ignore_rule(buffer: &Buffer, issue: &Issue) -> Result<()> {
+    let Some(start) = paragraphs(buffer)?
+        .into_iter()
+        .find(|paragraph| paragraph.hash == issue.paragraph)
+        .map(|paragraph| paragraph.start)
+    else {
+        utils::warn("The paragraph of this finding changed since its review");
+        return Ok(());
+    };
+
+    let comment = format!("{} {}", IGNORE_MARKER, issue.rule);
+    let annotation = output::comment_lines(buffer, vec![comment])?;
+    buffer.clone().set_lines(start..start, true, annotation)?;
+
+    // Follow the paragraph to its new text
+    let new_hash = paragraphs_or_empty(buffer)
+        .into_iter()
+        .find(|paragraph| paragraph.start == start)
+        .map(|paragraph| paragraph.hash);
+
+    REVIEWS.with(|reviews| {
+        let mut reviews = reviews.borrow_mut();
+        let Some(review) = reviews.get_mut(&buffer.handle()) else {
+            return;
+        };
+        review
+            .issues
+            .retain(|other| !(other.paragraph == issue.paragraph && other.rule == issue.rule));
+        let Some(new_hash) = new_hash else {
+            return;
+        };
+        for other in review
+            .issues
+            .iter_mut()
+            .filter(|other| other.paragraph == issue.paragraph)
+        {
+            other.paragraph = new_hash;
+            other.line += 1;
+        }
+        review.checked.insert(new_hash);
+    });
+
+    publish(buffer, &paragraphs_or_empty(buffer))
+}
+
+/// Sends the line of an issue with only that issue to fix, replacing the
+/// line with the answer
+///
+/// Sent under `prose.grammar_role` like `:AichatGrammar`. The paragraph
+/// changes with the fix, so it is reviewed again afterwards.
+fn propose_fix(buffer: &Buffer, issue: &Issue) -> Result<()> {
+    let Some(row) = issue_row(issue, &paragraphs(buffer)?) else {
+        utils::warn("The paragraph of this finding changed since its review");
+        return Ok(());
+    };
+    let selection = Selection {
+        line1: row + 1,
+        line2: row + 1,
+        columns: None,
+    };
+    let text = selection.read(buffer)?.join("\n");
+
+    let mut config = get_config().clone();
+    config.output = Output::Replace;
+    if let Some(role) = config.prose.grammar_role.clone() {
+        config::override_section(&mut config, "role", &role)?;
+    }
+
+    let prompt = format!(
+        "{}\n\nIssue: \"{}\" should be \"{}\": {}\n\n{}",
+        PROPOSE_FIX_INSTRUCTIONS,
+        issue.original,
+        issue.replacement,
+        issue.message,
+        prompt::fenced(buffer, &text)?
+    );
+    crate::run_request_with(buffer.clone(), selection, prompt, None, config)?;
+    Ok(())
+}
+
+/// Replaces the text of an issue with its correction
+///
+/// The other issues of the paragraph move to its new text, so they stay
+/// listed without sending the paragraph again.
+fn apply_fix(buffer: &Buffer, issue: &Issue) -> Result<()> {
+    let paragraphs = paragraphs(buffer)?;
+    let Some(row) = issue_row(issue, &paragraphs) else {
+        utils::warn("The paragraph of this suggestion changed since its review");
+        return Ok(());
+    };
+
+    let text = buffer
+        .get_lines(row..row + 1, true)?
+        .next()
+        .map(|line| line.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let Some(col) = text
+        .get(issue.col..)
+        .filter(|rest| rest.starts_with(&issue.original))
+        .map(|_| issue.col)
+        .or_else(|| text.find(&issue.original))
+    else {
+        utils::warn("The text of this suggestion is no longer there");
+        return Ok(());
+    };
+
+    let fixed = format!(
+        "{}{}{}",
+        &text[..col],
+        issue.replacement,
+        &text[col + issue.original.len()..]
+    );
+    buffer.clone().set_lines(row..row + 1, true, [fixed])?;
+
+    // Follow the paragraph to its new text
+    let new_hash = paragraphs_or_empty(buffer)
+        .into_iter()
+        .find(|paragraph| paragraph.start <= row && row < paragraph.start + paragraph.lines.len())
+        .map(|paragraph| paragraph.hash);
+    let shift = issue.replacement.len() as isize - issue.original.len() as isize;
+
+    REVIEWS.with(|reviews| {
+        let mut reviews = reviews.borrow_mut();
+        let Some(review) = reviews.get_mut(&buffer.handle()) else {
+            return;
+        };
+        review.issues.retain(|other| {
+            !(other.paragraph == issue.paragraph
+                && other.line == issue.line
+                && other.col == issue.col
+                && other.original == issue.original)
+        });
+        let Some(new_hash) = new_hash else {
+            return;
+        };
+        for other in review
+            .issues
+            .iter_mut()
+            .filter(|other| other.paragraph == issue.paragraph)
+        {
+            other.paragraph = new_hash;
+            if other.line == issue.line && other.col > col {
+                other.col = (other.col as isize + shift).max(0) as usize;
+            }
+        }
+        review.checked.insert(new_hash);
+    });
+
+    publish(buffer, &paragraphs_or_empty(buffer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignore_comments_name_rules_up_to_their_end() {
+        let lines = [
+            "<!-- aichat-ignore: passive-voice, Style -->",
+            "% aichat-ignore: wordiness",
+            "No annotation here, style",
+        ];
+
+        let mut rules: Vec<String> = ignored_rules(lines).into_iter().collect();
+        rules.sort();
+
+        assert_eq!(rules, ["passive-voice", "style", "wordiness"]);
+    }
+
+    #[test]
+    fn review_leaves_out_the_ignored_rules_of_a_paragraph() {
+        let sent = [
+            (
+                1,
+                vec![
+                    "<!-- aichat-ignore: style -->".to_string(),
+                    "Their is a typo and it was decided to go.".to_string(),
+                ],
+            ),
+            (2, vec!["It was decided to go.".to_string()]),
+        ];
+        let response = "1 | spelling | Their is | There is | Wrong word\n\
+            1 | Style | it was decided | we decided | Passive voice\n\
+            2 | style | It was decided | We decided | Passive voice\n\
+            3 | spelling | typo | type | No such paragraph";
+
+        let issues = parse_issues(response, &sent);
+
+        let found: Vec<(u64, &str, usize, usize)> = issues
+            .iter()
+            .map(|issue| (issue.paragraph, issue.rule.as_str(), issue.line, issue.col))
+            .collect();
+        assert_eq!(found, [(1, "spelling", 1, 0), (2, "style", 0, 0)]);
+    }
+}
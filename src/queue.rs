@@ -0,0 +1,234 @@
+use crate::error::notify_error;
+use crate::job_runner::CancelToken;
+use crate::{config, job_runner, ui, utils};
+use nvim_oxi::{api, Array, Function, Object};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::Ordering;
+
+/// What becomes of a submitted request
+pub enum Slot {
+    /// A slot is free, the request starts
+    Free,
+    /// The request was dropped from the queue (`:AichatAbort`, `:AichatQueue
+    /// clear`, a cancel) and only releases what it holds, like its anchor
+    Dropped,
+}
+
+/// A request waiting for a free slot
+struct Queued {
+    key: u64,
+    description: String,
+    start: Box<dyn FnOnce(Slot) -> nvim_oxi::Result<()>>,
+}
+
+/// Requests that are running and the ones waiting for their turn
+struct Queue {
+    running: usize,
+    waiting: VecDeque<Queued>,
+    /// Cancellation of the running requests, by request key
+    cancels: HashMap<u64, CancelToken>,
+}
+
+thread_local! {
+    static QUEUE: RefCell<Queue> = RefCell::new(Queue {
+        running: 0,
+        waiting: VecDeque::new(),
+        cancels: HashMap::new(),
+    });
+}
+
+/// Starts a request right away if fewer than `max_concurrent_requests` are
+/// running, otherwise queues it until one finishes
+///
+/// Every started request must call `finished` once it is done. A request
+/// that fails to start is unregistered and its slot freed here.
+pub fn submit<F>(key: u64, description: String, start: F) -> nvim_oxi::Result<()>
+where
+    F: FnOnce(Slot) -> nvim_oxi::Result<()> + 'static,
+{
+    let limit = config::get_config().max_concurrent_requests;
+    let start_now = QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        let free = limit == 0 || queue.running < limit;
+        if free {
+            queue.running += 1;
+        }
+        free
+    });
+
+    if start_now {
+        return start(Slot::Free).inspect_err(|_| failed(key));
+    }
+
+    let waiting = QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        queue.waiting.push_back(Queued {
+            key,
+            description,
+            start: Box::new(start),
+        });
+        queue.waiting.len()
+    });
+    utils::info(&format!("Aichat request queued ({} waiting)", waiting));
+
+    Ok(())
+}
+
+/// Frees the slot of a finished request and starts the next queued one
+pub fn finished() {
+    let next = QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        queue.running = queue.running.saturating_sub(1);
+        let next = queue.waiting.pop_front();
+        if next.is_some() {
+            queue.running += 1;
+        }
+        next
+    });
+
+    if let Some(next) = next {
+        if let Err(err) = (next.start)(Slot::Free) {
+            notify_error(&err.into());
+            failed(next.key);
+        }
+    }
+}
+
+/// Releases a request that failed to start: its key, its cancellation and
+/// its slot
+fn failed(key: u64) {
+    job_runner::unregister(key);
+    untrack(key);
+    finished();
+}
+
+/// Tells requests dropped from the queue, which never got a slot
+fn drop_queued(dropped: impl IntoIterator<Item = Queued>) {
+    for queued in dropped {
+        job_runner::unregister(queued.key);
+        if let Err(err) = (queued.start)(Slot::Dropped) {
+            notify_error(&err.into());
+        }
+    }
+}
+
+/// Remembers how to cancel a started request, until `untrack` is called
+pub fn track(key: u64, cancel: CancelToken) {
+    QUEUE.with(|queue| queue.borrow_mut().cancels.insert(key, cancel));
+}
+
+/// Forgets the cancellation of a finished request
+pub fn untrack(key: u64) {
+    QUEUE.with(|queue| queue.borrow_mut().cancels.remove(&key));
+}
+
+/// Cancels one request: kills its aichat process if it is running, or drops
+/// it from the queue
+pub fn cancel(key: u64) {
+    let (cancel, queued) = QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        let queued = queue
+            .waiting
+            .iter()
+            .position(|queued| queued.key == key)
+            .and_then(|index| queue.waiting.remove(index));
+        (queue.cancels.remove(&key), queued)
+    });
+
+    if let Some(cancel) = &cancel {
+        cancel.store(true, Ordering::Relaxed);
+    }
+    if cancel.is_some() {
+        job_runner::unregister(key);
+    }
+    drop_queued(queued);
+}
+
+/// Handles `:AichatAbort`
+///
+/// Kills the aichat process of every running request and drops the queued ones
+pub fn abort() -> nvim_oxi::Result<()> {
+    let (cancels, dropped) = QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        (
+            std::mem::take(&mut queue.cancels),
+            std::mem::take(&mut queue.waiting),
+        )
+    });
+
+    for (key, cancel) in &cancels {
+        cancel.store(true, Ordering::Relaxed);
+        job_runner::unregister(*key);
+    }
+    let queued = dropped.len();
+    drop_queued(dropped);
+
+    if cancels.is_empty() && queued == 0 {
+        utils::info("No Aichat request to abort");
+    } else {
+        utils::info(&format!(
+            "Aborted {} running and {} queued Aichat requests",
+            cancels.len(),
+            queued
+        ));
+    }
+    Ok(())
+}
+
+/// Blocks until no request is running or queued, for scripts and headless
+/// instances; the event loop keeps running meanwhile so answers are applied
+///
+/// Returns whether every request finished within `timeout_ms` (default 60s).
+///
+/// Exposed to Lua as `require("aichat_nvim").wait(timeout_ms)`
+pub fn wait(timeout_ms: Option<u64>) -> nvim_oxi::Result<bool> {
+    let idle = Function::<(), bool>::from_fn(|()| {
+        job_runner::in_flight_count() == 0 && waiting_count() == 0
+    });
+    let args = Array::from_iter([
+        Object::from(timeout_ms.unwrap_or(60_000) as i64),
+        Object::from(idle),
+    ]);
+    Ok(api::call_function(
+        "luaeval",
+        ("vim.wait(_A[1], _A[2], 50)", args),
+    )?)
+}
+
+/// Number of requests waiting for a free slot
+pub fn waiting_count() -> usize {
+    QUEUE.with(|queue| queue.borrow().waiting.len())
+}
+
+/// Handles `:AichatQueue [clear]`
+///
+/// Shows the running and queued requests, or drops the queued ones with `clear`
+pub fn show_queue(arg: Option<&str>) -> nvim_oxi::Result<()> {
+    match arg {
+        Some("clear") => {
+            let dropped = QUEUE.with(|queue| std::mem::take(&mut queue.borrow_mut().waiting));
+            let count = dropped.len();
+            drop_queued(dropped);
+            utils::info(&format!("Dropped {} queued Aichat requests", count));
+            Ok(())
+        }
+        Some(other) => {
+            utils::warn(&format!("Unknown :AichatQueue argument: {}", other));
+            Ok(())
+        }
+        None => {
+            let lines = QUEUE.with(|queue| {
+                let queue = queue.borrow();
+                let mut lines = vec![format!("Running: {}", queue.running)];
+                lines.push(format!("Queued: {}", queue.waiting.len()));
+                for (index, queued) in queue.waiting.iter().enumerate() {
+                    lines.push(format!("  {}. {}", index + 1, queued.description));
+                }
+                lines
+            });
+            ui::open_float("Aichat Queue", lines)?;
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use crate::config::{get_config, Mode};
+use crate::error::AichatError;
+use crate::selection::Selection;
+use nvim_oxi::{
+    api::{self, types::CommandArgs, Buffer, Window},
+    Array, Dictionary, Object,
+};
+use std::cell::RefCell;
+
+/// The terminal buffer running the aichat REPL and its job id
+struct Repl {
+    buffer: Buffer,
+    job: i64,
+}
+
+thread_local! {
+    static REPL: RefCell<Option<Repl>> = const { RefCell::new(None) };
+}
+
+/// Handles `:[range]AichatRepl`
+///
+/// Hides the REPL window when it is visible, otherwise shows it, starting
+/// aichat with the configured role/agent, session and RAG if it isn't
+/// running. With a range the selected code is typed into the REPL as the
+/// start of a `:::` multi-line message, to be finished and closed with `:::`.
+pub fn toggle(args: CommandArgs) -> nvim_oxi::Result<()> {
+    let seed = if args.range > 0 {
+        let buffer = api::get_current_buf();
+        let selection = Selection::from_command(&args, &buffer)?;
+        Some(selection.read(&buffer)?.join("\n"))
+    } else {
+        None
+    };
+
+    let running = REPL.with(|repl| {
+        repl.borrow()
+            .as_ref()
+            .filter(|repl| repl.buffer.is_valid())
+            .map(|repl| (repl.buffer.clone(), repl.job))
+    });
+
+    let job = match running {
+        Some((buffer, job)) => {
+            let window: i64 = api::call_function("bufwinid", (buffer.handle(),))?;
+            if window != -1 && seed.is_none() {
+                Window::from(window as i32).close(false)?;
+                return Ok(());
+            }
+            if window == -1 {
+                api::command("botright vsplit")?;
+                api::get_current_win().set_buf(&buffer)?;
+            }
+            job
+        }
+        None => start()?,
+    };
+
+    if let Some(seed) = seed {
+        let _: i64 = api::call_function("chansend", (job, format!(":::\n{}\n", seed)))?;
+    }
+
+    api::command("startinsert")?;
+    Ok(())
+}
+
+/// Opens a terminal split running the aichat REPL and remembers it
+fn start() -> nvim_oxi::Result<i64> {
+    let config = get_config().clone();
+
+    // `--macro` without text would run the macro instead of starting the REPL
+    let mut args = config.args();
+    if let Mode::Macro = config.mode_flag {
+        args.drain(..2);
+    }
+
+    // `jobstart` would only return -1, after the split was opened
+    let executable: i64 = api::call_function("executable", (&*config.aichat_path,))?;
+    if executable != 1 {
+        return Err(AichatError::binary_not_found(&*config.aichat_path).into());
+    }
+
+    let cmd = Array::from_iter(std::iter::once(config.aichat_path.to_string()).chain(args));
+    let env = Dictionary::from_iter(config.envs());
+    let opts = Dictionary::from_iter([("term", Object::from(true)), ("env", Object::from(env))]);
+
+    api::command("botright vsplit | enew")?;
+    let job: i64 = api::call_function("jobstart", (cmd, opts))?;
+
+    REPL.with(|repl| {
+        *repl.borrow_mut() = Some(Repl {
+            buffer: api::get_current_buf(),
+            job,
+        })
+    });
+
+    Ok(job)
+}
@@ -0,0 +1,197 @@
+use crate::error::{notify_error, AichatError, Result};
+use crate::ui::Keys;
+use crate::{config, job_runner, ui, utils};
+use nvim_oxi::{api, Array};
+use std::cell::RefCell;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+/// Line that introduces each file of the manifest in the response
+const FILE_MARKER: &str = "FILE:";
+
+/// A file proposed by aichat, with a path relative to the working directory
+struct ScaffoldFile {
+    path: PathBuf,
+    contents: String,
+}
+
+/// Handles `:AichatScaffold {description}`
+///
+/// The description is prompted for when not given. The proposed files are
+/// shown for confirmation before anything is written.
+pub fn aichat_scaffold(description: Option<String>) -> nvim_oxi::Result<()> {
+    let description = match description {
+        Some(description) => description,
+        None => {
+            ui::input("Aichat Scaffold >", "", |description| match description {
+                Some(description) => Ok(aichat_scaffold(Some(description))?),
+                None => Ok(()),
+            });
+            return Ok(());
+        }
+    };
+
+    let root: String = api::call_function("getcwd", Array::new())?;
+    let config = config::get_config().clone();
+    let prompt = format!(
+        "Create the files of this project: {}\n\
+         Reply with every file as a line `{} relative/path` followed by a code block \
+         with the complete contents of the file. Do not add anything else.",
+        description, FILE_MARKER
+    );
+
+    utils::info("Generating project files");
+
+    job_runner::run_in_background(
+        move || {
+            let response = job_runner::run_aichat_response(&config, &prompt)?;
+            parse_manifest(&response)
+        },
+        move |result| {
+            if let Err(err) = result.and_then(|files| show_confirmation(root.into(), files)) {
+                notify_error(&err);
+            }
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Parses `FILE: path` lines each followed by a code block into files
+///
+/// Paths that would escape the working directory are rejected
+fn parse_manifest(response: &str) -> Result<Vec<ScaffoldFile>> {
+    let mut files = Vec::new();
+    let mut path: Option<PathBuf> = None;
+    let mut contents: Option<String> = None;
+
+    for line in response.lines() {
+        let trimmed = line.trim();
+
+        if let Some(body) = contents.as_mut() {
+            if trimmed.starts_with("```") {
+                // End of the file's code block
+                if let (Some(path), Some(contents)) = (path.take(), contents.take()) {
+                    files.push(ScaffoldFile { path, contents });
+                }
+            } else {
+                body.push_str(line);
+                body.push('\n');
+            }
+        } else if let Some(rest) = trimmed.strip_prefix(FILE_MARKER) {
+            let rest = rest.trim().trim_matches('`');
+            path = Some(utils::relative_path(rest)?);
+        } else if trimmed.starts_with("```") && path.is_some() {
+            // Start of the file's code block, the language tag is skipped
+            contents = Some(String::new());
+        }
+    }
+
+    if files.is_empty() {
+        return Err(AichatError::application(
+            "aichat did not propose any files for the project",
+        ));
+    }
+
+    Ok(files)
+}
+
+/// Renders the proposed paths as an indented tree, marking files that exist
+fn render_tree(root: &Path, files: &[ScaffoldFile]) -> Vec<String> {
+    let mut paths: Vec<&Path> = files.iter().map(|file| file.path.as_path()).collect();
+    paths.sort();
+
+    let mut lines = Vec::new();
+    let mut previous: Vec<String> = Vec::new();
+    for path in paths {
+        let components: Vec<String> = path
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .map(|component| component.as_os_str().to_string_lossy().into_owned())
+            .collect();
+
+        // Directories already printed for the previous path are not repeated
+        let shared = previous
+            .iter()
+            .zip(&components)
+            .take(components.len().saturating_sub(1))
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        for (depth, name) in components.iter().enumerate().skip(shared) {
+            let indent = "  ".repeat(depth + 1);
+            if depth + 1 < components.len() {
+                lines.push(format!("{}{}/", indent, name));
+            } else if root.join(path).exists() {
+                lines.push(format!("{}{} (exists, skipped)", indent, name));
+            } else {
+                lines.push(format!("{}{}", indent, name));
+            }
+        }
+        previous = components;
+    }
+
+    lines
+}
+
+/// Shows the proposed tree in a float and creates the files on the accept keys
+fn show_confirmation(root: PathBuf, files: Vec<ScaffoldFile>) -> Result<()> {
+    let keys = config::get_config().keys.clone();
+    let mut lines = vec![
+        format!("Files to create in {}:", root.display()),
+        String::new(),
+    ];
+    lines.extend(render_tree(&root, &files));
+    lines.push(String::new());
+    lines.push(format!(
+        "{} create files  {} cancel",
+        Keys::hint(&keys.accept),
+        Keys::hint(&keys.cancel)
+    ));
+
+    let (mut buffer, window) = ui::open_float("Aichat Scaffold", lines)?;
+
+    // The keymap callback can fire more than once, the files are only created once
+    let files = Rc::new(RefCell::new(Some(files)));
+    let create_window = window.clone();
+    ui::set_keymaps(
+        &mut buffer,
+        &keys.accept,
+        "Create the proposed files",
+        move || {
+            ui::close(&create_window);
+            if let Some(files) = files.borrow_mut().take() {
+                match create_files(&root, &files) {
+                    Ok(created) => utils::info(&format!("Created {} files", created)),
+                    Err(err) => notify_error(&err),
+                }
+            }
+        },
+    )?;
+
+    // Declining closes the float like cancelling does
+    ui::set_keymaps(&mut buffer, &keys.reject, "Close", move || {
+        ui::close(&window)
+    })?;
+
+    Ok(())
+}
+
+/// Writes the files below `root`, leaving existing files untouched
+///
+/// Returns how many files were created
+fn create_files(root: &Path, files: &[ScaffoldFile]) -> Result<usize> {
+    let mut created = 0;
+    for file in files {
+        let path = root.join(&file.path);
+        if path.exists() {
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, &file.contents)?;
+        created += 1;
+    }
+    Ok(created)
+}
@@ -0,0 +1,134 @@
+use crate::config::get_config;
+use crate::error::{notify_error, Result};
+use crate::{job_runner, ui, utils};
+use nvim_oxi::api::{
+    self,
+    opts::{OptionOpts, OptionScope::Local},
+    Buffer, Window,
+};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// Line put between a prompt and its answer
+const SEPARATOR: &str = "---";
+
+// Scratchpads by name
+thread_local! {
+    static SCRATCHPADS: RefCell<BTreeMap<String, Buffer>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Handles `:AichatScratch [name]`
+///
+/// Shows the markdown scratchpad of that name, `default` without one,
+/// creating it on first use. `<CR>` on a line sends everything above it, the
+/// line included, and the answer is inserted below it after a separator.
+pub fn open(name: Option<&str>) -> Result<()> {
+    let name = name
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .unwrap_or("default");
+    let existing = SCRATCHPADS.with(|pads| {
+        pads.borrow()
+            .get(name)
+            .filter(|buffer| buffer.is_valid())
+            .cloned()
+    });
+
+    let buffer = match existing {
+        Some(buffer) => buffer,
+        None => create(name)?,
+    };
+    let window: i64 = api::call_function("bufwinid", (buffer.handle(),))?;
+    if window == -1 {
+        api::command("belowright split")?;
+        api::get_current_win().set_buf(&buffer)?;
+    } else {
+        api::set_current_win(&Window::from(window as i32))?;
+    }
+    Ok(())
+}
+
+/// Names of the open scratchpads, for completion
+pub fn names() -> Vec<String> {
+    SCRATCHPADS.with(|pads| {
+        pads.borrow()
+            .iter()
+            .filter(|(_, buffer)| buffer.is_valid())
+            .map(|(name, _)| name.clone())
+            .collect()
+    })
+}
+
+/// Creates the buffer of a scratchpad with its `<CR>` mapping
+fn create(name: &str) -> Result<Buffer> {
+    let mut buffer = api::create_buf(true, true)?;
+    buffer.set_name(format!("aichat-scratch://{}", name))?;
+    let opts = OptionOpts::builder().scope(Local).buffer(&buffer).build();
+    api::set_option_value("buftype", "nofile", &opts)?;
+    api::set_option_value("bufhidden", "hide", &opts)?;
+    api::set_option_value("swapfile", false, &opts)?;
+    api::set_option_value("filetype", "markdown", &opts)?;
+
+    let target = buffer.clone();
+    ui::set_keymaps(
+        &mut buffer,
+        &["<CR>".to_string()],
+        "Send the scratchpad up to this line to Aichat",
+        move || {
+            if let Err(err) = send(target.clone()) {
+                notify_error(&err);
+            }
+        },
+    )?;
+
+    SCRATCHPADS.with(|pads| pads.borrow_mut().insert(name.to_string(), buffer.clone()));
+    Ok(buffer)
+}
+
+/// Sends the lines up to the cursor and inserts the answer below them
+fn send(buffer: Buffer) -> Result<()> {
+    let row = api::get_current_win().get_cursor()?.0;
+    let prompt = buffer
+        .get_lines(0..row, false)?
+        .map(|line| line.to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if prompt.trim().is_empty() {
+        utils::warn("Nothing to send, write a prompt first");
+        return Ok(());
+    }
+
+    let config = get_config().clone();
+    utils::info("Asking Aichat");
+
+    job_runner::run_in_background(
+        move || job_runner::run_aichat_response(&config, &prompt),
+        move |result| {
+            if let Err(err) = result.and_then(|response| insert(buffer, row, &response)) {
+                notify_error(&err);
+            }
+        },
+    )
+}
+
+/// Inserts the answer between separators below `row`, leaving an empty line
+/// for the next prompt
+fn insert(mut buffer: Buffer, row: usize, response: &str) -> Result<()> {
+    if !buffer.is_valid() {
+        return Ok(());
+    }
+    let row = row.min(buffer.line_count()?);
+
+    let mut lines = vec![String::new(), SEPARATOR.to_string(), String::new()];
+    lines.extend(response.trim_end().lines().map(String::from));
+    lines.extend([String::new(), SEPARATOR.to_string(), String::new()]);
+    let next_prompt = row + lines.len();
+    buffer.set_lines(row..row, false, lines)?;
+
+    // Continue below the answer when the scratchpad is the current buffer
+    let mut window = api::get_current_win();
+    if window.get_buf()? == buffer {
+        window.set_cursor(next_prompt, 0)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,525 @@
+use crate::error::{notify_error, Result};
+use nvim_oxi::{
+    api::{
+        self,
+        opts::{GetExtmarkByIdOpts, SetExtmarkOpts},
+        types::CommandArgs,
+        Buffer,
+    },
+    Array, Object,
+};
+
+/// Namespace of the extmarks anchoring the targets of running requests
+const ANCHOR_NAMESPACE: &str = "aichat_nvim_anchor";
+
+/// Lines written by one `set_lines` call of `Selection::write_in_chunks`
+const WRITE_CHUNK_LINES: usize = 1000;
+
+/// Region of the buffer a command reads from and writes back to
+///
+/// Lines are 1-based and inclusive, matching `line1`/`line2` of user commands
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Selection {
+    pub line1: usize,
+    pub line2: usize,
+    /// Byte columns of a charwise selection: the start in `line1` and the
+    /// exclusive end in `line2`. `None` selects whole lines.
+    pub columns: Option<(usize, usize)>,
+}
+
+impl Selection {
+    /// Resolves the target region of a command invocation
+    ///
+    /// An explicit range (a visual selection, `%`, `10,20`) is used as given.
+    /// Without a range the function enclosing the cursor is used when
+    /// treesitter knows one, otherwise the paragraph under the cursor, so an
+    /// accidental `:Aichat` never rewrites the whole file.
+    pub fn from_command(args: &CommandArgs, buffer: &Buffer) -> Result<Self> {
+        if args.range > 0 {
+            return Ok(Self {
+                line1: args.line1,
+                line2: args.line2,
+                columns: charwise_columns(args, buffer)?,
+            });
+        }
+
+        Self::around_cursor(buffer)
+    }
+
+    /// The function enclosing the cursor, or the paragraph under it when
+    /// treesitter doesn't know one
+    pub fn around_cursor(buffer: &Buffer) -> Result<Self> {
+        match enclosing_function()? {
+            Some(selection) => Ok(selection),
+            None => enclosing_paragraph(buffer),
+        }
+    }
+
+    /// The block of non-blank lines under the cursor
+    pub fn paragraph_at_cursor(buffer: &Buffer) -> Result<Self> {
+        enclosing_paragraph(buffer)
+    }
+
+    /// The sentence under the cursor, as a charwise selection within its
+    /// paragraph
+    ///
+    /// Sentences end at `.`, `!` or `?` followed by whitespace or the end of
+    /// the paragraph, and may span lines.
+    pub fn sentence_at_cursor(buffer: &Buffer) -> Result<Self> {
+        let paragraph = enclosing_paragraph(buffer)?;
+        let (cursor_line, cursor_col) = api::get_current_win().get_cursor()?;
+        let lines = paragraph.read(buffer)?;
+
+        // Every character with its position, line breaks as whitespace
+        let mut chars = Vec::new();
+        for (line, text) in (paragraph.line1..).zip(&lines) {
+            chars.extend(text.char_indices().map(|(col, c)| (line, col, c)));
+            if line < paragraph.line2 {
+                chars.push((line, text.len(), '\n'));
+            }
+        }
+        if chars.iter().all(|(_, _, c)| c.is_whitespace()) {
+            return Ok(paragraph);
+        }
+
+        let cursor = chars
+            .iter()
+            .position(|&(line, col, _)| {
+                line > cursor_line || (line == cursor_line && col >= cursor_col)
+            })
+            .unwrap_or(chars.len() - 1);
+        let ends_sentence = |i: usize| {
+            matches!(chars[i].2, '.' | '!' | '?')
+                && chars.get(i + 1).is_none_or(|(_, _, c)| c.is_whitespace())
+        };
+
+        let mut start = (0..cursor)
+            .rev()
+            .find(|&i| ends_sentence(i))
+            .map_or(0, |i| i + 1);
+        while start < chars.len() - 1 && chars[start].2.is_whitespace() {
+            start += 1;
+        }
+        let mut end = (start..chars.len())
+            .find(|&i| ends_sentence(i))
+            .unwrap_or(chars.len() - 1);
+        while end > start && chars[end].2.is_whitespace() {
+            end -= 1;
+        }
+
+        let (line1, start_col, _) = chars[start];
+        let (line2, end_col, last) = chars[end];
+        Ok(Self {
+            line1,
+            line2,
+            columns: Some((start_col, end_col + last.len_utf8())),
+        })
+    }
+
+    /// An empty selection right after `line`, replacing it inserts new lines
+    pub fn below(line: usize) -> Self {
+        Self {
+            line1: line + 1,
+            line2: line,
+            columns: None,
+        }
+    }
+
+    /// Pins the selection to the buffer with extmarks, so it can be found
+    /// again after the buffer was edited
+    pub fn anchor(&self, buffer: &Buffer) -> Result<Anchor> {
+        let mut buffer = buffer.clone();
+        let ns = api::create_namespace(ANCHOR_NAMESPACE);
+
+        // An insertion point is the end of the line it follows
+        let kind = if self.line2 < self.line1 {
+            match self.line2 {
+                0 => AnchorKind::Top,
+                line => {
+                    let col = line_len(&buffer, line - 1)?;
+                    AnchorKind::After(set_mark(&mut buffer, ns, line - 1, col, false)?)
+                }
+            }
+        } else {
+            let (start_col, end_col) = match self.columns {
+                Some(columns) => columns,
+                None => (0, line_len(&buffer, self.line2 - 1)?),
+            };
+            // The start stays before text typed at it, the end moves past text typed at it
+            AnchorKind::Range {
+                start: set_mark(&mut buffer, ns, self.line1 - 1, start_col, false)?,
+                end: set_mark(&mut buffer, ns, self.line2 - 1, end_col, true)?,
+                charwise: self.columns.is_some(),
+                empty: self.line1 == self.line2 && start_col == end_col,
+            }
+        };
+
+        Ok(Anchor { buffer, ns, kind })
+    }
+
+    /// The 0-based, end-exclusive line range used by the buffer API
+    pub fn line_range(&self) -> std::ops::Range<usize> {
+        self.line1 - 1..self.line2
+    }
+
+    /// Reads the selected text, cut to the columns of a charwise selection
+    pub fn read(&self, buffer: &Buffer) -> Result<Vec<String>> {
+        let mut lines = get_lines(buffer, self.line_range())?;
+
+        if let Some((start_col, end_col)) = self.columns {
+            if let Some(last) = lines.last_mut() {
+                last.truncate(char_boundary(last, end_col));
+            }
+            if let Some(first) = lines.first_mut() {
+                first.replace_range(..char_boundary(first, start_col), "");
+            }
+        }
+
+        Ok(lines)
+    }
+
+    /// The same lines without the columns of a charwise selection
+    pub fn linewise(&self) -> Self {
+        Self {
+            columns: None,
+            ..*self
+        }
+    }
+
+    /// Replaces the selected text with `replacement`
+    ///
+    /// For a charwise selection the text before the start column and after
+    /// the end column is kept around the replacement. Returns the whole lines
+    /// that were written.
+    pub fn replace(&self, buffer: &mut Buffer, replacement: Vec<String>) -> Result<Vec<String>> {
+        let replacement = self.whole_lines(buffer, replacement)?;
+        buffer.set_lines(self.line_range(), true, replacement.clone())?;
+        Ok(replacement)
+    }
+
+    /// Like `replace`, handing the whole lines written to `on_written` once
+    /// they are all in the buffer
+    ///
+    /// Long replacements are written `WRITE_CHUNK_LINES` at a time: the first
+    /// chunk replaces the selection right away, each following one is
+    /// appended from its own scheduled callback, so Neovim redraws and reads
+    /// input in between. The chunks are joined into one undo step, and an
+    /// extmark on the last line written keeps them together if lines are
+    /// added or removed above meanwhile. Short replacements call
+    /// `on_written` right away.
+    pub fn replace_in_chunks<F>(
+        &self,
+        buffer: &mut Buffer,
+        replacement: Vec<String>,
+        on_written: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(Vec<String>) -> Result<()> + 'static,
+    {
+        let replacement = self.whole_lines(buffer, replacement)?;
+        if replacement.len() <= WRITE_CHUNK_LINES {
+            buffer.set_lines(self.line_range(), true, replacement.clone())?;
+            return on_written(replacement);
+        }
+
+        buffer.set_lines(
+            self.line_range(),
+            true,
+            replacement[..WRITE_CHUNK_LINES].to_vec(),
+        )?;
+        let ns = api::create_namespace(ANCHOR_NAMESPACE);
+        let last = self.line1 + WRITE_CHUNK_LINES - 2;
+        let mark = set_mark(buffer, ns, last, 0, false)?;
+        append_chunks(
+            buffer.clone(),
+            ns,
+            mark,
+            replacement,
+            WRITE_CHUNK_LINES,
+            on_written,
+        );
+        Ok(())
+    }
+
+    /// The whole lines `replacement` makes of the selected ones, with the
+    /// text around a charwise selection kept
+    fn whole_lines(&self, buffer: &Buffer, replacement: Vec<String>) -> Result<Vec<String>> {
+        let mut replacement = replacement;
+
+        if let Some((start_col, end_col)) = self.columns {
+            let first = get_lines(buffer, self.line1 - 1..self.line1)?.concat();
+            let last = get_lines(buffer, self.line2 - 1..self.line2)?.concat();
+
+            if replacement.is_empty() {
+                replacement.push(String::new());
+            }
+            replacement[0].insert_str(0, &first[..char_boundary(&first, start_col)]);
+            if let Some(line) = replacement.last_mut() {
+                line.push_str(&last[char_boundary(&last, end_col)..]);
+            }
+        }
+
+        Ok(replacement)
+    }
+}
+
+/// Appends the lines of `replacement` from `written` on, one chunk per
+/// scheduled callback, after the line marked by `mark`
+///
+/// A failure, e.g. the buffer was wiped, is reported and leaves the rest of
+/// the lines out.
+fn append_chunks<F>(
+    buffer: Buffer,
+    ns: u32,
+    mark: u32,
+    replacement: Vec<String>,
+    written: usize,
+    on_written: F,
+) where
+    F: FnOnce(Vec<String>) -> Result<()> + 'static,
+{
+    nvim_oxi::schedule(move |_| -> nvim_oxi::Result<()> {
+        let mut buffer = buffer;
+        let end = (written + WRITE_CHUNK_LINES).min(replacement.len());
+        let appended = get_mark(&buffer, ns, mark).and_then(|(row, _)| {
+            // Join the undo step of the previous chunk
+            api::call_function::<_, Object>(
+                "luaeval",
+                (UNDOJOIN_SOURCE, Object::from(buffer.handle())),
+            )?;
+            buffer.set_lines(row + 1..row + 1, true, replacement[written..end].to_vec())?;
+            let opts = SetExtmarkOpts::builder()
+                .id(mark)
+                .right_gravity(false)
+                .build();
+            buffer.set_extmark(ns, row + end - written, 0, &opts)?;
+            Ok(())
+        });
+
+        let result = match appended {
+            Ok(()) if end < replacement.len() => {
+                append_chunks(buffer, ns, mark, replacement, end, on_written);
+                Ok(())
+            }
+            Ok(()) => {
+                let _ = buffer.del_extmark(ns, mark);
+                on_written(replacement)
+            }
+            Err(err) => {
+                let _ = buffer.del_extmark(ns, mark);
+                Err(err)
+            }
+        };
+        if let Err(err) = result {
+            notify_error(&err);
+        }
+        Ok(())
+    });
+}
+
+/// Runs `:undojoin` in buffer `_A`, ignoring the error after an undo
+const UNDOJOIN_SOURCE: &str = "vim.api.nvim_buf_call(_A, function() pcall(vim.cmd.undojoin) end)";
+
+/// Detects a charwise visual selection matching the command range
+///
+/// Returns its byte columns, or `None` for linewise and blockwise selections
+/// or ranges that were typed rather than selected
+fn charwise_columns(args: &CommandArgs, buffer: &Buffer) -> Result<Option<(usize, usize)>> {
+    let visual_mode: String = api::call_function("visualmode", Array::new())?;
+    let (start_line, start_col) = buffer.get_mark('<')?;
+    let (end_line, end_col) = buffer.get_mark('>')?;
+
+    if visual_mode != "v" || start_line != args.line1 || end_line != args.line2 {
+        return Ok(None);
+    }
+
+    // The `>` mark points at the start of the last selected character
+    let last = get_lines(buffer, end_line - 1..end_line)?.concat();
+    let end_col = char_boundary(&last, end_col);
+    let end_col = end_col + last[end_col..].chars().next().map_or(0, char::len_utf8);
+
+    Ok(Some((start_col, end_col)))
+}
+
+/// Reads buffer lines as Rust strings
+fn get_lines(buffer: &Buffer, range: std::ops::Range<usize>) -> Result<Vec<String>> {
+    Ok(buffer
+        .get_lines(range, false)?
+        .map(|line| line.to_string_lossy().into_owned())
+        .collect())
+}
+
+/// A selection pinned to its buffer with extmarks while a request runs
+pub struct Anchor {
+    buffer: Buffer,
+    ns: u32,
+    kind: AnchorKind,
+}
+
+/// What an anchor marks
+enum AnchorKind {
+    /// Insertion before the first line, which needs no mark
+    Top,
+    /// Insertion after the line holding the mark
+    After(u32),
+    /// The start and end of the selected text, `empty` when they were at
+    /// the same position, so a deletion can't be told apart
+    Range {
+        start: u32,
+        end: u32,
+        charwise: bool,
+        empty: bool,
+    },
+}
+
+impl Anchor {
+    /// Where the selection is now, or None if its text was deleted
+    pub fn current(&self) -> Result<Option<Selection>> {
+        match self.kind {
+            AnchorKind::Top => Ok(Some(Selection::below(0))),
+            AnchorKind::After(mark) => {
+                let (row, _) = get_mark(&self.buffer, self.ns, mark)?;
+                Ok(Some(Selection::below(row + 1)))
+            }
+            AnchorKind::Range {
+                start,
+                end,
+                charwise,
+                empty,
+            } => {
+                let (start_row, start_col) = get_mark(&self.buffer, self.ns, start)?;
+                let (end_row, end_col) = get_mark(&self.buffer, self.ns, end)?;
+
+                // Deleting the whole selection collapses both marks onto one position
+                if !empty && (end_row, end_col) <= (start_row, start_col) {
+                    return Ok(None);
+                }
+
+                Ok(Some(Selection {
+                    line1: start_row + 1,
+                    line2: end_row + 1,
+                    columns: charwise.then_some((start_col, end_col)),
+                }))
+            }
+        }
+    }
+
+    /// Like `current`, and removes the extmarks
+    pub fn resolve(self) -> Result<Option<Selection>> {
+        let selection = self.current();
+        let mut buffer = self.buffer;
+        match self.kind {
+            AnchorKind::Top => {}
+            AnchorKind::After(mark) => {
+                let _ = buffer.del_extmark(self.ns, mark);
+            }
+            AnchorKind::Range { start, end, .. } => {
+                let _ = buffer.del_extmark(self.ns, start);
+                let _ = buffer.del_extmark(self.ns, end);
+            }
+        }
+        selection
+    }
+}
+
+/// Places an anchor extmark, `right_gravity` decides on which side of text
+/// inserted at it the mark ends up
+fn set_mark(
+    buffer: &mut Buffer,
+    ns: u32,
+    row: usize,
+    col: usize,
+    right_gravity: bool,
+) -> Result<u32> {
+    let opts = SetExtmarkOpts::builder()
+        .right_gravity(right_gravity)
+        .build();
+    Ok(buffer.set_extmark(ns, row, col, &opts)?)
+}
+
+/// Reads the 0-based position of an anchor extmark
+fn get_mark(buffer: &Buffer, ns: u32, id: u32) -> Result<(usize, usize)> {
+    let (row, col, _) = buffer.get_extmark_by_id(ns, id, &GetExtmarkByIdOpts::default())?;
+    Ok((row, col))
+}
+
+/// Byte length of a 0-based line
+fn line_len(buffer: &Buffer, row: usize) -> Result<usize> {
+    Ok(get_lines(buffer, row..row + 1)?.concat().len())
+}
+
+/// Clamps a byte column to the line and moves it back onto a char boundary
+fn char_boundary(line: &str, col: usize) -> usize {
+    let mut col = col.min(line.len());
+    while !line.is_char_boundary(col) {
+        col -= 1;
+    }
+    col
+}
+
+/// Asks treesitter for the innermost function or method around the cursor
+fn enclosing_function() -> Result<Option<Selection>> {
+    let range: Vec<usize> = api::call_function("luaeval", (ENCLOSING_FUNCTION_SOURCE,))?;
+
+    Ok(match range.as_slice() {
+        [line1, line2] if *line1 > 0 && line2 >= line1 => Some(Selection {
+            line1: *line1,
+            line2: *line2,
+            columns: None,
+        }),
+        _ => None,
+    })
+}
+
+/// Finds the block of non-blank lines around the cursor
+fn enclosing_paragraph(buffer: &Buffer) -> Result<Selection> {
+    let (cursor_line, _) = api::get_current_win().get_cursor()?;
+    let lines = get_lines(buffer, 0..buffer.line_count()?)?;
+
+    let is_blank = |line: usize| {
+        lines
+            .get(line - 1)
+            .is_none_or(|text| text.trim().is_empty())
+    };
+
+    let mut line1 = cursor_line;
+    let mut line2 = cursor_line;
+    if !is_blank(cursor_line) {
+        while line1 > 1 && !is_blank(line1 - 1) {
+            line1 -= 1;
+        }
+        while line2 < lines.len() && !is_blank(line2 + 1) {
+            line2 += 1;
+        }
+    }
+
+    Ok(Selection {
+        line1,
+        line2,
+        columns: None,
+    })
+}
+
+/// Returns `{ line1, line2 }` of the enclosing function, or `{ 0, 0 }` when
+/// there is no parser or no function around the cursor
+///
+/// Only definitions count, so calls like `call_expression` or
+/// `method_invocation` around the cursor are skipped.
+const ENCLOSING_FUNCTION_SOURCE: &str = r#"(function()
+  local definitions = {
+    function_definition = true,
+    function_declaration = true,
+    function_item = true,
+    method_definition = true,
+    method_declaration = true,
+  }
+  local ok, node = pcall(vim.treesitter.get_node)
+  while ok and node do
+    if definitions[node:type()] then
+      local start_row, _, end_row, end_col = node:range()
+      return { start_row + 1, end_col == 0 and end_row or end_row + 1 }
+    end
+    node = node:parent()
+  end
+  return { 0, 0 }
+end)()"#;
@@ -0,0 +1,134 @@
+use crate::error::{notify_error, Result};
+use crate::ui::Keys;
+use crate::{job_runner, trust, ui, utils};
+use nvim_oxi::{api, Dictionary, Object};
+
+/// Keys, description and action of a mapping of the confirmation float
+type Action = (Vec<String>, &'static str, fn(&str) -> Result<()>);
+
+/// Handles `:AichatShell {description}`
+///
+/// The description is prompted for when not given. The generated command is
+/// shown for confirmation before anything runs, unless it was allowed always
+/// in this project.
+pub fn aichat_shell(description: Option<String>) -> nvim_oxi::Result<()> {
+    let description = match description {
+        Some(description) => description,
+        None => {
+            ui::input("Aichat Shell >", "", |description| match description {
+                Some(description) => Ok(aichat_shell(Some(description))?),
+                None => Ok(()),
+            });
+            return Ok(());
+        }
+    };
+
+    utils::info("Generating shell command");
+
+    job_runner::run_in_background(
+        move || job_runner::generate_shell_command(&description),
+        |result| {
+            if let Err(err) = result.and_then(|command| confirm(&command)) {
+                notify_error(&err);
+            }
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Runs a command allowed always in the project, or asks what to do with it
+fn confirm(command: &str) -> Result<()> {
+    if command.is_empty() {
+        utils::warn("aichat did not generate a command");
+        return Ok(());
+    }
+    if trust::is_allowed(command)? {
+        utils::info(&format!("Running allowed command: {}", command));
+        return run_in_terminal(command);
+    }
+    show_confirmation(command)
+}
+
+/// Shows the generated command in a float with the actions that can be taken
+/// on it: allow once, allow always in this project, edit, copy or deny
+fn show_confirmation(command: &str) -> Result<()> {
+    let keys = crate::config::get_config().keys.clone();
+    let mut lines = vec!["Will run in a terminal:".to_string(), String::new()];
+    lines.extend(command.lines().map(|line| format!("  {}", line)));
+    lines.push(String::new());
+    lines.push(format!(
+        "{} allow once  a allow always in this project  : edit on the command line  y copy  {} deny",
+        Keys::hint(&keys.accept),
+        Keys::hint(&keys.cancel)
+    ));
+
+    let (mut buffer, window) = ui::open_float("Aichat Shell", lines)?;
+
+    let actions: [Action; 4] = [
+        (
+            keys.accept.clone(),
+            "Run the command in a terminal",
+            run_in_terminal,
+        ),
+        (
+            vec!["a".into()],
+            "Always allow the command in this project and run it",
+            allow_always,
+        ),
+        (
+            vec![":".into()],
+            "Edit the command on the command line",
+            edit_on_cmdline,
+        ),
+        (vec!["y".into()], "Copy the command", copy_command),
+    ];
+
+    for (lhs, desc, action) in actions {
+        let command = command.to_string();
+        let window = window.clone();
+        ui::set_keymaps(&mut buffer, &lhs, desc, move || {
+            ui::close(&window);
+            if let Err(err) = action(&command) {
+                notify_error(&err);
+            }
+        })?;
+    }
+
+    // Declining closes the float like cancelling does
+    let window = window.clone();
+    ui::set_keymaps(&mut buffer, &keys.reject, "Deny", move || {
+        ui::close(&window)
+    })?;
+
+    Ok(())
+}
+
+/// Runs the command in a terminal split
+fn run_in_terminal(command: &str) -> Result<()> {
+    api::command("botright new")?;
+    let opts = Dictionary::from_iter([("term", Object::from(true))]);
+    let _: i64 = api::call_function("jobstart", (command, opts))?;
+    api::command("startinsert")?;
+    Ok(())
+}
+
+/// Remembers the command as allowed in the project, then runs it
+fn allow_always(command: &str) -> Result<()> {
+    trust::allow_always(command)?;
+    run_in_terminal(command)
+}
+
+/// Puts the command on the command line as `:!{command}` without running it
+fn edit_on_cmdline(command: &str) -> Result<()> {
+    let cmdline = format!(":!{}", command.lines().collect::<Vec<_>>().join("; "));
+    let _: i64 = api::call_function("feedkeys", (cmdline, "n"))?;
+    Ok(())
+}
+
+/// Copies the command to the unnamed and clipboard registers
+fn copy_command(command: &str) -> Result<()> {
+    utils::copy_to_registers(command);
+    utils::info("Copied shell command");
+    Ok(())
+}
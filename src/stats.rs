@@ -0,0 +1,108 @@
+use crate::error::{AichatError, Result};
+use crate::ui;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Request counters for one model, role or agent
+#[derive(Default, Clone)]
+struct Counts {
+    requests: usize,
+    succeeded: usize,
+    failed: usize,
+    cancelled: usize,
+    latency: Duration,
+    bytes_sent: usize,
+    bytes_received: usize,
+}
+
+impl Counts {
+    /// Adds the counters of `other` to these
+    fn add(&mut self, other: &Counts) {
+        self.requests += other.requests;
+        self.succeeded += other.succeeded;
+        self.failed += other.failed;
+        self.cancelled += other.cancelled;
+        self.latency += other.latency;
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+    }
+
+    /// Describes the counters on one line
+    fn describe(&self) -> String {
+        let average = self
+            .latency
+            .checked_div(self.requests as u32)
+            .unwrap_or_default();
+        format!(
+            "{} requests, {} ok, {} failed, {} cancelled, avg {:.1}s, {} sent, {} received",
+            self.requests,
+            self.succeeded,
+            self.failed,
+            self.cancelled,
+            average.as_secs_f64(),
+            format_bytes(self.bytes_sent),
+            format_bytes(self.bytes_received)
+        )
+    }
+}
+
+// Statistics of this Neovim session, keyed by what answered the requests
+thread_local! {
+    static STATS: RefCell<BTreeMap<String, Counts>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Counts a finished request
+pub fn record(
+    label: &str,
+    result: &Result<()>,
+    latency: Duration,
+    bytes_sent: usize,
+    bytes_received: usize,
+) {
+    STATS.with(|stats| {
+        let mut stats = stats.borrow_mut();
+        let counts = stats.entry(label.to_string()).or_default();
+        counts.requests += 1;
+        match result {
+            Ok(()) => counts.succeeded += 1,
+            Err(AichatError::Cancelled) => counts.cancelled += 1,
+            Err(_) => counts.failed += 1,
+        }
+        counts.latency += latency;
+        counts.bytes_sent += bytes_sent;
+        counts.bytes_received += bytes_received;
+    });
+}
+
+/// Shows the statistics of this session in a float, in total and per model
+pub fn show_stats() -> nvim_oxi::Result<()> {
+    let lines = STATS.with(|stats| {
+        let stats = stats.borrow();
+        let mut total = Counts::default();
+        for counts in stats.values() {
+            total.add(counts);
+        }
+
+        let mut lines = vec![format!("Total: {}", total.describe())];
+        if !stats.is_empty() {
+            lines.push(String::new());
+        }
+        for (label, counts) in stats.iter() {
+            lines.push(format!("{}: {}", label, counts.describe()));
+        }
+        lines
+    });
+
+    ui::open_float("Aichat Stats", lines)?;
+    Ok(())
+}
+
+/// Formats a byte count with a binary unit
+fn format_bytes(bytes: usize) -> String {
+    match bytes {
+        0..=1023 => format!("{} B", bytes),
+        1024..=1048575 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1048576.0),
+    }
+}
@@ -0,0 +1,102 @@
+use crate::config::get_config;
+use crate::error::{notify_error, Result};
+use crate::prompt::{self, Location, PromptBuilder};
+use crate::selection::Selection;
+use crate::{job_runner, ui, utils};
+use nvim_oxi::api::{self, types::CommandArgs, Buffer};
+
+/// Lengths a summary can have, with the instruction asking for each
+const PRESETS: [(&str, &str); 3] = [
+    ("tl;dr", "Summarize this text in one or two sentences."),
+    ("paragraph", "Summarize this text in one paragraph."),
+    (
+        "detailed",
+        "Summarize this text in detail, with a short section per part of it.",
+    ),
+];
+
+/// Handles `:[range]AichatSummarize[!] [preset]`
+///
+/// Summarizes the range, or the whole buffer without one, into a float, or
+/// a scratch buffer with `!`. The text is never changed. The preset is
+/// picked when not given.
+pub fn aichat_summarize(args: CommandArgs) -> Result<()> {
+    let buffer = api::get_current_buf();
+    let selection = if args.range > 0 {
+        Selection::from_command(&args, &buffer)?
+    } else {
+        Selection {
+            line1: 1,
+            line2: buffer.line_count()?.max(1),
+            columns: None,
+        }
+    };
+    let scratch = args.bang;
+
+    if let Some(preset) = args
+        .args
+        .as_deref()
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+    {
+        return summarize(buffer, selection, preset, scratch);
+    }
+
+    let opts = ui::SelectOpts {
+        prompt: Some("Summary length".to_string()),
+        kind: None,
+    };
+    let names: Vec<&str> = PRESETS.iter().map(|(name, _)| *name).collect();
+    ui::vim_ui_select(names, Some(opts), move |preset, _index| {
+        if let Some(preset) = preset {
+            if let Err(err) = summarize(buffer.clone(), selection, &preset, scratch) {
+                notify_error(&err);
+            }
+        }
+    })?;
+    Ok(())
+}
+
+/// Names of the presets, for completion
+pub fn presets() -> Vec<String> {
+    PRESETS.iter().map(|(name, _)| name.to_string()).collect()
+}
+
+/// Asks for a summary of the selection and shows it once it arrives
+fn summarize(buffer: Buffer, selection: Selection, preset: &str, scratch: bool) -> Result<()> {
+    let Some((_, instruction)) = PRESETS.iter().find(|(name, _)| *name == preset) else {
+        utils::warn(&format!(
+            "Unknown summary length {}, use one of {}",
+            preset,
+            presets().join(", ")
+        ));
+        return Ok(());
+    };
+
+    let text = prompt::fenced(&buffer, &selection.read(&buffer)?.join("\n"))?;
+    let prompt = PromptBuilder::new(instruction)
+        .location(&Location::current(&buffer, &selection))
+        .part(text)
+        .build();
+
+    let config = get_config().clone();
+    utils::info("Asking Aichat for a summary");
+
+    job_runner::run_in_background(
+        move || job_runner::run_aichat_response(&config, &prompt),
+        move |result| {
+            let shown = result.and_then(|response| {
+                let lines = response.lines().map(String::from).collect();
+                if scratch {
+                    ui::open_scratch(lines, "markdown")?;
+                } else {
+                    ui::open_float("Aichat Summary", lines)?;
+                }
+                Ok(())
+            });
+            if let Err(err) = shown {
+                notify_error(&err);
+            }
+        },
+    )
+}
@@ -0,0 +1,128 @@
+use crate::config::get_config;
+use nvim_oxi::{api, Dictionary, Function, Object};
+use std::cell::RefCell;
+use std::io::Write;
+
+// Lua callbacks registered with `on_event`
+thread_local! {
+    static LISTENERS: RefCell<Vec<Function<Dictionary, ()>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Value of an event field
+pub enum Value {
+    Str(String),
+    Int(i64),
+}
+
+/// A structured event about plugin usage
+///
+/// Events only ever go to the local sinks configured by the user: the Lua
+/// callbacks registered with `on_event` and the `telemetry_file`. Nothing is
+/// sent over the network.
+pub struct Event {
+    name: &'static str,
+    fields: Vec<(&'static str, Value)>,
+}
+
+impl Event {
+    /// Creates an event without fields
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            fields: Vec::new(),
+        }
+    }
+
+    /// Adds a string field
+    pub fn str(mut self, key: &'static str, value: impl Into<String>) -> Self {
+        self.fields.push((key, Value::Str(value.into())));
+        self
+    }
+
+    /// Adds an integer field
+    pub fn int(mut self, key: &'static str, value: i64) -> Self {
+        self.fields.push((key, Value::Int(value)));
+        self
+    }
+
+    /// Converts the event to the table passed to Lua callbacks
+    fn to_dictionary(&self) -> Dictionary {
+        let mut dict = Dictionary::new();
+        dict.insert("event", Object::from(self.name));
+        for (key, value) in &self.fields {
+            let value = match value {
+                Value::Str(s) => Object::from(s.as_str()),
+                Value::Int(i) => Object::from(*i),
+            };
+            dict.insert(*key, value);
+        }
+        dict
+    }
+
+    /// Serializes the event as a single JSON line
+    fn to_json(&self) -> String {
+        let fields = std::iter::once(("event", json_string(self.name)))
+            .chain(self.fields.iter().map(|(key, value)| {
+                let value = match value {
+                    Value::Str(s) => json_string(s),
+                    Value::Int(i) => i.to_string(),
+                };
+                (*key, value)
+            }))
+            .map(|(key, value)| format!("{}:{}", json_string(key), value))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!("{{{}}}", fields)
+    }
+}
+
+/// Registers a Lua callback receiving every event as a table
+pub fn on_event(callback: Function<Dictionary, ()>) -> nvim_oxi::Result<()> {
+    LISTENERS.with(|listeners| listeners.borrow_mut().push(callback));
+    Ok(())
+}
+
+/// Sends an event to the registered callbacks and the telemetry file
+///
+/// Failing sinks are ignored so telemetry can never break a request
+pub fn emit(event: Event) {
+    let listeners = LISTENERS.with(|listeners| listeners.borrow().clone());
+    for listener in listeners {
+        let _ = listener.call(event.to_dictionary());
+    }
+
+    let path = get_config().telemetry_file.clone();
+    if let Some(path) = path {
+        let _ = append_to_file(&path, &event.to_json());
+    }
+}
+
+/// Appends a line to the telemetry file, expanding `~` and variables
+fn append_to_file(path: &str, line: &str) -> crate::error::Result<()> {
+    let path: String = api::call_function("expand", (path,))?;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{}", line)?;
+    Ok(())
+}
+
+/// Quotes and escapes a string for JSON
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,161 @@
+use crate::config::{self, get_config};
+use crate::error::{notify_error, Result};
+use crate::selection::Selection;
+use crate::{history, job_runner, utils};
+use nvim_oxi::{api, Object};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// Lists shown by the extension, as `:Telescope aichat <kind>`
+const KINDS: [&str; 3] = ["roles", "sessions", "history"];
+
+// Previews run aichat, so each one is only asked for once
+thread_local! {
+    static PREVIEWS: RefCell<HashMap<(String, String), Vec<String>>> = RefCell::new(HashMap::new());
+}
+
+/// Registers the `aichat` Telescope extension when telescope is installed
+///
+/// The extension is available to `require('telescope').load_extension('aichat')`
+/// and loaded right away, so `:Telescope aichat roles` works after `setup()`.
+pub fn register() -> Result<()> {
+    let installed: bool =
+        api::call_function("luaeval", ("pcall(require, 'telescope')", Object::nil()))?;
+    if installed {
+        api::call_function::<_, Object>("luaeval", (REGISTER_SOURCE, Object::nil()))?;
+    }
+    Ok(())
+}
+
+/// The entries of a picker
+///
+/// Exposed to Lua as `require("aichat_nvim").telescope_items(kind)`
+pub fn items(kind: String) -> Vec<String> {
+    match kind.as_str() {
+        "roles" => config::complete_section_values("role", ""),
+        "sessions" => config::complete_section_values("session", ""),
+        "history" => history::instructions(),
+        _ => Vec::new(),
+    }
+}
+
+/// The preview lines of an entry
+///
+/// Roles and sessions show what `aichat --info` reports about them, history
+/// entries the instruction itself.
+///
+/// Exposed to Lua as `require("aichat_nvim").telescope_preview(kind, value)`
+pub fn preview((kind, value): (String, String)) -> Vec<String> {
+    let flag = match kind.as_str() {
+        "roles" => "--role",
+        "sessions" => "--session",
+        _ => return value.lines().map(String::from).collect(),
+    };
+
+    let key = (kind.clone(), value.clone());
+    if let Some(lines) = PREVIEWS.with(|previews| previews.borrow().get(&key).cloned()) {
+        return lines;
+    }
+
+    let lines = match describe(flag, &value) {
+        Ok(info) => info.lines().map(String::from).collect(),
+        Err(err) => vec![err.to_string()],
+    };
+    PREVIEWS.with(|previews| previews.borrow_mut().insert(key, lines.clone()));
+    lines
+}
+
+/// Runs `aichat <flag> <value> --info`
+fn describe(flag: &str, value: &str) -> Result<String> {
+    let mut cmd = job_runner::base_command(&get_config());
+    cmd.args([flag, value, "--info"]);
+    job_runner::run_command(cmd, "")
+}
+
+/// Acts on the chosen entry: roles and sessions become the current ones, a
+/// history entry is offered again as the instruction for the code around
+/// the cursor
+///
+/// Exposed to Lua as `require("aichat_nvim").telescope_choose(kind, value)`
+pub fn choose((kind, value): (String, String)) -> nvim_oxi::Result<()> {
+    match kind.as_str() {
+        "roles" => config::set_section("role", Some(&value)),
+        "sessions" => config::set_section("session", Some(&value)),
+        "history" => {
+            if let Err(err) = ask_again(&value) {
+                notify_error(&err);
+            }
+            Ok(())
+        }
+        _ => {
+            utils::warn(&format!(
+                "Unknown Aichat picker {}, use one of {}",
+                kind,
+                KINDS.join(", ")
+            ));
+            Ok(())
+        }
+    }
+}
+
+/// Opens the `:Aichat` prompt filled with a past instruction
+fn ask_again(instruction: &str) -> Result<()> {
+    let buffer = api::get_current_buf();
+    let output = get_config().output;
+    let selection = output.target(Selection::around_cursor(&buffer)?);
+    Ok(crate::aichat_at(
+        buffer,
+        selection,
+        output,
+        instruction,
+        None,
+    )?)
+}
+
+/// Makes `telescope._extensions.aichat` available and loads it. Every
+/// picker takes its entries, previews and action from the functions above.
+const REGISTER_SOURCE: &str = r#"(function()
+  package.preload['telescope._extensions.aichat'] = function()
+    local plugin = require('aichat_nvim')
+    local actions = require('telescope.actions')
+    local state = require('telescope.actions.state')
+    local conf = require('telescope.config').values
+
+    local function picker(kind, title)
+      return function(opts)
+        opts = opts or {}
+        require('telescope.pickers').new(opts, {
+          prompt_title = title,
+          finder = require('telescope.finders').new_table({ results = plugin.telescope_items(kind) }),
+          sorter = conf.generic_sorter(opts),
+          previewer = require('telescope.previewers').new_buffer_previewer({
+            define_preview = function(self, entry)
+              local lines = plugin.telescope_preview(kind, entry[1])
+              vim.api.nvim_buf_set_lines(self.state.bufnr, 0, -1, false, lines)
+            end,
+          }),
+          attach_mappings = function(bufnr)
+            actions.select_default:replace(function()
+              local entry = state.get_selected_entry()
+              actions.close(bufnr)
+              if entry then
+                vim.schedule(function() plugin.telescope_choose(kind, entry[1]) end)
+              end
+            end)
+            return true
+          end,
+        }):find()
+      end
+    end
+
+    return require('telescope').register_extension({
+      exports = {
+        roles = picker('roles', 'Aichat Roles'),
+        sessions = picker('sessions', 'Aichat Sessions'),
+        history = picker('history', 'Aichat Prompt History'),
+        aichat = picker('roles', 'Aichat Roles'),
+      },
+    })
+  end
+  require('telescope').load_extension('aichat')
+end)()"#;
@@ -0,0 +1,203 @@
+use crate::config::{get_config, get_config_mut, AichatConfig};
+use crate::{job_runner, utils};
+use nvim_oxi::api;
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Features that run on their own, after a response or while editing,
+/// switched at runtime with `:AichatToggle`
+#[derive(Clone, Copy)]
+pub enum Automatic {
+    /// Hunk signs and review keys, `features.inline`
+    Inline,
+    /// `format_after_insert`
+    Format,
+    /// Review of prose buffers, `features.prose`
+    Prose,
+}
+
+impl Automatic {
+    /// Every automatic feature, in the order they are listed
+    const ALL: [Automatic; 3] = [Automatic::Inline, Automatic::Format, Automatic::Prose];
+
+    /// Name used by `:AichatToggle` and the status component
+    fn name(self) -> &'static str {
+        match self {
+            Automatic::Inline => "inline",
+            Automatic::Format => "format",
+            Automatic::Prose => "prose",
+        }
+    }
+
+    /// Whether the feature is turned on in the configuration
+    fn configured(self, config: &AichatConfig) -> bool {
+        match self {
+            Automatic::Inline => config.features.inline,
+            Automatic::Format => config.format_after_insert,
+            Automatic::Prose => config.features.prose,
+        }
+    }
+
+    /// Turns the feature on or off in the configuration
+    fn set(self, config: &mut AichatConfig, enabled: bool) {
+        match self {
+            Automatic::Inline => config.features.inline = enabled,
+            Automatic::Format => config.format_after_insert = enabled,
+            Automatic::Prose => config.features.prose = enabled,
+        }
+    }
+}
+
+// Pauses every automatic feature without touching their configuration
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Whether an automatic feature is turned on and not paused
+pub fn is_active(feature: Automatic) -> bool {
+    !PAUSED.load(Ordering::Relaxed) && feature.configured(&get_config())
+}
+
+/// Handles `:AichatToggle [feature]`
+///
+/// Without a feature every automatic feature is paused or resumed at once,
+/// keeping their configuration. With a feature that one is turned on or off
+/// until the next `setup()`.
+pub fn toggle(arg: Option<&str>) -> nvim_oxi::Result<()> {
+    let feature = match arg.map(str::trim).filter(|arg| !arg.is_empty()) {
+        None => None,
+        Some(name) => match Automatic::ALL.into_iter().find(|f| f.name() == name) {
+            Some(feature) => Some(feature),
+            None => {
+                utils::warn(&format!("Usage: AichatToggle [{}]", complete("").join("|")));
+                return Ok(());
+            }
+        },
+    };
+
+    match feature {
+        None => {
+            let paused = !PAUSED.fetch_xor(true, Ordering::Relaxed);
+            utils::info(if paused {
+                "Paused the automatic Aichat features"
+            } else {
+                "Resumed the automatic Aichat features"
+            });
+        }
+        Some(feature) => {
+            let mut config = get_config_mut();
+            let enabled = !feature.configured(&config);
+            feature.set(&mut config, enabled);
+            drop(config);
+            // Commands and autocommands follow the feature flags
+            crate::register_feature_commands()?;
+            utils::info(&format!(
+                "Turned Aichat {} {}",
+                feature.name(),
+                if enabled { "on" } else { "off" }
+            ));
+        }
+    }
+
+    let _ = api::command("redrawstatus!");
+    Ok(())
+}
+
+// The model used before `:AichatLocal` switched to `local_model`, restored
+// when it switches back
+static REMOTE_MODEL: Lazy<Mutex<Option<Box<str>>>> = Lazy::new(|| Mutex::new(None));
+
+/// Whether requests go to `local_model`
+fn is_local(config: &AichatConfig) -> bool {
+    config.local_model.is_some() && config.model == config.local_model
+}
+
+/// Handles `:AichatLocal [on|off]`, switching the model to `local_model`
+/// and back to the one used before, toggling without an argument
+pub fn local(arg: Option<&str>) -> nvim_oxi::Result<()> {
+    let mut config = get_config_mut();
+    let Some(local_model) = config.local_model.clone() else {
+        drop(config);
+        utils::warn("Set local_model in setup() to switch to a local model");
+        return Ok(());
+    };
+
+    let local = match arg.map(str::trim).unwrap_or_default() {
+        "" => !is_local(&config),
+        "on" => true,
+        "off" => false,
+        _ => {
+            drop(config);
+            utils::warn("Usage: AichatLocal [on|off]");
+            return Ok(());
+        }
+    };
+
+    let mut remote = REMOTE_MODEL.lock().unwrap_or_else(|e| e.into_inner());
+    if local && !is_local(&config) {
+        *remote = config.model.replace(local_model);
+    } else if !local && is_local(&config) {
+        config.model = remote.take();
+    }
+    let status = match &config.model {
+        Some(model) if local => format!("Aichat uses the local model {}", model),
+        Some(model) => format!("Aichat uses {}", model),
+        None => "Aichat uses the model of the role or aichat's default".to_string(),
+    };
+    drop(remote);
+    drop(config);
+
+    utils::info(&status);
+    let _ = api::command("redrawstatus!");
+    Ok(())
+}
+
+/// Completes the feature names of `:AichatToggle`
+pub fn complete(arg_lead: &str) -> Vec<String> {
+    Automatic::ALL
+        .into_iter()
+        .map(Automatic::name)
+        .filter(|name| name.starts_with(arg_lead))
+        .map(String::from)
+        .collect()
+}
+
+/// Short state for a statusline, e.g. `aichat 1 running [inline format]`
+///
+/// With a `local_model`, `local` or `remote` tells which one requests use.
+///
+/// Exposed to Lua as `require("aichat_nvim").status()`
+pub fn status(_: ()) -> String {
+    let mut status = "aichat".to_string();
+
+    {
+        let config = get_config();
+        if config.local_model.is_some() {
+            status.push_str(if is_local(&config) {
+                " local"
+            } else {
+                " remote"
+            });
+        }
+    }
+
+    let running = job_runner::in_flight_count();
+    if running > 0 {
+        status.push_str(&format!(" {} running", running));
+    }
+
+    if PAUSED.load(Ordering::Relaxed) {
+        status.push_str(" [paused]");
+    } else {
+        let config = get_config();
+        let active: Vec<&str> = Automatic::ALL
+            .into_iter()
+            .filter(|feature| feature.configured(&config))
+            .map(Automatic::name)
+            .collect();
+        if !active.is_empty() {
+            status.push_str(&format!(" [{}]", active.join(" ")));
+        }
+    }
+
+    status
+}
@@ -0,0 +1,177 @@
+use crate::config::{self, get_config, AichatConfig};
+use crate::selection::Selection;
+use nvim_oxi::api::{self, Buffer};
+use std::cell::RefCell;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A prompt and the response it got, with the settings it was sent with
+struct Exchange {
+    time: String,
+    /// File and lines the request was about, e.g. `src/lib.rs:10-20`
+    location: Option<String>,
+    mode: String,
+    model: Option<String>,
+    session: Option<String>,
+    rag: Option<String>,
+    prompt: String,
+    response: String,
+}
+
+// Exchanges of this Neovim session
+thread_local! {
+    static EXCHANGES: RefCell<Vec<Exchange>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Adds an answered request on the lines of `selection` to the transcript
+pub fn record(
+    config: &AichatConfig,
+    buffer: &Buffer,
+    selection: &Selection,
+    prompt: &str,
+    response: &str,
+) {
+    let time: String = api::call_function("strftime", ("%Y-%m-%d %H:%M:%S",)).unwrap_or_default();
+    let location = buffer
+        .get_name()
+        .ok()
+        .filter(|path| !path.as_os_str().is_empty())
+        .and_then(|path| {
+            api::call_function::<_, String>(
+                "fnamemodify",
+                (path.to_string_lossy().into_owned(), ":~:."),
+            )
+            .ok()
+        })
+        .map(|file| format!("{}:{}-{}", file, selection.line1, selection.line2));
+
+    EXCHANGES.with(|exchanges| {
+        exchanges.borrow_mut().push(Exchange {
+            time,
+            location,
+            mode: format!("{} {}", config.mode_flag.name(), config.mode_arg),
+            model: config.model.as_deref().map(String::from),
+            session: config.session_name(),
+            rag: config.rag.as_deref().map(String::from),
+            prompt: prompt.to_string(),
+            response: response.to_string(),
+        })
+    });
+}
+
+/// Handles `:AichatExport {path}`, writing the transcript as markdown
+pub fn export(path: &str) -> nvim_oxi::Result<()> {
+    let path: String = api::call_function("expand", (path,))?;
+    let markdown = EXCHANGES.with(|exchanges| render(&exchanges.borrow()));
+
+    match markdown {
+        Some(markdown) => {
+            if let Err(err) = std::fs::write(Path::new(&path), markdown) {
+                crate::error::notify_error(&err.into());
+            } else {
+                crate::utils::info(&format!("Exported the Aichat transcript to {}", path));
+            }
+        }
+        None => crate::utils::warn("No Aichat exchanges to export yet"),
+    }
+
+    Ok(())
+}
+
+/// Handles `:AichatSaveExchange [path]`, appending the last exchange to the
+/// notes file, `notes_file` by default
+///
+/// Relative paths start at the project root, so every file of a project
+/// adds to the same notes.
+pub fn save_last(path: Option<&str>) -> nvim_oxi::Result<()> {
+    let Some(entry) = EXCHANGES.with(|exchanges| exchanges.borrow().last().map(render_exchange))
+    else {
+        crate::utils::warn("No Aichat exchange to save yet");
+        return Ok(());
+    };
+
+    let path = path
+        .map(String::from)
+        .unwrap_or_else(|| get_config().notes_file.to_string());
+    let expanded: String = api::call_function("expand", (path.as_str(),))?;
+    let mut notes = PathBuf::from(expanded);
+    if notes.is_relative() {
+        if let Some(root) = config::project_root() {
+            notes = root.join(notes);
+        }
+    }
+
+    match append(&notes, &entry) {
+        Ok(()) => crate::utils::info(&format!("Saved the Aichat exchange to {}", notes.display())),
+        Err(err) => crate::error::notify_error(&err.into()),
+    }
+    Ok(())
+}
+
+/// Appends an entry to the notes file, creating it and its directory first
+fn append(notes: &Path, entry: &str) -> std::io::Result<()> {
+    if let Some(dir) = notes.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let is_new = !notes.exists();
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(notes)?;
+    if is_new {
+        file.write_all(b"# AI notes\n")?;
+    }
+    file.write_all(entry.as_bytes())
+}
+
+/// Renders the exchanges as markdown, or None when there are none
+fn render(exchanges: &[Exchange]) -> Option<String> {
+    if exchanges.is_empty() {
+        return None;
+    }
+
+    let mut markdown = String::from("# Aichat transcript\n");
+    for exchange in exchanges {
+        markdown.push_str(&render_exchange(exchange));
+    }
+
+    Some(markdown)
+}
+
+/// Renders one exchange as a markdown section
+fn render_exchange(exchange: &Exchange) -> String {
+    let mut markdown = format!("\n## {} ({})\n\n", exchange.time, exchange.mode);
+    for (name, value) in [
+        ("File", &exchange.location),
+        ("Model", &exchange.model),
+        ("Session", &exchange.session),
+        ("RAG", &exchange.rag),
+    ] {
+        if let Some(value) = value {
+            markdown.push_str(&format!("- {}: {}\n", name, value));
+        }
+    }
+
+    for (title, text) in [
+        ("Prompt", &exchange.prompt),
+        ("Response", &exchange.response),
+    ] {
+        let fence = fence_for(text);
+        markdown.push_str(&format!(
+            "\n### {}\n\n{}\n{}\n{}\n",
+            title,
+            fence,
+            text.trim_end(),
+            fence
+        ));
+    }
+
+    markdown
+}
+
+/// A backtick fence longer than any backtick run in `text`, so code blocks
+/// inside prompts and responses don't end it early
+fn fence_for(text: &str) -> String {
+    let longest = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    "`".repeat(longest.max(3) + 1)
+}
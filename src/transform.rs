@@ -0,0 +1,59 @@
+use nvim_oxi::conversion::FromObject;
+use nvim_oxi::{Dictionary, Function, Object};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// Lua transformers registered with `setup({ transforms = { ... } })`, keyed by
+// the role, agent or macro they apply to
+thread_local! {
+    static TRANSFORMS: RefCell<HashMap<String, Function<String, Object>>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Replaces the registered transformers with the functions of `transforms`
+pub fn register(transforms: Dictionary) -> nvim_oxi::Result<()> {
+    let mut registered = HashMap::new();
+    for (name, transform) in transforms {
+        registered.insert(
+            name.to_string_lossy().into_owned(),
+            Function::<String, Object>::from_object(transform)?,
+        );
+    }
+
+    TRANSFORMS.with(|transforms| *transforms.borrow_mut() = registered);
+    Ok(())
+}
+
+/// Splits a response into lines, passing it through the transformer of
+/// `name` first if one is registered
+///
+/// A transformer may return a string or a list of lines. When it fails the
+/// response is used as is, so a broken transformer never loses an answer.
+pub fn apply(name: &str, response: String) -> Vec<String> {
+    let transform = TRANSFORMS.with(|transforms| transforms.borrow().get(name).cloned());
+
+    let transformed = transform.map(|transform| -> nvim_oxi::Result<Vec<String>> {
+        let result = transform.call(response.clone())?;
+        match String::from_object(result.clone()) {
+            Ok(text) => Ok(split_lines(&text)),
+            Err(_) => Vec::<String>::from_object(result).map_err(nvim_oxi::Error::from),
+        }
+    });
+
+    match transformed {
+        Some(Ok(lines)) => lines,
+        Some(Err(err)) => {
+            crate::utils::warn(&format!(
+                "Transform for {} failed, using the response as is: {}",
+                name, err
+            ));
+            split_lines(&response)
+        }
+        None => split_lines(&response),
+    }
+}
+
+/// Splits text into lines without a trailing empty line
+pub fn split_lines(text: &str) -> Vec<String> {
+    text.split_terminator('\n').map(String::from).collect()
+}
@@ -0,0 +1,141 @@
+use crate::config;
+use crate::error::{AichatError, Result};
+use nvim_oxi::api;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Commands allowed to run without confirmation, keyed by project root
+type Allowed = HashMap<String, Vec<String>>;
+
+/// Version of the allowed commands file written by this build
+///
+/// - 0: the bare map of project roots to commands
+/// - 1: `{ "version": 1, "projects": {...} }`
+const ALLOWED_VERSION: u32 = 1;
+
+/// An allowed commands file of any version
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedAllowed {
+    Versioned { version: u32, projects: Allowed },
+    Bare(Allowed),
+}
+
+/// The allowed commands file as this build writes it
+#[derive(Serialize)]
+struct AllowedFile<'a> {
+    version: u32,
+    projects: &'a Allowed,
+}
+
+/// The file keeping the allowed commands, in Neovim's data directory
+fn allowed_file() -> Result<PathBuf> {
+    let data: String = api::call_function("stdpath", ("data",))?;
+    Ok(PathBuf::from(data)
+        .join("aichat_nvim")
+        .join("allowed_commands.json"))
+}
+
+/// The project the commands are allowed for, the working directory's root
+fn project_key() -> String {
+    config::project_root()
+        .map(|root| root.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Reads the allowed commands, none when the file doesn't exist yet or
+/// can't be read, which is only warned about
+fn read_allowed() -> Result<Allowed> {
+    let path = allowed_file()?;
+    let parsed = match std::fs::read_to_string(&path) {
+        Ok(text) => parse_allowed(&text)?.ok_or_else(|| "it is not valid JSON".to_string()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Allowed::new()),
+        Err(err) => Err(err.to_string()),
+    };
+    Ok(parsed.unwrap_or_else(|reason| {
+        crate::utils::warn(&format!(
+            "Ignoring the allowed commands of {}: {}",
+            path.display(),
+            reason
+        ));
+        Allowed::new()
+    }))
+}
+
+/// The allowed commands of the text of the file, migrated from older
+/// versions, `None` when it doesn't parse
+fn parse_allowed(text: &str) -> Result<Option<Allowed>> {
+    match serde_json::from_str::<SavedAllowed>(text) {
+        Ok(SavedAllowed::Bare(projects)) => Ok(Some(projects)),
+        Ok(SavedAllowed::Versioned { version, projects }) if version <= ALLOWED_VERSION => {
+            Ok(Some(projects))
+        }
+        Ok(SavedAllowed::Versioned { version, .. }) => Err(AichatError::application(format!(
+            "The allowed commands file has version {version}, written by a newer aichat_nvim"
+        ))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Whether `command` was allowed always in the current project
+pub fn is_allowed(command: &str) -> Result<bool> {
+    Ok(read_allowed()?
+        .get(&project_key())
+        .is_some_and(|commands| commands.iter().any(|allowed| allowed == command)))
+}
+
+/// Allows `command` to run without confirmation in the current project
+pub fn allow_always(command: &str) -> Result<()> {
+    let mut allowed = read_allowed()?;
+    let commands = allowed.entry(project_key()).or_default();
+    if !commands.iter().any(|allowed| allowed == command) {
+        commands.push(command.to_string());
+    }
+
+    let path = allowed_file()?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let file = AllowedFile {
+        version: ALLOWED_VERSION,
+        projects: &allowed,
+    };
+    let text = serde_json::to_string_pretty(&file)
+        .map_err(|err| AichatError::application(err.to_string()))?;
+    // Replaced through a temporary file, an interrupted write never loses
+    // the commands allowed so far
+    let temporary = path.with_extension(format!("json.{}.tmp", std::process::id()));
+    std::fs::write(&temporary, text)?;
+    std::fs::rename(&temporary, &path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowed_files_of_version_0_are_migrated() {
+        let allowed = parse_allowed(r#"{"/src/app": ["make test"]}"#)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(allowed["/src/app"], ["make test"]);
+    }
+
+    #[test]
+    fn allowed_files_keep_their_version() {
+        let allowed = Allowed::from([("/src/app".to_string(), vec!["make".to_string()])]);
+        let text = serde_json::to_string(&AllowedFile {
+            version: ALLOWED_VERSION,
+            projects: &allowed,
+        })
+        .unwrap();
+
+        assert_eq!(text, r#"{"version":1,"projects":{"/src/app":["make"]}}"#);
+        assert_eq!(parse_allowed(&text).unwrap(), Some(allowed));
+        assert!(parse_allowed(r#"{"version":2,"projects":{}}"#).is_err());
+        assert_eq!(parse_allowed("not json").unwrap(), None);
+    }
+}
@@ -1,24 +1,647 @@
 use nvim_oxi::Result;
 use nvim_oxi::{
-    api::{self},
+    api::{
+        self,
+        opts::{CreateAutocmdOpts, OptionOpts, OptionScope::Local, SetKeymapOpts},
+        Buffer, Window,
+    },
     Array, Dictionary, Function, Object,
 };
-use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
-/// Displays an input prompt and returns user input, or None if cancelled
+/// Window settings of the floats showing long text, set with `float = { ... }`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct FloatOpts {
+    pub wrap: bool,
+    pub linebreak: bool,
+    pub concealcursor: Box<str>,
+    pub scrolloff: u32,
+    /// Height limit, the editor height is used when unset
+    pub max_height: Option<u32>,
+    /// Show the visible line range in the border when the content doesn't fit
+    pub scroll_indicator: bool,
+}
+
+impl Default for FloatOpts {
+    fn default() -> Self {
+        Self {
+            wrap: true,
+            linebreak: true,
+            concealcursor: Box::from("nc"),
+            scrolloff: 3,
+            max_height: None,
+            scroll_indicator: true,
+        }
+    }
+}
+
+/// Keys shared by every prompt and float of the plugin, set with `keys = { ... }`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Keys {
+    /// Confirms the proposed action
+    pub accept: Vec<String>,
+    /// Declines the proposed action
+    pub reject: Vec<String>,
+    /// Closes a float without doing anything
+    pub cancel: Vec<String>,
+    /// Keeps the hunk under the cursor of an applied answer
+    pub accept_hunk: Vec<String>,
+    /// Puts back the original lines of the hunk under the cursor
+    pub revert_hunk: Vec<String>,
+}
+
+impl Default for Keys {
+    fn default() -> Self {
+        Self {
+            accept: vec!["<CR>".into()],
+            reject: vec!["n".into()],
+            cancel: vec!["q".into(), "<Esc>".into()],
+            accept_hunk: vec!["ga".into()],
+            revert_hunk: vec!["gr".into()],
+        }
+    }
+}
+
+impl Keys {
+    /// Describes the keys of an action for the hints shown in floats
+    pub fn hint(keys: &[String]) -> String {
+        keys.join("/")
+    }
+}
+
+/// Opens a centered floating window showing `lines` in a read-only scratch buffer
+///
+/// The window is sized to its content but capped to the editor and to the
+/// configured `max_height`, so long content stays scrollable. The cancel keys
+/// close it, `<C-d>`/`<C-u>` and `gg`/`G` always scroll it.
 ///
 /// # Arguments
-/// * `prompt` - The prompt to display before the input field
+/// * `title` - The title shown in the window border
+/// * `lines` - The content of the window
 ///
 /// # Returns
-/// * `Result<Option<String>>` - User input or None if cancelled
-pub fn show_input_prompt(prompt: &str) -> Result<Option<Box<str>>> {
-    let input: String = api::call_function("input", (prompt,))?;
-    Ok(if input.is_empty() {
-        None
+/// * `Result<(Buffer, Window)>` - The scratch buffer and the window, for extra keymaps
+pub fn open_float(title: &str, lines: Vec<String>) -> Result<(Buffer, Window)> {
+    let float_opts = crate::config::get_config().float.clone();
+    let keys = crate::config::get_config().keys.clone();
+    let mut buffer = api::create_buf(false, true)?;
+
+    // Get editor dimensions
+    let current_window = api::get_current_win();
+    let width_editor = current_window.get_width()? as u32;
+    let height_editor = current_window.get_height()? as u32;
+
+    // Size to the content, leaving room for the border
+    let content_width = lines
+        .iter()
+        .map(|line| line.chars().count())
+        .max()
+        .unwrap_or(0) as u32;
+    let width = content_width
+        .max(title.chars().count() as u32 + 4)
+        .min(width_editor.saturating_sub(4))
+        .max(1);
+
+    // Wrapped lines take as many rows as they need at that width
+    let content_height: u32 = if float_opts.wrap {
+        lines
+            .iter()
+            .map(|line| (line.chars().count() as u32).div_ceil(width).max(1))
+            .sum()
     } else {
-        Some(input.into())
-    })
+        lines.len() as u32
+    };
+    let height = content_height
+        .min(height_editor.saturating_sub(4))
+        .min(float_opts.max_height.unwrap_or(u32::MAX))
+        .max(1);
+    let scrollable = content_height > height;
+
+    buffer.set_lines(0..0, false, lines)?;
+
+    // Make buffer read-only
+    let opts = OptionOpts::builder().scope(Local).buffer(&buffer).build();
+    api::set_option_value("modifiable", false, &opts)?;
+    api::set_option_value("buftype", "nofile", &opts)?;
+    api::set_option_value("bufhidden", "wipe", &opts)?;
+
+    let win_config = api::types::WindowConfig::builder()
+        .relative(api::types::WindowRelativeTo::Editor)
+        .width(width)
+        .height(height)
+        .row(height_editor.saturating_sub(height) / 2)
+        .col(width_editor.saturating_sub(width) / 2)
+        .style(api::types::WindowStyle::Minimal)
+        .border(api::types::WindowBorder::Rounded)
+        .title(api::types::WindowTitle::SimpleString(title.into()))
+        .title_pos(api::types::WindowTitlePosition::Center)
+        .build();
+
+    let window = api::open_win(&buffer, true, &win_config)?;
+
+    // Reading settings
+    let win_opts = OptionOpts::builder().scope(Local).win(&window).build();
+    api::set_option_value("wrap", float_opts.wrap, &win_opts)?;
+    api::set_option_value("linebreak", float_opts.linebreak, &win_opts)?;
+    api::set_option_value("concealcursor", &*float_opts.concealcursor, &win_opts)?;
+    api::set_option_value("scrolloff", float_opts.scrolloff as i64, &win_opts)?;
+
+    for lhs in &keys.cancel {
+        buffer.set_keymap(
+            api::types::Mode::Normal,
+            lhs,
+            ":q<CR>",
+            &SetKeymapOpts::builder().noremap(true).silent(true).build(),
+        )?;
+    }
+
+    // Keep the default scrolling motions even if the user remapped them globally
+    for lhs in ["<C-d>", "<C-u>", "gg", "G"] {
+        buffer.set_keymap(
+            api::types::Mode::Normal,
+            lhs,
+            lhs,
+            &SetKeymapOpts::builder().noremap(true).silent(true).build(),
+        )?;
+    }
+
+    if scrollable && float_opts.scroll_indicator {
+        show_scroll_position(&window)?;
+    }
+
+    Ok((buffer, window))
+}
+
+/// Keeps the visible line range of a float in its bottom border
+///
+/// # Arguments
+/// * `window` - The float to track
+///
+/// # Returns
+/// * `Result<()>` - Whether the indicator could be set up
+fn show_scroll_position(window: &Window) -> Result<()> {
+    let update = |window: &Window| -> Result<()> {
+        let handle = window.handle();
+        let top: i64 = api::call_function("line", ("w0", handle))?;
+        let bottom: i64 = api::call_function("line", ("w$", handle))?;
+        let total: i64 = api::call_function("line", ("$", handle))?;
+        let footer = format!(" {}-{}/{} ", top, bottom, total);
+
+        window.clone().set_config(
+            &api::types::WindowConfig::builder()
+                .footer(api::types::WindowTitle::SimpleString(footer.into()))
+                .footer_pos(api::types::WindowTitlePosition::Right)
+                .build(),
+        )?;
+        Ok(())
+    };
+
+    update(window)?;
+
+    let window = window.clone();
+    api::create_autocmd(
+        ["WinScrolled"],
+        &CreateAutocmdOpts::builder()
+            .patterns([window.handle().to_string().as_str()])
+            .callback(move |_| -> Result<bool> {
+                // Returning true removes the autocommand once the float is gone
+                if !window.is_valid() {
+                    return Ok(true);
+                }
+                update(&window)?;
+                Ok(false)
+            })
+            .build(),
+    )?;
+
+    Ok(())
+}
+
+/// Maps each key of `lhs` to `callback` in normal mode, local to `buffer`
+///
+/// # Arguments
+/// * `buffer` - The buffer the keymaps belong to
+/// * `lhs` - The keys to map
+/// * `desc` - Description of the keymaps
+/// * `callback` - What the keys do
+///
+/// # Returns
+/// * `Result<()>` - Whether every key could be mapped
+pub fn set_keymaps<F>(buffer: &mut Buffer, lhs: &[String], desc: &str, callback: F) -> Result<()>
+where
+    F: Fn() + Clone + 'static,
+{
+    for key in lhs {
+        let callback = callback.clone();
+        buffer.set_keymap(
+            api::types::Mode::Normal,
+            key,
+            "",
+            &SetKeymapOpts::builder()
+                .callback(move |_| callback())
+                .noremap(true)
+                .silent(true)
+                .desc(desc)
+                .build(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Closes a float if it is still open
+pub fn close(window: &Window) {
+    if window.is_valid() {
+        let _ = window.clone().close(true);
+    }
+}
+
+/// Opens an editable float pre-filled with `text` for writing a multi-line prompt
+///
+/// The accept keys in normal mode hand the text to `on_submit` and close the
+/// float, the cancel keys close it without sending anything. `<Up>`,
+/// `<Down>` and `<C-r>` recall past prompts.
+///
+/// # Arguments
+/// * `title` - The title shown in the window border
+/// * `text` - The text the composer starts with
+/// * `on_submit` - Receives the composed text
+///
+/// # Returns
+/// * `Result<()>` - Whether the composer could be opened
+pub fn open_composer<F>(title: &str, text: &str, on_submit: F) -> Result<()>
+where
+    F: FnOnce(String) + 'static,
+{
+    let keys = crate::config::get_config().keys.clone();
+    let mut buffer = api::create_buf(false, true)?;
+    let lines: Vec<&str> = text.lines().collect();
+
+    let current_window = api::get_current_win();
+    let width_editor = current_window.get_width()? as u32;
+    let height_editor = current_window.get_height()? as u32;
+    let width = width_editor.saturating_sub(4).clamp(1, 80);
+    let height = (lines.len() as u32 + 2)
+        .max(5)
+        .min(height_editor.saturating_sub(4))
+        .max(1);
+
+    buffer.set_lines(0..0, false, lines)?;
+
+    let opts = OptionOpts::builder().scope(Local).buffer(&buffer).build();
+    api::set_option_value("buftype", "nofile", &opts)?;
+    api::set_option_value("bufhidden", "wipe", &opts)?;
+    api::set_option_value("filetype", "markdown", &opts)?;
+
+    let footer = format!(
+        " {} send  {} cancel  <Up>/<C-r> history ",
+        Keys::hint(&keys.accept),
+        Keys::hint(&keys.cancel)
+    );
+    let win_config = api::types::WindowConfig::builder()
+        .relative(api::types::WindowRelativeTo::Editor)
+        .width(width)
+        .height(height)
+        .row(height_editor.saturating_sub(height) / 2)
+        .col(width_editor.saturating_sub(width) / 2)
+        .border(api::types::WindowBorder::Rounded)
+        .title(api::types::WindowTitle::SimpleString(title.into()))
+        .title_pos(api::types::WindowTitlePosition::Center)
+        .footer(api::types::WindowTitle::SimpleString(footer.into()))
+        .footer_pos(api::types::WindowTitlePosition::Right)
+        .build();
+    let window = api::open_win(&buffer, true, &win_config)?;
+
+    // The keymaps can fire more than once, the text is only sent once
+    let on_submit = Rc::new(RefCell::new(Some(on_submit)));
+    let submit_buffer = buffer.clone();
+    let submit_window = window.clone();
+    set_keymaps(&mut buffer, &keys.accept, "Send the prompt", move || {
+        let text = submit_buffer
+            .get_lines(.., false)
+            .map(|lines| lines.map(|line| line.to_string_lossy().into_owned()))
+            .map(|lines| lines.collect::<Vec<_>>().join("\n"))
+            .unwrap_or_default();
+        let _ = submit_window.clone().close(true);
+        if let Some(on_submit) = on_submit.borrow_mut().take() {
+            on_submit(text);
+        }
+    })?;
+
+    let cancel_window = window.clone();
+    set_keymaps(
+        &mut buffer,
+        &keys.cancel,
+        "Close without sending",
+        move || {
+            let _ = cancel_window.clone().close(true);
+        },
+    )?;
+
+    set_history_keymaps(&mut buffer, &window)?;
+    Ok(())
+}
+
+/// Where the composer is in the prompt history, most recent entry first,
+/// and the text it had before the first step back
+struct HistoryCursor {
+    entries: Vec<String>,
+    index: Option<usize>,
+    draft: String,
+}
+
+/// Readline-style recall of the prompt history in the composer
+///
+/// `<Up>` on the first line and `<Down>` on the last one step through the
+/// past instructions, stepping past the newest one restores the draft.
+/// Elsewhere they move the cursor as usual. `<C-r>` in normal mode searches
+/// the history with the picker, insert mode keeps it for pasting registers.
+fn set_history_keymaps(buffer: &mut Buffer, window: &Window) -> Result<()> {
+    let cursor = Rc::new(RefCell::new(HistoryCursor {
+        entries: crate::history::instructions(),
+        index: None,
+        draft: String::new(),
+    }));
+
+    let both = &[api::types::Mode::Normal, api::types::Mode::Insert];
+    for (lhs, desc, older) in [
+        ("<Up>", "Previous prompt from the history", true),
+        ("<Down>", "Next prompt from the history", false),
+    ] {
+        let cursor = cursor.clone();
+        let history_buffer = buffer.clone();
+        let history_window = window.clone();
+        set_mode_keymaps(buffer, both, lhs, desc, move || {
+            let _ = step_history(&history_buffer, &history_window, &cursor, older);
+        })?;
+    }
+
+    let search_buffer = buffer.clone();
+    let search_window = window.clone();
+    let search = move || {
+        let entries = crate::history::instructions();
+        if entries.is_empty() {
+            crate::utils::info("The prompt history is empty");
+            return;
+        }
+        let opts = SelectOpts {
+            prompt: Some("Prompt history".to_string()),
+            kind: None,
+        };
+        let buffer = search_buffer.clone();
+        let window = search_window.clone();
+        let _ = vim_ui_select(entries, Some(opts), move |entry, _index| {
+            if let Some(entry) = entry {
+                if window.is_valid() {
+                    let _ = set_composer_text(&buffer, &window, &entry);
+                    let _ = api::set_current_win(&window);
+                }
+            }
+        });
+    };
+    let normal = &[api::types::Mode::Normal];
+    set_mode_keymaps(buffer, normal, "<C-r>", "Search the prompt history", search)?;
+
+    Ok(())
+}
+
+/// Moves the cursor a line, or to the next history entry on the first or
+/// last line
+fn step_history(
+    buffer: &Buffer,
+    window: &Window,
+    cursor: &RefCell<HistoryCursor>,
+    older: bool,
+) -> Result<()> {
+    let (row, col) = window.get_cursor()?;
+    let line_count = buffer.line_count()?;
+    let target = match older {
+        true if row > 1 => Some(row - 1),
+        false if row < line_count => Some(row + 1),
+        _ => None,
+    };
+    if let Some(target) = target {
+        let width = buffer
+            .get_lines(target - 1..target, false)?
+            .next()
+            .map_or(0, |line| line.to_string_lossy().len());
+        return Ok(window.clone().set_cursor(target, col.min(width))?);
+    }
+
+    let mut cursor = cursor.borrow_mut();
+    let index = match (cursor.index, older) {
+        (None, true) if !cursor.entries.is_empty() => {
+            cursor.draft = buffer
+                .get_lines(.., false)?
+                .map(|line| line.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+                .join("\n");
+            Some(0)
+        }
+        (Some(index), true) => Some((index + 1).min(cursor.entries.len() - 1)),
+        (Some(0), false) => None,
+        (Some(index), false) => Some(index - 1),
+        (None, _) => return Ok(()),
+    };
+    cursor.index = index;
+    let text = match index {
+        Some(index) => cursor.entries[index].clone(),
+        None => cursor.draft.clone(),
+    };
+    set_composer_text(buffer, window, &text)
+}
+
+/// Replaces the text of the composer, leaving the cursor at its end
+fn set_composer_text(buffer: &Buffer, window: &Window, text: &str) -> Result<()> {
+    let lines: Vec<&str> = text.split('\n').collect();
+    let last = lines.last().map_or(0, |line| line.len());
+    let line_count = lines.len();
+    buffer.clone().set_lines(.., false, lines)?;
+    window.clone().set_cursor(line_count, last)?;
+    Ok(())
+}
+
+/// Sets a buffer-local mapping in each of `modes` calling `callback`
+fn set_mode_keymaps<F>(
+    buffer: &mut Buffer,
+    modes: &[api::types::Mode],
+    lhs: &str,
+    desc: &str,
+    callback: F,
+) -> Result<()>
+where
+    F: Fn() + Clone + 'static,
+{
+    for &mode in modes {
+        let callback = callback.clone();
+        buffer.set_keymap(
+            mode,
+            lhs,
+            "",
+            &SetKeymapOpts::builder()
+                .callback(move |_| callback())
+                .noremap(true)
+                .silent(true)
+                .desc(desc)
+                .build(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Opens `lines` in a new scratch buffer in a split below the current window
+///
+/// # Arguments
+/// * `lines` - The content of the buffer
+/// * `filetype` - The filetype of the buffer, for highlighting
+///
+/// # Returns
+/// * `Result<Buffer>` - The scratch buffer
+pub fn open_scratch(lines: Vec<String>, filetype: &str) -> Result<Buffer> {
+    let mut buffer = api::create_buf(true, true)?;
+    buffer.set_lines(0..0, false, lines)?;
+
+    let opts = OptionOpts::builder().scope(Local).buffer(&buffer).build();
+    api::set_option_value("buftype", "nofile", &opts)?;
+    api::set_option_value("bufhidden", "hide", &opts)?;
+    api::set_option_value("filetype", filetype, &opts)?;
+
+    api::command("belowright split")?;
+    api::get_current_win().set_buf(&buffer)?;
+
+    Ok(buffer)
+}
+
+/// Whether no UI is attached, as with `nvim --headless` or a scripted
+/// `--embed` instance, so nobody can answer a prompt
+pub fn is_headless() -> bool {
+    api::list_uis().next().is_none()
+}
+
+/// Asks for a line of text with `vim.ui.input`, pre-filled with `default`
+///
+/// The prompt is opened from a scheduled callback, so it never fires inside
+/// a fast event or while text is locked, and cmdline UIs replacing
+/// `vim.ui.input` (noice, dressing, snacks) show it their way. `on_input`
+/// receives the text, or `None` when the prompt was cancelled or left
+/// empty; its errors are notified. Without a UI nobody can answer, so
+/// `on_input` receives `None` right away.
+///
+/// # Arguments
+/// * `prompt` - The prompt to display before the input field
+/// * `default` - The text the input starts with
+/// * `on_input` - Function to call with the user input
+pub fn input<F>(prompt: &str, default: &str, on_input: F)
+where
+    F: FnOnce(Option<String>) -> crate::error::Result<()> + 'static,
+{
+    if is_headless() {
+        crate::utils::warn(&format!(
+            "No UI is attached to answer '{}', pass the text as a command argument",
+            prompt.trim_end()
+        ));
+        if let Err(err) = on_input(None) {
+            crate::error::notify_error(&err);
+        }
+        return;
+    }
+
+    let prompt = format!("{} ", prompt.trim_end());
+    let default = default.to_string();
+
+    nvim_oxi::schedule(move |_| -> Result<()> {
+        let callback = Function::<Option<String>, ()>::from_fn_once(move |input| {
+            let input = input.filter(|input| !input.is_empty());
+            if let Err(err) = on_input(input) {
+                crate::error::notify_error(&err);
+            }
+            Ok::<_, nvim_oxi::Error>(())
+        });
+        let args = Array::from_iter([
+            Object::from(prompt),
+            Object::from(default),
+            Object::from(callback),
+        ]);
+        if let Err(err) = api::call_function::<_, Object>("luaeval", (INPUT_SOURCE, args)) {
+            crate::error::notify_error(&err.into());
+        }
+        Ok(())
+    });
+}
+
+/// Asks for text through `vim.ui.input`, `_A` holding the prompt, the
+/// default text and the callback receiving the answer
+const INPUT_SOURCE: &str = r#"vim.ui.input({ prompt = _A[1], default = _A[2] }, _A[3])"#;
+
+/// Asks a yes/no question with `vim.ui.select`
+///
+/// The question goes through the configured picker, so cmdline and picker
+/// plugins show it their way. `on_answer` receives whether "Yes" was picked,
+/// dismissing the picker answers no; its errors are notified. Without a UI
+/// the question is only echoed and answered no.
+///
+/// # Arguments
+/// * `question` - The question to display
+/// * `on_answer` - Function to call with whether the user answered yes
+pub fn confirm<F>(question: &str, on_answer: F)
+where
+    F: FnOnce(bool) -> crate::error::Result<()> + Send + 'static,
+{
+    if is_headless() {
+        crate::utils::warn(&format!("{} No UI is attached, answering no", question));
+    }
+    choose(question, &["Yes", "No"], move |choice| {
+        on_answer(choice == 1)
+    });
+}
+
+/// Asks a question with `vim.ui.select`, answered with one of `choices`
+///
+/// # Arguments
+/// * `question` - The question to display
+/// * `choices` - The answers, in the order they are listed
+/// * `on_choice` - Function to call with the 1-based index of the answer, 0
+///   when the picker was dismissed or no UI is attached; its errors are
+///   notified
+pub fn choose<F>(question: &str, choices: &[&str], on_choice: F)
+where
+    F: FnOnce(usize) -> crate::error::Result<()> + Send + 'static,
+{
+    let answer = move |choice| {
+        if let Err(err) = on_choice(choice) {
+            crate::error::notify_error(&err);
+        }
+    };
+    if is_headless() {
+        answer(0);
+        return;
+    }
+
+    // The picker callback may be called again, the question is answered once
+    let pending = Arc::new(Mutex::new(Some(answer)));
+    let take =
+        |pending: &Mutex<Option<_>>| pending.lock().unwrap_or_else(|e| e.into_inner()).take();
+
+    let on_select = {
+        let pending = pending.clone();
+        move |_: Option<String>, index: Option<usize>| {
+            if let Some(answer) = take(&pending) {
+                answer(index.unwrap_or(0));
+            }
+        }
+    };
+    let items: Vec<String> = choices.iter().map(|choice| choice.to_string()).collect();
+    if let Err(err) = vim_ui_select(items, Some(SelectOpts::with_prompt(question)), on_select) {
+        crate::error::notify_error(&err.into());
+        // The picker didn't open, nobody can answer
+        if let Some(answer) = take(&pending) {
+            answer(0);
+        }
+    }
 }
 
 /// Options for vim.ui.select() wrapper
@@ -57,8 +680,9 @@ impl Default for SelectOpts {
 
 /// Wrapper around vim.ui.select() that provides a Rust-friendly interface
 ///
-/// This function calls Neovim's built-in vim.ui.select() which respects user's
-/// UI configuration (telescope, fzf, etc.) rather than using our custom floating window.
+/// The selection is shown with the picker configured in `setup()` (telescope,
+/// fzf-lua, snacks, mini.pick), falling back to Neovim's built-in vim.ui.select()
+/// which respects user's UI configuration.
 ///
 /// # Arguments
 /// * `items` - Vector of items to select from (accepts both String and &str)
@@ -99,6 +723,13 @@ where
         return Ok(());
     }
 
+    // Nobody can pick without a UI, and the picker plugins need one
+    if is_headless() {
+        crate::utils::warn("No UI is attached to pick from, pass the value as a command argument");
+        callback(None, None);
+        return Ok(());
+    }
+
     let opts = opts.unwrap_or_default();
 
     // Convert items to Lua array - need to build it manually
@@ -150,17 +781,11 @@ where
     // Convert the Rust callback to a Lua function
     let lua_callback: Function<nvim_oxi::Array, ()> = Function::from_fn(callback_wrapper);
 
-    // Call vim.ui.select with the prepared arguments
-    api::call_function::<_, ()>(
-        "vim.ui.select",
-        (
-            items_array,
-            Object::from(opts_dict),
-            Object::from(lua_callback),
-        ),
-    )?;
+    // Copy the backend out so the config lock isn't held while the picker runs
+    let backend = crate::config::get_config().picker;
 
-    Ok(())
+    // Open the configured picker with the prepared arguments
+    crate::picker::open(backend, items_array, opts_dict, lua_callback)
 }
 
 /// Convenience function for vim_ui_select that accepts a slice of string-like items
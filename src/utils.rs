@@ -1,7 +1,35 @@
 use nvim_oxi::api::{self, types::LogLevel};
+use nvim_oxi::libuv::TimerHandle;
+use once_cell::sync::Lazy;
+use std::path::{Component, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
 
 /// Utility functions for common Neovim operations
 
+/// How long request outcomes are collected before a summary is shown
+const SUMMARY_WINDOW: Duration = Duration::from_secs(2);
+
+/// Outcome of a request, as counted in the batched summary
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Done,
+    Failed,
+    Retrying,
+}
+
+/// Outcomes counted since the summary window opened
+#[derive(Default)]
+struct Summary {
+    open: bool,
+    done: usize,
+    failed: usize,
+    retrying: usize,
+}
+
+// Global static to store the outcomes waiting for the next summary
+static SUMMARY: Lazy<Mutex<Summary>> = Lazy::new(|| Mutex::new(Summary::default()));
+
 /// Shows an info notification to the user
 ///
 /// # Arguments
@@ -22,7 +50,6 @@ pub fn error(msg: &str) {
 ///
 /// # Arguments
 /// * `msg` - The warning message to display
-#[allow(dead_code)]
 pub fn warn(msg: &str) {
     let _ = api::notify(msg, LogLevel::Warn, &Default::default());
 }
@@ -45,3 +72,98 @@ pub fn trace(msg: &str) {
     let _ = api::notify(msg, LogLevel::Trace, &Default::default());
 }
 
+/// Copies text to the unnamed register, and to the `+` register when a
+/// clipboard provider is available
+///
+/// # Arguments
+/// * `text` - The text to copy
+pub fn copy_to_registers(text: &str) {
+    let _ = api::call_function::<_, i64>("setreg", ("\"", text));
+    if api::call_function::<_, i64>("has", ("clipboard",)).unwrap_or(0) == 1 {
+        let _ = api::call_function::<_, i64>("setreg", ("+", text));
+    }
+}
+
+/// Checks that a path proposed by aichat stays inside the working directory
+///
+/// # Arguments
+/// * `path` - The path as written in the response
+///
+/// # Returns
+/// * `Result<PathBuf>` - The relative path, or an error for absolute paths and `..`
+pub fn relative_path(path: &str) -> crate::error::Result<PathBuf> {
+    let path = PathBuf::from(path);
+    let inside = !path.as_os_str().is_empty()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+    if inside {
+        Ok(path)
+    } else {
+        Err(crate::error::AichatError::application(format!(
+            "Refusing to write {} outside of the working directory",
+            path.display()
+        )))
+    }
+}
+
+/// Reports the outcome of a request without flooding the notification area
+///
+/// A lone request shows `msg` right away. While other requests are still
+/// pending, outcomes are collected instead and shown as a single summary
+/// ("3 done, 1 failed, 2 pending") once the window elapses.
+///
+/// # Arguments
+/// * `outcome` - What happened to the request
+/// * `msg` - The message shown when the outcome is not batched
+/// * `pending` - The number of requests still running
+pub fn report(outcome: Outcome, msg: &str, pending: usize) {
+    let mut summary = SUMMARY.lock().unwrap_or_else(|e| e.into_inner());
+
+    if !summary.open && pending == 0 {
+        match outcome {
+            Outcome::Done => info(msg),
+            Outcome::Retrying => warn(msg),
+            // Failures are already shown with their details
+            Outcome::Failed => {}
+        }
+        return;
+    }
+
+    match outcome {
+        Outcome::Done => summary.done += 1,
+        Outcome::Failed => summary.failed += 1,
+        Outcome::Retrying => summary.retrying += 1,
+    }
+
+    if !summary.open {
+        summary.open = true;
+        let _ = TimerHandle::once(SUMMARY_WINDOW, || {
+            // Timer callbacks run in a fast event, so defer to a safe point
+            nvim_oxi::schedule(|_| -> nvim_oxi::Result<()> {
+                flush_summary();
+                Ok(())
+            });
+        });
+    }
+}
+
+/// Shows the collected outcomes as one notification and closes the window
+fn flush_summary() {
+    let summary = std::mem::take(&mut *SUMMARY.lock().unwrap_or_else(|e| e.into_inner()));
+    let pending = crate::job_runner::in_flight_count();
+
+    let mut parts = vec![format!("{} done", summary.done)];
+    if summary.failed > 0 {
+        parts.push(format!("{} failed", summary.failed));
+    }
+    if summary.retrying > 0 {
+        parts.push(format!("{} retried", summary.retrying));
+    }
+    if pending > 0 {
+        parts.push(format!("{} pending", pending));
+    }
+
+    info(&format!("Aichat: {}", parts.join(", ")));
+}
@@ -0,0 +1,523 @@
+use crate::convert::Format;
+use crate::error::{notify_error, AichatError, Result};
+use crate::selection::Selection;
+use crate::{job_runner, ui, utils};
+use nvim_oxi::{
+    api::{
+        self,
+        opts::{OptionOpts, OptionScope::Local},
+        Buffer,
+    },
+    conversion::FromObject,
+    Array, Dictionary, Function, Object,
+};
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// A check an answer has to pass before it is written
+#[derive(Clone)]
+enum Validator {
+    /// Parses as JSON, YAML or TOML
+    Format(Format),
+    /// Every `(`, `[` and `{` is closed, outside of strings and comments
+    BalancedBraces,
+    /// `cargo check` passes with the answer in a copy of the file's
+    /// workspace
+    CargoCheck,
+    /// A Lua function given the answer, returning `nil` or `true` when it is
+    /// valid and a message otherwise
+    Lua(Function<String, Object>),
+}
+
+impl Validator {
+    /// Reads a validator name or a Lua function
+    fn from_object(obj: Object) -> nvim_oxi::Result<Self> {
+        if let Ok(function) = Function::<String, Object>::from_object(obj.clone()) {
+            return Ok(Self::Lua(function));
+        }
+        let name = String::from_object(obj)?;
+        match name.as_str() {
+            "json" => Ok(Self::Format(Format::Json)),
+            "yaml" => Ok(Self::Format(Format::Yaml)),
+            "toml" => Ok(Self::Format(Format::Toml)),
+            "balanced_braces" => Ok(Self::BalancedBraces),
+            "cargo_check" => Ok(Self::CargoCheck),
+            _ => Err(AichatError::application(format!(
+                "Unknown Aichat validator {}, use json, yaml, toml, balanced_braces, \
+                 cargo_check or a function",
+                name
+            ))
+            .into()),
+        }
+    }
+
+    /// Checks the answer, returning why it is rejected
+    ///
+    /// `cargo_check` runs in the background, see `CargoCheck`, so it passes
+    /// here.
+    fn check(&self, text: &str) -> std::result::Result<(), String> {
+        match self {
+            Self::Format(format) => format
+                .validate(text)
+                .map_err(|err| format!("not valid {}: {}", format.name(), err)),
+            Self::BalancedBraces => balanced_braces(text),
+            Self::CargoCheck => Ok(()),
+            Self::Lua(function) => match function.call(text.to_string()) {
+                Ok(result) if result.is_nil() => Ok(()),
+                Ok(result) => match bool::from_object(result.clone()) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => Err("rejected by the validator".to_string()),
+                    Err(_) => Err(String::from_object(result)
+                        .unwrap_or_else(|_| "rejected by the validator".to_string())),
+                },
+                Err(err) => Err(format!("the validator failed: {}", err)),
+            },
+        }
+    }
+}
+
+// Validators registered with `setup({ validators = { ... } })`, keyed by
+// filetype
+thread_local! {
+    static VALIDATORS: RefCell<HashMap<String, Vec<Validator>>> = RefCell::new(HashMap::new());
+}
+
+/// Replaces the registered validators with the ones of `validators`, a
+/// validator or a list of them per filetype
+pub fn register(validators: Dictionary) -> nvim_oxi::Result<()> {
+    let mut registered = HashMap::new();
+    for (filetype, value) in validators {
+        let list = match Array::from_object(value.clone()) {
+            Ok(list) => list.into_iter().collect(),
+            _ => vec![value],
+        };
+        registered.insert(
+            filetype.to_string_lossy().into_owned(),
+            list.into_iter()
+                .map(Validator::from_object)
+                .collect::<nvim_oxi::Result<Vec<_>>>()?,
+        );
+    }
+
+    VALIDATORS.with(|validators| *validators.borrow_mut() = registered);
+    Ok(())
+}
+
+/// What to do with an answer after validation
+pub enum Verdict {
+    /// Passed, or failed and written anyway
+    Write,
+    /// Send the request again
+    Retry,
+    /// Don't write it
+    Reject,
+}
+
+/// Runs the validators of the buffer's filetype, and the format a request
+/// asked for, on the answer about to replace `selection`
+///
+/// The first failure asks whether to retry, write anyway or give up, and
+/// `on_verdict` runs once it is answered. `cargo_check` runs last, on a
+/// background thread, once the other validators passed.
+pub fn check<F>(
+    buffer: &Buffer,
+    selection: &Selection,
+    lines: &[String],
+    format: Option<Format>,
+    on_verdict: F,
+) -> Result<()>
+where
+    F: FnOnce(Verdict) -> Result<()> + Send + 'static,
+{
+    let opts = OptionOpts::builder().scope(Local).buffer(buffer).build();
+    let filetype: String = api::get_option_value("filetype", &opts).unwrap_or_default();
+
+    let mut validators: Vec<Validator> = format.map(Validator::Format).into_iter().collect();
+    VALIDATORS.with(|registered| {
+        validators.extend(
+            registered
+                .borrow()
+                .get(&filetype)
+                .cloned()
+                .unwrap_or_default(),
+        );
+    });
+
+    let text = lines.join("\n");
+    if let Some(failure) = validators
+        .iter()
+        .find_map(|validator| validator.check(&text).err())
+    {
+        ask(&failure, on_verdict);
+        return Ok(());
+    }
+
+    let cargo_check = match validators
+        .iter()
+        .any(|validator| matches!(validator, Validator::CargoCheck))
+    {
+        true => CargoCheck::prepare(buffer, selection, &text),
+        false => None,
+    };
+    let Some(cargo_check) = cargo_check else {
+        return on_verdict(Verdict::Write);
+    };
+    utils::info("Running cargo check on the Aichat answer");
+    job_runner::run_in_background(
+        move || cargo_check.run(),
+        move |outcome| {
+            let result = match outcome {
+                CargoOutcome::Passed => on_verdict(Verdict::Write),
+                CargoOutcome::NotRun(reason) => {
+                    utils::warn(&format!("cargo check did not run: {}", reason));
+                    on_verdict(Verdict::Write)
+                }
+                CargoOutcome::Rejected(failure) => {
+                    ask(&failure, on_verdict);
+                    Ok(())
+                }
+            };
+            if let Err(err) = result {
+                notify_error(&err);
+            }
+        },
+    )
+}
+
+/// Asks what to do with an answer that failed validation
+fn ask<F>(failure: &str, on_verdict: F)
+where
+    F: FnOnce(Verdict) -> Result<()> + Send + 'static,
+{
+    // Compiler output can be long, the dialog shows its start
+    let message = failure.lines().take(10).collect::<Vec<_>>().join("\n");
+    let question = format!("The Aichat answer is {}", message);
+    ui::choose(
+        &question,
+        &["Retry", "Insert anyway", "Cancel"],
+        move |choice| {
+            on_verdict(match choice {
+                1 => Verdict::Retry,
+                2 => Verdict::Write,
+                _ => Verdict::Reject,
+            })
+        },
+    );
+}
+
+/// Checks that brackets are closed in order, skipping string literals and
+/// comments
+fn balanced_braces(text: &str) -> std::result::Result<(), String> {
+    let mut open: Vec<(char, usize)> = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            '"' => {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        '\n' => line += 1,
+                        _ => {}
+                    }
+                }
+            }
+            // A char literal like '{' or '\n', not a lifetime
+            '\'' => {
+                let mut ahead = chars.clone();
+                match (ahead.next(), ahead.next()) {
+                    (Some('\\'), _) => {
+                        for c in chars.by_ref().skip(2) {
+                            if c == '\'' {
+                                break;
+                            }
+                        }
+                    }
+                    (Some(_), Some('\'')) => {
+                        chars.nth(1);
+                    }
+                    _ => {}
+                }
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        line += 1;
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = ' ';
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        line += 1;
+                    }
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+            }
+            '(' | '[' | '{' => open.push((c, line)),
+            ')' | ']' | '}' => {
+                let expected = match c {
+                    ')' => '(',
+                    ']' => '[',
+                    _ => '{',
+                };
+                match open.pop() {
+                    Some((opened, _)) if opened == expected => {}
+                    Some((opened, opened_line)) => {
+                        return Err(format!(
+                            "unbalanced: `{}` on line {} closes `{}` of line {}",
+                            c, line, opened, opened_line
+                        ))
+                    }
+                    None => {
+                        return Err(format!(
+                            "unbalanced: `{}` on line {} closes nothing",
+                            c, line
+                        ))
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    match open.last() {
+        Some((opened, opened_line)) => Err(format!(
+            "unbalanced: `{}` of line {} is never closed",
+            opened, opened_line
+        )),
+        None => Ok(()),
+    }
+}
+
+/// How a `cargo check` of an answer went
+enum CargoOutcome {
+    Passed,
+    /// With the errors reported for the file
+    Rejected(String),
+    /// Why cargo could not check the answer, which doesn't reject it
+    NotRun(String),
+}
+
+/// A `cargo check` of an answer, run on a copy of the workspace of the file
+/// so the file on disk is never touched
+///
+/// The copy and its target directory are kept in
+/// `stdpath("cache")/aichat_nvim/cargo_check`, one pair per workspace, so
+/// the dependencies are only built by the first check.
+struct CargoCheck {
+    path: PathBuf,
+    candidate: String,
+    cache: PathBuf,
+}
+
+impl CargoCheck {
+    /// Reads what the check needs from Neovim, `None` when the buffer is
+    /// not a file on disk
+    fn prepare(buffer: &Buffer, selection: &Selection, text: &str) -> Option<Self> {
+        let path = buffer.get_name().ok().filter(|path| path.is_file())?;
+        let candidate = candidate_text(buffer, selection, text).ok()?;
+        let cache: String = api::call_function("stdpath", ("cache",)).ok()?;
+        Some(Self {
+            path,
+            candidate,
+            cache: Path::new(&cache).join("aichat_nvim").join("cargo_check"),
+        })
+    }
+
+    /// Copies the workspace with the answer in place and checks it, on a
+    /// background thread
+    ///
+    /// Only errors reported for the file count, so existing errors elsewhere
+    /// in the workspace don't reject every answer.
+    fn run(self) -> CargoOutcome {
+        let Some(dir) = self.path.parent() else {
+            return CargoOutcome::NotRun("the file has no directory".into());
+        };
+        let root = match workspace_root(dir) {
+            Ok(root) => root,
+            Err(reason) => return CargoOutcome::NotRun(reason),
+        };
+        let Ok(relative) = self.path.strip_prefix(&root) else {
+            return CargoOutcome::NotRun("the file is outside of its workspace".into());
+        };
+
+        let mut hasher = DefaultHasher::new();
+        root.hash(&mut hasher);
+        let scratch = self.cache.join(format!("{:016x}", hasher.finish()));
+        let copy = scratch.join("workspace");
+        let copied = match std::fs::remove_dir_all(&copy) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+            _ => copy_workspace(&root, &copy),
+        }
+        .and_then(|()| std::fs::write(copy.join(relative), &self.candidate));
+        if let Err(err) = copied {
+            return CargoOutcome::NotRun(format!("copying the workspace failed: {}", err));
+        }
+
+        let output = Command::new("cargo")
+            .args(["check", "--quiet", "--message-format", "short"])
+            .current_dir(copy.join(relative).parent().unwrap_or(&copy))
+            .env("CARGO_TARGET_DIR", scratch.join("target"))
+            .stdin(Stdio::null())
+            .output();
+        let output = match output {
+            Ok(output) => output,
+            Err(err) => return CargoOutcome::NotRun(err.to_string()),
+        };
+        if output.status.success() {
+            return CargoOutcome::Passed;
+        }
+
+        let file_name = path_tail(&self.path);
+        let errors: Vec<String> = String::from_utf8_lossy(&output.stderr)
+            .lines()
+            .filter(|line| line.contains(&file_name) && line.contains("error"))
+            .map(String::from)
+            .collect();
+        match errors.is_empty() {
+            true => CargoOutcome::Passed,
+            false => {
+                CargoOutcome::Rejected(format!("rejected by cargo check:\n{}", errors.join("\n")))
+            }
+        }
+    }
+}
+
+/// The root directory of the cargo workspace `dir` belongs to
+fn workspace_root(dir: &Path) -> std::result::Result<PathBuf, String> {
+    let output = Command::new("cargo")
+        .args(["locate-project", "--workspace", "--message-format", "plain"])
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|err| err.to_string())?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    let manifest = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    manifest
+        .parent()
+        .map(Path::to_path_buf)
+        .ok_or_else(|| "cargo found no workspace".into())
+}
+
+/// Copies the files of a workspace, leaving out `target` and `.git`
+fn copy_workspace(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == "target" || name == ".git" {
+            continue;
+        }
+        let source = entry.path();
+        if source.is_dir() {
+            copy_workspace(&source, &to.join(&name))?;
+        } else {
+            std::fs::copy(&source, to.join(&name))?;
+        }
+    }
+    Ok(())
+}
+
+/// The text of the buffer with the answer in place of the selection
+fn candidate_text(buffer: &Buffer, selection: &Selection, text: &str) -> Result<String> {
+    let lines: Vec<String> = buffer
+        .get_lines(0..buffer.line_count()?, false)?
+        .map(|line| line.to_string_lossy().into_owned())
+        .collect();
+    let line1 = selection.line1.saturating_sub(1).min(lines.len());
+    let line2 = selection.line2.clamp(line1, lines.len());
+
+    let mut replacement = text.to_string();
+    if let Some((start_col, end_col)) = selection.columns {
+        let first = lines.get(line1).map_or("", String::as_str);
+        let last = lines
+            .get(line2.saturating_sub(1))
+            .map_or("", String::as_str);
+        replacement = format!(
+            "{}{}{}",
+            first.get(..start_col).unwrap_or(first),
+            replacement,
+            last.get(end_col..).unwrap_or_default()
+        );
+    }
+
+    let mut candidate = lines[..line1].to_vec();
+    candidate.push(replacement);
+    candidate.extend_from_slice(&lines[line2..]);
+    Ok(candidate.join("\n") + "\n")
+}
+
+/// The file name with its parent directory, as cargo's short messages show
+/// it relative to the workspace
+fn path_tail(path: &Path) -> String {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned());
+    let parent = path
+        .parent()
+        .and_then(Path::file_name)
+        .map(|parent| parent.to_string_lossy().into_owned());
+    match (parent, name) {
+        (Some(parent), Some(name)) => format!("{}/{}", parent, name),
+        (None, Some(name)) => name,
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn brackets_in_literals_and_comments_are_skipped() {
+        let text = r#"fn f<'a>(s: &'a str) -> [char; 3] {
+    // {
+    /* { */
+    let _ = "\"{";
+    ['{', '\'', '\\']
+}"#;
+        assert_eq!(balanced_braces(text), Ok(()));
+    }
+
+    #[test]
+    fn mismatched_bracket_names_both_lines() {
+        assert_eq!(
+            balanced_braces("fn f() {\n    (1]\n}"),
+            Err("unbalanced: `]` on line 2 closes `(` of line 2".to_string())
+        );
+        assert_eq!(
+            balanced_braces("{\n)"),
+            Err("unbalanced: `)` on line 2 closes `{` of line 1".to_string())
+        );
+    }
+
+    #[test]
+    fn stray_and_unclosed_brackets_are_reported() {
+        assert_eq!(
+            balanced_braces("let x = 1;\n}"),
+            Err("unbalanced: `}` on line 2 closes nothing".to_string())
+        );
+        assert_eq!(
+            balanced_braces("fn f() {\n    g(\n"),
+            Err("unbalanced: `(` of line 2 is never closed".to_string())
+        );
+    }
+}
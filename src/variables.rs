@@ -0,0 +1,71 @@
+use nvim_oxi::conversion::FromObject;
+use nvim_oxi::{Dictionary, Function, Object};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// Lua functions registered with `setup({ variables = { ... } })`, keyed by the
+// name of their placeholder
+thread_local! {
+    static VARIABLES: RefCell<HashMap<String, Function<Dictionary, Object>>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Replaces the registered variables with the functions of `variables`
+pub fn register(variables: Dictionary) -> nvim_oxi::Result<()> {
+    let mut registered = HashMap::new();
+    for (name, variable) in variables {
+        registered.insert(
+            name.to_string_lossy().into_owned(),
+            Function::<Dictionary, Object>::from_object(variable)?,
+        );
+    }
+
+    VARIABLES.with(|variables| *variables.borrow_mut() = registered);
+    Ok(())
+}
+
+/// Replaces the `{name}` placeholders of registered variables in `text` with
+/// what their function returns when given `context`
+///
+/// Only the variables `text` uses are called, once each. A function may
+/// return a string, a number or `nil` for an empty value; when it fails the
+/// placeholder is left as is.
+pub fn expand(text: &str, context: &Dictionary) -> String {
+    let used: Vec<(String, Function<Dictionary, Object>)> = VARIABLES.with(|variables| {
+        variables
+            .borrow()
+            .iter()
+            .filter(|(name, _)| text.contains(&placeholder(name)))
+            .map(|(name, function)| (name.clone(), function.clone()))
+            .collect()
+    });
+
+    let mut expanded = text.to_string();
+    for (name, function) in used {
+        match function.call(context.clone()).map(render) {
+            Ok(Some(value)) => expanded = expanded.replace(&placeholder(&name), &value),
+            Ok(None) => crate::utils::warn(&format!(
+                "Variable {} returned neither a string nor a number",
+                name
+            )),
+            Err(err) => crate::utils::warn(&format!("Variable {} failed: {}", name, err)),
+        }
+    }
+    expanded
+}
+
+/// `{name}`
+fn placeholder(name: &str) -> String {
+    format!("{{{}}}", name)
+}
+
+/// The text of a value returned by a variable, `None` for tables and functions
+fn render(value: Object) -> Option<String> {
+    if value.is_nil() {
+        return Some(String::new());
+    }
+    String::from_object(value.clone())
+        .ok()
+        .or_else(|| i64::from_object(value.clone()).ok().map(|n| n.to_string()))
+        .or_else(|| f64::from_object(value).ok().map(|n| n.to_string()))
+}
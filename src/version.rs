@@ -0,0 +1,98 @@
+use crate::error::{AichatError, Result};
+use once_cell::sync::OnceCell;
+use std::fmt;
+
+/// An aichat release, as printed by `aichat --version`
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Version(pub u32, pub u32, pub u32);
+
+impl Version {
+    /// Parses output like `aichat 0.25.0`
+    fn parse(output: &str) -> Option<Self> {
+        let version = output
+            .split_whitespace()
+            .find(|word| word.starts_with(|c: char| c.is_ascii_digit()))?;
+
+        // Pre-release and build suffixes don't matter for gating
+        let mut parts = version
+            .split(['-', '+'])
+            .next()?
+            .split('.')
+            .map(|part| part.parse::<u32>().ok());
+        let major = parts.next()??;
+        let minor = parts.next().flatten().unwrap_or(0);
+        let patch = parts.next().flatten().unwrap_or(0);
+        Some(Self(major, minor, patch))
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+/// aichat features that only exist in newer releases
+#[derive(Clone, Copy, Debug)]
+pub enum Capability {
+    /// `--code`, bare code responses
+    CodeFlag,
+    /// `--macro` and `--list-macros`
+    Macros,
+    /// `--agent-variable`
+    AgentVariables,
+}
+
+impl Capability {
+    /// Name of the feature as shown to the user
+    fn name(self) -> &'static str {
+        match self {
+            Capability::CodeFlag => "--code",
+            Capability::Macros => "Macros",
+            Capability::AgentVariables => "Agent variables",
+        }
+    }
+
+    /// First aichat release with the feature
+    fn min_version(self) -> Version {
+        match self {
+            Capability::CodeFlag => Version(0, 8, 0),
+            Capability::Macros => Version(0, 22, 0),
+            Capability::AgentVariables => Version(0, 25, 0),
+        }
+    }
+}
+
+// Global static to store the detected aichat version, `None` if it could not be read
+static DETECTED: OnceCell<Option<Version>> = OnceCell::new();
+
+/// Returns the installed aichat version, running `aichat --version` on first use
+///
+/// Detection failures are not fatal, the features are then assumed to exist
+/// and aichat itself reports what it does not understand
+pub fn detected() -> Option<Version> {
+    *DETECTED.get_or_init(|| {
+        let mut cmd = crate::job_runner::base_command(&crate::config::get_config());
+        let output = cmd.arg("--version").output().ok()?;
+        Version::parse(&String::from_utf8_lossy(&output.stdout))
+    })
+}
+
+/// Whether the installed aichat supports a feature
+pub fn supports(capability: Capability) -> bool {
+    detected().is_none_or(|version| version >= capability.min_version())
+}
+
+/// Fails with a "requires aichat >= X" error when a feature is missing
+pub fn require(capability: Capability) -> Result<()> {
+    match detected() {
+        Some(version) if version < capability.min_version() => {
+            Err(AichatError::UnsupportedVersion {
+                feature: capability.name(),
+                required: capability.min_version().to_string(),
+                found: version.to_string(),
+            })
+        }
+        _ => Ok(()),
+    }
+}